@@ -0,0 +1,907 @@
+//! Agent Extensibility (AgentX) Protocol, RFC 2741
+//!
+//! AgentX lets separate processes, subagents, serve parts of the MIB for a master agent, which
+//! speaks SNMP to the managers. This module has the PDU encoding shared by both ends, over
+//! a Unix domain socket. The master end is in agentx::master, so that the Agent can accept
+//! subagents and serve their subtrees alongside its own. The subagent end is in
+//! agentx::subagent, so that an OidMap can be served through another master, like snmpd.
+//!
+//! Both byte orders are understood. PDUs we send use network byte order, except replies and
+//! requests within a session opened in little endian order, which keep to that order.
+use rasn::types::{Integer, ObjectIdentifier, OctetString};
+use rasn_smi::v2::{
+    ApplicationSyntax, Counter32, Counter64, IpAddress, ObjectSyntax, Opaque, SimpleSyntax,
+    TimeTicks, Unsigned32,
+};
+use rasn_snmp::v3::{VarBind, VarBindValue};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+pub mod master;
+pub mod subagent;
+
+/// Where the master listens, unless configured otherwise.
+pub const DEFAULT_SOCKET: &str = "/var/agentx/master";
+/// Largest PDU accepted, a guard against garbage in the length field.
+const MAX_PDU_LEN: usize = 1 << 20;
+const HEADER_LEN: usize = 20;
+const AGENTX_VERSION: u8 = 1;
+
+/// Header flags, RFC 2741 section 6.1
+pub const INSTANCE_REGISTRATION: u8 = 0x01;
+pub const NEW_INDEX: u8 = 0x02;
+pub const ANY_INDEX: u8 = 0x04;
+pub const NON_DEFAULT_CONTEXT: u8 = 0x08;
+pub const NETWORK_BYTE_ORDER: u8 = 0x10;
+
+/// Response error values, RFC 2741 section 6.2.16, beyond the SNMP error-status values.
+pub const NO_AGENTX_ERROR: u16 = 0;
+pub const OPEN_FAILED: u16 = 256;
+pub const NOT_OPEN: u16 = 257;
+pub const UNSUPPORTED_CONTEXT: u16 = 262;
+pub const DUPLICATE_REGISTRATION: u16 = 263;
+pub const UNKNOWN_REGISTRATION: u16 = 264;
+pub const PARSE_ERROR: u16 = 266;
+pub const REQUEST_DENIED: u16 = 267;
+pub const PROCESSING_ERROR: u16 = 268;
+
+/// Close-PDU reasons, RFC 2741 section 6.2.2
+pub const REASON_OTHER: u8 = 1;
+pub const REASON_PARSE_ERROR: u8 = 2;
+pub const REASON_PROTOCOL_ERROR: u8 = 3;
+pub const REASON_TIMEOUTS: u8 = 4;
+pub const REASON_SHUTDOWN: u8 = 5;
+pub const REASON_BY_MANAGER: u8 = 6;
+
+/// A SearchRange: the start, whether it is included, and the end, if bounded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SearchRange {
+    pub start: ObjectIdentifier,
+    pub include: bool,
+    pub end: Option<ObjectIdentifier>,
+}
+
+/// A subtree, or with range_subid a range of subtrees, as in Register and Unregister PDUs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub priority: u8,
+    pub subtree: ObjectIdentifier,
+    pub range_subid: u8,
+    pub upper_bound: u32,
+}
+
+/// PDU bodies, RFC 2741 section 6.2
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Payload {
+    Open {
+        timeout: u8,
+        id: ObjectIdentifier,
+        descr: Vec<u8>,
+    },
+    Close {
+        reason: u8,
+    },
+    Register {
+        timeout: u8,
+        region: Region,
+    },
+    Unregister {
+        region: Region,
+    },
+    Get(Vec<SearchRange>),
+    GetNext(Vec<SearchRange>),
+    GetBulk {
+        non_repeaters: u16,
+        max_repetitions: u16,
+        ranges: Vec<SearchRange>,
+    },
+    TestSet(Vec<VarBind>),
+    CommitSet,
+    UndoSet,
+    CleanupSet,
+    Notify(Vec<VarBind>),
+    Ping,
+    IndexAllocate(Vec<VarBind>),
+    IndexDeallocate(Vec<VarBind>),
+    AddAgentCaps {
+        id: ObjectIdentifier,
+        descr: Vec<u8>,
+    },
+    RemoveAgentCaps {
+        id: ObjectIdentifier,
+    },
+    Response {
+        sys_up_time: u32,
+        error: u16,
+        index: u16,
+        varbinds: Vec<VarBind>,
+    },
+}
+
+impl Payload {
+    fn pdu_type(&self) -> u8 {
+        match self {
+            Payload::Open { .. } => 1,
+            Payload::Close { .. } => 2,
+            Payload::Register { .. } => 3,
+            Payload::Unregister { .. } => 4,
+            Payload::Get(_) => 5,
+            Payload::GetNext(_) => 6,
+            Payload::GetBulk { .. } => 7,
+            Payload::TestSet(_) => 8,
+            Payload::CommitSet => 9,
+            Payload::UndoSet => 10,
+            Payload::CleanupSet => 11,
+            Payload::Notify(_) => 12,
+            Payload::Ping => 13,
+            Payload::IndexAllocate(_) => 14,
+            Payload::IndexDeallocate(_) => 15,
+            Payload::AddAgentCaps { .. } => 16,
+            Payload::RemoveAgentCaps { .. } => 17,
+            Payload::Response { .. } => 18,
+        }
+    }
+
+    /// True for the PDU types that may carry a context.
+    fn has_context(pdu_type: u8) -> bool {
+        matches!(pdu_type, 3..=8 | 12..=17)
+    }
+}
+
+/// An AgentX PDU, header and body.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AgentxPdu {
+    pub flags: u8,
+    pub session_id: u32,
+    pub transaction_id: u32,
+    pub packet_id: u32,
+    pub context: Option<Vec<u8>>,
+    pub payload: Payload,
+}
+
+impl AgentxPdu {
+    /// A Response to this PDU, in the same byte order and with the same ids.
+    pub fn response(
+        &self,
+        sys_up_time: u32,
+        error: u16,
+        index: u16,
+        varbinds: Vec<VarBind>,
+    ) -> Self {
+        AgentxPdu {
+            flags: self.flags & NETWORK_BYTE_ORDER,
+            session_id: self.session_id,
+            transaction_id: self.transaction_id,
+            packet_id: self.packet_id,
+            context: None,
+            payload: Payload::Response {
+                sys_up_time,
+                error,
+                index,
+                varbinds,
+            },
+        }
+    }
+
+    /// Encode for sending, in the byte order given by the NETWORK_BYTE_ORDER flag.
+    ///
+    /// The context is left out of PDU types that cannot carry one.
+    pub fn encode(&self) -> Vec<u8> {
+        let pdu_type = self.payload.pdu_type();
+        let context = self
+            .context
+            .as_ref()
+            .filter(|_| Payload::has_context(pdu_type));
+        let mut flags = self.flags & !NON_DEFAULT_CONTEXT;
+        if context.is_some() {
+            flags |= NON_DEFAULT_CONTEXT;
+        }
+        let mut body = Writer {
+            buf: vec![],
+            big_endian: flags & NETWORK_BYTE_ORDER != 0,
+        };
+        if let Some(context) = context {
+            body.octets(context);
+        }
+        match &self.payload {
+            Payload::Open { timeout, id, descr } => {
+                body.bytes(&[*timeout, 0, 0, 0]);
+                body.oid(id, false);
+                body.octets(descr);
+            }
+            Payload::Close { reason } => body.bytes(&[*reason, 0, 0, 0]),
+            Payload::Register { timeout, region } => {
+                body.bytes(&[*timeout, region.priority, region.range_subid, 0]);
+                body.oid(&region.subtree, false);
+                if region.range_subid != 0 {
+                    body.u32(region.upper_bound);
+                }
+            }
+            Payload::Unregister { region } => {
+                body.bytes(&[0, region.priority, region.range_subid, 0]);
+                body.oid(&region.subtree, false);
+                if region.range_subid != 0 {
+                    body.u32(region.upper_bound);
+                }
+            }
+            Payload::Get(ranges) | Payload::GetNext(ranges) => body.ranges(ranges),
+            Payload::GetBulk {
+                non_repeaters,
+                max_repetitions,
+                ranges,
+            } => {
+                body.u16(*non_repeaters);
+                body.u16(*max_repetitions);
+                body.ranges(ranges);
+            }
+            Payload::TestSet(varbinds)
+            | Payload::Notify(varbinds)
+            | Payload::IndexAllocate(varbinds)
+            | Payload::IndexDeallocate(varbinds) => body.varbinds(varbinds),
+            Payload::CommitSet | Payload::UndoSet | Payload::CleanupSet | Payload::Ping => (),
+            Payload::AddAgentCaps { id, descr } => {
+                body.oid(id, false);
+                body.octets(descr);
+            }
+            Payload::RemoveAgentCaps { id } => body.oid(id, false),
+            Payload::Response {
+                sys_up_time,
+                error,
+                index,
+                varbinds,
+            } => {
+                body.u32(*sys_up_time);
+                body.u16(*error);
+                body.u16(*index);
+                body.varbinds(varbinds);
+            }
+        }
+        let mut out = Writer {
+            buf: vec![AGENTX_VERSION, pdu_type, flags, 0],
+            big_endian: body.big_endian,
+        };
+        out.u32(self.session_id);
+        out.u32(self.transaction_id);
+        out.u32(self.packet_id);
+        out.u32(body.buf.len() as u32);
+        out.buf.extend_from_slice(&body.buf);
+        out.buf
+    }
+
+    /// Decode a whole PDU, as framed by pdu_len.
+    pub fn decode(buf: &[u8]) -> io::Result<Self> {
+        let (head, body) = buf.split_at_checked(HEADER_LEN).ok_or_else(parse_error)?;
+        if head[0] != AGENTX_VERSION {
+            return Err(parse_error());
+        }
+        let pdu_type = head[1];
+        let flags = head[2];
+        let mut header = Reader {
+            buf: &head[4..],
+            big_endian: flags & NETWORK_BYTE_ORDER != 0,
+        };
+        let session_id = header.u32()?;
+        let transaction_id = header.u32()?;
+        let packet_id = header.u32()?;
+        let mut r = Reader {
+            buf: body,
+            big_endian: header.big_endian,
+        };
+        let context = if flags & NON_DEFAULT_CONTEXT != 0 && Payload::has_context(pdu_type) {
+            Some(r.octets()?)
+        } else {
+            None
+        };
+        let payload = match pdu_type {
+            1 => {
+                let timeout = r.bytes(4)?[0];
+                Payload::Open {
+                    timeout,
+                    id: r.oid()?.0,
+                    descr: r.octets()?,
+                }
+            }
+            2 => Payload::Close {
+                reason: r.bytes(4)?[0],
+            },
+            3 | 4 => {
+                let fixed = r.bytes(4)?;
+                let (timeout, priority, range_subid) = (fixed[0], fixed[1], fixed[2]);
+                let subtree = r.oid()?.0;
+                let upper_bound = if range_subid != 0 { r.u32()? } else { 0 };
+                let region = Region {
+                    priority,
+                    subtree,
+                    range_subid,
+                    upper_bound,
+                };
+                if pdu_type == 3 {
+                    Payload::Register { timeout, region }
+                } else {
+                    Payload::Unregister { region }
+                }
+            }
+            5 => Payload::Get(r.ranges()?),
+            6 => Payload::GetNext(r.ranges()?),
+            7 => Payload::GetBulk {
+                non_repeaters: r.u16()?,
+                max_repetitions: r.u16()?,
+                ranges: r.ranges()?,
+            },
+            8 => Payload::TestSet(r.varbinds()?),
+            9 => Payload::CommitSet,
+            10 => Payload::UndoSet,
+            11 => Payload::CleanupSet,
+            12 => Payload::Notify(r.varbinds()?),
+            13 => Payload::Ping,
+            14 => Payload::IndexAllocate(r.varbinds()?),
+            15 => Payload::IndexDeallocate(r.varbinds()?),
+            16 => Payload::AddAgentCaps {
+                id: r.oid()?.0,
+                descr: r.octets()?,
+            },
+            17 => Payload::RemoveAgentCaps { id: r.oid()?.0 },
+            18 => Payload::Response {
+                sys_up_time: r.u32()?,
+                error: r.u16()?,
+                index: r.u16()?,
+                varbinds: r.varbinds()?,
+            },
+            _ => return Err(parse_error()),
+        };
+        Ok(AgentxPdu {
+            flags,
+            session_id,
+            transaction_id,
+            packet_id,
+            context,
+            payload,
+        })
+    }
+}
+
+fn parse_error() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "AgentX parse error")
+}
+
+/// Length of the PDU at the start of buf, header included, or None if the header is incomplete.
+pub fn pdu_len(buf: &[u8]) -> io::Result<Option<usize>> {
+    let Some(head) = buf.get(..HEADER_LEN) else {
+        return Ok(None);
+    };
+    let len_bytes: [u8; 4] = head[16..20].try_into().unwrap();
+    let len = if head[2] & NETWORK_BYTE_ORDER != 0 {
+        u32::from_be_bytes(len_bytes)
+    } else {
+        u32::from_le_bytes(len_bytes)
+    } as usize;
+    if head[0] != AGENTX_VERSION || !len.is_multiple_of(4) || len > MAX_PDU_LEN {
+        return Err(parse_error());
+    }
+    Ok(Some(HEADER_LEN + len))
+}
+
+/// Take the complete PDUs from the front of buf, leaving any partial one.
+pub fn split_pdus(buf: &mut Vec<u8>) -> io::Result<Vec<AgentxPdu>> {
+    let mut pdus = vec![];
+    while let Some(len) = pdu_len(buf)? {
+        if buf.len() < len {
+            break;
+        }
+        pdus.push(AgentxPdu::decode(&buf[..len])?);
+        buf.drain(..len);
+    }
+    Ok(pdus)
+}
+
+/// A Response's error, index and varbinds.
+pub type ResponseFields = (u16, u16, Vec<VarBind>);
+
+/// A connection carrying AgentX PDUs, used by both ends.
+///
+/// The stream is left in blocking mode. PDUs that arrive while waiting for a Response
+/// are queued in pending, for the caller to handle afterwards.
+pub(crate) struct Channel {
+    pub(crate) stream: UnixStream,
+    buf: Vec<u8>,
+    pub(crate) pending: VecDeque<AgentxPdu>,
+    next_packet: u32,
+}
+
+impl Channel {
+    pub(crate) fn new(stream: UnixStream) -> Self {
+        Channel {
+            stream,
+            buf: vec![],
+            pending: VecDeque::new(),
+            next_packet: 0,
+        }
+    }
+
+    /// A fresh packetID for a request.
+    pub(crate) fn packet_id(&mut self) -> u32 {
+        self.next_packet = self.next_packet.wrapping_add(1);
+        self.next_packet
+    }
+
+    pub(crate) fn send(&mut self, pdu: &AgentxPdu) -> io::Result<()> {
+        self.stream.write_all(&pdu.encode())
+    }
+
+    /// Send a request and wait up to timeout for its Response.
+    pub(crate) fn request(
+        &mut self,
+        pdu: &AgentxPdu,
+        timeout: Duration,
+    ) -> io::Result<ResponseFields> {
+        match self.request_pdu(pdu, timeout)?.payload {
+            Payload::Response {
+                error,
+                index,
+                varbinds,
+                ..
+            } => Ok((error, index, varbinds)),
+            _ => unreachable!("request_pdu only returns Responses"),
+        }
+    }
+
+    /// Send a request and wait up to timeout for the Response with the same ids.
+    ///
+    /// The Response to an Open has the new sessionID. Responses to earlier requests,
+    /// which gave up waiting, are dropped.
+    pub(crate) fn request_pdu(
+        &mut self,
+        pdu: &AgentxPdu,
+        timeout: Duration,
+    ) -> io::Result<AgentxPdu> {
+        let is_open = matches!(pdu.payload, Payload::Open { .. });
+        self.send(pdu)?;
+        let deadline = Instant::now() + timeout;
+        let mut chunk = [0; 4096];
+        loop {
+            let mut reply = None;
+            for received in split_pdus(&mut self.buf)? {
+                match received.payload {
+                    Payload::Response { .. }
+                        if received.packet_id == pdu.packet_id
+                            && (is_open || received.session_id == pdu.session_id) =>
+                    {
+                        reply = Some(received)
+                    }
+                    Payload::Response { .. } => {
+                        log::debug!("Late AgentX response {0}", received.packet_id)
+                    }
+                    _ => self.pending.push_back(received),
+                }
+            }
+            if let Some(reply) = reply {
+                return Ok(reply);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.stream.set_read_timeout(Some(left))?;
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(amt) => self.buf.extend_from_slice(&chunk[..amt]),
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Read whatever has arrived, without waiting, and queue the PDUs.
+    ///
+    /// The end of the stream is an UnexpectedEof error, after queueing any complete PDUs.
+    pub(crate) fn read_available(&mut self) -> io::Result<()> {
+        let mut chunk = [0; 4096];
+        self.stream.set_nonblocking(true)?;
+        let mut result = Ok(());
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    result = Err(io::ErrorKind::UnexpectedEof.into());
+                    break;
+                }
+                Ok(amt) => self.buf.extend_from_slice(&chunk[..amt]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        self.stream.set_nonblocking(false)?;
+        let pdus = split_pdus(&mut self.buf)?;
+        self.pending.extend(pdus);
+        result
+    }
+}
+
+/// Null OID, as used for an unbounded SearchRange end.
+fn null_oid() -> ObjectIdentifier {
+    ObjectIdentifier::new_unchecked(Vec::<u32>::new().into())
+}
+
+/// Internet prefix 1.3.6.1, which OIDs may abbreviate, RFC 2741 section 5.1
+const INTERNET: [u32; 4] = [1, 3, 6, 1];
+
+struct Writer {
+    buf: Vec<u8>,
+    big_endian: bool,
+}
+
+impl Writer {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    fn u16(&mut self, val: u16) {
+        if self.big_endian {
+            self.bytes(&val.to_be_bytes())
+        } else {
+            self.bytes(&val.to_le_bytes())
+        }
+    }
+
+    fn u32(&mut self, val: u32) {
+        if self.big_endian {
+            self.bytes(&val.to_be_bytes())
+        } else {
+            self.bytes(&val.to_le_bytes())
+        }
+    }
+
+    fn u64(&mut self, val: u64) {
+        if self.big_endian {
+            self.bytes(&val.to_be_bytes())
+        } else {
+            self.bytes(&val.to_le_bytes())
+        }
+    }
+
+    fn octets(&mut self, octets: &[u8]) {
+        self.u32(octets.len() as u32);
+        self.bytes(octets);
+        let pad = (4 - octets.len() % 4) % 4;
+        self.bytes(&[0; 3][..pad]);
+    }
+
+    fn oid(&mut self, oid: &ObjectIdentifier, include: bool) {
+        let (prefix, subids) = match oid.get(4) {
+            Some(&prefix) if oid.starts_with(&INTERNET) && (1..=255).contains(&prefix) => {
+                (prefix as u8, &oid[5..])
+            }
+            _ => (0, &oid[..]),
+        };
+        self.bytes(&[subids.len() as u8, prefix, u8::from(include), 0]);
+        for subid in subids {
+            self.u32(*subid);
+        }
+    }
+
+    fn ranges(&mut self, ranges: &[SearchRange]) {
+        for range in ranges {
+            self.oid(&range.start, range.include);
+            self.oid(range.end.as_ref().unwrap_or(&null_oid()), false);
+        }
+    }
+
+    fn varbinds(&mut self, varbinds: &[VarBind]) {
+        for varbind in varbinds {
+            let type_pos = self.buf.len();
+            self.u16(0);
+            self.u16(0);
+            self.oid(&varbind.name, false);
+            let vtype = match &varbind.value {
+                VarBindValue::Unspecified => 5,
+                VarBindValue::NoSuchObject => 128,
+                VarBindValue::NoSuchInstance => 129,
+                VarBindValue::EndOfMibView => 130,
+                VarBindValue::Value(ObjectSyntax::Simple(simple)) => match simple {
+                    SimpleSyntax::Integer(int) => {
+                        let int: i32 = int.try_into().unwrap_or_default();
+                        self.u32(int as u32);
+                        2
+                    }
+                    SimpleSyntax::String(octets) => {
+                        self.octets(octets);
+                        4
+                    }
+                    SimpleSyntax::ObjectId(oid) => {
+                        self.oid(oid, false);
+                        6
+                    }
+                },
+                VarBindValue::Value(ObjectSyntax::ApplicationWide(app)) => match app {
+                    ApplicationSyntax::Address(addr) => {
+                        self.octets(&addr.0[..]);
+                        64
+                    }
+                    ApplicationSyntax::Counter(counter) => {
+                        self.u32(counter.0);
+                        65
+                    }
+                    ApplicationSyntax::Unsigned(gauge) => {
+                        self.u32(gauge.0);
+                        66
+                    }
+                    ApplicationSyntax::Ticks(ticks) => {
+                        self.u32(ticks.0);
+                        67
+                    }
+                    ApplicationSyntax::Arbitrary(opaque) => {
+                        self.octets(opaque.as_ref());
+                        68
+                    }
+                    ApplicationSyntax::BigCounter(counter) => {
+                        self.u64(counter.0);
+                        70
+                    }
+                },
+            };
+            let vtype: u16 = vtype;
+            let type_bytes = if self.big_endian {
+                vtype.to_be_bytes()
+            } else {
+                vtype.to_le_bytes()
+            };
+            self.buf[type_pos..type_pos + 2].copy_from_slice(&type_bytes);
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let (bytes, rest) = self.buf.split_at_checked(len).ok_or_else(parse_error)?;
+        self.buf = rest;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes: [u8; 2] = self.bytes(2)?.try_into().unwrap();
+        Ok(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes: [u8; 4] = self.bytes(4)?.try_into().unwrap();
+        Ok(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let bytes: [u8; 8] = self.bytes(8)?.try_into().unwrap();
+        Ok(if self.big_endian {
+            u64::from_be_bytes(bytes)
+        } else {
+            u64::from_le_bytes(bytes)
+        })
+    }
+
+    fn octets(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        let octets = self.bytes(len)?.to_vec();
+        self.bytes((4 - len % 4) % 4)?;
+        Ok(octets)
+    }
+
+    /// An OID and its include flag.
+    fn oid(&mut self) -> io::Result<(ObjectIdentifier, bool)> {
+        let fixed = self.bytes(4)?;
+        let (n_subid, prefix, include) = (fixed[0], fixed[1], fixed[2] != 0);
+        let mut arcs = vec![];
+        if prefix != 0 {
+            arcs.extend_from_slice(&INTERNET);
+            arcs.push(u32::from(prefix));
+        }
+        for _ in 0..n_subid {
+            arcs.push(self.u32()?);
+        }
+        Ok((ObjectIdentifier::new_unchecked(arcs.into()), include))
+    }
+
+    fn ranges(&mut self) -> io::Result<Vec<SearchRange>> {
+        let mut ranges = vec![];
+        while !self.buf.is_empty() {
+            let (start, include) = self.oid()?;
+            let (end, _) = self.oid()?;
+            ranges.push(SearchRange {
+                start,
+                include,
+                end: Some(end).filter(|end| !end.is_empty()),
+            });
+        }
+        Ok(ranges)
+    }
+
+    fn varbinds(&mut self) -> io::Result<Vec<VarBind>> {
+        let mut varbinds = vec![];
+        while !self.buf.is_empty() {
+            let vtype = self.u16()?;
+            self.u16()?;
+            let (name, _) = self.oid()?;
+            let simple = |simple| VarBindValue::Value(ObjectSyntax::Simple(simple));
+            let app = |app| VarBindValue::Value(ObjectSyntax::ApplicationWide(app));
+            let value = match vtype {
+                2 => simple(SimpleSyntax::Integer(Integer::from(self.u32()? as i32))),
+                4 => simple(SimpleSyntax::String(OctetString::from(self.octets()?))),
+                5 => VarBindValue::Unspecified,
+                6 => simple(SimpleSyntax::ObjectId(self.oid()?.0)),
+                64 => {
+                    let addr: [u8; 4] = self.octets()?.try_into().map_err(|_| parse_error())?;
+                    app(ApplicationSyntax::Address(IpAddress { 0: addr.into() }))
+                }
+                65 => app(ApplicationSyntax::Counter(Counter32 { 0: self.u32()? })),
+                66 => app(ApplicationSyntax::Unsigned(Unsigned32 { 0: self.u32()? })),
+                67 => app(ApplicationSyntax::Ticks(TimeTicks { 0: self.u32()? })),
+                68 => app(ApplicationSyntax::Arbitrary(opaque(self.octets()?)?)),
+                70 => app(ApplicationSyntax::BigCounter(Counter64(self.u64()?))),
+                128 => VarBindValue::NoSuchObject,
+                129 => VarBindValue::NoSuchInstance,
+                130 => VarBindValue::EndOfMibView,
+                _ => return Err(parse_error()),
+            };
+            varbinds.push(VarBind { name, value });
+        }
+        Ok(varbinds)
+    }
+}
+
+/// Opaque holding raw bytes, which rasn only builds by decoding.
+fn opaque(bytes: Vec<u8>) -> io::Result<Opaque> {
+    let mut ber = rasn::ber::encode(&OctetString::from(bytes)).map_err(|_| parse_error())?;
+    // Application 4, primitive, in place of the universal OCTET STRING tag
+    ber[0] = 0x44;
+    rasn::ber::decode(&ber).map_err(|_| parse_error())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oid(arcs: &[u32]) -> ObjectIdentifier {
+        ObjectIdentifier::new_unchecked(arcs.to_vec().into())
+    }
+
+    fn all_types() -> Vec<VarBind> {
+        let app = |app| VarBindValue::Value(ObjectSyntax::ApplicationWide(app));
+        let values = vec![
+            VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(
+                -5,
+            )))),
+            VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::String(
+                OctetString::from_static(b"hello"),
+            ))),
+            VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::ObjectId(oid(&[
+                1, 3, 6, 1, 4, 1, 99,
+            ])))),
+            app(ApplicationSyntax::Address(IpAddress {
+                0: [192, 0, 2, 1].into(),
+            })),
+            app(ApplicationSyntax::Counter(Counter32 { 0: 7 })),
+            app(ApplicationSyntax::Unsigned(Unsigned32 { 0: 8 })),
+            app(ApplicationSyntax::Ticks(TimeTicks { 0: 9 })),
+            app(ApplicationSyntax::Arbitrary(opaque(vec![1, 2, 3]).unwrap())),
+            app(ApplicationSyntax::BigCounter(Counter64(1 << 40))),
+            VarBindValue::Unspecified,
+            VarBindValue::NoSuchObject,
+            VarBindValue::NoSuchInstance,
+            VarBindValue::EndOfMibView,
+        ];
+        (1..)
+            .zip(values)
+            .map(|(n, value)| VarBind {
+                name: oid(&[1, 3, 6, 1, 4, 1, 99, n]),
+                value,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for flags in [0, NETWORK_BYTE_ORDER] {
+            let pdus = [
+                Payload::Open {
+                    timeout: 5,
+                    id: oid(&[1, 3, 6, 1, 4, 1, 99]),
+                    descr: b"test subagent".to_vec(),
+                },
+                Payload::Register {
+                    timeout: 0,
+                    region: Region {
+                        priority: 127,
+                        subtree: oid(&[1, 3, 6, 1, 2, 1, 2, 2, 1, 1]),
+                        range_subid: 10,
+                        upper_bound: 22,
+                    },
+                },
+                Payload::GetBulk {
+                    non_repeaters: 1,
+                    max_repetitions: 10,
+                    ranges: vec![SearchRange {
+                        start: oid(&[1, 3, 6, 1, 4, 1, 99]),
+                        include: true,
+                        end: Some(oid(&[1, 3, 6, 1, 4, 1, 100])),
+                    }],
+                },
+                Payload::TestSet(all_types()),
+                Payload::Response {
+                    sys_up_time: 1234,
+                    error: NOT_OPEN,
+                    index: 2,
+                    varbinds: all_types(),
+                },
+            ];
+            for payload in pdus {
+                let pdu = AgentxPdu {
+                    flags,
+                    session_id: 1,
+                    transaction_id: 2,
+                    packet_id: 3,
+                    context: Some(b"vrf1".to_vec())
+                        .filter(|_| Payload::has_context(payload.pdu_type())),
+                    payload,
+                };
+                let mut buf = pdu.encode();
+                assert_eq!(pdu_len(&buf).unwrap(), Some(buf.len()));
+                let mut decoded = AgentxPdu::decode(&buf).unwrap();
+                decoded.flags &= !NON_DEFAULT_CONTEXT;
+                assert_eq!(decoded, pdu);
+                // Framing across partial reads
+                let tail = buf.split_off(7);
+                assert!(split_pdus(&mut buf).unwrap().is_empty());
+                buf.extend_from_slice(&tail);
+                buf.extend_from_slice(&tail[..3]);
+                assert_eq!(split_pdus(&mut buf).unwrap().len(), 1);
+                assert_eq!(buf.len(), 3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        // RFC 2741 section 5.1 example, 1.3.6.1.2.1.1.1.0 with the internet prefix
+        let pdu = AgentxPdu {
+            flags: NETWORK_BYTE_ORDER,
+            session_id: 0,
+            transaction_id: 0,
+            packet_id: 0,
+            context: None,
+            payload: Payload::RemoveAgentCaps {
+                id: oid(&[1, 3, 6, 1, 2, 1, 1, 1, 0]),
+            },
+        };
+        let buf = pdu.encode();
+        assert_eq!(&buf[..4], &[1, 17, NETWORK_BYTE_ORDER, 0]);
+        assert_eq!(&buf[16..20], &[0, 0, 0, 20]);
+        assert_eq!(&buf[20..24], &[4, 2, 0, 0]);
+        assert_eq!(&buf[24..28], &[0, 0, 0, 1]);
+        // Truncated or bad version
+        assert!(AgentxPdu::decode(&buf[..buf.len() - 4]).is_err());
+        let mut bad = buf.clone();
+        bad[0] = 2;
+        assert!(pdu_len(&bad).is_err());
+    }
+}
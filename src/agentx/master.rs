@@ -0,0 +1,819 @@
+//! AgentX master agent, RFC 2741
+//!
+//! Subagents connect to a Unix domain socket, open sessions and register subtrees.
+//! Each registered subtree goes into the OidMap as a ProxyKeeper, which forwards the
+//! keeper calls to the subagent, so the Agent serves it like any other keeper.
+//!
+//! Get becomes an AgentX Get. GetNext, and the repetitions of GetBulk, become AgentX
+//! GetNext PDUs bounded by the end of the subtree, so the walk moves on to the next
+//! keeper when the subagent has nothing more, as RFC 2741 section 7.2.1.2 allows.
+//! A Set is TestSet, then CommitSet, UndoSet if a later keeper fails to commit,
+//! and CleanupSet. Every set varbind of a session goes in one TestSet, however many
+//! of its subtrees are involved.
+//!
+//! Registrations only go in the default context, and may not overlap each other or the
+//! agent's own keepers, as OidMap lookups assume disjoint subtrees. Priorities are not
+//! used, for the same reason. A range registration is served as a subtree per value
+//! in the range. When a session closes, or the subagent disconnects, its subtrees are
+//! removed from the OidMap.
+//!
+//! The master reads the socket in between SNMP messages. Requests to a subagent
+//! wait for its Response, for the region's or session's timeout, or 5 seconds.
+//! Other PDUs that arrive meanwhile are queued, and handled by the next serve.
+//! After a timeout the session's requests fail at once with genErr, rather than
+//! waiting again, until the subagent sends something in that session.
+use super::{
+    AgentxPdu, Channel, Payload, Region, ResponseFields, SearchRange, DUPLICATE_REGISTRATION,
+    INSTANCE_REGISTRATION, NETWORK_BYTE_ORDER, NOT_OPEN, NO_AGENTX_ERROR, PARSE_ERROR,
+    PROCESSING_ERROR, REASON_PARSE_ERROR, REQUEST_DENIED, UNKNOWN_REGISTRATION,
+    UNSUPPORTED_CONTEXT,
+};
+use crate::keeper::{Access, OidErr, OidKeeper};
+use crate::oidmap::OidMap;
+use log::{debug, info, warn};
+use rasn::types::ObjectIdentifier;
+use rasn_snmp::v3::{VarBind, VarBindValue};
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Wait for a Response, unless the subagent asked for another timeout.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most subtrees a range registration may expand to.
+const MAX_RANGE: u32 = 256;
+
+/// A Set in progress for one session.
+#[derive(Default)]
+struct Transaction {
+    id: u32,
+    varbinds: Vec<VarBind>,
+    /// Outcome of the TestSet, with the name of the failing varbind
+    tested: Option<Result<(), (OidErr, Option<ObjectIdentifier>)>>,
+    committed: Option<Result<(), OidErr>>,
+    undone: bool,
+}
+
+/// An open session, RFC 2741 section 7.1.1
+struct Session {
+    id: u32,
+    timeout: Duration,
+    network_order: bool,
+    transaction: Option<Transaction>,
+    /// A request timed out, and the subagent has not been heard from since
+    timed_out: bool,
+}
+
+/// A subagent's connection, shared between the Master and the ProxyKeepers of its sessions.
+struct Connection {
+    id: u64,
+    channel: Channel,
+    sessions: Vec<Session>,
+    next_transaction: u32,
+    closed: bool,
+}
+
+impl Connection {
+    fn session(&mut self, session_id: u32) -> Option<&mut Session> {
+        self.sessions
+            .iter_mut()
+            .find(|session| session.id == session_id)
+    }
+
+    /// A request in the session's byte order.
+    fn pdu(
+        &mut self,
+        session_id: u32,
+        transaction_id: u32,
+        payload: Payload,
+    ) -> Result<AgentxPdu, OidErr> {
+        if self.closed {
+            return Err(OidErr::NoSuchName);
+        }
+        let session = self.session(session_id).ok_or(OidErr::NoSuchName)?;
+        let flags = if session.network_order {
+            NETWORK_BYTE_ORDER
+        } else {
+            0
+        };
+        Ok(AgentxPdu {
+            flags,
+            session_id,
+            transaction_id,
+            packet_id: self.channel.packet_id(),
+            context: None,
+            payload,
+        })
+    }
+
+    /// Send a PDU that has no Response.
+    fn send(
+        &mut self,
+        session_id: u32,
+        transaction_id: u32,
+        payload: Payload,
+    ) -> Result<(), OidErr> {
+        let pdu = self.pdu(session_id, transaction_id, payload)?;
+        self.channel.send(&pdu).map_err(|err| {
+            warn!("AgentX write failed {err}");
+            self.closed = true;
+            OidErr::GenErr
+        })
+    }
+
+    /// Send a PDU and wait for its Response, returning the error, index and varbinds.
+    ///
+    /// A timeout is genErr, RFC 2741 section 7.2.5, as is any request to a session
+    /// that has timed out since. Other failures close the connection.
+    fn request(
+        &mut self,
+        session_id: u32,
+        transaction_id: u32,
+        payload: Payload,
+        timeout: Duration,
+    ) -> Result<ResponseFields, OidErr> {
+        if self
+            .session(session_id)
+            .is_some_and(|session| session.timed_out)
+        {
+            return Err(OidErr::GenErr);
+        }
+        let pdu = self.pdu(session_id, transaction_id, payload)?;
+        self.channel.request(&pdu, timeout).map_err(|err| {
+            if err.kind() == io::ErrorKind::TimedOut {
+                warn!("AgentX session {session_id} timed out");
+                if let Some(session) = self.session(session_id) {
+                    session.timed_out = true;
+                }
+            } else {
+                warn!("Closing AgentX connection: {err}");
+                self.closed = true;
+            }
+            OidErr::GenErr
+        })
+    }
+
+    /// Read whatever has arrived, without waiting, queueing the PDUs.
+    fn read_pdus(&mut self) {
+        match self.channel.read_available() {
+            Ok(()) => (),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                warn!("Closing AgentX connection: {err}");
+                // Best effort, the sessions are going anyway
+                let close = AgentxPdu {
+                    flags: NETWORK_BYTE_ORDER,
+                    session_id: 0,
+                    transaction_id: 0,
+                    packet_id: 0,
+                    context: None,
+                    payload: Payload::Close {
+                        reason: REASON_PARSE_ERROR,
+                    },
+                };
+                let _ = self.channel.send(&close);
+                self.closed = true;
+            }
+            Err(_) => self.closed = true,
+        }
+    }
+}
+
+/// Translate the error in an AgentX Response to an OidErr.
+fn response_err(error: u16) -> OidErr {
+    OidErr::from_error_status(error.into())
+}
+
+/// Forwards the keeper calls for one registered subtree to the subagent's session.
+struct ProxyKeeper {
+    conn: Arc<Mutex<Connection>>,
+    session_id: u32,
+    subtree: ObjectIdentifier,
+    instance: bool,
+    timeout: Option<Duration>,
+}
+
+impl ProxyKeeper {
+    /// First OID after the subtree, the end of GetNext search ranges.
+    fn end(&self) -> Option<ObjectIdentifier> {
+        let mut arcs = self.subtree.to_vec();
+        let last = arcs.last_mut()?;
+        *last = last.checked_add(1)?;
+        Some(ObjectIdentifier::new_unchecked(arcs.into()))
+    }
+
+    /// Send a PDU within the session's current transaction, waiting for the Response.
+    fn request(&self, payload: Payload) -> Result<ResponseFields, OidErr> {
+        let mut conn = self.conn.lock().unwrap();
+        let session = conn.session(self.session_id).ok_or(OidErr::NoSuchName)?;
+        let timeout = self.timeout.unwrap_or(session.timeout);
+        let transaction_id = session.transaction.as_ref().map_or(0, |trans| trans.id);
+        conn.request(self.session_id, transaction_id, payload, timeout)
+    }
+
+    /// Apply f to the session's transaction, which must have begun.
+    fn with_transaction<T>(
+        &self,
+        f: impl FnOnce(&mut Transaction) -> Result<T, OidErr>,
+    ) -> Result<T, OidErr> {
+        let mut conn = self.conn.lock().unwrap();
+        let session = conn.session(self.session_id).ok_or(OidErr::NoSuchName)?;
+        f(session.transaction.as_mut().ok_or(OidErr::GenErr)?)
+    }
+}
+
+impl OidKeeper for ProxyKeeper {
+    /// Instance registrations behave as scalars, so GetNext reaches the instance itself.
+    fn is_scalar(&self, _oid: ObjectIdentifier) -> bool {
+        self.instance
+    }
+
+    fn get(&self, oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
+        let range = SearchRange {
+            start: oid,
+            include: false,
+            end: None,
+        };
+        let (error, _, mut varbinds) = self.request(Payload::Get(vec![range]))?;
+        if error != NO_AGENTX_ERROR {
+            return Err(response_err(error));
+        }
+        varbinds.pop().map(|bind| bind.value).ok_or(OidErr::GenErr)
+    }
+
+    fn get_next(&self, oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
+        let range = SearchRange {
+            start: oid.clone(),
+            include: false,
+            end: self.end(),
+        };
+        let (error, _, mut varbinds) = self.request(Payload::GetNext(vec![range]))?;
+        if error != NO_AGENTX_ERROR {
+            return Err(response_err(error));
+        }
+        match varbinds.pop() {
+            // Anything outside the subtree is as good as the end of it
+            Some(bind) if bind.name > oid && bind.name.starts_with(&self.subtree) => Ok(bind),
+            Some(_) => Ok(VarBind {
+                name: oid,
+                value: VarBindValue::EndOfMibView,
+            }),
+            None => Err(OidErr::GenErr),
+        }
+    }
+
+    /// The subagent decides, when it gets the TestSet.
+    fn access(&self, _oid: ObjectIdentifier) -> Access {
+        Access::ReadWrite
+    }
+
+    /// Start the session's transaction, unless another of its subtrees already has.
+    fn begin_transaction(&mut self) -> Result<(), OidErr> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.next_transaction = conn.next_transaction.wrapping_add(1);
+        let id = conn.next_transaction;
+        let session = conn.session(self.session_id).ok_or(OidErr::NoSuchName)?;
+        session.transaction.get_or_insert_with(|| Transaction {
+            id,
+            ..Default::default()
+        });
+        Ok(())
+    }
+
+    fn set(&mut self, oid: ObjectIdentifier, value: VarBindValue) -> Result<VarBindValue, OidErr> {
+        self.with_transaction(|trans| {
+            trans.varbinds.push(VarBind {
+                name: oid,
+                value: value.clone(),
+            });
+            Ok(value)
+        })
+    }
+
+    /// The first test sends a TestSet with every varbind of the session.
+    ///
+    /// The error is then reported against the varbind the subagent named.
+    fn test(&self, oid: ObjectIdentifier, _value: &VarBindValue) -> Result<(), OidErr> {
+        let tested = self.with_transaction(|trans| Ok(trans.tested.clone()))?;
+        let tested = match tested {
+            Some(tested) => tested,
+            None => {
+                let varbinds = self.with_transaction(|trans| Ok(trans.varbinds.clone()))?;
+                let tested = match self.request(Payload::TestSet(varbinds.clone())) {
+                    Ok((NO_AGENTX_ERROR, _, _)) => Ok(()),
+                    Ok((error, index, _)) => {
+                        let name = usize::from(index)
+                            .checked_sub(1)
+                            .and_then(|idx| varbinds.get(idx))
+                            .map(|bind| bind.name.clone());
+                        Err((response_err(error), name))
+                    }
+                    Err(err) => Err((err, None)),
+                };
+                self.with_transaction(|trans| {
+                    trans.tested = Some(tested.clone());
+                    Ok(())
+                })?;
+                tested
+            }
+        };
+        match tested {
+            Err((err, None)) => Err(err),
+            Err((err, Some(name))) if name == oid => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// The first commit sends the CommitSet, for every subtree of the session.
+    fn commit(&mut self) -> Result<(), OidErr> {
+        if let Some(committed) = self.with_transaction(|trans| Ok(trans.committed))? {
+            return committed;
+        }
+        let committed = match self.request(Payload::CommitSet) {
+            Ok((NO_AGENTX_ERROR, _, _)) => Ok(()),
+            Ok((error, _, _)) => Err(response_err(error)),
+            Err(err) => Err(err),
+        };
+        self.with_transaction(|trans| {
+            trans.committed = Some(committed);
+            Ok(())
+        })?;
+        committed
+    }
+
+    /// Nothing has changed before the commit, the CleanupSet is sent by cleanup.
+    fn rollback(&mut self) -> Result<(), OidErr> {
+        Ok(())
+    }
+
+    fn undo(&mut self) -> Result<(), OidErr> {
+        if self.with_transaction(|trans| Ok(std::mem::replace(&mut trans.undone, true)))? {
+            return Ok(());
+        }
+        match self.request(Payload::UndoSet)? {
+            (NO_AGENTX_ERROR, _, _) => Ok(()),
+            (error, _, _) => Err(response_err(error)),
+        }
+    }
+
+    /// End the transaction, with a CleanupSet if the subagent has seen the TestSet.
+    fn cleanup(&mut self) {
+        let mut conn = self.conn.lock().unwrap();
+        let Some(session) = conn.session(self.session_id) else {
+            return;
+        };
+        let Some(trans) = session.transaction.take() else {
+            return;
+        };
+        if trans.tested.is_some() {
+            // There is no Response to a CleanupSet
+            let _ = conn.send(self.session_id, trans.id, Payload::CleanupSet);
+        }
+    }
+}
+
+/// A registration, and the subtrees it put in the OidMap.
+struct Registration {
+    conn_id: u64,
+    session_id: u32,
+    region: Region,
+    subtrees: Vec<ObjectIdentifier>,
+}
+
+/// AgentX master, listening for subagents on a Unix domain socket.
+pub struct Master {
+    listener: UnixListener,
+    path: PathBuf,
+    connections: Vec<Arc<Mutex<Connection>>>,
+    registrations: Vec<Registration>,
+    next_connection: u64,
+    next_session: u32,
+}
+
+impl Master {
+    /// Listen on the socket at path, replacing a socket left over from an earlier run.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        Ok(Master {
+            listener: UnixListener::bind(path)?,
+            path: path.to_path_buf(),
+            connections: vec![],
+            registrations: vec![],
+            next_connection: 0,
+            next_session: 0,
+        })
+    }
+
+    /// The socket path.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// File descriptors to poll, the listener then each connection.
+    pub fn fds(&self) -> Vec<RawFd> {
+        let conns = self
+            .connections
+            .iter()
+            .map(|conn| conn.lock().unwrap().channel.stream.as_raw_fd());
+        std::iter::once(self.listener.as_raw_fd())
+            .chain(conns)
+            .collect()
+    }
+
+    /// True if PDUs are queued, so serve should be called without waiting.
+    pub fn has_pending(&self) -> bool {
+        self.connections
+            .iter()
+            .any(|conn| !conn.lock().unwrap().channel.pending.is_empty())
+    }
+
+    /// Number of registered subtrees.
+    pub fn registrations(&self) -> usize {
+        self.registrations.len()
+    }
+
+    /// Handle activity on the fds that are ready, by index into fds, and any queued PDUs.
+    ///
+    /// Registrations go into oid_map, which must be the sorted default context.
+    /// sys_up_time, in hundredths of a second, goes in the Responses.
+    /// Returns the number of PDUs handled.
+    pub fn serve(&mut self, ready: &[usize], oid_map: &mut OidMap, sys_up_time: u32) -> usize {
+        for &idx in ready {
+            if idx == 0 {
+                self.accept();
+            } else if let Some(conn) = self.connections.get(idx - 1) {
+                conn.lock().unwrap().read_pdus();
+            }
+        }
+        let mut count = 0;
+        for conn in self.connections.clone() {
+            loop {
+                let Some(pdu) = conn.lock().unwrap().channel.pending.pop_front() else {
+                    break;
+                };
+                count += 1;
+                let reply = self.handle(&conn, pdu, oid_map, sys_up_time);
+                if let Some(reply) = reply {
+                    let mut conn = conn.lock().unwrap();
+                    if conn.channel.send(&reply).is_err() {
+                        conn.closed = true;
+                    }
+                }
+            }
+        }
+        let (closed, open): (Vec<_>, Vec<_>) = std::mem::take(&mut self.connections)
+            .into_iter()
+            .partition(|conn| conn.lock().unwrap().closed);
+        self.connections = open;
+        for conn in closed {
+            let conn_id = conn.lock().unwrap().id;
+            info!("AgentX subagent {conn_id} disconnected");
+            self.unregister(oid_map, |reg| reg.conn_id == conn_id);
+        }
+        count
+    }
+
+    fn accept(&mut self) {
+        match self.listener.accept() {
+            Ok((stream, _)) => {
+                self.next_connection += 1;
+                debug!("AgentX subagent {0} connected", self.next_connection);
+                self.connections.push(Arc::new(Mutex::new(Connection {
+                    id: self.next_connection,
+                    channel: Channel::new(stream),
+                    sessions: vec![],
+                    next_transaction: 0,
+                    closed: false,
+                })));
+            }
+            Err(err) => warn!("AgentX accept failed {err}"),
+        }
+    }
+
+    /// Remove the matching registrations and their subtrees, returning how many there were.
+    fn unregister(
+        &mut self,
+        oid_map: &mut OidMap,
+        matches: impl Fn(&Registration) -> bool,
+    ) -> usize {
+        let (gone, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.registrations)
+            .into_iter()
+            .partition(|reg| matches(reg));
+        self.registrations = kept;
+        for reg in &gone {
+            for subtree in &reg.subtrees {
+                oid_map.remove(subtree);
+            }
+        }
+        gone.len()
+    }
+
+    /// Handle a PDU from a subagent, returning the Response to send, if any.
+    fn handle(
+        &mut self,
+        conn: &Arc<Mutex<Connection>>,
+        pdu: AgentxPdu,
+        oid_map: &mut OidMap,
+        sys_up_time: u32,
+    ) -> Option<AgentxPdu> {
+        let conn_id = conn.lock().unwrap().id;
+        let is_open = match conn.lock().unwrap().session(pdu.session_id) {
+            Some(session) => {
+                // Anything from the subagent shows it is answering again
+                session.timed_out = false;
+                true
+            }
+            None => false,
+        };
+        let error = match &pdu.payload {
+            Payload::Open { timeout, id, descr } => {
+                self.next_session += 1;
+                let session_id = self.next_session;
+                info!(
+                    "AgentX session {session_id} opened by {id:?} {0}",
+                    String::from_utf8_lossy(descr)
+                );
+                conn.lock().unwrap().sessions.push(Session {
+                    id: session_id,
+                    timeout: timeout_or_default(*timeout, DEFAULT_TIMEOUT),
+                    network_order: pdu.flags & NETWORK_BYTE_ORDER != 0,
+                    transaction: None,
+                    timed_out: false,
+                });
+                let mut reply = pdu.response(sys_up_time, NO_AGENTX_ERROR, 0, vec![]);
+                reply.session_id = session_id;
+                return Some(reply);
+            }
+            Payload::Response { .. } => {
+                debug!("Unexpected AgentX response {0}", pdu.packet_id);
+                return None;
+            }
+            _ if !is_open => NOT_OPEN,
+            Payload::Close { reason } => {
+                info!("AgentX session {0} closed, reason {reason}", pdu.session_id);
+                self.unregister(oid_map, |reg| {
+                    reg.conn_id == conn_id && reg.session_id == pdu.session_id
+                });
+                let mut conn = conn.lock().unwrap();
+                conn.sessions.retain(|session| session.id != pdu.session_id);
+                NO_AGENTX_ERROR
+            }
+            Payload::Register { .. } if pdu.context.is_some() => UNSUPPORTED_CONTEXT,
+            Payload::Register { timeout, region } => {
+                self.register(conn, &pdu, *timeout, region, oid_map)
+            }
+            Payload::Unregister { region } => {
+                let count = self.unregister(oid_map, |reg| {
+                    reg.conn_id == conn_id
+                        && reg.session_id == pdu.session_id
+                        && reg.region == *region
+                });
+                if count == 0 {
+                    UNKNOWN_REGISTRATION
+                } else {
+                    NO_AGENTX_ERROR
+                }
+            }
+            Payload::Notify(varbinds) => {
+                info!("AgentX notification {varbinds:?}");
+                NO_AGENTX_ERROR
+            }
+            Payload::Ping | Payload::AddAgentCaps { .. } | Payload::RemoveAgentCaps { .. } => {
+                NO_AGENTX_ERROR
+            }
+            Payload::IndexAllocate(_) | Payload::IndexDeallocate(_) => PROCESSING_ERROR,
+            _ => {
+                warn!("AgentX subagent sent {0:?}", pdu.payload);
+                PARSE_ERROR
+            }
+        };
+        Some(pdu.response(sys_up_time, error, 0, vec![]))
+    }
+
+    /// Put a ProxyKeeper in oid_map for each subtree of the region, returning the AgentX error.
+    fn register(
+        &mut self,
+        conn: &Arc<Mutex<Connection>>,
+        pdu: &AgentxPdu,
+        timeout: u8,
+        region: &Region,
+        oid_map: &mut OidMap,
+    ) -> u16 {
+        let Some(subtrees) = expand_range(region) else {
+            return REQUEST_DENIED;
+        };
+        if subtrees.iter().any(|subtree| overlaps(oid_map, subtree)) {
+            return DUPLICATE_REGISTRATION;
+        }
+        let timeout = Some(timeout_or_default(timeout, Duration::ZERO)).filter(|t| !t.is_zero());
+        for subtree in &subtrees {
+            info!("AgentX session {0} registered {subtree:?}", pdu.session_id);
+            let keeper = ProxyKeeper {
+                conn: conn.clone(),
+                session_id: pdu.session_id,
+                subtree: subtree.clone(),
+                instance: pdu.flags & INSTANCE_REGISTRATION != 0,
+                timeout,
+            };
+            oid_map.insert(subtree.clone(), Box::new(keeper));
+        }
+        self.registrations.push(Registration {
+            conn_id: conn.lock().unwrap().id,
+            session_id: pdu.session_id,
+            region: region.clone(),
+            subtrees,
+        });
+        NO_AGENTX_ERROR
+    }
+}
+
+impl Drop for Master {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn timeout_or_default(seconds: u8, default: Duration) -> Duration {
+    if seconds == 0 {
+        default
+    } else {
+        Duration::from_secs(seconds.into())
+    }
+}
+
+/// The subtrees of a region, one for each value in its range, or None if there are too many.
+fn expand_range(region: &Region) -> Option<Vec<ObjectIdentifier>> {
+    if region.subtree.is_empty() {
+        return None;
+    }
+    if region.range_subid == 0 {
+        return Some(vec![region.subtree.clone()]);
+    }
+    let pos = usize::from(region.range_subid) - 1;
+    let first = *region.subtree.get(pos)?;
+    if region.upper_bound < first || region.upper_bound - first >= MAX_RANGE {
+        return None;
+    }
+    let subtrees = (first..=region.upper_bound)
+        .map(|arc| {
+            let mut arcs = region.subtree.to_vec();
+            arcs[pos] = arc;
+            ObjectIdentifier::new_unchecked(arcs.into())
+        })
+        .collect();
+    Some(subtrees)
+}
+
+/// True if subtree is within an entry of oid_map, or contains one.
+fn overlaps(oid_map: &OidMap, subtree: &ObjectIdentifier) -> bool {
+    match oid_map.search(subtree) {
+        Ok(_) => true,
+        Err(insert_point) => {
+            insert_point < oid_map.len() && oid_map.oid(insert_point).starts_with(subtree)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scalar::ScalarMemOid;
+    use rasn::types::Integer;
+    use rasn_smi::v2::{ObjectSyntax, SimpleSyntax};
+
+    fn oid(arcs: &[u32]) -> ObjectIdentifier {
+        ObjectIdentifier::new_unchecked(arcs.to_vec().into())
+    }
+
+    fn int(value: i32) -> ObjectSyntax {
+        ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(value)))
+    }
+
+    #[test]
+    fn test_expand_range() {
+        let mut region = Region {
+            priority: 127,
+            subtree: oid(&[1, 3, 6, 1, 2, 1, 2, 2, 1, 1, 1]),
+            range_subid: 11,
+            upper_bound: 3,
+        };
+        let subtrees = expand_range(&region).unwrap();
+        assert_eq!(subtrees.len(), 3);
+        assert_eq!(subtrees[2], oid(&[1, 3, 6, 1, 2, 1, 2, 2, 1, 1, 3]));
+        region.upper_bound = 1000;
+        assert!(expand_range(&region).is_none());
+        region.range_subid = 12;
+        assert!(expand_range(&region).is_none());
+    }
+
+    #[test]
+    fn test_timeout() {
+        use crate::transport;
+        use std::os::unix::net::UnixStream;
+        use std::thread;
+        use std::time::Instant;
+
+        fn pdu(session_id: u32, payload: Payload) -> AgentxPdu {
+            AgentxPdu {
+                flags: NETWORK_BYTE_ORDER,
+                session_id,
+                transaction_id: 0,
+                packet_id: 1,
+                context: None,
+                payload,
+            }
+        }
+
+        /// Wait for the next PDU from the master.
+        fn read(channel: &mut Channel) -> AgentxPdu {
+            while channel.pending.is_empty() {
+                transport::wait_readable(&[channel.stream.as_raw_fd()], None).unwrap();
+                channel.read_available().unwrap();
+            }
+            channel.pending.pop_front().unwrap()
+        }
+
+        let path = std::env::temp_dir().join(format!("snmp-master-{0}", std::process::id()));
+        let mut master = Master::bind(&path).unwrap();
+        let mut oid_map = OidMap::new();
+        let mut subagent = Channel::new(UnixStream::connect(&path).unwrap());
+        master.serve(&[0], &mut oid_map, 0);
+        let open = Payload::Open {
+            timeout: 1,
+            id: oid(&[1, 3, 6, 1, 4, 1, 99]),
+            descr: b"silent subagent".to_vec(),
+        };
+        subagent.send(&pdu(0, open)).unwrap();
+        master.serve(&[1], &mut oid_map, 0);
+        let session_id = read(&mut subagent).session_id;
+        let region = Region {
+            priority: 127,
+            subtree: oid(&[1, 3, 6, 1, 4, 1, 99]),
+            range_subid: 0,
+            upper_bound: 0,
+        };
+        let register = Payload::Register { timeout: 0, region };
+        subagent.send(&pdu(session_id, register)).unwrap();
+        master.serve(&[1], &mut oid_map, 0);
+        assert!(matches!(
+            read(&mut subagent).payload,
+            Payload::Response { error: 0, .. }
+        ));
+        assert_eq!(oid_map.len(), 1);
+
+        // The subagent never answers, so the first Get waits for the session's timeout
+        let instance = oid(&[1, 3, 6, 1, 4, 1, 99, 1, 0]);
+        let start = Instant::now();
+        assert_eq!(oid_map.idx(0).get(instance.clone()), Err(OidErr::GenErr));
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        // And later ones fail without asking it
+        let start = Instant::now();
+        assert_eq!(oid_map.idx(0).get(instance.clone()), Err(OidErr::GenErr));
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(matches!(read(&mut subagent).payload, Payload::Get(_)));
+        assert!(subagent.pending.is_empty());
+
+        // Until it is heard from again
+        subagent.send(&pdu(session_id, Payload::Ping)).unwrap();
+        master.serve(&[1], &mut oid_map, 0);
+        assert!(matches!(
+            read(&mut subagent).payload,
+            Payload::Response { .. }
+        ));
+        let answer = thread::spawn(move || {
+            let get = read(&mut subagent);
+            assert!(matches!(get.payload, Payload::Get(_)));
+            let value = VarBindValue::Value(int(42));
+            let varbinds = vec![VarBind {
+                name: oid(&[1, 3, 6, 1, 4, 1, 99, 1, 0]),
+                value,
+            }];
+            subagent
+                .send(&get.response(0, NO_AGENTX_ERROR, 0, varbinds))
+                .unwrap();
+        });
+        assert_eq!(
+            oid_map.idx(0).get(instance),
+            Ok(VarBindValue::Value(int(42)))
+        );
+        answer.join().unwrap();
+    }
+
+    #[test]
+    fn test_overlaps() {
+        let mut oid_map = OidMap::new();
+        let keeper = ScalarMemOid::new(int(1), crate::keeper::OType::Integer, Access::ReadOnly);
+        oid_map.push(oid(&[1, 3, 6, 1, 4, 1, 99, 1]), Box::new(keeper));
+        assert!(overlaps(&oid_map, &oid(&[1, 3, 6, 1, 4, 1, 99])));
+        assert!(overlaps(&oid_map, &oid(&[1, 3, 6, 1, 4, 1, 99, 1])));
+        assert!(overlaps(&oid_map, &oid(&[1, 3, 6, 1, 4, 1, 99, 1, 0])));
+        assert!(!overlaps(&oid_map, &oid(&[1, 3, 6, 1, 4, 1, 99, 2])));
+        assert!(!overlaps(&oid_map, &oid(&[1, 3, 6, 1, 4, 1, 9])));
+    }
+}
@@ -0,0 +1,667 @@
+//! AgentX subagent, RFC 2741
+//!
+//! Serves an OidMap through another master agent, such as net-snmp's snmpd, for systems
+//! where that already owns the SNMP port. The keepers are used unchanged: the subagent
+//! opens a session, registers every OID in the map, and answers the master's requests.
+//!
+//! Get, GetNext and GetBulk are answered as the Agent would, without access control,
+//! which is the master's job. Sets keep the Agent's transaction steps: TestSet calls
+//! begin_transaction, set and test, CommitSet calls commit, CleanupSet rolls back
+//! whatever was not committed and calls cleanup. UndoSet calls undo for the keepers
+//! that committed, and rollback for the rest, as rollback is only for before the commit.
+//!
+//! Scalars are registered as instance registrations, each table as a subtree. Only the
+//! default context is served. Notifications and index allocation are not supported.
+use super::{
+    AgentxPdu, Channel, Payload, Region, ResponseFields, SearchRange, INSTANCE_REGISTRATION,
+    NETWORK_BYTE_ORDER, NO_AGENTX_ERROR, PARSE_ERROR, REASON_SHUTDOWN,
+};
+use crate::keeper::OidErr;
+use crate::oidmap::OidMap;
+use crate::snmp_agent::{get_value, next_varbind};
+use crate::transport;
+use log::{debug, info, warn};
+use rasn::types::ObjectIdentifier;
+use rasn_snmp::v2::Pdu;
+use rasn_snmp::v3::{VarBind, VarBindValue};
+use std::collections::BTreeSet;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the master to answer Open and Register.
+const MASTER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Registration priority, RFC 2741 section 6.2.3 suggests 127 by default.
+const DEFAULT_PRIORITY: u8 = 127;
+
+/// A Set in progress, RFC 2741 section 7.2.4
+struct Transaction {
+    id: u32,
+    /// Keepers involved, by index into the OidMap, in OID order
+    keeps: Vec<usize>,
+    /// How many of keeps have committed
+    committed: usize,
+    undone: bool,
+}
+
+/// A session with a master agent.
+pub struct Subagent {
+    channel: Channel,
+    session_id: u32,
+    transaction: Option<Transaction>,
+    start_time: Instant,
+}
+
+impl Subagent {
+    /// Connect to the master's socket, usually agentx::DEFAULT_SOCKET, and open a session.
+    ///
+    /// id identifies the subagent, often its enterprise OID, and descr describes it.
+    pub fn connect(path: impl AsRef<Path>, id: ObjectIdentifier, descr: &str) -> io::Result<Self> {
+        let stream = UnixStream::connect(path)?;
+        let mut subagent = Subagent {
+            channel: Channel::new(stream),
+            session_id: 0,
+            transaction: None,
+            start_time: Instant::now(),
+        };
+        let open = Payload::Open {
+            timeout: 0,
+            id,
+            descr: descr.as_bytes().to_vec(),
+        };
+        let pdu = subagent.pdu(open);
+        let reply = subagent.channel.request_pdu(&pdu, MASTER_TIMEOUT)?;
+        match reply.payload {
+            Payload::Response {
+                error: NO_AGENTX_ERROR,
+                ..
+            } => subagent.session_id = reply.session_id,
+            Payload::Response { error, .. } => {
+                return Err(io::Error::other(format!(
+                    "AgentX Open failed, error {error}"
+                )));
+            }
+            _ => unreachable!("request_pdu only returns Responses"),
+        }
+        info!("AgentX session {0} open", subagent.session_id);
+        Ok(subagent)
+    }
+
+    /// The session ID the master assigned.
+    pub fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    /// Register a subtree, or with instance, a single instance such as a scalar.
+    ///
+    /// Returns the AgentX error from the master's Response, zero for success.
+    pub fn register(&mut self, subtree: &ObjectIdentifier, instance: bool) -> io::Result<u16> {
+        let mut pdu = self.pdu(Payload::Register {
+            timeout: 0,
+            region: Region {
+                priority: DEFAULT_PRIORITY,
+                subtree: subtree.clone(),
+                range_subid: 0,
+                upper_bound: 0,
+            },
+        });
+        if instance {
+            pdu.flags |= INSTANCE_REGISTRATION;
+        }
+        let (error, _, _) = self.channel.request(&pdu, MASTER_TIMEOUT)?;
+        Ok(error)
+    }
+
+    /// Register every OID in the OidMap, returning the number the master accepted.
+    ///
+    /// Refusals, such as duplicateRegistration for a subtree the master serves itself,
+    /// are logged and skipped.
+    pub fn register_all(&mut self, oid_map: &mut OidMap) -> io::Result<usize> {
+        let mut count = 0;
+        for which in 0..oid_map.len() {
+            let oid = oid_map.oid(which).clone();
+            let instance = oid_map.idx(which).is_scalar(oid.clone());
+            match self.register(&oid, instance)? {
+                NO_AGENTX_ERROR => count += 1,
+                error => warn!("AgentX master refused {oid:?}, error {error}"),
+            }
+        }
+        info!("Registered {count} of {0} OIDs", oid_map.len());
+        Ok(count)
+    }
+
+    /// Close the session.
+    pub fn close(mut self) -> io::Result<()> {
+        let pdu = self.pdu(Payload::Close {
+            reason: REASON_SHUTDOWN,
+        });
+        self.channel.request(&pdu, MASTER_TIMEOUT).map(|_| ())
+    }
+
+    /// Handle the master's requests, waiting up to timeout for some to arrive.
+    ///
+    /// Returns the number of PDUs handled, which is zero on timeout. The OidMap must
+    /// be sorted. An error means the master has gone, or closed the session.
+    pub fn serve(&mut self, oid_map: &mut OidMap, timeout: Option<Duration>) -> io::Result<usize> {
+        // PDUs that arrived just before the master went are still handled
+        let mut read_result = Ok(());
+        if self.channel.pending.is_empty() {
+            let ready = transport::wait_readable(&[self.channel.stream.as_raw_fd()], timeout)?;
+            if !ready.is_empty() {
+                read_result = self.channel.read_available();
+            }
+        }
+        let mut count = 0;
+        while let Some(pdu) = self.channel.pending.pop_front() {
+            count += 1;
+            if let Payload::Close { reason } = pdu.payload {
+                info!("AgentX master closed the session, reason {reason}");
+                return Err(io::ErrorKind::ConnectionAborted.into());
+            }
+            if let Some(reply) = self.handle(pdu, oid_map) {
+                self.channel.send(&reply)?;
+            }
+        }
+        read_result.map(|_| count)
+    }
+
+    /// Serve the master until the connection or session ends, which is returned as an error.
+    pub fn loop_forever(&mut self, oid_map: &mut OidMap) -> io::Error {
+        loop {
+            if let Err(err) = self.serve(oid_map, None) {
+                return err;
+            }
+        }
+    }
+
+    /// A PDU for this session, in network byte order.
+    fn pdu(&mut self, payload: Payload) -> AgentxPdu {
+        AgentxPdu {
+            flags: NETWORK_BYTE_ORDER,
+            session_id: self.session_id,
+            transaction_id: 0,
+            packet_id: self.channel.packet_id(),
+            context: None,
+            payload,
+        }
+    }
+
+    /// Answer a request from the master, or None for those without a Response.
+    fn handle(&mut self, pdu: AgentxPdu, oid_map: &mut OidMap) -> Option<AgentxPdu> {
+        let fields = if pdu.context.is_some() {
+            // Nothing was registered in other contexts
+            Err((OidErr::GenErr, 1))
+        } else {
+            match &pdu.payload {
+                Payload::Get(ranges) => self.get(ranges, oid_map),
+                Payload::GetNext(ranges) => self.get_next(ranges, oid_map),
+                Payload::GetBulk {
+                    non_repeaters,
+                    max_repetitions,
+                    ranges,
+                } => self.get_bulk(*non_repeaters, *max_repetitions, ranges, oid_map),
+                Payload::TestSet(varbinds) => {
+                    Ok(self.test_set(pdu.transaction_id, varbinds, oid_map))
+                }
+                Payload::CommitSet => Ok(self.commit_set(pdu.transaction_id, oid_map)),
+                Payload::UndoSet => Ok(self.undo_set(pdu.transaction_id, oid_map)),
+                Payload::CleanupSet => {
+                    self.cleanup_set(pdu.transaction_id, oid_map);
+                    return None;
+                }
+                Payload::Response { .. } => {
+                    debug!("Late AgentX response {0}", pdu.packet_id);
+                    return None;
+                }
+                _ => {
+                    warn!("AgentX master sent {0:?}", pdu.payload);
+                    return Some(self.response(&pdu, (PARSE_ERROR, 0, vec![])));
+                }
+            }
+        };
+        let fields = fields.unwrap_or_else(|(err, index)| {
+            warn!("AgentX {0:?} failed {err:?}", pdu.payload);
+            (err.read_error_status() as u16, index, vec![])
+        });
+        Some(self.response(&pdu, fields))
+    }
+
+    fn response(&self, pdu: &AgentxPdu, (error, index, varbinds): ResponseFields) -> AgentxPdu {
+        let sys_up_time = (self.start_time.elapsed().as_millis() / 10) as u32;
+        pdu.response(sys_up_time, error, index, varbinds)
+    }
+
+    /// Get, RFC 2741 section 7.2.3.1
+    fn get(
+        &self,
+        ranges: &[SearchRange],
+        oid_map: &mut OidMap,
+    ) -> Result<ResponseFields, (OidErr, u16)> {
+        let mut varbinds = vec![];
+        for (index, range) in (1..).zip(ranges) {
+            let value = get_value(&range.start, oid_map).map_err(|err| (err, index))?;
+            varbinds.push(VarBind {
+                name: range.start.clone(),
+                value,
+            });
+        }
+        Ok((NO_AGENTX_ERROR, 0, varbinds))
+    }
+
+    /// GetNext, RFC 2741 section 7.2.3.2
+    fn get_next(
+        &self,
+        ranges: &[SearchRange],
+        oid_map: &mut OidMap,
+    ) -> Result<ResponseFields, (OidErr, u16)> {
+        let mut varbinds = vec![];
+        for (index, range) in (1..).zip(ranges) {
+            varbinds.push(next_in_range(range, oid_map).map_err(|err| (err, index))?);
+        }
+        Ok((NO_AGENTX_ERROR, 0, varbinds))
+    }
+
+    /// GetBulk, RFC 2741 section 7.2.3.3
+    ///
+    /// Repetitions stop once a whole one is endOfMibView.
+    fn get_bulk(
+        &self,
+        non_repeaters: u16,
+        max_repetitions: u16,
+        ranges: &[SearchRange],
+        oid_map: &mut OidMap,
+    ) -> Result<ResponseFields, (OidErr, u16)> {
+        let split = ranges.len().min(non_repeaters.into());
+        let (_, _, mut varbinds) = self.get_next(&ranges[..split], oid_map)?;
+        let mut repeaters = ranges[split..].to_vec();
+        for _ in 0..max_repetitions {
+            let mut all_ended = true;
+            for (index, range) in (split as u16 + 1..).zip(repeaters.iter_mut()) {
+                let bind = next_in_range(range, oid_map).map_err(|err| (err, index))?;
+                if bind.value != VarBindValue::EndOfMibView {
+                    all_ended = false;
+                }
+                range.start = bind.name.clone();
+                range.include = false;
+                varbinds.push(bind);
+            }
+            if all_ended || repeaters.is_empty() {
+                break;
+            }
+        }
+        Ok((NO_AGENTX_ERROR, 0, varbinds))
+    }
+
+    /// TestSet, RFC 2741 section 7.2.4.1: begin_transaction, set and test.
+    fn test_set(
+        &mut self,
+        transaction_id: u32,
+        varbinds: &[VarBind],
+        oid_map: &mut OidMap,
+    ) -> ResponseFields {
+        if let Some(trans) = self.transaction.take() {
+            warn!("AgentX transaction {0} abandoned", trans.id);
+            rollback(&trans, oid_map);
+        }
+        let keeps: BTreeSet<usize> = varbinds
+            .iter()
+            .filter_map(|bind| oid_map.search(&bind.name).ok())
+            .collect();
+        for which in &keeps {
+            let _ = oid_map.idx(*which).begin_transaction();
+        }
+        self.transaction = Some(Transaction {
+            id: transaction_id,
+            keeps: keeps.into_iter().collect(),
+            committed: 0,
+            undone: false,
+        });
+        let mut staged = vec![];
+        for (index, bind) in (1..).zip(varbinds) {
+            let set_result = match oid_map.search(&bind.name) {
+                Err(_) => Err(OidErr::NoCreation),
+                Ok(which) => {
+                    staged.push(which);
+                    oid_map
+                        .idx(which)
+                        .set(bind.name.clone(), bind.value.clone())
+                }
+            };
+            if let Err(err) = set_result {
+                return (err.error_status() as u16, index, vec![]);
+            }
+        }
+        for ((index, which), bind) in (1..).zip(&staged).zip(varbinds) {
+            if let Err(err) = oid_map.idx(*which).test(bind.name.clone(), &bind.value) {
+                return (err.error_status() as u16, index, vec![]);
+            }
+        }
+        (NO_AGENTX_ERROR, 0, vec![])
+    }
+
+    /// The transaction, if transaction_id is the one under way.
+    fn transaction(&mut self, transaction_id: u32) -> Option<&mut Transaction> {
+        self.transaction
+            .as_mut()
+            .filter(|trans| trans.id == transaction_id)
+    }
+
+    /// CommitSet, RFC 2741 section 7.2.4.2
+    fn commit_set(&mut self, transaction_id: u32, oid_map: &mut OidMap) -> ResponseFields {
+        let Some(trans) = self.transaction(transaction_id) else {
+            return (Pdu::ERROR_STATUS_COMMIT_FAILED as u16, 0, vec![]);
+        };
+        while let Some(which) = trans.keeps.get(trans.committed) {
+            if let Err(err) = oid_map.idx(*which).commit() {
+                warn!("Commit failed {err:?}");
+                return (Pdu::ERROR_STATUS_COMMIT_FAILED as u16, 0, vec![]);
+            }
+            trans.committed += 1;
+        }
+        (NO_AGENTX_ERROR, 0, vec![])
+    }
+
+    /// UndoSet, RFC 2741 section 7.2.4.3
+    fn undo_set(&mut self, transaction_id: u32, oid_map: &mut OidMap) -> ResponseFields {
+        let Some(trans) = self.transaction(transaction_id) else {
+            return (Pdu::ERROR_STATUS_UNDO_FAILED as u16, 0, vec![]);
+        };
+        let mut error = NO_AGENTX_ERROR;
+        for which in trans.keeps[..trans.committed].iter().rev() {
+            if let Err(err) = oid_map.idx(*which).undo() {
+                warn!("Undo failed {err:?}");
+                error = Pdu::ERROR_STATUS_UNDO_FAILED as u16;
+            }
+        }
+        trans.undone = true;
+        rollback(trans, oid_map);
+        (error, 0, vec![])
+    }
+
+    /// CleanupSet, RFC 2741 section 7.2.4.4, which has no Response.
+    fn cleanup_set(&mut self, transaction_id: u32, oid_map: &mut OidMap) {
+        if self.transaction(transaction_id).is_none() {
+            return;
+        }
+        let Some(trans) = self.transaction.take() else {
+            return;
+        };
+        if !trans.undone {
+            rollback(&trans, oid_map);
+        }
+        for which in &trans.keeps {
+            oid_map.idx(*which).cleanup();
+        }
+    }
+}
+
+/// Roll back the keepers of a transaction that have not committed.
+fn rollback(trans: &Transaction, oid_map: &mut OidMap) {
+    for which in &trans.keeps[trans.committed..] {
+        if let Err(err) = oid_map.idx(*which).rollback() {
+            warn!("Rollback failed {err:?}");
+        }
+    }
+}
+
+/// The first instance in the search range, or its start with endOfMibView.
+fn next_in_range(range: &SearchRange, oid_map: &mut OidMap) -> Result<VarBind, OidErr> {
+    if range.include {
+        if let Ok(value @ VarBindValue::Value(_)) = get_value(&range.start, oid_map) {
+            return Ok(VarBind {
+                name: range.start.clone(),
+                value,
+            });
+        }
+    }
+    let bind = next_varbind(&range.start, oid_map, |_| true)?;
+    match &range.end {
+        Some(end) if bind.name >= *end => Ok(VarBind {
+            name: range.start.clone(),
+            value: VarBindValue::EndOfMibView,
+        }),
+        _ => Ok(bind),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agentx::{pdu_len, DUPLICATE_REGISTRATION};
+    use crate::keeper::{Access, OType};
+    use crate::scalar::ScalarMemOid;
+    use rasn::types::{Integer, OctetString};
+    use rasn_smi::v2::{ObjectSyntax, SimpleSyntax};
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    const SCALAR_A: [u32; 9] = [1, 3, 6, 1, 4, 1, 99, 1, 0];
+    const SCALAR_B: [u32; 9] = [1, 3, 6, 1, 4, 1, 99, 2, 0];
+    const SYS_NAME: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 5, 0];
+
+    fn oid(arcs: &[u32]) -> ObjectIdentifier {
+        ObjectIdentifier::new_unchecked(arcs.to_vec().into())
+    }
+
+    fn int(value: i32) -> VarBindValue {
+        VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(
+            value,
+        ))))
+    }
+
+    fn string(value: &'static [u8]) -> VarBindValue {
+        VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::String(
+            OctetString::from_static(value),
+        )))
+    }
+
+    fn scalar(value: VarBindValue, otype: OType, access: Access) -> Box<ScalarMemOid> {
+        let VarBindValue::Value(value) = value else {
+            panic!("Scalars need a value");
+        };
+        Box::new(ScalarMemOid::new(value, otype, access))
+    }
+
+    fn range(start: &[u32], include: bool, end: Option<&[u32]>) -> SearchRange {
+        SearchRange {
+            start: oid(start),
+            include,
+            end: end.map(oid),
+        }
+    }
+
+    /// A stand-in master, checking the subagent's answers as it goes.
+    struct StandIn {
+        stream: UnixStream,
+        packet_id: u32,
+    }
+
+    impl StandIn {
+        fn read(&mut self) -> AgentxPdu {
+            let mut buf = vec![0; 20];
+            self.stream.read_exact(&mut buf).unwrap();
+            buf.resize(pdu_len(&buf).unwrap().unwrap(), 0);
+            self.stream.read_exact(&mut buf[20..]).unwrap();
+            AgentxPdu::decode(&buf).unwrap()
+        }
+
+        fn reply(&mut self, request: &AgentxPdu, session_id: u32, error: u16) {
+            let mut reply = request.response(0, error, 0, vec![]);
+            reply.session_id = session_id;
+            self.stream.write_all(&reply.encode()).unwrap();
+        }
+
+        fn send(&mut self, transaction_id: u32, payload: Payload) {
+            self.packet_id += 1;
+            let pdu = AgentxPdu {
+                flags: 0,
+                session_id: 42,
+                transaction_id,
+                packet_id: self.packet_id,
+                context: None,
+                payload,
+            };
+            self.stream.write_all(&pdu.encode()).unwrap();
+        }
+
+        fn ask(&mut self, transaction_id: u32, payload: Payload) -> ResponseFields {
+            self.send(transaction_id, payload);
+            let reply = self.read();
+            assert_eq!(reply.packet_id, self.packet_id);
+            assert_eq!(reply.flags & NETWORK_BYTE_ORDER, 0);
+            match reply.payload {
+                Payload::Response {
+                    error,
+                    index,
+                    varbinds,
+                    ..
+                } => (error, index, varbinds),
+                other => panic!("Expected a Response, got {other:?}"),
+            }
+        }
+
+        fn values(&mut self, payload: Payload) -> Vec<(ObjectIdentifier, VarBindValue)> {
+            let (error, _, varbinds) = self.ask(0, payload);
+            assert_eq!(error, NO_AGENTX_ERROR);
+            varbinds
+                .into_iter()
+                .map(|bind| (bind.name, bind.value))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_subagent() {
+        let path = std::env::temp_dir().join(format!("snmp-subagent-{0}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let master = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut master = StandIn {
+                stream,
+                packet_id: 0,
+            };
+            let open = master.read();
+            assert!(matches!(open.payload, Payload::Open { .. }));
+            master.reply(&open, 42, NO_AGENTX_ERROR);
+            // In OID order, sysName belongs to the master already
+            for (subtree, error) in [
+                (SYS_NAME, DUPLICATE_REGISTRATION),
+                (SCALAR_A, NO_AGENTX_ERROR),
+                (SCALAR_B, NO_AGENTX_ERROR),
+            ] {
+                let register = master.read();
+                assert_eq!(register.session_id, 42);
+                assert_ne!(register.flags & INSTANCE_REGISTRATION, 0);
+                let Payload::Register { region, .. } = &register.payload else {
+                    panic!("Expected Register, got {0:?}", register.payload);
+                };
+                assert_eq!(region.subtree, oid(&subtree));
+                master.reply(&register, 42, error);
+            }
+
+            let get = Payload::Get(vec![
+                range(&SCALAR_A, false, None),
+                range(&[1, 3, 6, 1, 4, 1, 99, 3, 0], false, None),
+            ]);
+            assert_eq!(
+                master.values(get),
+                [
+                    (oid(&SCALAR_A), int(1)),
+                    (
+                        oid(&[1, 3, 6, 1, 4, 1, 99, 3, 0]),
+                        VarBindValue::NoSuchObject
+                    )
+                ]
+            );
+            let get_next = Payload::GetNext(vec![
+                range(&[1, 3, 6, 1, 4, 1, 99], false, None),
+                range(&SCALAR_A, false, Some(&[1, 3, 6, 1, 4, 1, 99, 2])),
+                range(&SCALAR_A, true, None),
+                range(&SCALAR_B, false, None),
+            ]);
+            assert_eq!(
+                master.values(get_next),
+                [
+                    (oid(&SCALAR_A), int(1)),
+                    (oid(&SCALAR_A), VarBindValue::EndOfMibView),
+                    (oid(&SCALAR_A), int(1)),
+                    (oid(&SCALAR_B), VarBindValue::EndOfMibView),
+                ]
+            );
+            let get_bulk = Payload::GetBulk {
+                non_repeaters: 0,
+                max_repetitions: 5,
+                ranges: vec![range(&[1, 3, 6, 1, 4, 1, 99], false, None)],
+            };
+            let names: Vec<_> = master
+                .values(get_bulk)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect();
+            assert_eq!(names, [oid(&SCALAR_A), oid(&SCALAR_B), oid(&SCALAR_B)]);
+
+            // A Set that commits
+            let set_a = |value| {
+                Payload::TestSet(vec![VarBind {
+                    name: oid(&SCALAR_A),
+                    value,
+                }])
+            };
+            assert_eq!(master.ask(1, set_a(int(7))).0, NO_AGENTX_ERROR);
+            assert_eq!(master.ask(1, Payload::CommitSet).0, NO_AGENTX_ERROR);
+            master.send(1, Payload::CleanupSet);
+            let get = Payload::Get(vec![range(&SCALAR_A, false, None)]);
+            assert_eq!(master.values(get.clone())[0].1, int(7));
+
+            // One the keeper refuses
+            let set_b = Payload::TestSet(vec![VarBind {
+                name: oid(&SCALAR_B),
+                value: string(b"x"),
+            }]);
+            let (error, index, _) = master.ask(2, set_b);
+            assert_eq!(error, Pdu::ERROR_STATUS_NOT_WRITABLE as u16);
+            assert_eq!(index, 1);
+            master.send(2, Payload::CleanupSet);
+
+            // And one undone after the commit
+            assert_eq!(master.ask(3, set_a(int(9))).0, NO_AGENTX_ERROR);
+            assert_eq!(master.ask(3, Payload::CommitSet).0, NO_AGENTX_ERROR);
+            assert_eq!(master.ask(3, Payload::UndoSet).0, NO_AGENTX_ERROR);
+            master.send(3, Payload::CleanupSet);
+            assert_eq!(master.values(get)[0].1, int(7));
+            master.send(
+                0,
+                Payload::Close {
+                    reason: REASON_SHUTDOWN,
+                },
+            );
+        });
+
+        let mut oid_map = OidMap::new();
+        oid_map.push(
+            oid(&SCALAR_A),
+            scalar(int(1), OType::Integer, Access::ReadWrite),
+        );
+        oid_map.push(
+            oid(&SCALAR_B),
+            scalar(string(b"abc"), OType::String, Access::ReadOnly),
+        );
+        oid_map.push(
+            oid(&SYS_NAME),
+            scalar(string(b"host"), OType::String, Access::ReadWrite),
+        );
+        oid_map.sort();
+        let mut subagent =
+            Subagent::connect(&path, oid(&[1, 3, 6, 1, 4, 1, 99]), "test subagent").unwrap();
+        assert_eq!(subagent.session_id(), 42);
+        assert_eq!(subagent.register_all(&mut oid_map).unwrap(), 2);
+        let err = subagent.loop_forever(&mut oid_map);
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        master.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
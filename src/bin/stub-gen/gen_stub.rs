@@ -1,7 +1,9 @@
-use crate::parser::{Entry, ModuleCompliance, ObjectIdentity, ObjectType, TextConvention};
+use crate::parser::{
+    Entry, ModuleCompliance, NotificationType, ObjectIdentity, ObjectType, TextConvention,
+};
 use crate::resolver;
 use log::{debug, error, info, warn};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::{Error, Write};
 
@@ -473,12 +475,18 @@ fn set(
 fn begin_transaction(&mut self) -> Result<(), OidErr> {{
         self.table.begin_transaction()
     }}
+fn test(&self, oid: ObjectIdentifier, value: &VarBindValue) -> Result<(), OidErr> {{
+        self.table.test(oid, value)
+    }}
 fn commit(&mut self) -> Result<(), OidErr> {{
         self.table.commit()
     }}
 fn rollback(&mut self) -> Result<(), OidErr> {{
         self.table.rollback()
     }}
+fn undo(&mut self) -> Result<(), OidErr> {{
+        self.table.undo()
+    }}
 }}
 
 "
@@ -547,6 +555,9 @@ impl OidKeeper for {struct_name} {{
     fn rollback(&mut self) -> Result<(), OidErr> {{
         self.scalar.rollback()
     }}
+    fn undo(&mut self) -> Result<(), OidErr> {{
+        self.scalar.undo()
+    }}
 }}
 "
         )
@@ -651,6 +662,235 @@ fn write_module_compliances(
     Ok(())
 }
 
+// Argument type, and the expression making an ObjectSyntax from it, for notification objects
+const ARG_INT: (&str, &str) = ("i32", "simple_from_int({v})");
+const ARG_UNSIGNED: (&str, &str) = (
+    "u32",
+    "ObjectSyntax::ApplicationWide(rasn_smi::v2::ApplicationSyntax::Unsigned(rasn_smi::v2::Unsigned32 { 0: {v} }))",
+);
+const ARG_COUNTER: (&str, &str) = (
+    "u32",
+    "ObjectSyntax::ApplicationWide(rasn_smi::v2::ApplicationSyntax::Counter(rasn_smi::v2::Counter32 { 0: {v} }))",
+);
+const ARG_BIG_COUNTER: (&str, &str) = (
+    "u64",
+    "ObjectSyntax::ApplicationWide(rasn_smi::v2::ApplicationSyntax::BigCounter(rasn_smi::v2::Counter64 { 0: {v} }))",
+);
+const ARG_TICKS: (&str, &str) = (
+    "u32",
+    "ObjectSyntax::ApplicationWide(rasn_smi::v2::ApplicationSyntax::Ticks(rasn_smi::v2::TimeTicks { 0: {v} }))",
+);
+const ARG_ADDRESS: (&str, &str) = (
+    "[u8; 4]",
+    "ObjectSyntax::ApplicationWide(rasn_smi::v2::ApplicationSyntax::Address(rasn_smi::v1::IpAddress({v}.into())))",
+);
+const ARG_STRING: (&str, &str) = (
+    "&[u8]",
+    "ObjectSyntax::Simple(SimpleSyntax::String(OctetString::from({v}.to_vec())))",
+);
+const ARG_OID: (&str, &str) = (
+    "ObjectIdentifier",
+    "ObjectSyntax::Simple(SimpleSyntax::ObjectId({v}))",
+);
+
+static NAME_NOTIFY_ARGS: [(&str, (&str, &str)); 36] = [
+    ("INTEGER", ARG_INT),
+    ("Integer32", ARG_INT),
+    ("TruthValue", ARG_INT),
+    ("TimeInterval", ARG_INT),
+    ("TestAndIncr", ARG_INT),
+    ("RowStatus", ARG_INT),
+    ("StorageType", ARG_INT),
+    ("EntryStatus", ARG_INT),
+    ("InterfaceIndex", ARG_INT),
+    ("InterfaceIndexOrZero", ARG_INT),
+    ("IANAStorageMediaType", ARG_INT),
+    ("IANAifType", ARG_INT),
+    ("Unsigned32", ARG_UNSIGNED),
+    ("Gauge32", ARG_UNSIGNED),
+    ("Counter32", ARG_COUNTER),
+    ("Counter", ARG_COUNTER),
+    ("Counter64", ARG_BIG_COUNTER),
+    ("TimeTicks", ARG_TICKS),
+    ("TimeStamp", ARG_TICKS),
+    ("IpAddress", ARG_ADDRESS),
+    ("OCTET", ARG_STRING),
+    ("BITS", ARG_STRING),
+    ("DisplayString", ARG_STRING),
+    ("SnmpAdminString", ARG_STRING),
+    ("OwnerString", ARG_STRING),
+    ("PhysAddress", ARG_STRING),
+    ("MacAddress", ARG_STRING),
+    ("DateAndTime", ARG_STRING),
+    ("UUIDorZero", ARG_STRING),
+    ("SnmpEngineID", ARG_STRING),
+    ("InetAddress", ARG_STRING),
+    ("SnmpTagValue", ARG_STRING),
+    ("OBJECT", ARG_OID),
+    ("AutonomousType", ARG_OID),
+    ("RowPointer", ARG_OID),
+    ("VariablePointer", ARG_OID),
+];
+
+/// Argument type and conversion for a notification object, falling back to ObjectSyntax
+/// when the syntax isn't known here, so the caller builds the value.
+fn notify_arg(
+    name: &str,
+    object_types: &HashMap<&str, ObjectType>,
+    tcs: &HashMap<&str, TextConvention>,
+) -> (&'static str, &'static str) {
+    if let Some(data) = object_types.get(name) {
+        let mut syntax = data.syntax.trim();
+        if tcs.contains_key(syntax) {
+            syntax = tcs[syntax].syntax.trim();
+        }
+        let val = syntax.split([' ', '(', '{']).next().unwrap();
+        for (key, arg) in NAME_NOTIFY_ARGS.iter() {
+            if val == *key {
+                return *arg;
+            }
+        }
+        warn!("No argument type for {name} syntax {syntax}, taking ObjectSyntax");
+    }
+    ("ObjectSyntax", "{v}")
+}
+
+/// Columns need an instance index from the caller, scalars are always .0.
+/// Imported columns have no entry here, so fall back on the parent's name.
+fn notify_column(name: &str, object_types: &HashMap<&str, ObjectType>) -> bool {
+    match object_types.get(name) {
+        Some(data) => {
+            let parent = data.val.parent;
+            data.col
+                || parent.ends_with("Entry")
+                || object_types
+                    .get(parent)
+                    .is_some_and(|p| !p.index.is_empty() || p.augments.len() > 2)
+        }
+        None => true,
+    }
+}
+
+fn write_notifications(
+    out: &mut fs::File,
+    notifications: &[NotificationType],
+    object_types: &HashMap<&str, ObjectType>,
+    tcs: &HashMap<&str, TextConvention>,
+    resolve: &resolver::Resolver,
+    names: &[&str],
+) -> Result<(), Error> {
+    //Write a builder function for each NOTIFICATION-TYPE"""
+    if notifications.is_empty() {
+        return Ok(());
+    }
+    out.write_all(
+        b"\n// Builders for NOTIFICATION-TYPE. Each returns the snmpTrapOID and varbinds\n",
+    )?;
+    out.write_all(b"// to pass to NotificationSender::notify\n\n")?;
+    let mut arcs: HashSet<&str> = names.iter().copied().collect();
+    for nt in notifications {
+        for name in std::iter::once(&nt.name).chain(nt.objects.iter()) {
+            if !resolve.check_name(name) || !arcs.insert(name) {
+                continue;
+            }
+            let uname = upper_snake(name);
+            let arc = resolve.lookup(name);
+            let larc = arc.len();
+            out.write_all(format!("const ARC_{uname}: [u32; {larc}] = {arc:?};\n").as_bytes())?;
+        }
+    }
+    out.write_all(
+        br"
+fn notify_var_bind(arc: &[u32], instance: &[u32], value: ObjectSyntax) -> VarBind {
+    let mut name = arc.to_vec();
+    name.extend_from_slice(instance);
+    VarBind {
+        name: ObjectIdentifier::new(name).unwrap(),
+        value: VarBindValue::Value(value),
+    }
+}
+",
+    )?;
+    for nt in notifications {
+        let unresolved: Vec<&&str> = std::iter::once(&nt.name)
+            .chain(nt.objects.iter())
+            .filter(|name| !resolve.check_name(name))
+            .collect();
+        if !unresolved.is_empty() {
+            error!(
+                "Skipping NOTIFICATION-TYPE {}, unresolved {unresolved:?}",
+                nt.name
+            );
+            continue;
+        }
+        let mut args = vec![];
+        let mut var_binds = vec![];
+        // One index per table, so all the columns of a table come from the same row
+        let mut rows: Vec<(Vec<u32>, String)> = vec![];
+        for obj in &nt.objects {
+            let lobj = lower_snake(obj);
+            let uobj = upper_snake(obj);
+            let (rtype, conv) = notify_arg(obj, object_types, tcs);
+            args.push(format!("    {lobj}: {rtype},\n"));
+            let instance = if notify_column(obj, object_types) {
+                let arc = resolve.lookup(obj);
+                let entry = arc[..arc.len() - 1].to_vec();
+                match rows.iter().find(|(arc, _)| *arc == entry) {
+                    Some((_, index)) => index.clone(),
+                    None => {
+                        let index = match object_types.get(obj) {
+                            Some(data) => format!("{}_index", lower_snake(data.val.parent)),
+                            None => format!("{lobj}_index"),
+                        };
+                        rows.push((entry, index.clone()));
+                        index
+                    }
+                }
+            } else {
+                "&[0]".to_string()
+            };
+            let value = conv.replace("{v}", &lobj);
+            var_binds.push(format!(
+                "            notify_var_bind(&ARC_{uobj}, {instance}, {value}),\n"
+            ));
+        }
+        for (_, index) in &rows {
+            args.push(format!("    {index}: &[u32],\n"));
+        }
+        let name = nt.name;
+        let lname = lower_snake(name);
+        let uname = upper_snake(name);
+        let allow = if args.len() > 7 {
+            "#[allow(clippy::too_many_arguments)]\n"
+        } else {
+            ""
+        };
+        let args = args.concat();
+        let var_binds = var_binds.concat();
+        out.write_all(b"\n")?;
+        if nt.description.len() > 2 {
+            out.write_all(slash_b(nt.description).as_bytes())?;
+        }
+        out.write_all(format!("/// Build the {name} notification\n").as_bytes())?;
+        out.write_all(
+            format!(
+                "{allow}pub fn {lname}(
+{args}) -> (ObjectIdentifier, Vec<VarBind>) {{
+    (
+        ObjectIdentifier::new(&ARC_{uname}).unwrap(),
+        vec![
+{var_binds}        ],
+    )
+}}
+"
+            )
+            .as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn gen_stub(
     object_types: &HashMap<&str, ObjectType>,
     resolve: resolver::Resolver,
@@ -658,6 +898,7 @@ pub fn gen_stub(
     entries: &HashMap<&str, Entry>,
     object_ids: &[ObjectIdentity],
     mod_comps: &[ModuleCompliance],
+    notifications: &[NotificationType],
     mib_name: &str,
     out_dir: &str,
 ) -> Result<(), Error> {
@@ -682,6 +923,7 @@ pub fn gen_stub(
     let stub_start = r"
 use crate::config::ComplianceStatements;
 use crate::keeper::{Access, OidErr, OidKeeper, OType};
+use crate::notifier::NotificationSender;
 use crate::scalar::ScalarMemOid;
 use crate::table::TableMemOid;
 use crate::oidmap::OidMap;
@@ -774,9 +1016,17 @@ fn ticks_from_int(value:u32) -> ObjectSyntax {
     let names = ordered_names(object_types, &resolve);
     write_arcs(&mut out, object_ids, &resolve, &names, mod_comps)?;
     write_ot_structs(&mut out, object_types, tcs, entries, &names)?;
+    write_notifications(&mut out, notifications, object_types, tcs, &resolve, &names)?;
     let ot = r"
 
-pub fn load_stub(oid_map: &mut OidMap, comp: &mut ComplianceStatements) {
+// Keepers that raise notifications can keep a clone of notifications, and pass it what
+// the builders above return. Until then, it and comp are unused.
+#[allow(unused_variables)]
+pub fn load_stub(
+    oid_map: &mut OidMap,
+    notifications: &NotificationSender,
+    comp: &mut ComplianceStatements,
+) {
 ";
     out.write_all(ot.as_bytes())?;
     write_object_ids(&mut out, object_ids)?;
@@ -796,7 +1046,7 @@ pub fn loader(mib_files: Vec<String>) -> Result<(), Error> {
 //! Do not edit - it will be over-written next time you run stub-gen
 ";
     src.write_all(doc)?;
-    src.write_all(b"use crate::oidmap::OidMap;\nuse crate::config::ComplianceStatements;\n\n")?;
+    src.write_all(b"use crate::config::ComplianceStatements;\nuse crate::notifier::NotificationSender;\nuse crate::oidmap::OidMap;\n\n")?;
     let mut stubs = vec![];
     for mib_name in mib_files {
         let mut base_name = mib_name.split("-MIB").next().unwrap().to_lowercase();
@@ -806,13 +1056,15 @@ pub fn loader(mib_files: Vec<String>) -> Result<(), Error> {
         stubs.push(stub_name);
     }
     for stub in &stubs {
-        src.write_all(format!("mod {stub};\n").as_bytes())?;
+        src.write_all(format!("pub mod {stub};\n").as_bytes())?;
     }
     src.write_all(
-        b"\n\n///Generated function to load all stubs\npub fn load_stubs(oid_map: &mut OidMap, comp: &mut ComplianceStatements) {\n",
+        b"\n\n///Generated function to load all stubs\npub fn load_stubs(\n    oid_map: &mut OidMap,\n    notifications: &NotificationSender,\n    comp: &mut ComplianceStatements,\n) {\n",
     )?;
     for stub in &stubs {
-        src.write_all(format!("    {stub}::load_stub(oid_map, comp);\n").as_bytes())?;
+        src.write_all(
+            format!("    {stub}::load_stub(oid_map, notifications, comp);\n").as_bytes(),
+        )?;
     }
     src.write_all(b"}\n")?;
     Ok(())
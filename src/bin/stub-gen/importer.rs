@@ -3,7 +3,8 @@ use crate::resolver;
 use log::{error, warn};
 use std::fs;
 
-const MIB_SEARCH_PATH: [&str; 3] = [
+const MIB_SEARCH_PATH: [&str; 4] = [
+    "mibs/",
     "/var/lib/mibs/ietf/",
     "/var/lib/mibs/iana/",
     "/usr/share/snmp/mibs/",
@@ -59,6 +59,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         "Unsigned32",
         "Counter64",
         "mib-2",
+        "enterprises",
         "transmission",
         "IpAddress",
         "OBJECT-IDENTITY",
@@ -129,8 +130,8 @@ fn main() -> Result<(), Box<dyn Error>> {
                     .collect();
                 if !miss.is_empty() {
                     let txt_opt = importer::find_mib_text(mib_name);
-                    if txt_opt.is_some() {
-                        iraw = txt_opt.unwrap().clone();
+                    if let Some(txt) = txt_opt {
+                        iraw = txt;
                         let extra = importer::process_one(&iraw, miss, mib_name, &mut res);
                         nodes.extend(extra);
                     } else {
@@ -186,13 +187,13 @@ fn main() -> Result<(), Box<dyn Error>> {
                             }
                         }
                         parser::MibNode::NtTy(o) => {
-                            /*let v = &o.val;
+                            let v = &o.val;
                             let parent = v.parent;
                             let added = res.try_add(o.name, v.parent, &v.num);
                             if good_parent && !added && pass > 1 {
-                                error!("Unknown compliance parent {parent}")
-                            }*/
-                            if pass > 1 {
+                                error!("Unknown notification parent {parent}")
+                            }
+                            if pass == 1 {
                                 for obj in &o.objects {
                                     if !res.check_name(obj) {
                                         warn!(
@@ -212,28 +213,22 @@ fn main() -> Result<(), Box<dyn Error>> {
             let mut entries: HashMap<&str, parser::Entry<'_>> = HashMap::new();
             let mut object_ids: Vec<parser::ObjectIdentity<'_>> = vec![];
             let mut mod_comps: Vec<parser::ModuleCompliance<'_>> = vec![];
+            let mut notifications: Vec<parser::NotificationType<'_>> = vec![];
             let gnodes = nodes.clone();
             for node in gnodes {
                 match node {
-                    parser::MibNode::ObTy(x) => {
-                        if cli.check_status(x.status) {
-                            object_types.insert(x.name, x);
-                        }
+                    parser::MibNode::ObTy(x) if cli.check_status(x.status) => {
+                        object_types.insert(x.name, x);
                     }
-                    parser::MibNode::Tc(x) => {
-                        if cli.check_status(x.status) {
-                            tcs.insert(x.name, x);
-                        }
+                    parser::MibNode::Tc(x) if cli.check_status(x.status) => {
+                        tcs.insert(x.name, x);
                     }
                     parser::MibNode::Ent(x) => {
                         entries.insert(x.name, x);
                     }
-                    parser::MibNode::ObIdy(x) => {
-                        if cli.check_status(x.status) {
-                            object_ids.push(x)
-                        }
-                    }
+                    parser::MibNode::ObIdy(x) if cli.check_status(x.status) => object_ids.push(x),
                     parser::MibNode::ModCp(x) => mod_comps.push(x),
+                    parser::MibNode::NtTy(x) if cli.check_status(x.status) => notifications.push(x),
                     _ => {}
                 }
             }
@@ -253,6 +248,7 @@ fn main() -> Result<(), Box<dyn Error>> {
                 &entries,
                 &object_ids,
                 &mod_comps,
+                &notifications,
                 mib_name,
                 &out_dir,
             );
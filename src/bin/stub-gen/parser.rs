@@ -79,7 +79,7 @@ pub struct NotificationType<'a> {
     pub name: &'a str,
     pub objects: Vec<&'a str>,
     pub status: &'a str,
-    description: &'a str,
+    pub description: &'a str,
     reference: &'a str,
     pub val: ParentNum<'a>,
 }
@@ -853,16 +853,16 @@ pub fn parse_mib<'a>(input: &'a str, nodes: &mut Vec<MibNode<'a>>) -> (bool, u32
     let (abc, _def) = parse_def(leftover_input).unwrap();
 
     let imp_res = parse_imports(abc);
-    let body;
-    let imps;
-    if imp_res.is_err() {
-        //let deb = &abc[0..128];
-        return (false, 2);
-        // body = abc;
-    } else {
-        (body, imps) = imp_res.unwrap();
-        nodes.push(imps);
-    }
+    let body = match imp_res {
+        Err(_) => {
+            //let deb = &abc[0..128];
+            return (false, 2);
+        }
+        Ok((body, imps)) => {
+            nodes.push(imps);
+            body
+        }
+    };
 
     let (end, mut defs) = parse_defs(body).unwrap();
     nodes.append(&mut defs);
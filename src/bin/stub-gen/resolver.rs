@@ -81,25 +81,17 @@ impl Resolver {
         let mut good = 0u32;
         for node in nodes {
             match node {
-                parser::MibNode::ModId(ref o) => {
-                    if self.try_add(o.name, o.val.parent, &o.val.num) {
-                        good += 1;
-                    }
+                parser::MibNode::ModId(ref o) if self.try_add(o.name, o.val.parent, &o.val.num) => {
+                    good += 1;
                 }
-                parser::MibNode::ObTy(ref o) => {
-                    if self.try_add(o.name, o.val.parent, &o.val.num) {
-                        good += 1;
-                    }
+                parser::MibNode::ObTy(ref o) if self.try_add(o.name, o.val.parent, &o.val.num) => {
+                    good += 1;
                 }
-                parser::MibNode::ObIdf(ref o) => {
-                    if self.try_add(o.name, o.val.parent, &o.val.num) {
-                        good += 1;
-                    }
+                parser::MibNode::ObIdf(ref o) if self.try_add(o.name, o.val.parent, &o.val.num) => {
+                    good += 1;
                 }
-                parser::MibNode::ObIdy(ref o) => {
-                    if self.try_add(o.name, o.val.parent, &o.val.num) {
-                        good += 1;
-                    }
+                parser::MibNode::ObIdy(ref o) if self.try_add(o.name, o.val.parent, &o.val.num) => {
+                    good += 1;
                 }
                 _ => (),
             };
@@ -7,12 +7,33 @@
 //! The following keys are compulsory, and the loader will panic if they are missing or not parsed:
 //! * EngineID - engine_id_from_str in engine_id module for details.
 //! * FQDN - Fully qualified hostname to use for system identification.
-//! * Listen - the listen address and port. For many systems, this will be 0.0.0.0:161, but you may only want to listen on a trusted interface for devices like firewalls and routers.
+//! * Listen - the listen address and port, unless AgentXMaster is set. For many systems, this will be 0.0.0.0:161, but you may only want to listen on a trusted interface for devices like firewalls and routers.
+//!   Repeat the line, or give several addresses separated by spaces or commas, to listen on more than one. See the transport module for IPv6,
+//!   and for the "tcp:", "tls:" and "dtls:" prefixes that listen for SNMP over TCP, TLS and DTLS.
 //! * StoragePath - path to writeable directory where persistence files will be written.
 //!
 //! These keys are optional, and zero length strings will be used if they are absent.
 //! * Contact - name and email (or other) address for person responsible for system where Agent is running
-//! * TrapSink - address and port where Trap PDUs will be sent when the agent has trap support.
+//! * TrapSink - address and port where Trap PDUs will be sent, such as 192.0.2.9:162. Needs TrapUser.
+//!   Like InformSink and NotifyConfig, only used to seed the notification tables, see the notify module.
+//! * TrapUser - user from users.txt that secures the traps, at the highest level its keys allow.
+//! * StartTrap - "coldStart" or "warmStart", the notification sent when the agent starts. coldStart if absent.
+//! * InformSink - address and port where InformRequest PDUs will be sent, then the timeout in hundredths of a
+//!   second and the number of retries, such as "192.0.2.9:162 1500 3". Needs InformUser.
+//! * InformUser - user of the inform receiver, "name hash authkey privacy privkey" as in users.txt without the
+//!   group, with the keys localized to the receiver's engine ID, which is discovered.
+//! * TlsCertificate - PEM file with the agent's certificate chain, needed to listen on TLS or DTLS.
+//! * TlsPrivateKey - PEM file with the private key for TlsCertificate.
+//! * TlsTrustAnchors - PEM file with the CA certificates that sign manager certificates.
+//! * TlsCertMap - file mapping manager certificates to securityNames, see the tsm module.
+//! * AgentXSocket - path of the Unix domain socket where AgentX subagents connect, usually /var/agentx/master.
+//!   The agent is not an AgentX master unless this is set.
+//! * AgentXMaster - path of the socket of another AgentX master, usually /var/agentx/master. If set, the agent
+//!   runs as a subagent of that master, registering all its OIDs there, rather than listening for SNMP itself.
+//! * ProxyConfig - file of proxy entries and the targets they forward to, see the proxy and target modules.
+//!   Requests are only forwarded to other agents if this is set.
+//! * NotifyConfig - file of notification entries and the targets they are sent to, see the notify and target
+//!   modules.
 //!
 //! Panics if the file cannot be found, has missing keys or on parse errors.
 //!
@@ -22,13 +43,26 @@ use log::{debug, error};
 use rasn::types::OctetString;
 use std::fs::{exists, read_to_string};
 
+#[derive(Default)]
 pub struct Config {
     pub engine_id: OctetString,
     pub fqdn: String,
-    pub listen: String,
+    pub listen: Vec<String>,
     pub storage_path: String,
     pub contact: String,
     pub trap_sink: String,
+    pub trap_user: String,
+    pub start_trap: String,
+    pub inform_sink: String,
+    pub inform_user: String,
+    pub tls_certificate: String,
+    pub tls_private_key: String,
+    pub tls_trust_anchors: String,
+    pub tls_cert_map: String,
+    pub agentx_socket: String,
+    pub agentx_master: String,
+    pub proxy_config: String,
+    pub notify_config: String,
 }
 
 const CONF_FILES: [&str; 3] = [
@@ -43,8 +77,20 @@ impl Config {
         let mut fqdn = "".to_string();
         let mut contact = "".to_string();
         let mut storage_path = "".to_string();
-        let mut listen = "".to_string();
+        let mut listen: Vec<String> = vec![];
         let mut trap_sink = "".to_string();
+        let mut trap_user = "".to_string();
+        let mut start_trap = "".to_string();
+        let mut inform_sink = "".to_string();
+        let mut inform_user = "".to_string();
+        let mut tls_certificate = "".to_string();
+        let mut tls_private_key = "".to_string();
+        let mut tls_trust_anchors = "".to_string();
+        let mut tls_cert_map = "".to_string();
+        let mut agentx_socket = "".to_string();
+        let mut agentx_master = "".to_string();
+        let mut proxy_config = "".to_string();
+        let mut notify_config = "".to_string();
         let mut got_eid = false;
         let mut got_fqdn = false;
         let mut got_listen = false;
@@ -61,8 +107,13 @@ impl Config {
                     got_fqdn = true;
                 }
                 "Listen" => {
-                    listen = parts[1].to_string();
-                    got_listen = true;
+                    listen.extend(
+                        parts[1]
+                            .split([' ', ','])
+                            .filter(|addr| !addr.is_empty())
+                            .map(str::to_string),
+                    );
+                    got_listen = !listen.is_empty();
                 }
                 "StoragePath" => {
                     storage_path = parts[1].to_string();
@@ -70,11 +121,25 @@ impl Config {
                 }
                 "Contact" => contact = parts[1].to_string(),
                 "TrapSink" => trap_sink = parts[1].to_string(),
+                "TrapUser" => trap_user = parts[1].to_string(),
+                "StartTrap" => start_trap = parts[1].to_string(),
+                "InformSink" => inform_sink = parts[1].to_string(),
+                "InformUser" => inform_user = parts[1].to_string(),
+                "TlsCertificate" => tls_certificate = parts[1].to_string(),
+                "TlsPrivateKey" => tls_private_key = parts[1].to_string(),
+                "TlsTrustAnchors" => tls_trust_anchors = parts[1].to_string(),
+                "TlsCertMap" => tls_cert_map = parts[1].to_string(),
+                "AgentXSocket" => agentx_socket = parts[1].to_string(),
+                "AgentXMaster" => agentx_master = parts[1].to_string(),
+                "ProxyConfig" => proxy_config = parts[1].to_string(),
+                "NotifyConfig" => notify_config = parts[1].to_string(),
                 _ => {
                     debug!("Unexpected keyword in config file {0}", parts[0]);
                 }
             }
         }
+        // A subagent leaves the listening to its master
+        got_listen |= !agentx_master.is_empty();
         if got_eid && got_fqdn && got_listen && got_path {
             debug!("All compulsory values found");
         } else {
@@ -100,6 +165,18 @@ impl Config {
             storage_path,
             contact,
             trap_sink,
+            trap_user,
+            start_trap,
+            inform_sink,
+            inform_user,
+            tls_certificate,
+            tls_private_key,
+            tls_trust_anchors,
+            tls_cert_map,
+            agentx_socket,
+            agentx_master,
+            proxy_config,
+            notify_config,
         }
     }
 
@@ -121,6 +198,12 @@ pub struct ComplianceStatements {
     pub claims: Vec<(&'static [u32], &'static str)>,
 }
 
+impl Default for ComplianceStatements {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ComplianceStatements {
     pub fn new() -> Self {
         ComplianceStatements { claims: vec![] }
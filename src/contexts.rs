@@ -0,0 +1,60 @@
+//! Registry of SNMP contexts.
+//!
+//! Each contextName has its own OidMap, so the same MIBs can be exposed once per VRF or
+//! container instance. The default context, with the empty name, is the OidMap passed to
+//! the agent loop, so an agent that registers no contexts behaves as before.
+//!
+use crate::oidmap::OidMap;
+use std::collections::HashMap;
+
+/// Mapping between non-default context names and their OidMaps.
+pub struct Contexts {
+    store: HashMap<Vec<u8>, OidMap>,
+}
+
+impl Contexts {
+    /// Return a new registry, with no contexts beyond the default.
+    pub fn new() -> Self {
+        Contexts {
+            store: HashMap::new(),
+        }
+    }
+
+    /// Register the OidMap for context name, replacing any earlier one.
+    pub fn insert(&mut self, name: &[u8], oid_map: OidMap) {
+        self.store.insert(name.to_vec(), oid_map);
+    }
+
+    /// True if name has been registered.
+    pub fn contains(&self, name: &[u8]) -> bool {
+        self.store.contains_key(name)
+    }
+
+    /// Remove the OidMap for name while a request is processed, put it back with insert.
+    pub fn take(&mut self, name: &[u8]) -> Option<OidMap> {
+        self.store.remove(name)
+    }
+
+    /// Sort every OidMap, as the agent does before it starts the loop.
+    pub fn sort(&mut self) {
+        for oid_map in self.store.values_mut() {
+            oid_map.sort();
+        }
+    }
+
+    /// Number of registered contexts, not counting the default.
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    /// True if only the default context exists.
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
+}
+
+impl Default for Contexts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,385 @@
+use crate::config::ComplianceStatements;
+use crate::keeper::{Access, OType, OidErr, OidKeeper};
+use crate::notifier::NotificationSender;
+use crate::oidmap::OidMap;
+use crate::scalar::ScalarMemOid;
+use crate::table::TableMemOid;
+use rasn::types::{Integer, ObjectIdentifier, OctetString};
+
+use rasn_smi::v2::{ApplicationSyntax, Counter32, ObjectSyntax, SimpleSyntax, TimeTicks};
+
+use rasn_snmp::v3::{VarBind, VarBindValue};
+
+fn simple_from_int(value: i32) -> ObjectSyntax {
+    ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(value)))
+}
+
+fn simple_from_str(value: &'static [u8]) -> ObjectSyntax {
+    ObjectSyntax::Simple(SimpleSyntax::String(OctetString::from_static(value)))
+}
+
+fn simple_from_vec(value: &'static [u32]) -> ObjectSyntax {
+    ObjectSyntax::Simple(SimpleSyntax::ObjectId(
+        ObjectIdentifier::new(value).unwrap(),
+    ))
+}
+
+fn counter_from_int(value: u32) -> ObjectSyntax {
+    ObjectSyntax::ApplicationWide(ApplicationSyntax::Counter(Counter32 { 0: value }))
+}
+
+fn ticks_from_int(value: u32) -> ObjectSyntax {
+    ObjectSyntax::ApplicationWide(ApplicationSyntax::Ticks(TimeTicks { 0: value }))
+}
+const ARC_EXAMPLE_LEVEL: [u32; 10] = [1, 3, 6, 1, 4, 1, 32473, 1, 1, 1];
+const ARC_EXAMPLE_LAST_CHANGE: [u32; 10] = [1, 3, 6, 1, 4, 1, 32473, 1, 1, 2];
+const ARC_EXAMPLE_TYPE: [u32; 10] = [1, 3, 6, 1, 4, 1, 32473, 1, 1, 3];
+const ARC_EXAMPLE_PORT_TABLE: [u32; 10] = [1, 3, 6, 1, 4, 1, 32473, 1, 1, 4];
+
+// Now the OBJECT-TYPES. These need actual code added to the stubs
+
+// The level being watched.
+
+struct KeepExampleLevel {
+    scalar: ScalarMemOid,
+}
+
+impl KeepExampleLevel {
+    fn new() -> Self {
+        KeepExampleLevel {
+            scalar: ScalarMemOid::new(counter_from_int(0), OType::Counter, Access::ReadOnly),
+        }
+    }
+}
+
+impl OidKeeper for KeepExampleLevel {
+    fn is_scalar(&self, _oid: ObjectIdentifier) -> bool {
+        true
+    }
+    fn get(&self, oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
+        self.scalar.get(oid)
+    }
+    fn get_next(&self, oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
+        self.scalar.get_next(oid)
+    }
+    fn access(&self, oid: ObjectIdentifier) -> Access {
+        self.scalar.access(oid)
+    }
+    fn set(&mut self, oid: ObjectIdentifier, value: VarBindValue) -> Result<VarBindValue, OidErr> {
+        self.scalar.set(oid, value)
+    }
+    fn begin_transaction(&mut self) -> Result<(), OidErr> {
+        self.scalar.begin_transaction()
+    }
+    fn commit(&mut self) -> Result<(), OidErr> {
+        self.scalar.commit()
+    }
+    fn rollback(&mut self) -> Result<(), OidErr> {
+        self.scalar.rollback()
+    }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
+}
+// sysUpTime when a port last changed state.
+
+struct KeepExampleLastChange {
+    scalar: ScalarMemOid,
+}
+
+impl KeepExampleLastChange {
+    fn new() -> Self {
+        KeepExampleLastChange {
+            scalar: ScalarMemOid::new(ticks_from_int(0), OType::Ticks, Access::ReadOnly),
+        }
+    }
+}
+
+impl OidKeeper for KeepExampleLastChange {
+    fn is_scalar(&self, _oid: ObjectIdentifier) -> bool {
+        true
+    }
+    fn get(&self, oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
+        self.scalar.get(oid)
+    }
+    fn get_next(&self, oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
+        self.scalar.get_next(oid)
+    }
+    fn access(&self, oid: ObjectIdentifier) -> Access {
+        self.scalar.access(oid)
+    }
+    fn set(&mut self, oid: ObjectIdentifier, value: VarBindValue) -> Result<VarBindValue, OidErr> {
+        self.scalar.set(oid, value)
+    }
+    fn begin_transaction(&mut self) -> Result<(), OidErr> {
+        self.scalar.begin_transaction()
+    }
+    fn commit(&mut self) -> Result<(), OidErr> {
+        self.scalar.commit()
+    }
+    fn rollback(&mut self) -> Result<(), OidErr> {
+        self.scalar.rollback()
+    }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
+}
+// The kind of device.
+
+struct KeepExampleType {
+    scalar: ScalarMemOid,
+}
+
+impl KeepExampleType {
+    fn new() -> Self {
+        KeepExampleType {
+            scalar: ScalarMemOid::new(
+                simple_from_vec(&[1, 3, 6, 1]),
+                OType::ObjectId,
+                Access::ReadOnly,
+            ),
+        }
+    }
+}
+
+impl OidKeeper for KeepExampleType {
+    fn is_scalar(&self, _oid: ObjectIdentifier) -> bool {
+        true
+    }
+    fn get(&self, oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
+        self.scalar.get(oid)
+    }
+    fn get_next(&self, oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
+        self.scalar.get_next(oid)
+    }
+    fn access(&self, oid: ObjectIdentifier) -> Access {
+        self.scalar.access(oid)
+    }
+    fn set(&mut self, oid: ObjectIdentifier, value: VarBindValue) -> Result<VarBindValue, OidErr> {
+        self.scalar.set(oid, value)
+    }
+    fn begin_transaction(&mut self) -> Result<(), OidErr> {
+        self.scalar.begin_transaction()
+    }
+    fn commit(&mut self) -> Result<(), OidErr> {
+        self.scalar.commit()
+    }
+    fn rollback(&mut self) -> Result<(), OidErr> {
+        self.scalar.rollback()
+    }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
+}
+// A port.
+
+struct KeepExamplePortTable {
+    table: TableMemOid,
+}
+
+impl KeepExamplePortTable {
+    fn new() -> Self {
+        let base_oid: ObjectIdentifier = ObjectIdentifier::new(&ARC_EXAMPLE_PORT_TABLE).unwrap();
+
+        let mut tab = KeepExamplePortTable {
+            table: TableMemOid::new(
+                vec![
+                    simple_from_int(4),
+                    simple_from_str(b"b"),
+                    simple_from_int(4),
+                    simple_from_int(4),
+                    counter_from_int(0),
+                ],
+                5,
+                &base_oid,
+                vec![
+                    OType::Integer,
+                    OType::String,
+                    OType::Integer,
+                    OType::Integer,
+                    OType::Counter,
+                ],
+                vec![
+                    Access::NoAccess,
+                    Access::ReadOnly,
+                    Access::ReadOnly,
+                    Access::ReadOnly,
+                    Access::ReadOnly,
+                ],
+                vec![1],
+                false,
+            ),
+        };
+
+        tab.table.set_data(vec![vec![
+            simple_from_int(4),
+            simple_from_str(b"b"),
+            simple_from_int(4),
+            simple_from_int(4),
+            counter_from_int(0),
+        ]]);
+        tab
+    }
+}
+
+impl OidKeeper for KeepExamplePortTable {
+    fn is_scalar(&self, _oid: ObjectIdentifier) -> bool {
+        false
+    }
+    fn get(&self, oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
+        self.table.get(oid)
+    }
+    fn get_next(&self, oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
+        self.table.get_next(oid)
+    }
+    fn access(&self, oid: ObjectIdentifier) -> Access {
+        self.table.access(oid)
+    }
+    fn set(&mut self, oid: ObjectIdentifier, value: VarBindValue) -> Result<VarBindValue, OidErr> {
+        self.table.set(oid, value)
+    }
+    fn begin_transaction(&mut self) -> Result<(), OidErr> {
+        self.table.begin_transaction()
+    }
+    fn test(&self, oid: ObjectIdentifier, value: &VarBindValue) -> Result<(), OidErr> {
+        self.table.test(oid, value)
+    }
+    fn commit(&mut self) -> Result<(), OidErr> {
+        self.table.commit()
+    }
+    fn rollback(&mut self) -> Result<(), OidErr> {
+        self.table.rollback()
+    }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.table.undo()
+    }
+}
+
+// Builders for NOTIFICATION-TYPE. Each returns the snmpTrapOID and varbinds
+// to pass to NotificationSender::notify
+
+const ARC_EXAMPLE_RESTART: [u32; 10] = [1, 3, 6, 1, 4, 1, 32473, 1, 0, 1];
+const ARC_EXAMPLE_PORT_DOWN: [u32; 10] = [1, 3, 6, 1, 4, 1, 32473, 1, 0, 2];
+const ARC_EXAMPLE_PORT_ADMIN_STATUS: [u32; 12] = [1, 3, 6, 1, 4, 1, 32473, 1, 1, 4, 1, 3];
+const ARC_EXAMPLE_PORT_OPER_STATUS: [u32; 12] = [1, 3, 6, 1, 4, 1, 32473, 1, 1, 4, 1, 4];
+const ARC_EXAMPLE_PORT_NAME: [u32; 12] = [1, 3, 6, 1, 4, 1, 32473, 1, 1, 4, 1, 2];
+const ARC_EXAMPLE_LEVEL_HIGH: [u32; 10] = [1, 3, 6, 1, 4, 1, 32473, 1, 0, 3];
+const ARC_EXAMPLE_PORT_ERRORS: [u32; 12] = [1, 3, 6, 1, 4, 1, 32473, 1, 1, 4, 1, 5];
+
+fn notify_var_bind(arc: &[u32], instance: &[u32], value: ObjectSyntax) -> VarBind {
+    let mut name = arc.to_vec();
+    name.extend_from_slice(instance);
+    VarBind {
+        name: ObjectIdentifier::new(name).unwrap(),
+        value: VarBindValue::Value(value),
+    }
+}
+
+// The device restarted, like coldStart.
+/// Build the exampleRestart notification
+pub fn example_restart() -> (ObjectIdentifier, Vec<VarBind>) {
+    (ObjectIdentifier::new(&ARC_EXAMPLE_RESTART).unwrap(), vec![])
+}
+
+// A port went down, like linkDown.
+/// Build the examplePortDown notification
+pub fn example_port_down(
+    example_port_admin_status: i32,
+    example_port_oper_status: i32,
+    example_port_name: &[u8],
+    example_port_entry_index: &[u32],
+) -> (ObjectIdentifier, Vec<VarBind>) {
+    (
+        ObjectIdentifier::new(&ARC_EXAMPLE_PORT_DOWN).unwrap(),
+        vec![
+            notify_var_bind(
+                &ARC_EXAMPLE_PORT_ADMIN_STATUS,
+                example_port_entry_index,
+                simple_from_int(example_port_admin_status),
+            ),
+            notify_var_bind(
+                &ARC_EXAMPLE_PORT_OPER_STATUS,
+                example_port_entry_index,
+                simple_from_int(example_port_oper_status),
+            ),
+            notify_var_bind(
+                &ARC_EXAMPLE_PORT_NAME,
+                example_port_entry_index,
+                ObjectSyntax::Simple(SimpleSyntax::String(OctetString::from(
+                    example_port_name.to_vec(),
+                ))),
+            ),
+        ],
+    )
+}
+
+// The level crossed its threshold.
+/// Build the exampleLevelHigh notification
+pub fn example_level_high(
+    example_level: u32,
+    example_last_change: u32,
+    example_type: ObjectIdentifier,
+    example_port_errors: u32,
+    example_port_entry_index: &[u32],
+) -> (ObjectIdentifier, Vec<VarBind>) {
+    (
+        ObjectIdentifier::new(&ARC_EXAMPLE_LEVEL_HIGH).unwrap(),
+        vec![
+            notify_var_bind(
+                &ARC_EXAMPLE_LEVEL,
+                &[0],
+                ObjectSyntax::ApplicationWide(rasn_smi::v2::ApplicationSyntax::Unsigned(
+                    rasn_smi::v2::Unsigned32 { 0: example_level },
+                )),
+            ),
+            notify_var_bind(
+                &ARC_EXAMPLE_LAST_CHANGE,
+                &[0],
+                ObjectSyntax::ApplicationWide(rasn_smi::v2::ApplicationSyntax::Ticks(
+                    rasn_smi::v2::TimeTicks {
+                        0: example_last_change,
+                    },
+                )),
+            ),
+            notify_var_bind(
+                &ARC_EXAMPLE_TYPE,
+                &[0],
+                ObjectSyntax::Simple(SimpleSyntax::ObjectId(example_type)),
+            ),
+            notify_var_bind(
+                &ARC_EXAMPLE_PORT_ERRORS,
+                example_port_entry_index,
+                ObjectSyntax::ApplicationWide(rasn_smi::v2::ApplicationSyntax::Counter(
+                    rasn_smi::v2::Counter32 {
+                        0: example_port_errors,
+                    },
+                )),
+            ),
+        ],
+    )
+}
+
+// Keepers that raise notifications can keep a clone of notifications, and pass it what
+// the builders above return. Until then, it and comp are unused.
+#[allow(unused_variables)]
+pub fn load_stub(
+    oid_map: &mut OidMap,
+    notifications: &NotificationSender,
+    comp: &mut ComplianceStatements,
+) {
+    let oid_example_level: ObjectIdentifier = ObjectIdentifier::new(&ARC_EXAMPLE_LEVEL).unwrap();
+    let k_example_level: Box<dyn OidKeeper> = Box::new(KeepExampleLevel::new());
+    oid_map.push(oid_example_level, k_example_level);
+    let oid_example_last_change: ObjectIdentifier =
+        ObjectIdentifier::new(&ARC_EXAMPLE_LAST_CHANGE).unwrap();
+    let k_example_last_change: Box<dyn OidKeeper> = Box::new(KeepExampleLastChange::new());
+    oid_map.push(oid_example_last_change, k_example_last_change);
+    let oid_example_type: ObjectIdentifier = ObjectIdentifier::new(&ARC_EXAMPLE_TYPE).unwrap();
+    let k_example_type: Box<dyn OidKeeper> = Box::new(KeepExampleType::new());
+    oid_map.push(oid_example_type, k_example_type);
+    let oid_example_port_table: ObjectIdentifier =
+        ObjectIdentifier::new(&ARC_EXAMPLE_PORT_TABLE).unwrap();
+    let k_example_port_table: Box<dyn OidKeeper> = Box::new(KeepExamplePortTable::new());
+    oid_map.push(oid_example_port_table, k_example_port_table);
+    // Module Compliance values, uncomment when implemented
+}
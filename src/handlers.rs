@@ -2,6 +2,7 @@
 //!
 //! Do not edit - it will be over-written next time you run stub-gen
 use crate::config::{ComplianceStatements, Config};
+use crate::notifier::NotificationSender;
 use crate::oidmap::OidMap;
 use crate::snmp_agent::Agent;
 use crate::usm::Users;
@@ -14,10 +15,11 @@ pub fn load_stubs(
     config: &Config,
     agent: &Agent,
     users: &Users,
+    notifications: &NotificationSender,
     comp: &mut ComplianceStatements,
 ) {
-    snmp_user_based_sm_stub::load_stub(oid_map, config, agent, users, comp);
+    snmp_user_based_sm_stub::load_stub(oid_map, config, agent, users, notifications, comp);
 
     // Do this one last, as it loads the gtahered compliance statements
-    snmpv2_stub::load_stub(oid_map, config, agent, comp);
+    snmpv2_stub::load_stub(oid_map, config, agent, notifications, comp);
 }
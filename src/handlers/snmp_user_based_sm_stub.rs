@@ -1,8 +1,9 @@
 use crate::config::{ComplianceStatements, Config};
 use crate::keeper::{Access, OType, OidErr, OidKeeper};
+use crate::notifier::NotificationSender;
 use crate::oidmap::OidMap;
-use crate::scalar::{PersistentScalar, ScalarMemOid};
-use crate::snmp_agent::Agent;
+use crate::scalar::PersistentScalar;
+use crate::snmp_agent::{Agent, StatsCounter};
 use crate::table::TableMemOid;
 use crate::usm::Users;
 use log::{debug, warn};
@@ -58,15 +59,14 @@ const COMPLIANCE_USM_MIB_COMPLIANCE: [u32; 10] = [1, 3, 6, 1, 6, 3, 15, 2, 1, 1]
 // user that was not known to the SNMP engine.
 //
 
-#[derive(PartialEq, Eq, Hash)]
 struct KeepUsmStatsUnknownUserNames {
-    bad_users: u32,
+    bad_users: StatsCounter,
 }
 
 impl KeepUsmStatsUnknownUserNames {
     fn new(agent: &Agent) -> Self {
         KeepUsmStatsUnknownUserNames {
-            bad_users: agent.unknown_users,
+            bad_users: agent.unknown_users.clone(),
         }
     }
 }
@@ -76,7 +76,7 @@ impl OidKeeper for KeepUsmStatsUnknownUserNames {
         true
     }
     fn get(&self, _oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
-        Ok(VarBindValue::Value(counter_from_int(self.bad_users)))
+        Ok(VarBindValue::Value(counter_from_int(self.bad_users.get())))
     }
     fn get_next(&self, _oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
         Err(OidErr::OutOfRange)
@@ -156,21 +156,23 @@ impl OidKeeper for KeepUsmUserSpinLock {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of packets received by the SNMP
 // engine which were dropped because they didn't
 // contain the expected digest value.
 //
 
-#[derive(PartialEq, Eq, Hash)]
 struct KeepUsmStatsWrongDigests {
-    wrong: u32,
+    wrong: StatsCounter,
 }
 
 impl KeepUsmStatsWrongDigests {
     fn new(agent: &Agent) -> Self {
         KeepUsmStatsWrongDigests {
-            wrong: agent.wrong_digests,
+            wrong: agent.wrong_digests.clone(),
         }
     }
 }
@@ -180,7 +182,7 @@ impl OidKeeper for KeepUsmStatsWrongDigests {
         true
     }
     fn get(&self, _oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
-        Ok(VarBindValue::Value(counter_from_int(self.wrong)))
+        Ok(VarBindValue::Value(counter_from_int(self.wrong.get())))
     }
     fn get_next(&self, _oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
         Err(OidErr::OutOfRange)
@@ -210,15 +212,14 @@ impl OidKeeper for KeepUsmStatsWrongDigests {
 // decrypted.
 //
 
-#[derive(PartialEq, Eq, Hash)]
 struct KeepUsmStatsDecryptionErrors {
-    err_cnt: u32,
+    err_cnt: StatsCounter,
 }
 
 impl KeepUsmStatsDecryptionErrors {
     fn new(agent: &Agent) -> Self {
         KeepUsmStatsDecryptionErrors {
-            err_cnt: agent.decryption_errors,
+            err_cnt: agent.decryption_errors.clone(),
         }
     }
 }
@@ -228,7 +229,7 @@ impl OidKeeper for KeepUsmStatsDecryptionErrors {
         true
     }
     fn get(&self, _oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
-        Ok(VarBindValue::Value(counter_from_int(self.err_cnt)))
+        Ok(VarBindValue::Value(counter_from_int(self.err_cnt.get())))
     }
     fn get_next(&self, _oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
         Err(OidErr::OutOfRange)
@@ -258,15 +259,14 @@ impl OidKeeper for KeepUsmStatsDecryptionErrors {
 // snmpEngineID that was not known to the SNMP engine.
 //
 
-#[derive(PartialEq, Eq, Hash)]
 struct KeepUsmStatsUnknownEngineIDs {
-    scalar: ScalarMemOid,
+    unknown: StatsCounter,
 }
 
 impl KeepUsmStatsUnknownEngineIDs {
-    fn new() -> Self {
+    fn new(agent: &Agent) -> Self {
         KeepUsmStatsUnknownEngineIDs {
-            scalar: ScalarMemOid::new(counter_from_int(0), OType::Counter, Access::ReadOnly),
+            unknown: agent.unknown_engine_ids.clone(),
         }
     }
 }
@@ -275,26 +275,30 @@ impl OidKeeper for KeepUsmStatsUnknownEngineIDs {
     fn is_scalar(&self, _oid: ObjectIdentifier) -> bool {
         true
     }
-    fn get(&self, oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
-        self.scalar.get(oid)
+    fn get(&self, _oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
+        Ok(VarBindValue::Value(counter_from_int(self.unknown.get())))
     }
-    fn get_next(&self, oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
-        self.scalar.get_next(oid)
+    fn get_next(&self, _oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
+        Err(OidErr::OutOfRange)
     }
-    fn access(&self, oid: ObjectIdentifier) -> Access {
-        self.scalar.access(oid)
+    fn access(&self, _oid: ObjectIdentifier) -> Access {
+        Access::ReadOnly
     }
-    fn set(&mut self, oid: ObjectIdentifier, value: VarBindValue) -> Result<VarBindValue, OidErr> {
-        self.scalar.set(oid, value)
+    fn set(
+        &mut self,
+        _oid: ObjectIdentifier,
+        _value: VarBindValue,
+    ) -> Result<VarBindValue, OidErr> {
+        Err(OidErr::NotWritable)
     }
     fn begin_transaction(&mut self) -> Result<(), OidErr> {
-        self.scalar.begin_transaction()
+        Ok(())
     }
     fn commit(&mut self) -> Result<(), OidErr> {
-        self.scalar.commit()
+        Ok(())
     }
     fn rollback(&mut self) -> Result<(), OidErr> {
-        self.scalar.rollback()
+        Ok(())
     }
 }
 // The total number of packets received by the SNMP
@@ -302,15 +306,14 @@ impl OidKeeper for KeepUsmStatsUnknownEngineIDs {
 // outside of the authoritative SNMP engine's window.
 //
 
-#[derive(PartialEq, Eq, Hash)]
 struct KeepUsmStatsNotInTimeWindows {
-    not_in_window: u32,
+    not_in_window: StatsCounter,
 }
 
 impl KeepUsmStatsNotInTimeWindows {
     fn new(agent: &Agent) -> Self {
         KeepUsmStatsNotInTimeWindows {
-            not_in_window: agent.not_in_time_window,
+            not_in_window: agent.not_in_time_window.clone(),
         }
     }
 }
@@ -320,7 +323,9 @@ impl OidKeeper for KeepUsmStatsNotInTimeWindows {
         true
     }
     fn get(&self, _oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
-        Ok(VarBindValue::Value(counter_from_int(self.not_in_window)))
+        Ok(VarBindValue::Value(counter_from_int(
+            self.not_in_window.get(),
+        )))
     }
     fn get_next(&self, _oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
         Err(OidErr::OutOfRange)
@@ -351,15 +356,14 @@ impl OidKeeper for KeepUsmStatsNotInTimeWindows {
 // or otherwise unavailable.
 //
 
-#[derive(PartialEq, Eq, Hash)]
 struct KeepUsmStatsUnsupportedSecLevels {
-    scalar: ScalarMemOid,
+    unsupported: StatsCounter,
 }
 
 impl KeepUsmStatsUnsupportedSecLevels {
-    fn new() -> Self {
+    fn new(agent: &Agent) -> Self {
         KeepUsmStatsUnsupportedSecLevels {
-            scalar: ScalarMemOid::new(counter_from_int(0), OType::Counter, Access::ReadOnly),
+            unsupported: agent.unsupported_sec_levels.clone(),
         }
     }
 }
@@ -368,8 +372,10 @@ impl OidKeeper for KeepUsmStatsUnsupportedSecLevels {
     fn is_scalar(&self, _oid: ObjectIdentifier) -> bool {
         true
     }
-    fn get(&self, oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
-        self.scalar.get(oid)
+    fn get(&self, _oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
+        Ok(VarBindValue::Value(counter_from_int(
+            self.unsupported.get(),
+        )))
     }
     fn get_next(&self, _oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
         Err(OidErr::OutOfRange)
@@ -509,12 +515,18 @@ impl OidKeeper for KeepUsmUserTable {
     fn begin_transaction(&mut self) -> Result<(), OidErr> {
         self.table.begin_transaction()
     }
+    fn test(&self, oid: ObjectIdentifier, value: &VarBindValue) -> Result<(), OidErr> {
+        self.table.test(oid, value)
+    }
     fn commit(&mut self) -> Result<(), OidErr> {
         self.table.commit()
     }
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.table.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.table.undo()
+    }
 }
 
 pub fn load_stub(
@@ -522,6 +534,7 @@ pub fn load_stub(
     config: &Config,
     agent: &Agent,
     users: &Users,
+    _notifications: &NotificationSender,
     comp: &mut ComplianceStatements,
 ) {
     // The next group is for OBJECT-IDENTITY.
@@ -581,7 +594,7 @@ pub fn load_stub(
     let oid_usm_stats_unknown_engine_i_ds: ObjectIdentifier =
         ObjectIdentifier::new(&ARC_USM_STATS_UNKNOWN_ENGINE_I_DS).unwrap();
     let k_usm_stats_unknown_engine_i_ds: Box<dyn OidKeeper> =
-        Box::new(KeepUsmStatsUnknownEngineIDs::new());
+        Box::new(KeepUsmStatsUnknownEngineIDs::new(agent));
     oid_map.push(
         oid_usm_stats_unknown_engine_i_ds,
         k_usm_stats_unknown_engine_i_ds,
@@ -597,7 +610,7 @@ pub fn load_stub(
     let oid_usm_stats_unsupported_sec_levels: ObjectIdentifier =
         ObjectIdentifier::new(&ARC_USM_STATS_UNSUPPORTED_SEC_LEVELS).unwrap();
     let k_usm_stats_unsupported_sec_levels: Box<dyn OidKeeper> =
-        Box::new(KeepUsmStatsUnsupportedSecLevels::new());
+        Box::new(KeepUsmStatsUnsupportedSecLevels::new(agent));
     oid_map.push(
         oid_usm_stats_unsupported_sec_levels,
         k_usm_stats_unsupported_sec_levels,
@@ -2,6 +2,7 @@ use std::time::Instant;
 
 use crate::config::{ComplianceStatements, Config};
 use crate::keeper::{Access, OType, OidErr, OidKeeper};
+use crate::notifier::NotificationSender;
 use crate::oidmap::OidMap;
 use crate::scalar::ScalarMemOid;
 use crate::snmp_agent::Agent;
@@ -121,6 +122,9 @@ impl OidKeeper for KeepSnmpInGetNexts {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of MIB objects which have been
 // altered successfully by the SNMP protocol entity as
@@ -164,6 +168,9 @@ impl OidKeeper for KeepSnmpInTotalSetVars {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // An advisory lock used to allow several cooperating
 // command generator applications to coordinate their
@@ -212,6 +219,9 @@ impl OidKeeper for KeepSnmpSetSerialNo {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP Get-Response PDUs which
 // have been generated by the SNMP protocol entity.
@@ -254,6 +264,9 @@ impl OidKeeper for KeepSnmpOutGetResponses {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP Get-Next PDUs which have
 // been generated by the SNMP protocol entity.
@@ -296,6 +309,9 @@ impl OidKeeper for KeepSnmpOutGetNexts {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP Messages which were
 // passed from the SNMP protocol entity to the
@@ -339,6 +355,9 @@ impl OidKeeper for KeepSnmpOutPkts {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 
 // A value which indicates the set of services that this
@@ -402,6 +421,9 @@ impl OidKeeper for KeepSysServices {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP PDUs which were
 // delivered to the SNMP protocol entity and for
@@ -446,6 +468,9 @@ impl OidKeeper for KeepSnmpInBadValues {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The time (in hundredths of a second) since the
 // network management portion of the system was last
@@ -539,6 +564,9 @@ impl OidKeeper for KeepSnmpInNoSuchNames {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP Get-Response PDUs which
 // have been accepted and processed by the SNMP protocol
@@ -582,6 +610,9 @@ impl OidKeeper for KeepSnmpInGetResponses {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP Trap PDUs which have been
 // accepted and processed by the SNMP protocol entity.
@@ -624,6 +655,9 @@ impl OidKeeper for KeepSnmpInTraps {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The vendor's authoritative identification of the
 // network management subsystem contained in the entity.
@@ -677,6 +711,9 @@ impl OidKeeper for KeepSysObjectID {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // An entry (conceptual row) in the sysORTable.
 
@@ -807,6 +844,9 @@ impl OidKeeper for KeepSysContact {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP PDUs which were delivered
 // to the SNMP protocol entity and for which the value
@@ -850,6 +890,9 @@ impl OidKeeper for KeepSnmpInGenErrs {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of community-based SNMP messages (for
 // example, SNMPv1) delivered to the SNMP entity which
@@ -902,6 +945,9 @@ impl OidKeeper for KeepSnmpInBadCommunityUses {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 
 // The total number of community-based SNMP messages (for
@@ -957,6 +1003,9 @@ impl OidKeeper for KeepSnmpInBadCommunityNames {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // A textual description of the entity.  This value should
 // include the full name and version identification of
@@ -1005,6 +1054,9 @@ impl OidKeeper for KeepSysDescr {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of Confirmed Class PDUs (such as
 // GetRequest-PDUs, GetNextRequest-PDUs,
@@ -1055,6 +1107,9 @@ impl OidKeeper for KeepSnmpSilentDrops {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The value of sysUpTime at the time of the most recent
 // change in state or value of any instance of sysORID.
@@ -1097,6 +1152,9 @@ impl OidKeeper for KeepSysORLastChange {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of ASN.1 or BER errors encountered by
 // the SNMP entity when decoding received SNMP messages.
@@ -1187,6 +1245,9 @@ impl OidKeeper for KeepSnmpInTooBigs {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of messages delivered to the SNMP
 // entity from the transport service.
@@ -1229,6 +1290,9 @@ impl OidKeeper for KeepSnmpInPkts {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP Get-Request PDUs which
 // have been accepted and processed by the SNMP
@@ -1272,6 +1336,9 @@ impl OidKeeper for KeepSnmpInGetRequests {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP PDUs which were generated
 // by the SNMP protocol entity and for which the value
@@ -1315,6 +1382,9 @@ impl OidKeeper for KeepSnmpOutNoSuchNames {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP PDUs which were generated
 // by the SNMP protocol entity and for which the value
@@ -1358,6 +1428,9 @@ impl OidKeeper for KeepSnmpOutBadValues {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // Indicates whether the SNMP entity is permitted to
 // generate authenticationFailure traps.  The value of this
@@ -1377,8 +1450,9 @@ struct KeepSnmpEnableAuthenTraps {
 
 impl KeepSnmpEnableAuthenTraps {
     fn new() -> Self {
+        // disabled(2), until a manager asks for authenticationFailure notifications
         KeepSnmpEnableAuthenTraps {
-            scalar: ScalarMemOid::new(simple_from_int(0), OType::Integer, Access::ReadWrite),
+            scalar: ScalarMemOid::new(simple_from_int(2), OType::Integer, Access::ReadWrite),
         }
     }
 }
@@ -1408,6 +1482,9 @@ impl OidKeeper for KeepSnmpEnableAuthenTraps {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The physical location of this node (e.g., 'telephone
 // closet, 3rd floor').  If the location is unknown, the
@@ -1455,6 +1532,9 @@ impl OidKeeper for KeepSysLocation {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP PDUs which were generated
 // by the SNMP protocol entity and for which the value
@@ -1498,6 +1578,9 @@ impl OidKeeper for KeepSnmpOutTooBigs {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP PDUs which were generated
 // by the SNMP protocol entity and for which the value
@@ -1541,6 +1624,9 @@ impl OidKeeper for KeepSnmpOutGenErrs {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP messages which were delivered
 // to the SNMP entity and were for an unsupported SNMP
@@ -1584,6 +1670,9 @@ impl OidKeeper for KeepSnmpInBadVersions {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number valid SNMP PDUs which were delivered
 // to the SNMP protocol entity and for which the value
@@ -1632,6 +1721,9 @@ impl OidKeeper for KeepSnmpInReadOnlys {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of MIB objects which have been
 // retrieved successfully by the SNMP protocol entity
@@ -1676,6 +1768,9 @@ impl OidKeeper for KeepSnmpInTotalReqVars {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP Get-Request PDUs which
 // have been generated by the SNMP protocol entity.
@@ -1718,6 +1813,9 @@ impl OidKeeper for KeepSnmpOutGetRequests {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP Set-Request PDUs which
 // have been generated by the SNMP protocol entity.
@@ -1760,6 +1858,9 @@ impl OidKeeper for KeepSnmpOutSetRequests {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP Set-Request PDUs which
 // have been accepted and processed by the SNMP protocol
@@ -1803,6 +1904,9 @@ impl OidKeeper for KeepSnmpInSetRequests {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // An administratively-assigned name for this managed
 // node.  By convention, this is the node's fully-qualified
@@ -1851,6 +1955,9 @@ impl OidKeeper for KeepSysName {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of Confirmed Class PDUs
 // (such as GetRequest-PDUs, GetNextRequest-PDUs,
@@ -1900,6 +2007,9 @@ impl OidKeeper for KeepSnmpProxyDrops {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 // The total number of SNMP Trap PDUs which have
 // been generated by the SNMP protocol entity.
@@ -1942,12 +2052,16 @@ impl OidKeeper for KeepSnmpOutTraps {
     fn rollback(&mut self) -> Result<(), OidErr> {
         self.scalar.rollback()
     }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.scalar.undo()
+    }
 }
 
 pub fn load_stub(
     oid_map: &mut OidMap,
     config: &Config,
     agent: &Agent,
+    _notifications: &NotificationSender,
     comp: &mut ComplianceStatements,
 ) {
     // Module Compliance values, uncomment when implemented
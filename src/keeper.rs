@@ -4,6 +4,7 @@
 //!
 use rasn::types::ObjectIdentifier;
 use rasn_smi::v2::{ApplicationSyntax, ObjectSyntax, SimpleSyntax};
+use rasn_snmp::v2::Pdu;
 use rasn_snmp::v3::{VarBind, VarBindValue};
 use std::hash::Hash;
 
@@ -16,6 +17,9 @@ const ROW_STATUS_CREATE_AND_GO: Integer = Integer::Primitive(4);
 const ROW_STATUS_CREATE_AND_WAIT: Integer = Integer::Primitive(5);
 const ROW_STATUS_DELETE: Integer = Integer::Primitive(6); */
 
+/// Errors returned by handlers.
+///
+/// Most correspond directly to an SNMPv2 error-status, see error_status for the mapping.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum OidErr {
     OutOfRange,
@@ -25,6 +29,76 @@ pub enum OidErr {
     NoAccess,
     NotWritable,
     GenErr,
+    WrongLength,
+    WrongValue,
+    WrongEncoding,
+    NoCreation,
+    InconsistentValue,
+    InconsistentName,
+    ResourceUnavailable,
+    AuthorizationError,
+}
+
+impl OidErr {
+    /// The error-status to return in a Response for this error.
+    ///
+    /// OutOfRange from a set means the value is out of range, so is wrongValue.
+    /// The missing object cases can only reach here from a set, where they mean
+    /// the variable cannot be created.
+    pub fn error_status(&self) -> u32 {
+        match self {
+            OidErr::OutOfRange => Pdu::ERROR_STATUS_WRONG_VALUE,
+            OidErr::WrongType => Pdu::ERROR_STATUS_WRONG_TYPE,
+            OidErr::NoSuchInstance => Pdu::ERROR_STATUS_NO_CREATION,
+            OidErr::NoSuchName => Pdu::ERROR_STATUS_NO_CREATION,
+            OidErr::NoAccess => Pdu::ERROR_STATUS_NO_ACCESS,
+            OidErr::NotWritable => Pdu::ERROR_STATUS_NOT_WRITABLE,
+            OidErr::GenErr => Pdu::ERROR_STATUS_GEN_ERR,
+            OidErr::WrongLength => Pdu::ERROR_STATUS_WRONG_LENGTH,
+            OidErr::WrongValue => Pdu::ERROR_STATUS_WRONG_VALUE,
+            OidErr::WrongEncoding => Pdu::ERROR_STATUS_WRONG_ENCODING,
+            OidErr::NoCreation => Pdu::ERROR_STATUS_NO_CREATION,
+            OidErr::InconsistentValue => Pdu::ERROR_STATUS_INCONSISTENT_VALUE,
+            OidErr::InconsistentName => Pdu::ERROR_STATUS_INCONSISTENT_NAME,
+            OidErr::ResourceUnavailable => Pdu::ERROR_STATUS_RESOURCE_UNAVAILABLE,
+            OidErr::AuthorizationError => Pdu::ERROR_STATUS_AUTHORIZATION_ERROR,
+        }
+    }
+
+    /// The OidErr for an error-status received from elsewhere, like an AgentX subagent.
+    ///
+    /// The SNMPv1 errors map to their SNMPv2 equivalents, RFC 3584 section 4.4.
+    /// Anything without a counterpart, such as commitFailed, becomes GenErr.
+    pub fn from_error_status(error_status: u32) -> Self {
+        match error_status {
+            Pdu::ERROR_STATUS_NO_SUCH_NAME => OidErr::NoSuchName,
+            Pdu::ERROR_STATUS_BAD_VALUE => OidErr::WrongValue,
+            Pdu::ERROR_STATUS_READ_ONLY => OidErr::NotWritable,
+            Pdu::ERROR_STATUS_NO_ACCESS => OidErr::NoAccess,
+            Pdu::ERROR_STATUS_WRONG_TYPE => OidErr::WrongType,
+            Pdu::ERROR_STATUS_WRONG_LENGTH => OidErr::WrongLength,
+            Pdu::ERROR_STATUS_WRONG_ENCODING => OidErr::WrongEncoding,
+            Pdu::ERROR_STATUS_WRONG_VALUE => OidErr::WrongValue,
+            Pdu::ERROR_STATUS_NO_CREATION => OidErr::NoCreation,
+            Pdu::ERROR_STATUS_INCONSISTENT_VALUE => OidErr::InconsistentValue,
+            Pdu::ERROR_STATUS_RESOURCE_UNAVAILABLE => OidErr::ResourceUnavailable,
+            Pdu::ERROR_STATUS_AUTHORIZATION_ERROR => OidErr::AuthorizationError,
+            Pdu::ERROR_STATUS_NOT_WRITABLE => OidErr::NotWritable,
+            Pdu::ERROR_STATUS_INCONSISTENT_NAME => OidErr::InconsistentName,
+            _ => OidErr::GenErr,
+        }
+    }
+
+    /// The error-status for a failed Get, GetNext or GetBulk.
+    ///
+    /// RFC 3416 only allows genErr, tooBig and authorizationError for retrievals,
+    /// missing objects having been turned into exceptions already.
+    pub fn read_error_status(&self) -> u32 {
+        match self {
+            OidErr::AuthorizationError => Pdu::ERROR_STATUS_AUTHORIZATION_ERROR,
+            _ => Pdu::ERROR_STATUS_GEN_ERR,
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
@@ -96,19 +170,44 @@ pub trait OidKeeper {
     /// Transaction must be active!
     fn set(&mut self, oid: ObjectIdentifier, value: VarBindValue) -> Result<VarBindValue, OidErr>;
 
+    /// Validate a varbind accepted by set, once every varbind in the PDU has been set.
+    ///
+    /// Called for each such varbind before any keeper commits, so checks can look across
+    /// the whole transaction, like createAndGo arriving with all mandatory columns.
+    /// The default accepts everything, as set has already checked the varbind on its own.
+    fn test(&self, _oid: ObjectIdentifier, _value: &VarBindValue) -> Result<(), OidErr> {
+        Ok(())
+    }
+
     /// Commit the transaction
+    ///
+    /// A commit that fails must leave the keeper as it was before the transaction.
     fn commit(&mut self) -> Result<(), OidErr>;
 
-    /// Rollback the transaction
+    /// Rollback the transaction, before commit
     fn rollback(&mut self) -> Result<(), OidErr>;
 
+    /// Undo the last committed transaction
+    ///
+    /// Called when a keeper later in the same Set fails to commit.
+    /// The default cannot undo anything, so the agent reports undoFailed.
+    fn undo(&mut self) -> Result<(), OidErr> {
+        Err(OidErr::GenErr)
+    }
+
+    /// End the Set, whatever the outcome
+    ///
+    /// Called on every keeper involved, after commit, rollback or undo. The default does
+    /// nothing, it is for keepers that forward to something that needs telling, like AgentX.
+    fn cleanup(&mut self) {}
+
     /// Is table empty? Always return false for scalars in default impl
     fn is_empty(&self) -> bool {
         false
     }
 
     /// Never call this, except override implementation for sysORTable
-    fn load_compliances(&mut self, comp: &ComplianceStatements) {
+    fn load_compliances(&mut self, _comp: &ComplianceStatements) {
         panic!("Only call overridden method on sysOrTable");
     }
 }
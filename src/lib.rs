@@ -40,10 +40,8 @@
 //! the mandatory features you can un-comment, and your module will appear in the sysORTable. Some managers
 //! might even do something useful with that!
 //!
-//! There is no usable support for notifications so far, although I am thinking about it, and the start of
-//! some code in src/notifier.rs. Don't take that seriously, I will probably do something completely
-//! different when I understand the problem better. Please raise an issue or start a discussion if you have
-//! a specific use case in mind, as that would really help me decide how to do it.
+//! Notifications are sent by src/notifier.rs, as traps or informs, to the destinations chosen by the
+//! SNMP-TARGET-MIB and SNMP-NOTIFICATION-MIB tables in src/notify.rs.
 //!
 //! There are known limitations for tables that use either the AUGMENTS or use index columns drawn from foreign
 //! tables. The generated stubs have a single row of junk data in all tables. For most tables, that row is
@@ -66,13 +64,21 @@
 //!
 //! The source files for the Rust stub generator are under src/bin/stub-gen. It uses the nom parser combinator
 //! library for parsing. It has a reasonably complete parser implementation, which can parse almost all the MIBs
-//! on my machine except for legacy MIBS in Smi v1 and a few bootstrap definition files. The code generator in this
-//! version ignores everything to do with notifications (but does detailed parsing).  It also does the wrong thing
+//! on my machine except for legacy MIBS in Smi v1 and a few bootstrap definition files. For each
+//! NOTIFICATION-TYPE, the code generator writes a function taking the values of its OBJECTS, and one instance
+//! index for each table its columns come from, and returning the snmpTrapOID and varbinds to pass to
+//! `NotificationSender::notify`. The stub modules are public, so application code can call them as
+//! `stubs::if_stub::link_down`. Objects whose syntax it doesn't recognise are passed as an `ObjectSyntax`.  It also does the wrong thing
 //! with AUGMENTS and tables that use foreign index columns (AUGMENTS is actually just a
 //! special case of foreign indices).
 //!
 //! The stub generator now sorts items by Oid order, so as to give stable output.
 //!
+//! The stub for mibs/SNMP-RUST-EXAMPLE-MIB, a small module under the documentation enterprise number with a
+//! scalar, a table and three notifications, is checked in as src/example_stub.rs. It is only compiled for
+//! `cargo test`, which checks the generated builders. To refresh it after changing the generator, run stub-gen
+//! on that MIB with another `-o` directory, copy the stub to src/example_stub.rs, and put back src/stubs.rs.
+//!
 //!## Workflow
 //! First build the stub generator with:
 //! ```shell
@@ -86,10 +92,11 @@
 //! ```
 //! where MIB1 and MIB2 and so on are the names of the MIB files to generate stubs from.
 //!
-//! The generator searches /var/lib/mibs/ietf, /var/lib/mibs/iana and /usr/share/snmp/mibs to find the files,
-//! and tries adding .txt extension as well. If your system has the files somewhere different, or you wish to include
-//! vendor mibs, edit src/bin/stub-gen/importer.rs and change the SEARCH_PATH constant. Arguably, this should be
-//! settable by the command line and / or an environment variable.
+//! The generator searches mibs/ in the current directory, then /var/lib/mibs/ietf, /var/lib/mibs/iana and
+//! /usr/share/snmp/mibs to find the files, and tries adding .txt extension as well. If your system has the
+//! files somewhere different, or you wish to include vendor mibs, edit src/bin/stub-gen/importer.rs and change
+//! the SEARCH_PATH constant. Arguably, this should be settable by the command line and / or an environment
+//! variable.
 //!
 //! The generated stubs will be placed under src/stubs/ (or whatever other stub directory
 //! you specified with the -o flag).
@@ -135,16 +142,28 @@
 //! snmpwalk -v 3 -l authPriv -a SHA -A password  -x AES -X password1 -u myv3user   127.0.0.1:2161 1.3.6.1 1.3.6.1.6.3
 //! ```
 
+pub mod agentx;
 pub mod config;
+pub mod contexts;
 mod engine_id;
+// Generated by stub-gen from mibs/SNMP-RUST-EXAMPLE-MIB, to test its output
+#[cfg(test)]
+mod example_stub;
 pub mod handlers;
 pub mod keeper;
-mod notifier;
+pub mod notifier;
+pub mod notify;
 pub mod oidmap;
 pub mod perms;
 mod privacy;
+pub mod proxy;
 mod scalar;
 pub mod snmp_agent;
 pub mod stubs;
 mod table;
+pub mod target;
+pub mod transport;
+#[cfg(feature = "tsm")]
+pub mod tsm;
 pub mod usm;
+pub mod vacm;
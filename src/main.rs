@@ -1,15 +1,26 @@
 #![warn(missing_docs)]
 //! See documentation src/lib.rs
-//! 
-use log::{debug, info};
-use snmp_rust_agent::config::{Config, ComplianceStatements};
+//!
+use log::{info, warn};
+use rasn::types::ObjectIdentifier;
+use snmp_rust_agent::agentx::subagent::Subagent;
+use snmp_rust_agent::config::{ComplianceStatements, Config};
 use snmp_rust_agent::handlers;
+use snmp_rust_agent::notify;
 use snmp_rust_agent::oidmap::OidMap;
 use snmp_rust_agent::perms;
+use snmp_rust_agent::proxy::Proxy;
 use snmp_rust_agent::snmp_agent::Agent;
 use snmp_rust_agent::stubs::load_stubs;
 use snmp_rust_agent::usm;
+use snmp_rust_agent::vacm;
+use std::thread;
+use std::time::Duration;
 
+/// Identifies the subagent to the AgentX master, the same as sysObjectID.
+const SUBAGENT_ID: [u32; 4] = [1, 3, 6, 1];
+/// Wait before reconnecting to the AgentX master.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
 
 /// Simplistic example main. Loads configuration from file.
 fn main() -> std::io::Result<()> {
@@ -22,17 +33,96 @@ fn main() -> std::io::Result<()> {
     let mut oid_map: OidMap = OidMap::new();
     // Load configuration
     let conf = Config::load();
+    let mut agent: Agent = Agent::new(conf.engine_id.clone());
     // Populate oid_map for stubs
-    load_stubs(&mut oid_map, &mut comp);
-    let mut agent: Agent = Agent::build(conf.engine_id.clone(), &conf.listen);
-    if conf.trap_sink.is_empty() {
-        debug!("No Trapsink defined in config, won't start notifier");
-    } else {
-        info!("Starting notifier for {0}", conf.trap_sink);
-        agent.start_notifier(&conf.trap_sink);
+    load_stubs(&mut oid_map, &agent.notification_sender(), &mut comp);
+    if !conf.tls_certificate.is_empty() {
+        set_tls(&mut agent, &conf)?;
+    }
+    // A subagent leaves the listening to its master
+    let subagent = !conf.agentx_master.is_empty();
+    for addr in conf.listen.iter().filter(|_| !subagent) {
+        info!("Listening on {addr}");
+        agent.listen(addr)?;
+    }
+    if !conf.agentx_socket.is_empty() {
+        info!("AgentX master on {0}", conf.agentx_socket);
+        agent.listen_agentx(&conf.agentx_socket)?;
+    }
+    if !conf.proxy_config.is_empty() {
+        let proxy = Proxy::load_from_file(&conf.proxy_config)?;
+        info!("Proxy forwarder with {0} entries", proxy.len());
+        agent.set_proxy(proxy);
     }
+    // Notification destinations from the target and notification tables, seeded from
+    // TrapSink, InformSink and NotifyConfig on first run
+    let destinations = notify::load_notify(&mut oid_map, &conf, &users)?;
+    agent.start_notifier().set_destinations(destinations);
+    agent.set_warm_start(conf.start_trap == "warmStart");
     // Some of the handlers use values from the config or the agent itself
-    handlers::load_stubs(&mut oid_map, &conf, &agent, &users, &mut comp);
-    agent.loop_forever(&mut oid_map, users);
+    handlers::load_stubs(
+        &mut oid_map,
+        &conf,
+        &agent,
+        &users,
+        &agent.notification_sender(),
+        &mut comp,
+    );
+    // Access control from the VACM tables, seeded from groups.txt on first run
+    let vacm = vacm::load_vacm(&mut oid_map, &conf, &perms, &users);
+    agent.set_vacm(vacm);
+    if subagent {
+        agent.sort_maps(&mut oid_map);
+        agent.send_start_trap();
+        run_subagent(&conf.agentx_master, &mut oid_map);
+    } else {
+        agent.loop_forever(&mut oid_map, users);
+    }
+    Ok(())
+}
+
+/// Serve oid_map through another AgentX master, reconnecting whenever it goes away.
+fn run_subagent(path: &str, oid_map: &mut OidMap) -> ! {
+    let id = ObjectIdentifier::new(&SUBAGENT_ID).unwrap();
+    loop {
+        info!("AgentX subagent of {path}");
+        let err = match Subagent::connect(path, id.clone(), "snmp-rust-agent") {
+            Ok(mut subagent) => match subagent.register_all(oid_map) {
+                Ok(_) => subagent.loop_forever(oid_map),
+                Err(err) => err,
+            },
+            Err(err) => err,
+        };
+        warn!("AgentX master {path}: {err}, reconnecting");
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+/// Load the certificates and certificate map for TLS and DTLS.
+#[cfg(feature = "tsm")]
+fn set_tls(agent: &mut Agent, conf: &Config) -> std::io::Result<()> {
+    use snmp_rust_agent::tsm::{CertToTsn, TlsConfig};
+    use std::path::Path;
+    let cert_map = if conf.tls_cert_map.is_empty() {
+        CertToTsn::new()
+    } else {
+        CertToTsn::load_from_file(&conf.tls_cert_map)?
+    };
+    let trust_anchors =
+        Some(Path::new(&conf.tls_trust_anchors)).filter(|_| !conf.tls_trust_anchors.is_empty());
+    let tls = TlsConfig::new(
+        Path::new(&conf.tls_certificate),
+        Path::new(&conf.tls_private_key),
+        trust_anchors,
+        cert_map,
+    )?;
+    agent.set_tls(tls);
+    Ok(())
+}
+
+/// TLS and DTLS need the tsm feature.
+#[cfg(not(feature = "tsm"))]
+fn set_tls(_agent: &mut Agent, _conf: &Config) -> std::io::Result<()> {
+    warn!("TlsCertificate ignored, build with the tsm feature for TLS and DTLS");
     Ok(())
 }
@@ -1,111 +1,1238 @@
-use rasn::types::{Integer, OctetString};
-use rasn_snmp::v2::{Pdu, VarBindList};
+//! Messages the agent originates, rather than answers.
+//!
+//! Outbound holds the sockets they leave from, and waits for replies with retries, or reads
+//! those that have arrived for a caller polling its sockets. usm_message and open_usm build and
+//! check SNMPv3 messages to another engine, which is authoritative for them, using what
+//! discovery has learnt of it in a RemoteEngine, and UsmExchange steps through a request with
+//! them. The proxy forwarder uses these.
+//!
+//! Notifier sends notifications, RFC 3416 section 4.2.6, to the destinations the target and
+//! notification tables of the notify module select, as traps or informs, over SNMPv2c or USM.
+//! Each starts with sysUpTime.0 and snmpTrapOID.0, followed by the varbinds given.
+//!
+//! The agent is authoritative for traps, so they carry its own snmpEngineID, boots and time, and
+//! are secured with a user of the agent, from users.txt. Informs are acknowledged, and the
+//! receiver is authoritative for those, so it is discovered like a proxy target, by usm_request.
+//! Each inform waits for its Response in a thread of its own, and the caller learns how it ended,
+//! acknowledged, timed out or rejected, through a channel.
+//!
+//! Handlers and application threads raise notifications through a NotificationSender, a
+//! cloneable handle on the agent's Notifier.
+use crate::notify::{Destination, Destinations};
+use crate::privacy;
+use crate::snmp_agent::{level_flags, UsmFailure, MAX_MSG_SIZE, REPORTABLE_FLAG};
+use crate::target::{MpModel, SECURITY_MODEL_V2C};
+use crate::usm::{self, User};
+use crate::vacm::SECURITY_MODEL_USM;
+use log::{debug, error, warn};
+use rasn::types::{Integer, ObjectIdentifier, OctetString};
+use rasn_smi::v2::{ApplicationSyntax, ObjectSyntax, SimpleSyntax, TimeTicks};
+use rasn_snmp::v2::Pdu;
+use rasn_snmp::v2c;
 use rasn_snmp::v3::{
-    HeaderData, Message, Pdus, ScopedPdu, ScopedPduData, Trap, USMSecurityParameters, VarBind,
+    GetRequest, HeaderData, InformRequest, Message, Pdus, ScopedPdu, ScopedPduData, Trap,
+    USMSecurityParameters, VarBind, VarBindValue,
 };
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io;
 use std::net::{SocketAddr, UdpSocket};
-//use std::str::FromStr;
+use std::os::fd::{AsRawFd, RawFd};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread::{self, JoinHandle};
-use std::time::Instant;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 const ZB: OctetString = OctetString::from_static(b"");
 
-pub struct Notifier {
-    socket: UdpSocket,
+/// Sockets for messages the agent originates, one per address family, bound on first use.
+#[derive(Default)]
+pub struct Outbound {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+impl Outbound {
+    pub fn new() -> Self {
+        Outbound::default()
+    }
+
+    /// The socket for sending to dst, binding it if need be.
+    fn socket(&mut self, dst: &SocketAddr) -> io::Result<&UdpSocket> {
+        let (slot, any) = if dst.is_ipv4() {
+            (&mut self.v4, "0.0.0.0:0")
+        } else {
+            (&mut self.v6, "[::]:0")
+        };
+        if slot.is_none() {
+            *slot = Some(UdpSocket::bind(any)?);
+        }
+        Ok(slot.as_ref().expect("Bound above"))
+    }
+
+    /// Send buf to dst, for messages that get no reply.
+    pub fn send_to(&mut self, buf: &[u8], dst: SocketAddr) -> io::Result<()> {
+        self.socket(&dst)?.send_to(buf, dst)?;
+        Ok(())
+    }
+
+    /// The sockets bound so far, to poll for replies.
+    pub fn fds(&self) -> Vec<RawFd> {
+        self.v4
+            .iter()
+            .chain(self.v6.iter())
+            .map(AsRawFd::as_raw_fd)
+            .collect()
+    }
+
+    /// Read the datagrams that have arrived on the sockets, without waiting, with their sources.
+    pub fn recv_waiting(&self) -> io::Result<Vec<(SocketAddr, Vec<u8>)>> {
+        let mut received = vec![];
+        let mut buf = [0; 65100];
+        for socket in self.v4.iter().chain(self.v6.iter()) {
+            socket.set_nonblocking(true)?;
+            let result = loop {
+                match socket.recv_from(&mut buf) {
+                    Ok((amt, src)) => received.push((src, buf[..amt].to_vec())),
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                    Err(err) => break Err(err),
+                }
+            };
+            socket.set_nonblocking(false)?;
+            result?;
+        }
+        Ok(received)
+    }
+
+    /// Send a request to dst, and wait for a reply from dst that accept takes.
+    ///
+    /// The request is sent again after each timeout, up to retries more times. Replies that
+    /// accept refuses, such as stale ones for an earlier request, are dropped. Returns None
+    /// once the retries are used up.
+    pub fn request<T>(
+        &mut self,
+        buf: &[u8],
+        dst: SocketAddr,
+        timeout: Duration,
+        retries: u32,
+        mut accept: impl FnMut(&[u8]) -> Option<T>,
+    ) -> io::Result<Option<T>> {
+        let socket = self.socket(&dst)?;
+        let mut reply = [0; 65100];
+        for _ in 0..=retries {
+            socket.send_to(buf, dst)?;
+            let deadline = Instant::now() + timeout;
+            loop {
+                let left = deadline.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    break;
+                }
+                socket.set_read_timeout(Some(left))?;
+                let (amt, src) = match socket.recv_from(&mut reply) {
+                    Ok(received) => received,
+                    Err(err)
+                        if matches!(
+                            err.kind(),
+                            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                        ) =>
+                    {
+                        break;
+                    }
+                    Err(err) => return Err(err),
+                };
+                if src != dst {
+                    debug!("Dropping reply from {src}, expected {dst}");
+                    continue;
+                }
+                if let Some(accepted) = accept(&reply[..amt]) {
+                    return Ok(Some(accepted));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// What a sender knows of an authoritative engine, RFC 3414 section 2.3
+///
+/// Learnt by discovery, and kept in step from the messages that engine authenticates.
+#[derive(Clone, Debug)]
+pub struct RemoteEngine {
+    pub engine_id: OctetString,
+    pub boots: u32,
+    time: u32,
+    synced: Instant,
+}
+
+impl RemoteEngine {
+    pub fn new(engine_id: OctetString, boots: u32, time: u32) -> Self {
+        RemoteEngine {
+            engine_id,
+            boots,
+            time,
+            synced: Instant::now(),
+        }
+    }
+
+    /// An engine still to be discovered.
+    pub fn unknown() -> Self {
+        RemoteEngine::new(ZB, 0, 0)
+    }
+
+    /// True once the snmpEngineID is known.
+    pub fn is_known(&self) -> bool {
+        !self.engine_id.is_empty()
+    }
+
+    /// Our estimate of the engine's snmpEngineTime now.
+    pub fn time(&self) -> u32 {
+        let elapsed = self
+            .synced
+            .elapsed()
+            .as_secs()
+            .try_into()
+            .unwrap_or(u32::MAX);
+        self.time.saturating_add(elapsed)
+    }
+
+    /// Take the engine's ID, boots and time from the security parameters of its message.
+    pub fn update(&mut self, usp: &USMSecurityParameters) {
+        *self = RemoteEngine::new(
+            usp.authoritative_engine_id.clone(),
+            usp.authoritative_engine_boots
+                .clone()
+                .try_into()
+                .unwrap_or(0),
+            usp.authoritative_engine_time
+                .clone()
+                .try_into()
+                .unwrap_or(0),
+        );
+    }
+}
+
+/// A message from another engine, checked and decrypted by open_usm.
+pub struct UsmReply {
+    pub message_id: i32,
+    pub flags: u8,
+    pub usp: USMSecurityParameters,
+    pub scoped_pdu: ScopedPdu,
+}
+
+/// Build a message for user at engine, which is authoritative for it, with the security level
+/// and reportable flag in flags.
+///
+/// For requests, engine is the receiver, and they are reportable. For traps, engine is the
+/// sender, the agent itself. With privacy, a fresh salt is used.
+pub fn usm_message(
+    message_id: i32,
+    flags: u8,
+    user: &User,
+    engine: &RemoteEngine,
+    scoped_pdu: ScopedPdu,
+) -> Vec<u8> {
+    let mut usp = USMSecurityParameters {
+        authoritative_engine_boots: Integer::from(engine.boots),
+        authoritative_engine_id: engine.engine_id.clone(),
+        authoritative_engine_time: Integer::from(engine.time()),
+        user_name: OctetString::from_slice(&user.name),
+        authentication_parameters: ZB,
+        privacy_parameters: ZB,
+    };
+    let scoped_data = if flags & 2 == 2 {
+        usp.privacy_parameters = OctetString::from_slice(&privacy::next_salt());
+        let mut clear = rasn::ber::encode(&scoped_pdu).unwrap();
+        let enc = privacy::encrypt(&mut clear, usp.clone(), &user.priv_key);
+        ScopedPduData::EncryptedPdu(OctetString::from(enc))
+    } else {
+        ScopedPduData::CleartextPdu(scoped_pdu)
+    };
+    let mut message = Message {
+        version: Integer::from(3),
+        global_data: HeaderData {
+            flags: OctetString::from(vec![flags & (3 | REPORTABLE_FLAG)]),
+            message_id: Integer::from(message_id),
+            max_size: Integer::from(MAX_MSG_SIZE),
+            security_model: Integer::from(SECURITY_MODEL_USM),
+        },
+        scoped_data,
+        security_parameters: ZB,
+    };
+    _ = message.encode_security_parameters(rasn::Codec::Ber, &usp);
+    if flags & 1 == 1 {
+        usm::set_auth(&mut message, user);
+    }
+    rasn::ber::encode(&message).unwrap()
+}
+
+/// A request that finds out an engine's snmpEngineID, RFC 3414 section 4
+///
+/// The reply is a usmStatsUnknownEngineIDs Report from the engine, with its ID, boots and time.
+pub fn discovery_message(message_id: i32, request_id: i32) -> Vec<u8> {
+    let pdu = Pdu {
+        request_id,
+        error_status: 0,
+        error_index: 0,
+        variable_bindings: vec![],
+    };
+    let message = Message {
+        version: Integer::from(3),
+        global_data: HeaderData {
+            flags: OctetString::from(vec![REPORTABLE_FLAG]),
+            message_id: Integer::from(message_id),
+            max_size: Integer::from(MAX_MSG_SIZE),
+            security_model: Integer::from(SECURITY_MODEL_USM),
+        },
+        scoped_data: ScopedPduData::CleartextPdu(ScopedPdu {
+            engine_id: ZB,
+            name: ZB,
+            data: Pdus::GetRequest(GetRequest(pdu)),
+        }),
+        security_parameters: ZB,
+    };
+    let mut message = message;
+    let usp = USMSecurityParameters {
+        authoritative_engine_boots: Integer::from(0),
+        authoritative_engine_id: ZB,
+        authoritative_engine_time: Integer::from(0),
+        user_name: ZB,
+        authentication_parameters: ZB,
+        privacy_parameters: ZB,
+    };
+    _ = message.encode_security_parameters(rasn::Codec::Ber, &usp);
+    rasn::ber::encode(&message).unwrap()
+}
+
+/// Decode a USM message sent to us for user, checking the digest and decrypting.
+///
+/// Unauthenticated messages are let through, as Reports to a sender are often sent that way;
+/// the flags say what was checked. None if the message is not for user, does not decode,
+/// or fails the digest.
+pub fn open_usm(buf: &[u8], user: &User) -> Option<UsmReply> {
+    let mut message: Message = rasn::ber::decode(buf).ok()?;
+    let flags = *message.global_data.flags.first()?;
+    let message_id = message.global_data.message_id.clone().try_into().ok()?;
+    let r_sp: Result<USMSecurityParameters, Box<dyn Display>> =
+        message.decode_security_parameters(rasn::Codec::Ber);
+    let usp = r_sp.ok()?;
+    if flags & 1 == 1 {
+        if usp.user_name.as_ref() != user.name.as_slice()
+            || usp.authentication_parameters.len() != 12
+        {
+            return None;
+        }
+        let digest = usp.authentication_parameters.to_vec();
+        if usm::set_auth(&mut message, user) != digest {
+            debug!("Wrong digest on reply for {:?}", usp.user_name);
+            return None;
+        }
+    }
+    let scoped_pdu = match message.scoped_data {
+        ScopedPduData::CleartextPdu(scoped_pdu) => scoped_pdu,
+        ScopedPduData::EncryptedPdu(_) if flags & 3 != 3 => return None,
+        ScopedPduData::EncryptedPdu(enc_octs) => {
+            if usp.privacy_parameters.len() != 8 {
+                return None;
+            }
+            let clear =
+                privacy::decrypt(&mut enc_octs.to_vec(), usp.clone(), &user.priv_key).ok()?;
+            rasn::ber::decode(&clear).ok()?
+        }
+    };
+    Some(UsmReply {
+        message_id,
+        flags,
+        usp,
+        scoped_pdu,
+    })
+}
+
+/// Reports to recover from, by discovery or resynchronization, before giving up.
+const MAX_REPORTS: usize = 2;
+
+/// Where a request goes over USM, and how it is secured.
+pub struct UsmTarget<'a> {
+    pub address: SocketAddr,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub user: &'a User<'a>,
+    /// Same scale as Perm: 1 noAuthNoPriv, 2 authNoPriv, 3 authPriv
+    pub security_level: u8,
+}
+
+/// A confirmed request over USM, a message at a time, for callers that wait for the replies
+/// themselves, such as the proxy forwarder. usm_request drives one to the end.
+///
+/// Each message is sent, with retries, until a reply that accept takes arrives for handle.
+/// After a discovery, resynchronization or rediscovery, there is another message to send.
+pub struct UsmExchange {
+    address: SocketAddr,
+    request_id: i32,
+    message_id: i32,
+    flags: u8,
+    discovering: bool,
+    reports: usize,
+}
+
+impl UsmExchange {
+    /// An exchange with the target at address, for a request carrying request_id.
+    pub fn new(address: SocketAddr, request_id: i32) -> Self {
+        UsmExchange {
+            address,
+            request_id,
+            message_id: 0,
+            flags: 0,
+            discovering: false,
+            reports: 0,
+        }
+    }
+
+    /// The next message to send to target: a discovery if engine is unknown, otherwise the
+    /// request that scoped_pdu builds. Its msgID is taken from ids.
+    pub fn message(
+        &mut self,
+        target: &UsmTarget,
+        engine: &RemoteEngine,
+        ids: &mut i32,
+        scoped_pdu: impl Fn(&RemoteEngine) -> ScopedPdu,
+    ) -> io::Result<Vec<u8>> {
+        if target.security_level > target.user.security_level() {
+            return Err(io::Error::other("usmUser cannot provide the securityLevel"));
+        }
+        self.message_id = next_id(ids);
+        self.flags = level_flags(target.security_level);
+        self.discovering = !engine.is_known();
+        if self.discovering {
+            return Ok(discovery_message(self.message_id, self.request_id));
+        }
+        Ok(usm_message(
+            self.message_id,
+            self.flags | REPORTABLE_FLAG,
+            target.user,
+            engine,
+            scoped_pdu(engine),
+        ))
+    }
+
+    /// The reply to the last message, if buf is one, checked and decrypted for user.
+    pub fn accept(&self, buf: &[u8], user: &User) -> Option<UsmReply> {
+        open_usm(buf, user).filter(|reply| reply.message_id == self.message_id)
+    }
+
+    /// Take the reply to the last message, keeping engine up to date.
+    ///
+    /// Returns the Response PDU, or None if there is another message to send. Fails for a
+    /// Report that cannot be recovered from.
+    pub fn handle(
+        &mut self,
+        reply: UsmReply,
+        engine: &mut RemoteEngine,
+    ) -> io::Result<Option<Pdu>> {
+        if self.discovering {
+            engine.update(&reply.usp);
+            if !engine.is_known() {
+                return Err(io::Error::other("discovery found no snmpEngineID"));
+            }
+            return Ok(None);
+        }
+        let authenticated = reply.flags & 1 == 1;
+        match reply.scoped_pdu.data {
+            Pdus::Response(r)
+                if reply.flags & 3 == self.flags && r.0.request_id == self.request_id =>
+            {
+                if authenticated {
+                    engine.update(&reply.usp);
+                }
+                Ok(Some(r.0))
+            }
+            Pdus::Report(r) => {
+                let arc = r.0.variable_bindings.first().map(|vb| vb.name.to_vec());
+                match arc.as_deref() {
+                    Some(arc) if arc == UsmFailure::NotInTimeWindow.arc() && authenticated => {
+                        debug!("Resynchronizing with {0}", self.address);
+                        engine.update(&reply.usp);
+                    }
+                    Some(arc) if arc == UsmFailure::UnknownEngineId.arc() => {
+                        debug!("Rediscovering {0}", self.address);
+                        *engine = RemoteEngine::unknown();
+                    }
+                    _ => return Err(io::Error::other(format!("Report {arc:?}"))),
+                }
+                self.reports += 1;
+                if self.reports > MAX_REPORTS {
+                    return Err(io::Error::other("too many Reports"));
+                }
+                Ok(None)
+            }
+            _ => Err(io::Error::other("unexpected reply")),
+        }
+    }
+
+    /// The retries of the last message are used up, so the exchange fails with TimedOut.
+    pub fn timed_out(&self, engine: &mut RemoteEngine) -> io::Error {
+        if !self.discovering {
+            // The target may have restarted, so find out again next time
+            *engine = RemoteEngine::unknown();
+        }
+        io::ErrorKind::TimedOut.into()
+    }
+}
+
+/// Send a confirmed request to target over USM, and return the Response PDU.
+///
+/// engine is what is known of the target, which is authoritative, and is kept up to date:
+/// discovered if unknown, resynchronized after notInTimeWindow, and forgotten after a timeout,
+/// in case the target restarted. scoped_pdu builds the request from the engine, which must
+/// carry request_id. Message IDs are taken from ids. Fails with TimedOut when the retries are
+/// used up, or with another error for a Report that cannot be recovered from.
+pub fn usm_request(
+    outbound: &mut Outbound,
+    target: &UsmTarget,
+    engine: &mut RemoteEngine,
+    ids: &mut i32,
+    request_id: i32,
+    scoped_pdu: impl Fn(&RemoteEngine) -> ScopedPdu,
+) -> io::Result<Pdu> {
+    let mut exchange = UsmExchange::new(target.address, request_id);
+    loop {
+        let buf = exchange.message(target, engine, ids, &scoped_pdu)?;
+        let reply = outbound.request(
+            &buf,
+            target.address,
+            target.timeout,
+            target.retries,
+            |reply| exchange.accept(reply, target.user),
+        )?;
+        let Some(reply) = reply else {
+            return Err(exchange.timed_out(engine));
+        };
+        if let Some(pdu) = exchange.handle(reply, engine)? {
+            return Ok(pdu);
+        }
+    }
+}
+
+/// Step id on to a fresh request-id or msgID, which must not be negative.
+pub(crate) fn next_id(id: &mut i32) -> i32 {
+    *id = id.wrapping_add(1) & i32::MAX;
+    *id
+}
+
+/// coldStart, RFC 3418
+pub const COLD_START_ARC: [u32; 10] = [1, 3, 6, 1, 6, 3, 1, 1, 5, 1];
+/// warmStart, RFC 3418
+pub const WARM_START_ARC: [u32; 10] = [1, 3, 6, 1, 6, 3, 1, 1, 5, 2];
+/// authenticationFailure, RFC 3418
+pub const AUTHENTICATION_FAILURE_ARC: [u32; 10] = [1, 3, 6, 1, 6, 3, 1, 1, 5, 5];
+/// sysUpTime.0, RFC 3418
+const SYS_UP_TIME_ARC: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 3, 0];
+/// snmpTrapOID.0, RFC 3418
+const SNMP_TRAP_OID_ARC: [u32; 11] = [1, 3, 6, 1, 6, 3, 1, 1, 4, 1, 0];
+
+/// A notification, before sysUpTime.0 and snmpTrapOID.0 are added.
+struct Notification {
+    trap_oid: ObjectIdentifier,
+    varbinds: Vec<VarBind>,
+}
+
+/// How an inform ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InformStatus {
+    /// The receiver sent back a Response, without error.
+    Acked,
+    /// No Response after all the retries.
+    TimedOut,
+    /// An error status or Report in reply, or the target cannot be sent to as configured.
+    Rejected,
+}
+
+/// Work for the notifier thread, in the order it was asked for.
+enum Command {
+    Destinations(Destinations),
+    Notify(Notification, Sender<InformStatus>),
+}
+
+/// A v2c message with community, for a trap or inform to a v2c target.
+fn v2c_message(community: &[u8], data: Pdus) -> Vec<u8> {
+    let message = v2c::Message {
+        version: Integer::from(1),
+        community: OctetString::from_slice(community),
+        data,
+    };
+    rasn::ber::encode(&message).unwrap()
+}
+
+/// How an inform to address ended, from the result of the request.
+fn inform_status(address: SocketAddr, result: io::Result<Pdu>) -> InformStatus {
+    match result {
+        Ok(pdu) if pdu.error_status == Pdu::ERROR_STATUS_NO_ERROR => InformStatus::Acked,
+        Ok(pdu) => {
+            warn!("Inform to {address} got error {0}", pdu.error_status);
+            InformStatus::Rejected
+        }
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => InformStatus::TimedOut,
+        Err(err) => {
+            warn!("Inform to {address} failed: {err}");
+            InformStatus::Rejected
+        }
+    }
+}
+
+/// The notifier thread's view of the agent, and where its notifications go.
+struct Originator {
+    outbound: Outbound,
+    destinations: Option<Destinations>,
+    /// What is known of each inform receiver, shared with the threads waiting on informs
+    receivers: HashMap<SocketAddr, Arc<Mutex<RemoteEngine>>>,
     engine_id: OctetString,
+    boots: u32,
     start_time: Instant,
     request_id: i32,
     message_id: i32,
-    target_addr: String,
-    child: JoinHandle<()>,
-    pub sender: Sender<i32>,
 }
 
-impl Notifier {
-    pub fn new(target: &str, engine_id: OctetString, start_time: Instant) -> Self {
-        let (tx, rx): (Sender<i32>, Receiver<i32>) = channel();
-        let socket: UdpSocket = UdpSocket::bind("0.0.0.0:0").expect("couldn't bind to address");
+impl Originator {
+    /// The varbinds of notification, after sysUpTime.0 and snmpTrapOID.0.
+    fn varbinds(&self, notification: Notification) -> Vec<VarBind> {
+        let sys_up_time = (self.start_time.elapsed().as_millis() / 10) as u32;
+        let mut varbinds = vec![
+            VarBind {
+                name: ObjectIdentifier::new(&SYS_UP_TIME_ARC).unwrap(),
+                value: VarBindValue::Value(ObjectSyntax::ApplicationWide(
+                    ApplicationSyntax::Ticks(TimeTicks { 0: sys_up_time }),
+                )),
+            },
+            VarBind {
+                name: ObjectIdentifier::new(&SNMP_TRAP_OID_ARC).unwrap(),
+                value: VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::ObjectId(
+                    notification.trap_oid,
+                ))),
+            },
+        ];
+        varbinds.extend(notification.varbinds);
+        varbinds
+    }
 
-        let child = thread::spawn(move || {
-            // The thread takes ownership over `rx`
-            // Each thread queues a message in the channel
-            loop {
-                let val = rx.recv();
-                if let Ok(num) = val {
-                    println!("Notifier got {num}");
-                    // FIXME send an actual message
-                    if num == 137 {
-                        break;
+    /// The SNMPv3 Trap message with pdu, at security level, for user.
+    fn trap_message(&mut self, pdu: Pdu, user: &User, level: u8) -> Vec<u8> {
+        let scoped_pdu = ScopedPdu {
+            engine_id: self.engine_id.clone(),
+            name: ZB,
+            data: Pdus::Trap(Trap(pdu)),
+        };
+        let engine_time = self
+            .start_time
+            .elapsed()
+            .as_secs()
+            .try_into()
+            .unwrap_or(u32::MAX);
+        let engine = RemoteEngine::new(self.engine_id.clone(), self.boots, engine_time);
+        let message_id = next_id(&mut self.message_id);
+        usm_message(message_id, level_flags(level), user, &engine, scoped_pdu)
+    }
+
+    /// Send a notification to every destination the tables select for it.
+    ///
+    /// Each inform sent, or that cannot be, gets its status sent to status.
+    fn notify(&mut self, notification: Notification, status: Sender<InformStatus>) {
+        let Some(destinations) = self.destinations.take() else {
+            debug!("No notification destinations, notification dropped");
+            return;
+        };
+        let selected = destinations.select(&notification.trap_oid, &notification.varbinds);
+        let varbinds = self.varbinds(notification);
+        for destination in selected {
+            let name = &destination.addr.name;
+            let params = &destination.params;
+            // None for a v2c community
+            let user = match (params.mp_model, params.security_model) {
+                (MpModel::V2c, SECURITY_MODEL_V2C) => None,
+                (MpModel::V3, SECURITY_MODEL_USM) => match destinations.user(&destination) {
+                    Some(user) if user.security_level() >= params.security_level => {
+                        Some(user.to_sender())
                     }
-                } // Just ignore receive errors
+                    _ => {
+                        warn!("No usmUser for target {name} at its securityLevel, skipped");
+                        if destination.inform {
+                            _ = status.send(InformStatus::Rejected);
+                        }
+                        continue;
+                    }
+                },
+                _ => {
+                    warn!("Target {name} has an unsupported security model, skipped");
+                    if destination.inform {
+                        _ = status.send(InformStatus::Rejected);
+                    }
+                    continue;
+                }
+            };
+            if destination.inform {
+                self.inform(destination, user, varbinds.clone(), status.clone());
+            } else {
+                self.trap(destination, user, varbinds.clone());
             }
+        }
+        self.destinations = Some(destinations);
+    }
+
+    /// Send a trap to destination, secured as user, or with the community if None.
+    fn trap(&mut self, destination: Destination, user: Option<User>, varbinds: Vec<VarBind>) {
+        let pdu = Pdu {
+            request_id: next_id(&mut self.request_id),
+            error_status: 0,
+            error_index: 0,
+            variable_bindings: varbinds,
+        };
+        let params = &destination.params;
+        let buf = match user {
+            Some(user) => self.trap_message(pdu, &user, params.security_level),
+            None => v2c_message(&params.security_name, Pdus::Trap(Trap(pdu))),
+        };
+        let address = destination.addr.address;
+        if let Err(err) = self.outbound.send_to(&buf, address) {
+            warn!("Trap to {address} failed: {err}");
+        }
+    }
 
-            // Sending is a non-blocking operation, the thread will continue
-            // immediately after sending its message
-            println!("thread finished");
+    /// Send an inform from a thread of its own, so other notifications need not wait for the
+    /// reply, and send how it ended to status.
+    fn inform(
+        &mut self,
+        destination: Destination,
+        user: Option<User<'static>>,
+        varbinds: Vec<VarBind>,
+        status: Sender<InformStatus>,
+    ) {
+        let request_id = next_id(&mut self.request_id);
+        let pdu = Pdu {
+            request_id,
+            error_status: 0,
+            error_index: 0,
+            variable_bindings: varbinds,
+        };
+        let Destination { addr, params, .. } = destination;
+        let Some(user) = user else {
+            let buf = v2c_message(
+                &params.security_name,
+                Pdus::InformRequest(InformRequest(pdu)),
+            );
+            thread::spawn(move || {
+                let result = Outbound::new().request(
+                    &buf,
+                    addr.address,
+                    addr.timeout,
+                    addr.retry_count,
+                    |reply| match rasn::ber::decode::<v2c::Message<Pdus>>(reply).ok()?.data {
+                        Pdus::Response(r) if r.0.request_id == request_id => Some(r.0),
+                        _ => None,
+                    },
+                );
+                let result = result.and_then(|reply| reply.ok_or(io::ErrorKind::TimedOut.into()));
+                // The caller may not be waiting
+                _ = status.send(inform_status(addr.address, result));
+            });
+            return;
+        };
+        // contextEngineID is ours, RFC 3413 section 3.3, whoever is authoritative
+        let scoped_pdu = ScopedPdu {
+            engine_id: self.engine_id.clone(),
+            name: ZB,
+            data: Pdus::InformRequest(InformRequest(pdu)),
+        };
+        let receiver = self
+            .receivers
+            .entry(addr.address)
+            .or_insert_with(|| Arc::new(Mutex::new(RemoteEngine::unknown())))
+            .clone();
+        let mut ids = rand::random::<i32>() & i32::MAX;
+        thread::spawn(move || {
+            let usm_target = UsmTarget {
+                address: addr.address,
+                timeout: addr.timeout,
+                retries: addr.retry_count,
+                user: &user,
+                security_level: params.security_level,
+            };
+            let mut engine = receiver.lock().unwrap().clone();
+            let result = usm_request(
+                &mut Outbound::new(),
+                &usm_target,
+                &mut engine,
+                &mut ids,
+                request_id,
+                |_| scoped_pdu.clone(),
+            );
+            *receiver.lock().unwrap() = engine;
+            // The caller may not be waiting
+            _ = status.send(inform_status(addr.address, result));
         });
+    }
+
+    /// Carry out commands until the Notifier is dropped.
+    fn run(mut self, rx: Receiver<Command>) {
+        for command in rx {
+            match command {
+                Command::Destinations(destinations) => {
+                    self.destinations = Some(destinations);
+                    self.receivers.clear();
+                }
+                Command::Notify(notification, status) => self.notify(notification, status),
+            }
+        }
+    }
+}
+
+/// Sends notifications from a thread of its own, so the agent never waits on them.
+pub struct Notifier {
+    sender: Sender<Command>,
+}
 
-        Notifier {
-            socket,
+impl Notifier {
+    /// Start the notifier thread.
+    ///
+    /// engine_id, boots and start_time are the agent's, so receivers see the same engine that
+    /// answers their requests. Nothing is sent until there are destinations.
+    pub fn new(engine_id: OctetString, boots: u32, start_time: Instant) -> Self {
+        let (sender, rx) = channel();
+        let originator = Originator {
+            outbound: Outbound::new(),
+            destinations: None,
+            receivers: HashMap::new(),
             engine_id,
+            boots,
             start_time,
-            request_id: rand::random::<i32>(),
-            message_id: rand::random::<i32>(),
-            target_addr: target.to_string(),
-            child,
-            sender: tx,
+            request_id: rand::random::<i32>() & i32::MAX,
+            message_id: rand::random::<i32>() & i32::MAX,
+        };
+        thread::spawn(move || originator.run(rx));
+        Notifier { sender }
+    }
+
+    /// Send notifications where the target and notification tables in destinations say.
+    pub fn set_destinations(&self, destinations: Destinations) {
+        self.command(Command::Destinations(destinations));
+    }
+
+    /// Send a notification, with snmpTrapOID.0 set to trap_oid, followed by varbinds.
+    ///
+    /// sysUpTime.0 is added in front. It goes as a trap or inform to each destination selected
+    /// for it when the notifier thread gets to it; this returns at once. Informs are sent again
+    /// after each timeout, up to the target's retries. The returned channel gets how each
+    /// inform ended, and is closed once they all have.
+    pub fn notify(
+        &self,
+        trap_oid: ObjectIdentifier,
+        varbinds: Vec<VarBind>,
+    ) -> Receiver<InformStatus> {
+        queue(&self.sender, Notification { trap_oid, varbinds })
+    }
+
+    /// Send the notifications queued on handle, and later clones of it, through this notifier.
+    ///
+    /// A handle can only be connected once; later calls leave it as it is.
+    pub fn connect(&self, handle: &NotificationSender) {
+        _ = handle.0.set(self.sender.clone());
+    }
+
+    /// Queue command for the notifier thread.
+    fn command(&self, command: Command) {
+        if self.sender.send(command).is_err() {
+            error!("Notifier thread has gone, notification dropped");
         }
     }
+}
 
-    pub fn msg_trap(self, vb: Vec<VarBind>) -> Message {
-        /*let vb: Vec<VarBind> = vec![VarBind {
-            name: ObjectIdentifier::new_unchecked(vec![1, 3, 6, 1, 6, 3, 15, 1, 1, 4].into()),
-            value: VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(
-                self.unknown_engine_ids,
-            )))),
-        }];*/
+/// Queue notification on sender, returning the channel for how its informs end.
+fn queue(sender: &Sender<Command>, notification: Notification) -> Receiver<InformStatus> {
+    let (status, rx) = channel();
+    if sender.send(Command::Notify(notification, status)).is_err() {
+        error!("Notifier thread has gone, notification dropped");
+    }
+    rx
+}
 
-        let pdu = Pdu {
-            request_id: self.request_id,
-            error_index: 0,
+/// Handle for raising notifications from handlers and application threads.
+///
+/// Clone it from Agent::notification_sender when a handler is built, like a StatsCounter, and
+/// pass clones to any thread that needs one. Notifications queued before the agent's notifier
+/// is started are dropped.
+#[derive(Clone, Debug, Default)]
+pub struct NotificationSender(Arc<OnceLock<Sender<Command>>>);
+
+impl NotificationSender {
+    /// Queue a notification, with snmpTrapOID.0 set to trap_oid, followed by varbinds.
+    ///
+    /// Returns at once. The notifier adds sysUpTime.0 and sends it where the notification tables
+    /// say, as for Notifier::notify, and the returned channel gets how each inform ended.
+    pub fn notify(
+        &self,
+        trap_oid: ObjectIdentifier,
+        varbinds: Vec<VarBind>,
+    ) -> Receiver<InformStatus> {
+        match self.0.get() {
+            Some(sender) => queue(sender, Notification { trap_oid, varbinds }),
+            None => {
+                debug!("No notifier, notification dropped");
+                channel().1
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::notify;
+    use crate::oidmap::OidMap;
+    use crate::perms::{Perm, View};
+    use crate::usm::Users;
+    use rasn_snmp::v2::Report;
+    use rasn_snmp::v3::Response;
+
+    const KEYS: &str = "sha1 0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b aes 0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c";
+    const SYS_NAME: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 5, 0];
+
+    /// Destinations seeded from config, in a fresh storage directory.
+    fn destinations(config: Config, users: &Users) -> Destinations {
+        let _ = std::fs::remove_dir_all(&config.storage_path);
+        std::fs::create_dir_all(&config.storage_path).unwrap();
+        notify::load_notify(&mut OidMap::new(), &config, users).unwrap()
+    }
+
+    #[test]
+    fn test_send_trap() {
+        let perms = vec![Perm {
+            read: true,
+            write: false,
+            security_level: 3,
+            group_name: b"test".to_vec(),
+            context: vec![],
+            read_view: View::default(),
+            write_view: View::default(),
+        }];
+        let user = User::from_str(&format!("trapper test {KEYS}"), &perms).unwrap();
+        let mut users = Users::new();
+        users
+            .users
+            .push(User::from_str(&format!("trapper test {KEYS}"), &perms).unwrap());
+        let sink = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sink.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let legacy = UdpSocket::bind("127.0.0.1:0").unwrap();
+        legacy
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let notify_config = "/tmp/snmp-rust-notifier-trap.txt";
+        std::fs::write(
+            notify_config,
+            format!(
+                "targetAddr legacy {0} 100 0 legacy-v2c legacy\n\
+                 targetParams legacy-v2c v2c v2c public noAuthNoPriv\n\
+                 notify legacy legacy trap\n",
+                legacy.local_addr().unwrap()
+            ),
+        )
+        .unwrap();
+        let config = Config {
+            storage_path: "/tmp/snmp-rust-notifier-trap".to_string(),
+            trap_sink: sink.local_addr().unwrap().to_string(),
+            trap_user: "trapper".to_string(),
+            notify_config: notify_config.to_string(),
+            ..Default::default()
+        };
+        let notifier = Notifier::new(OctetString::from_static(b"agent"), 7, Instant::now());
+        notifier.set_destinations(destinations(config, &users));
+        let sys_name = VarBind {
+            name: ObjectIdentifier::new(&SYS_NAME).unwrap(),
+            value: VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::String(
+                OctetString::from_static(b"agent"),
+            ))),
+        };
+        let cold_start = ObjectIdentifier::new(&COLD_START_ARC).unwrap();
+        let status = notifier.notify(cold_start.clone(), vec![sys_name.clone()]);
+        // No informs, so nothing to wait for
+        assert!(status.recv().is_err());
+        notifier.notify(cold_start.clone(), vec![]);
+
+        let mut buf = [0; 1500];
+        let mut traps = vec![];
+        for _ in 0..2 {
+            let amt = sink.recv(&mut buf).unwrap();
+            // Checks the digest, and decrypts
+            traps.push(open_usm(&buf[..amt], &user).unwrap());
+        }
+        let first = &traps[0];
+        // authPriv, and not reportable
+        assert_eq!(first.flags, 3);
+        assert_eq!(first.usp.authoritative_engine_id.as_ref(), b"agent");
+        assert_eq!(first.usp.authoritative_engine_boots, Integer::from(7));
+        assert_eq!(first.scoped_pdu.engine_id.as_ref(), b"agent");
+        let Pdus::Trap(ref trap) = first.scoped_pdu.data else {
+            panic!("Expected a Trap, got {:?}", first.scoped_pdu.data);
+        };
+        let varbinds = &trap.0.variable_bindings;
+        assert_eq!(varbinds.len(), 3);
+        assert_eq!(
+            varbinds[0].name,
+            ObjectIdentifier::new(&SYS_UP_TIME_ARC).unwrap()
+        );
+        assert!(matches!(
+            varbinds[0].value,
+            VarBindValue::Value(ObjectSyntax::ApplicationWide(ApplicationSyntax::Ticks(_)))
+        ));
+        assert_eq!(
+            varbinds[1].value,
+            VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::ObjectId(cold_start)))
+        );
+        assert_eq!(varbinds[2], sys_name);
+        // A fresh salt and message ID for each trap
+        assert_ne!(
+            traps[0].usp.privacy_parameters,
+            traps[1].usp.privacy_parameters
+        );
+        assert_ne!(traps[0].message_id, traps[1].message_id);
+
+        // The same traps, with the community, for the v2c target
+        let amt = legacy.recv(&mut buf).unwrap();
+        let message: v2c::Message<Pdus> = rasn::ber::decode(&buf[..amt]).unwrap();
+        assert_eq!(message.community.as_ref(), b"public");
+        let Pdus::Trap(trap) = message.data else {
+            panic!("Expected a Trap, got {:?}", message.data);
+        };
+        assert_eq!(trap.0.variable_bindings[2], sys_name);
+        assert!(legacy.recv(&mut buf).is_ok());
+    }
+
+    /// A notification receiver, which acks the first inform and rejects the rest.
+    fn receiver(socket: UdpSocket, user: User<'static>) -> thread::JoinHandle<usize> {
+        thread::spawn(move || {
+            let engine = RemoteEngine::new(OctetString::from_static(b"receiver"), 1, 10);
+            let mut buf = [0; 1500];
+            let mut informs = 0;
+            socket
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            while let Ok((amt, src)) = socket.recv_from(&mut buf) {
+                let message = open_usm(&buf[..amt], &user).unwrap();
+                let (flags, pdu) = match message.scoped_pdu.data {
+                    Pdus::GetRequest(r) if message.usp.authoritative_engine_id.is_empty() => {
+                        let arc = UsmFailure::UnknownEngineId.arc();
+                        (0, Pdus::Report(Report(report(r.0.request_id, arc))))
+                    }
+                    Pdus::InformRequest(r) if informs == 0 => {
+                        informs += 1;
+                        (3, Pdus::Response(Response(r.0)))
+                    }
+                    Pdus::InformRequest(r) => {
+                        informs += 1;
+                        let arc = UsmFailure::UnknownUserName.arc();
+                        (0, Pdus::Report(Report(report(r.0.request_id, arc))))
+                    }
+                    other => panic!("Unexpected {other:?}"),
+                };
+                let scoped_pdu = ScopedPdu {
+                    engine_id: engine.engine_id.clone(),
+                    name: ZB,
+                    data: pdu,
+                };
+                let reply = usm_message(message.message_id, flags, &user, &engine, scoped_pdu);
+                socket.send_to(&reply, src).unwrap();
+            }
+            informs
+        })
+    }
+
+    fn report(request_id: i32, arc: &'static [u32]) -> Pdu {
+        Pdu {
+            request_id,
             error_status: 0,
-            variable_bindings: vb,
+            error_index: 0,
+            variable_bindings: vec![VarBind {
+                name: ObjectIdentifier::new(arc).unwrap(),
+                value: VarBindValue::Unspecified,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_send_inform() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let user = User::remote_from_str(&format!("informer {KEYS}")).unwrap();
+        let receiver = receiver(socket, user.to_sender());
+        // Never answers
+        let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let notifier = Notifier::new(OctetString::from_static(b"agent"), 7, Instant::now());
+        let cold_start = ObjectIdentifier::new(&COLD_START_ARC).unwrap();
+        // Nowhere to send it
+        let status = notifier.notify(cold_start.clone(), vec![]);
+        assert!(status.recv().is_err());
+
+        let config = Config {
+            storage_path: "/tmp/snmp-rust-notifier-inform".to_string(),
+            inform_sink: format!("{address} 100 1"),
+            inform_user: format!("informer {KEYS}"),
+            ..Default::default()
         };
-        let trap: Trap = Trap(pdu);
-        let head = HeaderData {
-            flags: OctetString::from_static(b"\x00"),
-            message_id: Integer::from(self.message_id),
-            max_size: Integer::from(65000),
-            security_model: Integer::from(3),
+        notifier.set_destinations(destinations(config, &Users::new()));
+        let status = notifier.notify(cold_start.clone(), vec![]);
+        assert_eq!(status.recv().unwrap(), InformStatus::Acked);
+        assert!(status.recv().is_err());
+        let status = notifier.notify(cold_start.clone(), vec![]);
+        assert_eq!(status.recv().unwrap(), InformStatus::Rejected);
+        assert_eq!(receiver.join().unwrap(), 2);
+
+        let config = Config {
+            storage_path: "/tmp/snmp-rust-notifier-dead".to_string(),
+            inform_sink: format!("udp:{0} 5 1", dead.local_addr().unwrap()),
+            inform_user: format!("informer {KEYS}"),
+            ..Default::default()
         };
-        let scpd: ScopedPdu = ScopedPdu {
-            engine_id: self.engine_id.clone(),
-            name: OctetString::from_static(b""),
-            data: Pdus::Trap(trap),
+        notifier.set_destinations(destinations(config, &Users::new()));
+        let status = notifier.notify(cold_start, vec![]);
+        assert_eq!(status.recv().unwrap(), InformStatus::TimedOut);
+    }
+
+    #[test]
+    fn test_notification_sender() {
+        let sink = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sink.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let notify_config = "/tmp/snmp-rust-notifier-handle.txt";
+        std::fs::write(
+            notify_config,
+            format!(
+                "targetAddr sink {0} 100 0 sink-v2c sink\n\
+                 targetParams sink-v2c v2c v2c public noAuthNoPriv\n\
+                 notify sink sink trap\n",
+                sink.local_addr().unwrap()
+            ),
+        )
+        .unwrap();
+        let config = Config {
+            storage_path: "/tmp/snmp-rust-notifier-handle".to_string(),
+            notify_config: notify_config.to_string(),
+            ..Default::default()
         };
-        let spd: ScopedPduData = ScopedPduData::CleartextPdu(scpd);
-        let run_time: isize = self
-            .start_time
-            .elapsed()
-            .as_secs()
-            .try_into()
-            .unwrap_or(isize::MAX);
-        let usm: USMSecurityParameters = USMSecurityParameters {
-            authoritative_engine_boots: Integer::from(0), //self.boots),
-            authoritative_engine_id: self.engine_id.clone(),
-            authoritative_engine_time: Integer::from(run_time),
-            user_name: ZB,
-            authentication_parameters: ZB,
-            privacy_parameters: ZB,
-        };
-        let mut message: Message = Message {
-            version: Integer::from(3),
-            global_data: head,
-            scoped_data: spd,
-            security_parameters: ZB,
-        };
-        _ = message.encode_security_parameters(rasn::Codec::Ber, &usm);
-        message
+        let handle = NotificationSender::default();
+        let link_down = ObjectIdentifier::new(&[1, 3, 6, 1, 6, 3, 1, 1, 5, 3]).unwrap();
+        let if_index = VarBind {
+            name: ObjectIdentifier::new(&[1, 3, 6, 1, 2, 1, 2, 2, 1, 1, 2]).unwrap(),
+            value: VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(
+                2,
+            )))),
+        };
+        // Dropped, as there is no notifier yet
+        let status = handle.notify(link_down.clone(), vec![if_index.clone()]);
+        assert!(status.recv().is_err());
+
+        let notifier = Notifier::new(OctetString::from_static(b"agent"), 7, Instant::now());
+        notifier.set_destinations(destinations(config, &Users::new()));
+        notifier.connect(&handle);
+        let (clone, sent) = (handle.clone(), if_index.clone());
+        thread::spawn(move || clone.notify(link_down, vec![sent]))
+            .join()
+            .unwrap();
+
+        let mut buf = [0; 1500];
+        let amt = sink.recv(&mut buf).unwrap();
+        let message: v2c::Message<Pdus> = rasn::ber::decode(&buf[..amt]).unwrap();
+        let Pdus::Trap(trap) = message.data else {
+            panic!("Expected a Trap, got {:?}", message.data);
+        };
+        let varbinds = &trap.0.variable_bindings;
+        assert_eq!(
+            varbinds[0].name,
+            ObjectIdentifier::new(&SYS_UP_TIME_ARC).unwrap()
+        );
+        assert_eq!(varbinds[2], if_index);
+        // Only the one trap, sent after connect
+        sink.set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        assert!(sink.recv(&mut buf).is_err());
+    }
+
+    /// Builders generated by stub-gen from mibs/SNMP-RUST-EXAMPLE-MIB
+    #[test]
+    fn test_generated_builders() {
+        use crate::config::ComplianceStatements;
+        use crate::example_stub;
+        use rasn_smi::v2::{Counter32, Unsigned32};
+
+        const EXAMPLE: [u32; 8] = [1, 3, 6, 1, 4, 1, 32473, 1];
+        let arc = |tail: &[u32]| ObjectIdentifier::new([&EXAMPLE[..], tail].concat()).unwrap();
+        let names = |varbinds: &[VarBind]| -> Vec<ObjectIdentifier> {
+            varbinds.iter().map(|vb| vb.name.clone()).collect()
+        };
+
+        let (trap_oid, varbinds) = example_stub::example_restart();
+        assert_eq!(trap_oid, arc(&[0, 1]));
+        assert!(varbinds.is_empty());
+
+        // Every column comes from the one row
+        let (trap_oid, varbinds) = example_stub::example_port_down(1, 2, b"eth3", &[3]);
+        assert_eq!(trap_oid, arc(&[0, 2]));
+        assert_eq!(
+            names(&varbinds),
+            vec![
+                arc(&[1, 4, 1, 3, 3]),
+                arc(&[1, 4, 1, 4, 3]),
+                arc(&[1, 4, 1, 2, 3])
+            ]
+        );
+        assert_eq!(
+            varbinds[1].value,
+            VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(
+                2
+            ))))
+        );
+        assert_eq!(
+            varbinds[2].value,
+            VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::String(
+                OctetString::from_static(b"eth3")
+            )))
+        );
+
+        // Scalars are always .0
+        let device = ObjectIdentifier::new(&[1, 3, 6, 1, 4, 1, 32473, 2]).unwrap();
+        let (trap_oid, varbinds) =
+            example_stub::example_level_high(90, 1234, device.clone(), 17, &[3]);
+        assert_eq!(trap_oid, arc(&[0, 3]));
+        assert_eq!(
+            names(&varbinds),
+            vec![
+                arc(&[1, 1, 0]),
+                arc(&[1, 2, 0]),
+                arc(&[1, 3, 0]),
+                arc(&[1, 4, 1, 5, 3])
+            ]
+        );
+        let values: Vec<VarBindValue> = varbinds.into_iter().map(|vb| vb.value).collect();
+        assert_eq!(
+            values,
+            vec![
+                VarBindValue::Value(ObjectSyntax::ApplicationWide(ApplicationSyntax::Unsigned(
+                    Unsigned32 { 0: 90 }
+                ))),
+                VarBindValue::Value(ObjectSyntax::ApplicationWide(ApplicationSyntax::Ticks(
+                    TimeTicks { 0: 1234 }
+                ))),
+                VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::ObjectId(device))),
+                VarBindValue::Value(ObjectSyntax::ApplicationWide(ApplicationSyntax::Counter(
+                    Counter32 { 0: 17 }
+                ))),
+            ]
+        );
+
+        // The rest of the stub loads too
+        let mut oid_map = OidMap::new();
+        example_stub::load_stub(
+            &mut oid_map,
+            &NotificationSender::default(),
+            &mut ComplianceStatements::new(),
+        );
+        assert_eq!(oid_map.len(), 4);
     }
 }
@@ -0,0 +1,1023 @@
+//! Notification destinations, from SNMP-TARGET-MIB and SNMP-NOTIFICATION-MIB, RFC 3413
+//!
+//! snmpTargetAddrTable, snmpTargetParamsTable, snmpNotifyTable, snmpNotifyFilterProfileTable and
+//! snmpNotifyFilterTable are held as keepers in the OidMap, so managers can change them with
+//! RowStatus. As for the VACM tables, every change is written to a file under StoragePath. The
+//! Notifier reads the same tables for each notification, RFC 3413 section 3.3:
+//! * each active snmpNotifyTable row selects the active target addresses with its tag in their
+//!   tag list, and says whether they are sent traps or informs.
+//! * the target's parameters give the message processing model, security model, securityName and
+//!   securityLevel. SNMPv2c with a community, and SNMPv3 with USM, are supported.
+//! * if the parameters have an active filter profile, the notification is only sent when
+//!   snmpTrapOID.0 and the name of every varbind are in the profile's filter, decided as for a
+//!   VACM view. A profile with no filter entries lets everything through.
+//!
+//! Traps are secured with the agent's own users from users.txt, as the agent is authoritative
+//! for them. Informs need users of the receivers, with keys localized to each receiver's
+//! snmpEngineID, from InformUser and the usmUser lines of NotifyConfig. Those are only read from
+//! the configuration, and are not in any table.
+//!
+//! The first time the agent runs, the tables are seeded from the configuration:
+//! * TrapSink and TrapUser give a target "trap-sink", sent traps at the highest level the user
+//!   supports.
+//! * InformSink and InformUser give a target "inform-sink", sent informs.
+//! * NotifyConfig names a file in the target module's format, with these lines as well:
+//!   "notify name tag type", where type is "trap" or "inform", like snmpNotifyTable;
+//!   "notifyFilterProfile params profile", like snmpNotifyFilterProfileTable; and
+//!   "notifyFilter profile included|excluded subtree [mask]", like snmpNotifyFilterTable, with the
+//!   subtree and mask as in views.txt.
+//!
+//! For example, "notify noc noc-traps trap" with
+//! "targetAddr noc1 192.0.2.9:162 100 0 noc-usm noc-traps" and
+//! "targetParams noc-usm v3 usm admin authPriv" sends traps to 192.0.2.9 as the admin user.
+//!
+//! After that the stored tables are used, and the configuration only matters for the users of
+//! receivers. snmpNotifyFilterProfileTable is indexed by snmpTargetParamsName, and
+//! snmpNotifyFilterTable by snmpNotifyFilterProfileName, each kept in a hidden last column as
+//! for vacmAccessTable. Only UDP over IPv4 and IPv6 is supported as a transport.
+use crate::config::Config;
+use crate::keeper::{Access, OType};
+use crate::oidmap::OidMap;
+use crate::perms::{View, ViewEntry};
+use crate::scalar::ScalarMemOid;
+use crate::table::{
+    int_col, oid_col, str_col, PersistentTable, SharedTable, SharedTableKeeper, TableMemOid,
+    ROW_STATUS_ACTIVE,
+};
+use crate::target::{MpModel, TargetAddr, TargetParams, Targets};
+use crate::transport::{self, TransportDomain};
+use crate::usm::{User, Users};
+use crate::vacm::SECURITY_MODEL_USM;
+use log::{debug, warn};
+use rasn::types::{Integer, ObjectIdentifier, OctetString};
+use rasn_smi::v2::{ObjectSyntax, SimpleSyntax};
+use rasn_snmp::v3::VarBind;
+use std::fs::read_to_string;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const ARC_SNMP_TARGET_SPIN_LOCK: [u32; 9] = [1, 3, 6, 1, 6, 3, 12, 1, 1];
+const ARC_SNMP_TARGET_ADDR_TABLE: [u32; 9] = [1, 3, 6, 1, 6, 3, 12, 1, 2];
+const ARC_SNMP_TARGET_PARAMS_TABLE: [u32; 9] = [1, 3, 6, 1, 6, 3, 12, 1, 3];
+const ARC_SNMP_NOTIFY_TABLE: [u32; 9] = [1, 3, 6, 1, 6, 3, 13, 1, 1];
+const ARC_SNMP_NOTIFY_FILTER_PROFILE_TABLE: [u32; 9] = [1, 3, 6, 1, 6, 3, 13, 1, 2];
+const ARC_SNMP_NOTIFY_FILTER_TABLE: [u32; 9] = [1, 3, 6, 1, 6, 3, 13, 1, 3];
+
+/// snmpUDPDomain, RFC 3417, an IPv4 address and port in 6 octets
+const SNMP_UDP_DOMAIN: [u32; 7] = [1, 3, 6, 1, 6, 1, 1];
+/// transportDomainUdpIpv4, RFC 3419, the same as snmpUDPDomain
+const UDP_IPV4_DOMAIN: [u32; 9] = [1, 3, 6, 1, 2, 1, 100, 1, 1];
+/// transportDomainUdpIpv6, RFC 3419, an IPv6 address and port in 18 octets
+const UDP_IPV6_DOMAIN: [u32; 9] = [1, 3, 6, 1, 2, 1, 100, 1, 2];
+
+const MP_MODEL_V2C: u32 = 1;
+const MP_MODEL_V3: u32 = 3;
+const NOTIFY_TRAP: u32 = 1;
+const NOTIFY_INFORM: u32 = 2;
+const FILTER_INCLUDED: u32 = 1;
+const FILTER_EXCLUDED: u32 = 2;
+const STORAGE_NON_VOLATILE: u32 = 3;
+/// snmpTargetAddrTimeout and snmpTargetAddrRetryCount defaults, in hundredths of a second
+const DEFAULT_TIMEOUT: u32 = 1500;
+const DEFAULT_RETRIES: u32 = 3;
+
+const TRAP_SINK: &str = "trap-sink";
+const INFORM_SINK: &str = "inform-sink";
+
+// Columns, 1 based, of the five tables
+const ADDR_NAME_COL: usize = 1;
+const ADDR_DOMAIN_COL: usize = 2;
+const ADDR_ADDRESS_COL: usize = 3;
+const ADDR_TIMEOUT_COL: usize = 4;
+const ADDR_RETRY_COL: usize = 5;
+const ADDR_TAGS_COL: usize = 6;
+const ADDR_PARAMS_COL: usize = 7;
+const ADDR_STATUS_COL: usize = 9;
+const PARAMS_NAME_COL: usize = 1;
+const PARAMS_MP_MODEL_COL: usize = 2;
+const PARAMS_MODEL_COL: usize = 3;
+const PARAMS_SECURITY_NAME_COL: usize = 4;
+const PARAMS_LEVEL_COL: usize = 5;
+const PARAMS_STATUS_COL: usize = 7;
+const NOTIFY_NAME_COL: usize = 1;
+const NOTIFY_TAG_COL: usize = 2;
+const NOTIFY_TYPE_COL: usize = 3;
+const NOTIFY_STATUS_COL: usize = 5;
+const PROFILE_NAME_COL: usize = 1;
+const PROFILE_STATUS_COL: usize = 3;
+const PROFILE_PARAMS_COL: usize = 4;
+const FILTER_SUBTREE_COL: usize = 1;
+const FILTER_MASK_COL: usize = 2;
+const FILTER_TYPE_COL: usize = 3;
+const FILTER_STATUS_COL: usize = 5;
+const FILTER_PROFILE_COL: usize = 6;
+
+fn simple_from_int(value: u32) -> ObjectSyntax {
+    ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(value)))
+}
+
+fn simple_from_str(value: &[u8]) -> ObjectSyntax {
+    ObjectSyntax::Simple(SimpleSyntax::String(OctetString::from_slice(value)))
+}
+
+fn simple_from_vec(value: &[u32]) -> ObjectSyntax {
+    ObjectSyntax::Simple(SimpleSyntax::ObjectId(
+        ObjectIdentifier::new(value.to_vec()).unwrap(),
+    ))
+}
+
+/// snmpTargetAddrTDomain and snmpTargetAddrTAddress for a UDP address.
+fn taddress(address: &SocketAddr) -> (&'static [u32], Vec<u8>) {
+    match address.ip() {
+        IpAddr::V4(ip) => (
+            &SNMP_UDP_DOMAIN,
+            [&ip.octets()[..], &address.port().to_be_bytes()].concat(),
+        ),
+        IpAddr::V6(ip) => (
+            &UDP_IPV6_DOMAIN,
+            [&ip.octets()[..], &address.port().to_be_bytes()].concat(),
+        ),
+    }
+}
+
+/// The UDP address in snmpTargetAddrTAddress, if the domain is one we support.
+fn socket_addr(domain: &[u32], taddress: &[u8]) -> Option<SocketAddr> {
+    let ip: IpAddr = if domain == SNMP_UDP_DOMAIN || domain == UDP_IPV4_DOMAIN {
+        let octets: [u8; 4] = taddress.get(..4)?.try_into().ok()?;
+        Ipv4Addr::from(octets).into()
+    } else if domain == UDP_IPV6_DOMAIN {
+        let octets: [u8; 16] = taddress.get(..16)?.try_into().ok()?;
+        Ipv6Addr::from(octets).into()
+    } else {
+        return None;
+    };
+    let port: [u8; 2] = taddress.get(taddress.len() - 2..)?.try_into().ok()?;
+    if taddress.len() != if ip.is_ipv4() { 6 } else { 18 } {
+        return None;
+    }
+    Some(SocketAddr::new(ip, u16::from_be_bytes(port)))
+}
+
+/// Where a notification goes, as selected from the tables.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Destination {
+    pub addr: TargetAddr,
+    pub params: TargetParams,
+    /// True for an inform, false for a trap
+    pub inform: bool,
+}
+
+/// The notification tables, with the users that secure the messages, for the Notifier.
+pub struct Destinations {
+    addrs: SharedTable,
+    params: SharedTable,
+    notify: SharedTable,
+    profiles: SharedTable,
+    filters: SharedTable,
+    /// The agent's own users, for traps
+    users: Vec<User<'static>>,
+    /// Users of inform receivers
+    receivers: Vec<User<'static>>,
+}
+
+impl Destinations {
+    /// Where a notification of type trap_oid, with varbinds, goes, RFC 3413 section 3.3
+    ///
+    /// Each target address is selected at most once, as an inform if any notify entry selecting
+    /// it asks for one.
+    pub fn select(&self, trap_oid: &ObjectIdentifier, varbinds: &[VarBind]) -> Vec<Destination> {
+        let notify = self.notify.lock().unwrap();
+        let addrs = self.addrs.lock().unwrap();
+        let mut selected: Vec<Destination> = vec![];
+        for notify_row in notify.table().rows() {
+            let tag = str_col(notify_row, NOTIFY_TAG_COL);
+            if int_col(notify_row, NOTIFY_STATUS_COL) != ROW_STATUS_ACTIVE || tag.is_empty() {
+                continue;
+            }
+            let inform = int_col(notify_row, NOTIFY_TYPE_COL) == NOTIFY_INFORM;
+            for addr_row in addrs.table().rows() {
+                let tags = str_col(addr_row, ADDR_TAGS_COL);
+                if int_col(addr_row, ADDR_STATUS_COL) != ROW_STATUS_ACTIVE
+                    || !tags.split(u8::is_ascii_whitespace).any(|t| t == tag)
+                {
+                    continue;
+                }
+                let name = String::from_utf8_lossy(str_col(addr_row, ADDR_NAME_COL));
+                if let Some(seen) = selected.iter_mut().find(|dest| dest.addr.name == name) {
+                    seen.inform |= inform;
+                    continue;
+                }
+                let Some(addr) = target_addr(addr_row) else {
+                    debug!("Target {name} has no UDP address");
+                    continue;
+                };
+                let Some(params) = self.params(&addr.params) else {
+                    debug!("Target {name} has no active targetParams");
+                    continue;
+                };
+                let filter = self.filter(&params.name);
+                if !filter.contains(trap_oid)
+                    || !varbinds.iter().all(|vb| filter.contains(&vb.name))
+                {
+                    debug!("Notification filtered out for {name}");
+                    continue;
+                }
+                selected.push(Destination {
+                    addr,
+                    params,
+                    inform,
+                });
+            }
+        }
+        selected
+    }
+
+    /// The user to secure messages to destination, ours for traps or the receiver's for informs.
+    pub fn user(&self, destination: &Destination) -> Option<&User<'static>> {
+        let users = if destination.inform {
+            &self.receivers
+        } else {
+            &self.users
+        };
+        users
+            .iter()
+            .find(|user| user.name == destination.params.security_name)
+    }
+
+    /// The active target parameters called name.
+    fn params(&self, name: &str) -> Option<TargetParams> {
+        let params = self.params.lock().unwrap();
+        let row = params.table().rows().find(|row| {
+            int_col(row, PARAMS_STATUS_COL) == ROW_STATUS_ACTIVE
+                && str_col(row, PARAMS_NAME_COL) == name.as_bytes()
+        })?;
+        let mp_model = match int_col(row, PARAMS_MP_MODEL_COL) {
+            MP_MODEL_V2C => MpModel::V2c,
+            MP_MODEL_V3 => MpModel::V3,
+            _ => return None,
+        };
+        Some(TargetParams {
+            name: name.to_string(),
+            mp_model,
+            security_model: int_col(row, PARAMS_MODEL_COL),
+            security_name: str_col(row, PARAMS_SECURITY_NAME_COL).to_vec(),
+            security_level: int_col(row, PARAMS_LEVEL_COL).try_into().unwrap_or(3),
+        })
+    }
+
+    /// The filter for the target parameters called params, from the active filter profile.
+    ///
+    /// An empty View, which lets everything through, if there is no profile.
+    fn filter(&self, params: &str) -> View {
+        let profiles = self.profiles.lock().unwrap();
+        let Some(profile) = profiles.table().rows().find(|row| {
+            int_col(row, PROFILE_STATUS_COL) == ROW_STATUS_ACTIVE
+                && str_col(row, PROFILE_PARAMS_COL) == params.as_bytes()
+        }) else {
+            return View::default();
+        };
+        let profile = str_col(profile, PROFILE_NAME_COL);
+        let filters = self.filters.lock().unwrap();
+        let entries = filters
+            .table()
+            .rows()
+            .filter(|row| {
+                int_col(row, FILTER_STATUS_COL) == ROW_STATUS_ACTIVE
+                    && str_col(row, FILTER_PROFILE_COL) == profile
+            })
+            .map(|row| ViewEntry {
+                subtree: oid_col(row, FILTER_SUBTREE_COL),
+                mask: str_col(row, FILTER_MASK_COL).to_vec(),
+                included: int_col(row, FILTER_TYPE_COL) == FILTER_INCLUDED,
+            })
+            .collect();
+        View { entries }
+    }
+}
+
+/// A TargetAddr from a row of snmpTargetAddrTable, if it has a UDP address.
+fn target_addr(row: &[ObjectSyntax]) -> Option<TargetAddr> {
+    let address = socket_addr(
+        &oid_col(row, ADDR_DOMAIN_COL),
+        str_col(row, ADDR_ADDRESS_COL),
+    )?;
+    let text = |col| String::from_utf8_lossy(str_col(row, col)).into_owned();
+    Some(TargetAddr {
+        name: text(ADDR_NAME_COL),
+        address,
+        timeout: Duration::from_millis(u64::from(int_col(row, ADDR_TIMEOUT_COL)) * 10),
+        retry_count: int_col(row, ADDR_RETRY_COL),
+        params: text(ADDR_PARAMS_COL),
+        tags: text(ADDR_TAGS_COL)
+            .split_whitespace()
+            .map(str::to_string)
+            .collect(),
+    })
+}
+
+fn addrs_table() -> TableMemOid {
+    let base = ObjectIdentifier::new(&ARC_SNMP_TARGET_ADDR_TABLE).unwrap();
+    let mut table = TableMemOid::new(
+        vec![
+            simple_from_str(b""),
+            simple_from_vec(&SNMP_UDP_DOMAIN),
+            simple_from_str(b""),
+            simple_from_int(DEFAULT_TIMEOUT),
+            simple_from_int(DEFAULT_RETRIES),
+            simple_from_str(b""),
+            simple_from_str(b""),
+            simple_from_int(STORAGE_NON_VOLATILE),
+            simple_from_int(ROW_STATUS_ACTIVE),
+        ],
+        9,
+        &base,
+        vec![
+            OType::String,
+            OType::ObjectId,
+            OType::String,
+            OType::Integer,
+            OType::Integer,
+            OType::String,
+            OType::String,
+            OType::Integer,
+            OType::RowStatus,
+        ],
+        vec![
+            Access::NoAccess,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+        ],
+        vec![ADDR_NAME_COL],
+        true,
+    );
+    table.set_required_cols(vec![ADDR_DOMAIN_COL, ADDR_ADDRESS_COL, ADDR_PARAMS_COL]);
+    table
+}
+
+fn params_table() -> TableMemOid {
+    let base = ObjectIdentifier::new(&ARC_SNMP_TARGET_PARAMS_TABLE).unwrap();
+    let mut table = TableMemOid::new(
+        vec![
+            simple_from_str(b""),
+            simple_from_int(MP_MODEL_V3),
+            simple_from_int(SECURITY_MODEL_USM),
+            simple_from_str(b""),
+            simple_from_int(1),
+            simple_from_int(STORAGE_NON_VOLATILE),
+            simple_from_int(ROW_STATUS_ACTIVE),
+        ],
+        7,
+        &base,
+        vec![
+            OType::String,
+            OType::Integer,
+            OType::Integer,
+            OType::String,
+            OType::Integer,
+            OType::Integer,
+            OType::RowStatus,
+        ],
+        vec![
+            Access::NoAccess,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+        ],
+        vec![PARAMS_NAME_COL],
+        true,
+    );
+    table.set_required_cols(vec![
+        PARAMS_MP_MODEL_COL,
+        PARAMS_MODEL_COL,
+        PARAMS_SECURITY_NAME_COL,
+        PARAMS_LEVEL_COL,
+    ]);
+    table
+}
+
+fn notify_table() -> TableMemOid {
+    let base = ObjectIdentifier::new(&ARC_SNMP_NOTIFY_TABLE).unwrap();
+    TableMemOid::new(
+        vec![
+            simple_from_str(b""),
+            simple_from_str(b""),
+            simple_from_int(NOTIFY_TRAP),
+            simple_from_int(STORAGE_NON_VOLATILE),
+            simple_from_int(ROW_STATUS_ACTIVE),
+        ],
+        5,
+        &base,
+        vec![
+            OType::String,
+            OType::String,
+            OType::Integer,
+            OType::Integer,
+            OType::RowStatus,
+        ],
+        vec![
+            Access::NoAccess,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+        ],
+        vec![NOTIFY_NAME_COL],
+        true,
+    )
+}
+
+fn profiles_table() -> TableMemOid {
+    let base = ObjectIdentifier::new(&ARC_SNMP_NOTIFY_FILTER_PROFILE_TABLE).unwrap();
+    let mut table = TableMemOid::new(
+        vec![
+            simple_from_str(b""),
+            simple_from_int(STORAGE_NON_VOLATILE),
+            simple_from_int(ROW_STATUS_ACTIVE),
+            simple_from_str(b""),
+        ],
+        4,
+        &base,
+        vec![
+            OType::String,
+            OType::Integer,
+            OType::RowStatus,
+            OType::String,
+        ],
+        vec![
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::NoAccess,
+        ],
+        vec![PROFILE_PARAMS_COL],
+        true,
+    );
+    table.set_required_cols(vec![PROFILE_NAME_COL]);
+    table
+}
+
+fn filters_table() -> TableMemOid {
+    let base = ObjectIdentifier::new(&ARC_SNMP_NOTIFY_FILTER_TABLE).unwrap();
+    TableMemOid::new(
+        vec![
+            simple_from_vec(&[0, 0]),
+            simple_from_str(b""),
+            simple_from_int(FILTER_INCLUDED),
+            simple_from_int(STORAGE_NON_VOLATILE),
+            simple_from_int(ROW_STATUS_ACTIVE),
+            simple_from_str(b""),
+        ],
+        6,
+        &base,
+        vec![
+            OType::ObjectId,
+            OType::String,
+            OType::Integer,
+            OType::Integer,
+            OType::RowStatus,
+            OType::String,
+        ],
+        vec![
+            Access::NoAccess,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::NoAccess,
+        ],
+        vec![FILTER_PROFILE_COL, FILTER_SUBTREE_COL],
+        true,
+    )
+}
+
+/// Table rows, and users of inform receivers, read from the configuration.
+#[derive(Default)]
+struct Seed {
+    targets: Targets,
+    /// Name, tag and whether it is for informs
+    notify: Vec<(String, String, bool)>,
+    /// Target parameters name and profile name
+    profiles: Vec<(String, String)>,
+    /// Profile name and filter entry
+    filters: Vec<(String, ViewEntry)>,
+}
+
+impl Seed {
+    /// Add the entry on a line of the NotifyConfig file.
+    fn add_line(&mut self, line: &str) -> Option<()> {
+        if self.targets.add_line(line).ok()? {
+            return Some(());
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        match parts[..] {
+            ["notify", name, tag, kind] => {
+                let inform = match kind {
+                    "trap" => false,
+                    "inform" => true,
+                    _ => return None,
+                };
+                self.notify
+                    .push((name.to_string(), tag.to_string(), inform));
+            }
+            ["notifyFilterProfile", params, profile] => {
+                self.profiles
+                    .push((params.to_string(), profile.to_string()));
+            }
+            ["notifyFilter", profile, kind, subtree, ref mask @ ..] if mask.len() <= 1 => {
+                let included = match kind {
+                    "included" => true,
+                    "excluded" => false,
+                    _ => return None,
+                };
+                let subtree = subtree
+                    .trim_start_matches('.')
+                    .split('.')
+                    .map(u32::from_str)
+                    .collect::<Result<Vec<u32>, _>>()
+                    .ok()?;
+                let mask = match mask.first() {
+                    Some(mask) => hex::decode(mask).ok()?,
+                    None => vec![],
+                };
+                let entry = ViewEntry {
+                    subtree,
+                    mask,
+                    included,
+                };
+                self.filters.push((profile.to_string(), entry));
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Read the NotifyConfig file. Blank lines and lines starting with "#" are skipped.
+    fn load_file(&mut self, path: &str) -> io::Result<()> {
+        for (line_no, line) in read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if self.add_line(line).is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Bad notification entry on line {0} of {path}", line_no + 1),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a target for a TrapSink or InformSink, secured as user, and the notify entry for it.
+    fn add_sink(
+        &mut self,
+        name: &str,
+        address: SocketAddr,
+        timing: (u32, u32),
+        user: &User,
+        inform: bool,
+    ) {
+        let (timeout, retries) = timing;
+        self.targets.addrs.push(TargetAddr {
+            name: name.to_string(),
+            address,
+            timeout: Duration::from_millis(u64::from(timeout) * 10),
+            retry_count: retries,
+            params: name.to_string(),
+            tags: vec![name.to_string()],
+        });
+        self.targets.params.push(TargetParams {
+            name: name.to_string(),
+            mp_model: MpModel::V3,
+            security_model: SECURITY_MODEL_USM,
+            security_name: user.name.clone(),
+            security_level: user.security_level(),
+        });
+        self.notify
+            .push((name.to_string(), name.to_string(), inform));
+    }
+
+    /// Rows for the five tables, in the order of TABLES.
+    fn rows(&self) -> [Vec<Vec<ObjectSyntax>>; 5] {
+        let addrs = self
+            .targets
+            .addrs
+            .iter()
+            .map(|addr| {
+                let (domain, address) = taddress(&addr.address);
+                let centiseconds = (addr.timeout.as_millis() / 10)
+                    .try_into()
+                    .unwrap_or(u32::MAX);
+                vec![
+                    simple_from_str(addr.name.as_bytes()),
+                    simple_from_vec(domain),
+                    simple_from_str(&address),
+                    simple_from_int(centiseconds),
+                    simple_from_int(addr.retry_count),
+                    simple_from_str(addr.tags.join(" ").as_bytes()),
+                    simple_from_str(addr.params.as_bytes()),
+                    simple_from_int(STORAGE_NON_VOLATILE),
+                    simple_from_int(ROW_STATUS_ACTIVE),
+                ]
+            })
+            .collect();
+        let params = self
+            .targets
+            .params
+            .iter()
+            .map(|params| {
+                let mp_model = match params.mp_model {
+                    MpModel::V2c => MP_MODEL_V2C,
+                    MpModel::V3 => MP_MODEL_V3,
+                };
+                vec![
+                    simple_from_str(params.name.as_bytes()),
+                    simple_from_int(mp_model),
+                    simple_from_int(params.security_model),
+                    simple_from_str(&params.security_name),
+                    simple_from_int(params.security_level.into()),
+                    simple_from_int(STORAGE_NON_VOLATILE),
+                    simple_from_int(ROW_STATUS_ACTIVE),
+                ]
+            })
+            .collect();
+        let notify = self
+            .notify
+            .iter()
+            .map(|(name, tag, inform)| {
+                let kind = if *inform { NOTIFY_INFORM } else { NOTIFY_TRAP };
+                vec![
+                    simple_from_str(name.as_bytes()),
+                    simple_from_str(tag.as_bytes()),
+                    simple_from_int(kind),
+                    simple_from_int(STORAGE_NON_VOLATILE),
+                    simple_from_int(ROW_STATUS_ACTIVE),
+                ]
+            })
+            .collect();
+        let profiles = self
+            .profiles
+            .iter()
+            .map(|(params, profile)| {
+                vec![
+                    simple_from_str(profile.as_bytes()),
+                    simple_from_int(STORAGE_NON_VOLATILE),
+                    simple_from_int(ROW_STATUS_ACTIVE),
+                    simple_from_str(params.as_bytes()),
+                ]
+            })
+            .collect();
+        let filters = self
+            .filters
+            .iter()
+            .map(|(profile, entry)| {
+                let kind = if entry.included {
+                    FILTER_INCLUDED
+                } else {
+                    FILTER_EXCLUDED
+                };
+                vec![
+                    simple_from_vec(&entry.subtree),
+                    simple_from_str(&entry.mask),
+                    simple_from_int(kind),
+                    simple_from_int(STORAGE_NON_VOLATILE),
+                    simple_from_int(ROW_STATUS_ACTIVE),
+                    simple_from_str(profile.as_bytes()),
+                ]
+            })
+            .collect();
+        [addrs, params, notify, profiles, filters]
+    }
+}
+
+/// Parse InformSink, "address timeout retries", with the timeout in hundredths of a second.
+fn parse_inform_sink(sink: &str) -> io::Result<(SocketAddr, u32, u32)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("InformSink {sink}"));
+    let parts: Vec<&str> = sink.split_whitespace().collect();
+    let [address, timeout, retries] = parts[..] else {
+        return Err(invalid());
+    };
+    let address = match transport::split_domain(address) {
+        (TransportDomain::Udp, address) => transport::parse_listen_addr(address)?,
+        _ => return Err(invalid()),
+    };
+    let timeout = u32::from_str(timeout).map_err(|_| invalid())?;
+    let retries = u32::from_str(retries).map_err(|_| invalid())?;
+    Ok((address, timeout, retries))
+}
+
+/// The seed from TrapSink, InformSink and the NotifyConfig file.
+fn seed(config: &Config, users: &Users) -> io::Result<Seed> {
+    let mut seed = Seed::default();
+    if !config.notify_config.is_empty() {
+        seed.load_file(&config.notify_config)?;
+    }
+    if !config.trap_sink.is_empty() {
+        let sink = transport::parse_listen_addr(&config.trap_sink)?;
+        match users.lookup_user(config.trap_user.as_bytes().to_vec()) {
+            Some(user) => seed.add_sink(
+                TRAP_SINK,
+                sink,
+                (DEFAULT_TIMEOUT, DEFAULT_RETRIES),
+                user,
+                false,
+            ),
+            None => warn!("TrapUser not found in users.txt, no {TRAP_SINK} target"),
+        }
+    }
+    if !config.inform_sink.is_empty() {
+        let (sink, timeout, retries) = parse_inform_sink(&config.inform_sink)?;
+        let user = User::remote_from_str(&config.inform_user)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "InformUser"))?;
+        seed.add_sink(INFORM_SINK, sink, (timeout, retries), &user, true);
+        seed.targets.users.push(user);
+    }
+    Ok(seed)
+}
+
+/// Create the target and notification keepers, add them to oid_map, and return the
+/// Destinations to give to the Notifier.
+///
+/// Tables are loaded from StoragePath if they have been saved, otherwise seeded from the
+/// configuration. Fails if the configuration cannot be read.
+pub fn load_notify(
+    oid_map: &mut OidMap,
+    config: &Config,
+    users: &Users,
+) -> io::Result<Destinations> {
+    let seed = seed(config, users)?;
+    let mut shared = vec![];
+    for ((name, table), rows) in [
+        ("snmp_target_addr", addrs_table()),
+        ("snmp_target_params", params_table()),
+        ("snmp_notify", notify_table()),
+        ("snmp_notify_filter_profile", profiles_table()),
+        ("snmp_notify_filter", filters_table()),
+    ]
+    .into_iter()
+    .zip(seed.rows())
+    {
+        let file_name = config.storage_path.clone() + "/" + name;
+        let mut ptable = PersistentTable::new(table, file_name);
+        if ptable.load().is_ok() {
+            debug!("{name} reloaded from storage");
+        } else {
+            warn!("{name} seeded from the configuration - load from storage failed");
+            ptable.set_data(rows);
+        }
+        shared.push(Arc::new(Mutex::new(ptable)));
+    }
+    for (arc, table, hidden_col) in [
+        (&ARC_SNMP_TARGET_ADDR_TABLE, &shared[0], None),
+        (&ARC_SNMP_TARGET_PARAMS_TABLE, &shared[1], None),
+        (&ARC_SNMP_NOTIFY_TABLE, &shared[2], None),
+        (
+            &ARC_SNMP_NOTIFY_FILTER_PROFILE_TABLE,
+            &shared[3],
+            Some(PROFILE_PARAMS_COL),
+        ),
+        (
+            &ARC_SNMP_NOTIFY_FILTER_TABLE,
+            &shared[4],
+            Some(FILTER_PROFILE_COL),
+        ),
+    ] {
+        let keeper = SharedTableKeeper::new(table.clone(), arc.len(), hidden_col);
+        oid_map.push(
+            ObjectIdentifier::new(arc.to_vec()).unwrap(),
+            Box::new(keeper),
+        );
+    }
+    let spin_lock = ScalarMemOid::new(
+        ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(0))),
+        OType::TestAndIncr,
+        Access::ReadWrite,
+    );
+    oid_map.push(
+        ObjectIdentifier::new(&ARC_SNMP_TARGET_SPIN_LOCK).unwrap(),
+        Box::new(spin_lock),
+    );
+    let mut shared = shared.into_iter();
+    let mut next = || shared.next().expect("Five tables");
+    Ok(Destinations {
+        addrs: next(),
+        params: next(),
+        notify: next(),
+        profiles: next(),
+        filters: next(),
+        users: users.users.iter().map(User::to_sender).collect(),
+        receivers: seed.targets.users,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keeper::OidErr;
+    use crate::perms::Perm;
+    use rasn_snmp::v3::VarBindValue;
+
+    const KEYS: &str = "sha1 0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b aes 0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c";
+    const COLD_START: [u32; 10] = [1, 3, 6, 1, 6, 3, 1, 1, 5, 1];
+    const ENTERPRISE_TRAP: [u32; 9] = [1, 3, 6, 1, 4, 1, 32473, 1, 1];
+    const SYS_NAME: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 5, 0];
+
+    fn fixture(
+        perms: &Vec<Perm>,
+        storage: &str,
+        lines: &str,
+    ) -> io::Result<(OidMap, Destinations)> {
+        let _ = std::fs::remove_dir_all(storage);
+        std::fs::create_dir_all(storage).unwrap();
+        let notify_config = storage.to_string() + ".txt";
+        std::fs::write(&notify_config, lines).unwrap();
+        let config = Config {
+            storage_path: storage.to_string(),
+            trap_sink: "192.0.2.9:162".to_string(),
+            trap_user: "trapper".to_string(),
+            inform_sink: "192.0.2.10:162 100 1".to_string(),
+            inform_user: format!("informer {KEYS}"),
+            notify_config,
+            ..Default::default()
+        };
+        let mut users = Users::new();
+        users
+            .users
+            .push(User::from_str(&format!("trapper test {KEYS}"), perms).unwrap());
+        let mut oid_map = OidMap::new();
+        let destinations = load_notify(&mut oid_map, &config, &users)?;
+        oid_map.sort();
+        Ok((oid_map, destinations))
+    }
+
+    fn oid(arc: &[u32]) -> ObjectIdentifier {
+        ObjectIdentifier::new(arc.to_vec()).unwrap()
+    }
+
+    fn names(selected: &[Destination]) -> Vec<(&str, bool)> {
+        selected
+            .iter()
+            .map(|dest| (dest.addr.name.as_str(), dest.inform))
+            .collect()
+    }
+
+    const LEGACY: &str = "targetAddr legacy [::1]:1162 100 0 legacy-v2c noc\n\
+        targetParams legacy-v2c v2c v2c public noAuthNoPriv\n\
+        # Only the standard traps, without sysName\n\
+        notify noc noc trap\n\
+        notifyFilterProfile legacy-v2c standard\n\
+        notifyFilter standard included 1.3.6.1.6.3.1.1.5\n\
+        notifyFilter standard excluded 1.3.6.1.2.1.1.5\n";
+
+    #[test]
+    fn seeded_destinations() {
+        let perms = vec![Perm::from_str("t f 3 test").unwrap()];
+        let (mut oid_map, destinations) =
+            fixture(&perms, "/tmp/snmp-rust-notify-seed", LEGACY).unwrap();
+
+        let cold_start = oid(&COLD_START);
+        let selected = destinations.select(&cold_start, &[]);
+        assert_eq!(
+            names(&selected),
+            [
+                ("inform-sink", true),
+                ("legacy", false),
+                ("trap-sink", false)
+            ]
+        );
+        let legacy = &selected[1];
+        assert_eq!(legacy.addr.address, "[::1]:1162".parse().unwrap());
+        assert_eq!(legacy.addr.timeout, Duration::from_secs(1));
+        assert_eq!(legacy.params.mp_model, MpModel::V2c);
+        assert_eq!(legacy.params.security_name, b"public");
+        // Traps from our users, informs from the receiver's
+        assert_eq!(destinations.user(&selected[2]).unwrap().name, b"trapper");
+        assert_eq!(selected[2].params.security_level, 3);
+        assert_eq!(destinations.user(&selected[0]).unwrap().name, b"informer");
+        assert!(destinations.user(legacy).is_none());
+
+        // Filtered by type, and by the varbinds
+        let selected = destinations.select(&oid(&ENTERPRISE_TRAP), &[]);
+        assert_eq!(
+            names(&selected),
+            [("inform-sink", true), ("trap-sink", false)]
+        );
+        let sys_name = VarBind {
+            name: oid(&SYS_NAME),
+            value: VarBindValue::Unspecified,
+        };
+        assert_eq!(destinations.select(&cold_start, &[sys_name]).len(), 2);
+
+        // The hidden params name column stays hidden
+        let okeep = oid_map.idx(
+            oid_map
+                .search(&oid(&ARC_SNMP_NOTIFY_FILTER_PROFILE_TABLE))
+                .unwrap(),
+        );
+        let last = okeep
+            .get_next(oid(&[1, 3, 6, 1, 6, 3, 13, 1, 2, 1, 3, 0]))
+            .unwrap();
+        assert_eq!(last.name.get(10), Some(&3));
+        assert_eq!(okeep.get_next(last.name), Err(OidErr::OutOfRange));
+
+        for (lines, line_no) in [
+            ("notify noc noc sometimes\n", 1),
+            ("\n# Bad mask\nnotifyFilter standard included 1.3.6 0g\n", 3),
+            ("targetParams legacy-v2c v2c v2c public authPriv\n", 1),
+        ] {
+            let err = fixture(&perms, "/tmp/snmp-rust-notify-bad", lines)
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(&format!("line {line_no}")));
+        }
+        for bad in [
+            "192.0.2.9:162",
+            "192.0.2.9:162 soon 3",
+            "tcp:192.0.2.9:162 100 3",
+        ] {
+            assert!(parse_inform_sink(bad).is_err());
+        }
+        assert_eq!(
+            parse_inform_sink("udp:192.0.2.9:162 100 3").unwrap(),
+            ("192.0.2.9:162".parse().unwrap(), 100, 3)
+        );
+    }
+
+    #[test]
+    fn create_notify_row() {
+        let perms = vec![Perm::from_str("t f 3 test").unwrap()];
+        let (mut oid_map, destinations) =
+            fixture(&perms, "/tmp/snmp-rust-notify-create", "").unwrap();
+        let cold_start = oid(&COLD_START);
+        assert_eq!(
+            names(&destinations.select(&cold_start, &[])),
+            [("inform-sink", true), ("trap-sink", false)]
+        );
+
+        // Informs rather than traps to the trap sink as well
+        let column = |col: u32| {
+            let mut arc = ARC_SNMP_NOTIFY_TABLE.to_vec();
+            arc.extend([1, col]);
+            arc.extend(b"acked".map(u32::from));
+            oid(&arc)
+        };
+        let which = oid_map.search(&oid(&ARC_SNMP_NOTIFY_TABLE)).unwrap();
+        let okeep = oid_map.idx(which);
+        let changes = [
+            (column(5), simple_from_int(4)),
+            (column(2), simple_from_str(b"trap-sink")),
+            (column(3), simple_from_int(NOTIFY_INFORM)),
+        ];
+        okeep.begin_transaction().unwrap();
+        for (name, value) in &changes {
+            okeep
+                .set(name.clone(), VarBindValue::Value(value.clone()))
+                .unwrap();
+        }
+        for (name, value) in &changes {
+            okeep
+                .test(name.clone(), &VarBindValue::Value(value.clone()))
+                .unwrap();
+        }
+        okeep.commit().unwrap();
+        assert_eq!(
+            names(&destinations.select(&cold_start, &[])),
+            [("trap-sink", true), ("inform-sink", true)]
+        );
+
+        // Reloaded from storage rather than seeded again
+        let mut reloaded = PersistentTable::new(
+            notify_table(),
+            "/tmp/snmp-rust-notify-create/snmp_notify".to_string(),
+        );
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.table().rows().count(), 3);
+    }
+
+    #[test]
+    fn udp_taddress() {
+        for address in ["192.0.2.9:162", "[2001:db8::9]:10162"] {
+            let address: SocketAddr = address.parse().unwrap();
+            let (domain, taddress) = taddress(&address);
+            assert_eq!(socket_addr(domain, &taddress), Some(address));
+        }
+        let ipv4 = [192, 0, 2, 9, 0, 162];
+        assert!(socket_addr(&UDP_IPV4_DOMAIN, &ipv4).is_some());
+        assert_eq!(socket_addr(&UDP_IPV6_DOMAIN, &ipv4), None);
+        assert_eq!(socket_addr(&SNMP_UDP_DOMAIN, &ipv4[..5]), None);
+        assert_eq!(socket_addr(&[1, 3, 6, 1, 6, 1, 2], &ipv4), None);
+    }
+}
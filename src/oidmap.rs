@@ -38,6 +38,20 @@ impl OidMap {
         info!("Sorted");
     }
 
+    /// Insert OID and associated trait object, keeping the store sorted.
+    ///
+    /// For keepers added while the agent is running, like AgentX registrations.
+    pub fn insert(&mut self, oid: ObjectIdentifier, arg: Box<dyn OidKeeper>) {
+        let pos = self.store.partition_point(|a| a.0 < oid);
+        self.store.insert(pos, (oid, arg));
+    }
+
+    /// Remove the trait object registered with exactly this oid, if there is one.
+    pub fn remove(&mut self, oid: &ObjectIdentifier) -> Option<Box<dyn OidKeeper>> {
+        let pos = self.store.binary_search_by(|a| a.0.cmp(oid)).ok()?;
+        Some(self.store.remove(pos).1)
+    }
+
     /// Binary search for trait object associated with oid, or insert point if not exact match
     pub fn search(&self, oid: &ObjectIdentifier) -> Result<usize, usize> {
         self.store.binary_search_by(|a| {
@@ -4,19 +4,91 @@
 //!
 //! The permissions are read in from the file "groups.txt".
 //!
-//! This has a line per group. There are four entries per line:
+//! This has a line per group. There are four or five entries per line:
 //! * read permission ("t" or "f")
 //! * write permission ("t" or "f")
 //! * security level (1-3), where 1 is noAuth, 2 is AuthNoPriv, and 3 is AuthPriv
 //! * group name
+//! * optional context name the group may use. A trailing "*" matches any context with
+//!   that prefix, so "*" on its own allows every context. If absent, only the default
+//!   (empty) context is allowed.
 //!
-//! The big difference from the VACM model is these permissions are global, rather than confined
-//! to specific OIDs, and there is no provision to change them, except by editing groups.txt
+//! Groups can be confined to OID subtrees by the optional file "views.txt". This has a line per
+//! view family entry, with four or five entries per line:
+//! * group name, as in groups.txt
+//! * "read" or "write"
+//! * "included" or "excluded"
+//! * subtree, as a dotted OID
+//! * optional mask in hex, VACM style. Bit 7 of the first octet covers the first sub-identifier;
+//!   a zero bit makes that sub-identifier a wildcard. Missing bits are ones.
+//!
+//! As in RFC 3415, an OID is in the view if the longest matching subtree is included. A group with
+//! no entries for read, or write, is not restricted for that access. For example,
+//! "guest read included 1.3.6.1.2.1.1" confines guest to the system group, while
+//! "operator write included 1.3.6.1.4.1.99999" and
+//! "operator write excluded 1.3.6.1.6.3.15.1.2.2" restrict what operator may change.
+//!
+//! The big difference from the VACM model is there is no provision to change these permissions,
+//! except by editing the files.
 use rasn::types::ObjectIdentifier;
 use regex::Regex;
 use std::fs::read_to_string;
+use std::path::Path;
 use std::str::FromStr;
 
+const VIEWS_FILENAME: &str = "views.txt";
+
+/// One view family entry: a subtree, its wildcard mask, and whether it is included.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ViewEntry {
+    pub subtree: Vec<u32>,
+    pub mask: Vec<u8>,
+    pub included: bool,
+}
+
+impl ViewEntry {
+    /// True if oid is within the subtree, ignoring sub-identifiers masked out.
+    pub fn matches(&self, oid: &[u32]) -> bool {
+        if oid.len() < self.subtree.len() {
+            return false;
+        }
+        self.subtree.iter().enumerate().all(|(i, sub)| {
+            let wild = self
+                .mask
+                .get(i / 8)
+                .is_some_and(|octet| octet & (0x80 >> (i % 8)) == 0);
+            wild || oid[i] == *sub
+        })
+    }
+}
+
+/// A set of view family entries. An empty view places no restriction.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct View {
+    pub entries: Vec<ViewEntry>,
+}
+
+impl View {
+    /// True if oid is in the view, decided by the longest matching subtree.
+    ///
+    /// Ties are broken in favour of the lexicographically greater subtree, as RFC 3415 does.
+    pub fn contains(&self, oid: &[u32]) -> bool {
+        if self.entries.is_empty() {
+            return true;
+        }
+        self.entries
+            .iter()
+            .filter(|entry| entry.matches(oid))
+            .max_by(|a, b| {
+                a.subtree
+                    .len()
+                    .cmp(&b.subtree.len())
+                    .then_with(|| a.subtree.cmp(&b.subtree))
+            })
+            .is_some_and(|entry| entry.included)
+    }
+}
+
 /// Associates a group name with read and write permissions for a
 /// given security level.
 #[derive(Debug, PartialEq, Eq)]
@@ -25,8 +97,27 @@ pub struct Perm {
     pub write: bool,
     pub security_level: u8, // Just flags
     pub group_name: Vec<u8>,
+    pub context: Vec<u8>, // Context name, or prefix if it ends in '*'
+    pub read_view: View,
+    pub write_view: View,
 }
 
+/// Permissions for a securityName that is not in any group, such as a TSM securityName with
+/// no user of the same name when there is no VACM, or a user of another engine.
+pub static NO_ACCESS: Perm = Perm {
+    read: false,
+    write: false,
+    security_level: 3,
+    group_name: Vec::new(),
+    context: Vec::new(),
+    read_view: View {
+        entries: Vec::new(),
+    },
+    write_view: View {
+        entries: Vec::new(),
+    },
+};
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct ParsePermError;
 /// Returns a Perm struct from string data.
@@ -38,8 +129,10 @@ impl FromStr for Perm {
     type Err = ParsePermError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let re =
-            Regex::new(r"^(?<read>[tf]) (?<write>[tf]) (?<level>[1-3]) (?<name>[^ ]+)$").unwrap();
+        let re = Regex::new(
+            r"^(?<read>[tf]) (?<write>[tf]) (?<level>[1-3]) (?<name>[^ ]+)( (?<context>[^ ]+))?$",
+        )
+        .unwrap();
 
         let captures = re.captures(s).ok_or(ParsePermError)?;
 
@@ -48,14 +141,31 @@ impl FromStr for Perm {
             write: captures["write"] == *"t",
             security_level: captures["level"].parse().expect("Regex should have caught"),
             group_name: captures["name"].as_bytes().to_vec(),
+            context: captures
+                .name("context")
+                .map_or(vec![], |m| m.as_str().as_bytes().to_vec()),
+            read_view: View::default(),
+            write_view: View::default(),
         })
     }
 }
 
 impl Perm {
-    pub fn check(&self, flags: u8, set: bool, _oid: &ObjectIdentifier) -> bool {
-        // Ignore OID for now, but allow for a future version
-        // to use it.
+    /// True if the group may use the named context.
+    pub fn check_context(&self, context: &[u8]) -> bool {
+        match self.context.strip_suffix(b"*") {
+            Some(prefix) => context.starts_with(prefix),
+            None => context == self.context.as_slice(),
+        }
+    }
+
+    /// True if the security level in flags allows the access, and oid is in the matching view.
+    pub fn check(&self, flags: u8, set: bool, oid: &ObjectIdentifier) -> bool {
+        self.check_access(flags, set) && self.in_view(set, oid)
+    }
+
+    /// True if the security level in flags allows read, or write, access to anything at all.
+    pub fn check_access(&self, flags: u8, set: bool) -> bool {
         let sec_level = 1 + (flags & 1) + (flags & 2);
         if sec_level < self.security_level {
             return false;
@@ -66,9 +176,64 @@ impl Perm {
             self.read
         }
     }
+
+    /// True if oid is in the group's read, or write, view.
+    pub fn in_view(&self, set: bool, oid: &ObjectIdentifier) -> bool {
+        if set {
+            self.write_view.contains(oid)
+        } else {
+            self.read_view.contains(oid)
+        }
+    }
+}
+
+/// Parse a line of "views.txt", returning the group name, whether it is the write view, and the entry.
+fn parse_view_line(s: &str) -> Result<(Vec<u8>, bool, ViewEntry), ParsePermError> {
+    let re = Regex::new(
+        r"^(?<name>[^ ]+) (?<access>read|write) (?<kind>included|excluded) \.?(?<subtree>[0-9]+(\.[0-9]+)*)( (?<mask>[0-9a-fA-F]*))?$",
+    )
+    .unwrap();
+    let captures = re.captures(s).ok_or(ParsePermError)?;
+    let subtree = captures["subtree"]
+        .split('.')
+        .map(u32::from_str)
+        .collect::<Result<Vec<u32>, _>>()
+        .map_err(|_| ParsePermError)?;
+    let mask = match captures.name("mask") {
+        Some(m) => hex::decode(m.as_str()).map_err(|_| ParsePermError)?,
+        None => vec![],
+    };
+    let entry = ViewEntry {
+        subtree,
+        mask,
+        included: captures["kind"] == *"included",
+    };
+    Ok((
+        captures["name"].as_bytes().to_vec(),
+        captures["access"] == *"write",
+        entry,
+    ))
+}
+
+/// Add the view entries in text to the matching groups.
+///
+/// Panics on a parse error or unknown group, as for groups.txt.
+fn add_views(perms: &mut [Perm], text: &str) {
+    for line in text.lines() {
+        let (name, write, entry) = parse_view_line(line).expect("Parse error reading views.txt");
+        let perm = perms
+            .iter_mut()
+            .find(|perm| perm.group_name == name)
+            .expect("Unknown group in views.txt");
+        if write {
+            perm.write_view.entries.push(entry);
+        } else {
+            perm.read_view.entries.push(entry);
+        }
+    }
 }
 
-/// Read "groups.txt" and return group definitions.
+/// Read "groups.txt", and "views.txt" if present, and return group definitions.
 ///
 /// Panics if read fails - this is during startup so indicates a configuration error,
 /// or file system corruption.
@@ -77,5 +242,76 @@ pub fn load_perms() -> Vec<Perm> {
     for line in read_to_string("groups.txt").unwrap().lines() {
         perms.push(Perm::from_str(line).expect("Parse error reading groups.txt"));
     }
+    if Path::new(VIEWS_FILENAME).exists() {
+        add_views(&mut perms, &read_to_string(VIEWS_FILENAME).unwrap());
+    }
     perms
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn contexts() {
+        let default_only = Perm::from_str("t f 1 guest").unwrap();
+        assert!(default_only.check_context(b""));
+        assert!(!default_only.check_context(b"vrf1"));
+        let exact = Perm::from_str("t f 1 guest vrf1").unwrap();
+        assert!(exact.check_context(b"vrf1"));
+        assert!(!exact.check_context(b""));
+        assert!(!exact.check_context(b"vrf10"));
+        let prefix = Perm::from_str("t f 1 guest vrf*").unwrap();
+        assert!(prefix.check_context(b"vrf10"));
+        assert!(!prefix.check_context(b""));
+        let any = Perm::from_str("t t 3 admin *").unwrap();
+        assert!(any.check_context(b""));
+        assert!(any.check_context(b"container7"));
+    }
+
+    fn oid(arc: &'static [u32]) -> ObjectIdentifier {
+        ObjectIdentifier::new(arc).unwrap()
+    }
+
+    #[test]
+    fn views() {
+        let mut perms = vec![
+            Perm::from_str("t f 1 guest").unwrap(),
+            Perm::from_str("t t 2 operator").unwrap(),
+        ];
+        add_views(
+            &mut perms,
+            "guest read included 1.3.6.1.2.1.1\n\
+             operator write included .1.3.6.1.4.1.99999\n\
+             operator write included 1.3.6.1.6.3.15\n\
+             operator write excluded 1.3.6.1.6.3.15.1.2.2",
+        );
+        let guest = &perms[0];
+        assert!(guest.check(0, false, &oid(&[1, 3, 6, 1, 2, 1, 1, 5])));
+        assert!(!guest.check(0, false, &oid(&[1, 3, 6, 1, 2, 1, 2, 1])));
+        assert!(!guest.check(0, true, &oid(&[1, 3, 6, 1, 2, 1, 1, 5])));
+        let operator = &perms[1];
+        // No read entries, so reads are unrestricted
+        assert!(operator.check(1, false, &oid(&[1, 3, 6, 1, 2, 1, 2, 1])));
+        assert!(operator.check(1, true, &oid(&[1, 3, 6, 1, 4, 1, 99999, 1])));
+        assert!(operator.check(1, true, &oid(&[1, 3, 6, 1, 6, 3, 15, 1, 2, 1])));
+        assert!(!operator.check(1, true, &oid(&[1, 3, 6, 1, 6, 3, 15, 1, 2, 2, 1, 3])));
+        assert!(!operator.check(1, true, &oid(&[1, 3, 6, 1, 2, 1, 1, 5])));
+        assert!(!operator.check(0, true, &oid(&[1, 3, 6, 1, 4, 1, 99999, 1])));
+    }
+
+    #[test]
+    fn view_masks() {
+        // Any column of ifEntry, but only for interface 3
+        let (_, write, entry) =
+            parse_view_line("guest read included 1.3.6.1.2.1.2.2.1.0.3 ffbf").unwrap();
+        assert!(!write);
+        assert_eq!(entry.mask, vec![0xff, 0xbf]);
+        assert!(entry.matches(&[1, 3, 6, 1, 2, 1, 2, 2, 1, 7, 3]));
+        assert!(entry.matches(&[1, 3, 6, 1, 2, 1, 2, 2, 1, 2, 3, 0]));
+        assert!(!entry.matches(&[1, 3, 6, 1, 2, 1, 2, 2, 1, 7, 4]));
+        assert!(!entry.matches(&[1, 3, 6, 1, 2, 1, 2, 2, 1]));
+        assert!(parse_view_line("guest read included 1.3.6.1 fff").is_err());
+        assert!(parse_view_line("guest maybe included 1.3.6.1").is_err());
+    }
+}
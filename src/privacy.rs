@@ -1,21 +1,32 @@
 use aes::cipher::{AsyncStreamCipher, KeyIvInit};
 use rasn::types::IntegerType;
 use rasn_snmp::v3::USMSecurityParameters;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
 
 type Aes128CfbEnc = cfb_mode::Encryptor<aes::Aes128>;
 type Aes128CfbDec = cfb_mode::Decryptor<aes::Aes128>;
 
+/// msgPrivacyParameters that can't be made into an IV, or a privacy key that is too short.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PrivacyError;
+
 /// Calculate the Initial Value for the crypt.
 /// If you get this wrong, the first block comes out wrong,
 /// but it then recovers - this is a CFB feature
-fn make_iv(usp: USMSecurityParameters) -> [u8; 16] {
-    // Manager chooses salt, agent just uses it (except for traps, which we don't do)
+fn make_iv(usp: USMSecurityParameters) -> Result<[u8; 16], PrivacyError> {
+    // The sender chooses the salt: the manager for requests, which the agent just uses in the
+    // reply, or next_salt for the proxy requests and traps the agent sends itself
     let mut iv: [u8; 16] = [0; 16];
     let (boot32p, needed) = usp.authoritative_engine_boots.to_unsigned_bytes_be();
     let boot32 = boot32p.as_ref();
     let (time32p, t_needed) = usp.authoritative_engine_time.to_unsigned_bytes_be();
     let time32 = time32p.as_ref();
     let saltb = usp.privacy_parameters.clone();
+    // RFC 3826 section 3.1.2.1: 32 bit boots and time, and a 64 bit salt
+    if needed > 4 || t_needed > 4 || saltb.len() != 8 {
+        return Err(PrivacyError);
+    }
     for i in 0..needed {
         iv[i + 4 - needed] = boot32[i];
     }
@@ -25,22 +36,40 @@ fn make_iv(usp: USMSecurityParameters) -> [u8; 16] {
     for i in 0..8 {
         iv[i + 8] = saltb[i];
     }
-    iv
+    Ok(iv)
+}
+
+/// Salt for a message we encrypt, to go in msgPrivacyParameters.
+///
+/// RFC 3826 section 3.1.2.1: a 64 bit integer, starting from a random value and incremented
+/// for each message, so the IV is not repeated while boots and time stand still.
+pub fn next_salt() -> [u8; 8] {
+    static SALT: LazyLock<AtomicU64> = LazyLock::new(|| AtomicU64::new(rand::random()));
+    SALT.fetch_add(1, Ordering::Relaxed).to_be_bytes()
 }
 
 /// Decrypt the data
-pub fn decrypt(data: &mut [u8], usp: USMSecurityParameters, pkey: &[u8]) -> Vec<u8> {
-    let iv = make_iv(usp.clone());
-    let key: &[u8] = &pkey[0..16];
+///
+/// Fails if msgPrivacyParameters is not an 8 byte salt, or the key is short, as happens
+/// for a user without privacy.
+pub fn decrypt(
+    data: &mut [u8],
+    usp: USMSecurityParameters,
+    pkey: &[u8],
+) -> Result<Vec<u8>, PrivacyError> {
+    let iv = make_iv(usp)?;
+    let key: &[u8] = pkey.get(0..16).ok_or(PrivacyError)?;
     Aes128CfbDec::new_from_slices(key, &iv)
         .unwrap()
         .decrypt(data);
-    data.to_vec()
+    Ok(data.to_vec())
 }
 
 /// Encrypt the data
+///
+/// The salt in usp is always one we chose or have already decrypted with, so a bad one is a bug.
 pub fn encrypt(data: &mut [u8], usp: USMSecurityParameters, pkey: &[u8]) -> Vec<u8> {
-    let iv = make_iv(usp);
+    let iv = make_iv(usp).expect("Salt is 8 bytes");
     let key: &[u8] = &pkey[0..16];
     Aes128CfbEnc::new_from_slices(key, &iv)
         .unwrap()
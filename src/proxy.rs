@@ -0,0 +1,893 @@
+//! Proxy forwarder application, RFC 3413 section 3.5
+//!
+//! Requests for some contexts are not answered from an OidMap, but forwarded to another agent,
+//! such as a legacy device behind the gateway that only speaks SNMPv2c, or lacks privacy. The
+//! manager's request is authenticated and decrypted by this agent as usual, then sent on with the
+//! credentials of the target address and parameters, see the target module, and the Response
+//! comes back to the manager under the manager's own request-id and security.
+//!
+//! Matching an entry's paramsIn is the only authorization for a proxied request: it is forwarded
+//! before, and instead of, any check against groups or the VACM.
+//!
+//! Entries, in the style of snmpProxyTable, are read from the same file as the targets, a line
+//! each: "proxy name type contextEngineID contextName paramsIn singleTargetOut"
+//! * type is "read", for Get, GetNext and GetBulk, or "write", for Set.
+//! * contextEngineID is in hex, or "-" to match any.
+//! * contextName is "-" for the default context.
+//! * paramsIn names the targetParams that the request's securityModel, securityName and
+//!   securityLevel must match.
+//! * singleTargetOut names the targetAddr to forward to.
+//!
+//! For example "proxy router1-read read - router1 noc-in router1", with
+//! "targetParams noc-in v3 usm noc authPriv" and router1 from the target module's example,
+//! lets the noc user read the device at 192.0.2.1 through the context "router1".
+//!
+//! The first entry to match is used. RFC 3413 forwards contextEngineID and contextName
+//! unchanged, as is done for an entry naming a contextEngineID, which is the downstream
+//! agent's. An entry for any contextEngineID picks the target by contextName alone, so the
+//! downstream agent is sent its own engine ID and the default context, as a legacy device
+//! knows nothing of the context names used here.
+//!
+//! Error statuses are translated as for AgentX subagents, RFC 3584 section 4.4 for SNMPv1
+//! errors. A downstream Report, or no reply after the retries, becomes genErr.
+//! SNMPv1 and TSM targets, and the trap and inform types, are not supported.
+//!
+//! forward only sends the request. It waits in a table, under the request-id it was sent
+//! with, while the agent goes on serving others, and serve, which the agent calls when the
+//! outbound sockets are readable or a timeout is due, resends it or hands back the Response.
+use crate::keeper::OidErr;
+use crate::notifier::{self, Outbound, RemoteEngine, UsmExchange, UsmTarget};
+use crate::target::{MpModel, Targets};
+use crate::transport;
+use crate::vacm::SECURITY_MODEL_USM;
+use log::{debug, warn};
+use rasn::types::{Integer, OctetString};
+use rasn_snmp::v2::Pdu;
+use rasn_snmp::v2c;
+use rasn_snmp::v3::{Pdus, Response, ScopedPdu, VarBind};
+use std::collections::HashMap;
+use std::fs::read_to_string;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::RawFd;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Which requests an entry forwards, snmpProxyType
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyType {
+    Read,
+    Write,
+}
+
+/// A row of snmpProxyTable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyEntry {
+    pub name: String,
+    pub proxy_type: ProxyType,
+    pub context_engine_id: Option<Vec<u8>>,
+    pub context_name: Vec<u8>,
+    pub params_in: String,
+    pub single_target_out: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseProxyError;
+
+impl FromStr for ProxyEntry {
+    type Err = ParseProxyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() != 7 || parts[0] != "proxy" {
+            return Err(ParseProxyError);
+        }
+        let proxy_type = match parts[2] {
+            "read" => ProxyType::Read,
+            "write" => ProxyType::Write,
+            _ => return Err(ParseProxyError),
+        };
+        let context_engine_id = match parts[3] {
+            "-" => None,
+            id => Some(hex::decode(id).map_err(|_| ParseProxyError)?),
+        };
+        let context_name = match parts[4] {
+            "-" => vec![],
+            name => name.as_bytes().to_vec(),
+        };
+        Ok(ProxyEntry {
+            name: parts[1].to_string(),
+            proxy_type,
+            context_engine_id,
+            context_name,
+            params_in: parts[5].to_string(),
+            single_target_out: parts[6].to_string(),
+        })
+    }
+}
+
+/// What forward did with a request.
+#[derive(Debug)]
+pub enum Forwarded {
+    /// Answered at once, as it could not be sent
+    Response(Pdus),
+    /// Sent, and waiting under this request-id for serve to hand back the Response
+    Sent(i32),
+}
+
+/// The request sent on over USM, as the module describes.
+struct UsmRequest {
+    context_engine_id: Option<Vec<u8>>,
+    context_name: OctetString,
+    data: Pdus,
+}
+
+impl UsmRequest {
+    fn scoped_pdu(&self, engine: &RemoteEngine) -> ScopedPdu {
+        match &self.context_engine_id {
+            Some(id) => ScopedPdu {
+                engine_id: OctetString::from(id.clone()),
+                name: self.context_name.clone(),
+                data: self.data.clone(),
+            },
+            None => ScopedPdu {
+                engine_id: engine.engine_id.clone(),
+                name: OctetString::from_static(b""),
+                data: self.data.clone(),
+            },
+        }
+    }
+}
+
+/// The USM side of a forwarded request.
+struct UsmForward {
+    exchange: UsmExchange,
+    user: Vec<u8>,
+    security_level: u8,
+    request: UsmRequest,
+}
+
+/// A forwarded request waiting for its Response.
+struct Pending {
+    entry: String,
+    proxy_type: ProxyType,
+    /// The manager's request-id and varbinds
+    request_id: i32,
+    request_vb: Vec<VarBind>,
+    address: SocketAddr,
+    timeout: Duration,
+    retry_count: u32,
+    /// Retries left for the message in buf
+    retries: u32,
+    deadline: Instant,
+    buf: Vec<u8>,
+    /// None for SNMPv2c
+    usm: Option<UsmForward>,
+}
+
+impl Pending {
+    /// The Response for the manager, from the target's Response or what went wrong.
+    fn finish(self, result: io::Result<Pdu>) -> Pdus {
+        match result {
+            Ok(mut pdu) => {
+                pdu.request_id = self.request_id;
+                pdu.error_status = match pdu.error_status {
+                    Pdu::ERROR_STATUS_NO_ERROR | Pdu::ERROR_STATUS_TOO_BIG => pdu.error_status,
+                    status if self.proxy_type == ProxyType::Read => {
+                        OidErr::from_error_status(status).read_error_status()
+                    }
+                    status => OidErr::from_error_status(status).error_status(),
+                };
+                Pdus::Response(Response(pdu))
+            }
+            Err(err) => {
+                warn!("Proxy {0} failed: {err}", self.entry);
+                gen_err(self.request_id, self.request_vb)
+            }
+        }
+    }
+}
+
+/// The proxy entries, and what is needed to forward requests to their targets.
+pub struct Proxy {
+    entries: Vec<ProxyEntry>,
+    targets: Targets,
+    outbound: Outbound,
+    /// What discovery found of each USM target
+    engines: HashMap<SocketAddr, RemoteEngine>,
+    next_id: i32,
+    /// Forwarded requests, by the request-id they were sent with
+    pending: HashMap<i32, Pending>,
+    /// Responses that arrived while wait was looking for another
+    finished: Vec<(i32, Pdus)>,
+}
+
+impl Proxy {
+    /// A forwarder with no entries, for the given targets.
+    pub fn new(targets: Targets) -> Self {
+        Proxy {
+            entries: vec![],
+            targets,
+            outbound: Outbound::new(),
+            engines: HashMap::new(),
+            next_id: rand::random::<i32>() & i32::MAX,
+            pending: HashMap::new(),
+            finished: vec![],
+        }
+    }
+
+    /// Add an entry, after those already there.
+    ///
+    /// Errors if the entry names target parameters, a target address, or a USM user, that
+    /// the targets do not have.
+    pub fn add(&mut self, entry: ProxyEntry) -> io::Result<()> {
+        let missing = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Proxy {0} refers to a missing {what}", entry.name),
+            )
+        };
+        self.targets
+            .params(&entry.params_in)
+            .ok_or_else(|| missing("paramsIn"))?;
+        let target = self
+            .targets
+            .addr(&entry.single_target_out)
+            .ok_or_else(|| missing("targetAddr"))?;
+        let params = self
+            .targets
+            .params(&target.params)
+            .ok_or_else(|| missing("targetParams"))?;
+        if params.security_model == SECURITY_MODEL_USM
+            && self.targets.user(&params.security_name).is_none()
+        {
+            return Err(missing("usmUser"));
+        }
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Read targets and proxy entries from a file in the format of this module and the target
+    /// module.
+    ///
+    /// Blank lines and lines starting with "#" are skipped.
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut targets = Targets::new();
+        let mut entries = vec![];
+        for (line_no, line) in read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = match targets.add_line(line) {
+                Ok(true) => true,
+                Ok(false) => ProxyEntry::from_str(line)
+                    .map(|entry| entries.push(entry))
+                    .is_ok(),
+                Err(_) => false,
+            };
+            if !parsed {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Bad proxy or target entry on line {0}", line_no + 1),
+                ));
+            }
+        }
+        let mut proxy = Proxy::new(targets);
+        for entry in entries {
+            proxy.add(entry)?;
+        }
+        Ok(proxy)
+    }
+
+    /// Number of entries.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// True if there are no entries, so nothing is forwarded.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The first entry for a request, RFC 3413 section 3.5.1.1
+    fn find(
+        &self,
+        proxy_type: ProxyType,
+        security: (u32, &[u8], u8),
+        scoped_pdu: &ScopedPdu,
+    ) -> Option<&ProxyEntry> {
+        let (model, security_name, level) = security;
+        self.entries.iter().find(|entry| {
+            entry.proxy_type == proxy_type
+                && entry
+                    .context_engine_id
+                    .as_ref()
+                    .is_none_or(|id| id.as_slice() == scoped_pdu.engine_id.as_ref())
+                && entry.context_name == scoped_pdu.name.as_ref()
+                && self.targets.params(&entry.params_in).is_some_and(|params| {
+                    params.mp_model == MpModel::V3
+                        && params.security_model == model
+                        && params.security_name == security_name
+                        && params.security_level == level
+                })
+        })
+    }
+
+    /// Forward a request, or return None if no entry is for it.
+    ///
+    /// security is the request's securityModel, securityName and securityLevel, the last on
+    /// the Perm scale of 1 to 3.
+    pub fn forward(
+        &mut self,
+        security: (u32, &[u8], u8),
+        scoped_pdu: &ScopedPdu,
+    ) -> Option<Forwarded> {
+        let (proxy_type, request_id, request_vb) = request_fields(&scoped_pdu.data)?;
+        let entry = self.find(proxy_type, security, scoped_pdu)?.clone();
+        debug!("Forwarding for proxy entry {0}", entry.name);
+        let outbound_id = notifier::next_id(&mut self.next_id);
+        let sent = self
+            .start(&entry, scoped_pdu, outbound_id)
+            .and_then(|mut pending| {
+                self.send(&mut pending)?;
+                self.pending.insert(outbound_id, pending);
+                Ok(())
+            });
+        match sent {
+            Ok(()) => Some(Forwarded::Sent(outbound_id)),
+            Err(err) => {
+                warn!("Proxy {0} failed: {err}", entry.name);
+                Some(Forwarded::Response(gen_err(request_id, request_vb)))
+            }
+        }
+    }
+
+    /// The outbound sockets, to poll for Responses.
+    pub fn fds(&self) -> Vec<RawFd> {
+        self.outbound.fds()
+    }
+
+    /// How long until a forwarded request is due to be resent or given up, if any are waiting.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.pending
+            .values()
+            .map(|pending| pending.deadline.saturating_duration_since(now))
+            .min()
+    }
+
+    /// Take the Responses that have arrived, if readable, and resend or give up the forwarded
+    /// requests whose timeout has passed.
+    ///
+    /// Returns the Responses for the managers, with the request-ids forward returned.
+    pub fn serve(&mut self, readable: bool) -> Vec<(i32, Pdus)> {
+        let mut finished = std::mem::take(&mut self.finished);
+        if readable {
+            match self.outbound.recv_waiting() {
+                Ok(replies) => {
+                    for (src, reply) in replies {
+                        finished.extend(self.reply(src, &reply));
+                    }
+                }
+                Err(err) => warn!("Proxy receive failed {err}"),
+            }
+        }
+        let now = Instant::now();
+        let due: Vec<i32> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.deadline <= now)
+            .map(|(outbound_id, _)| *outbound_id)
+            .collect();
+        for outbound_id in due {
+            finished.extend(self.retry(outbound_id));
+        }
+        finished
+    }
+
+    /// Wait for the Response to a request forward sent, which serve has not handed back.
+    ///
+    /// For process_message, which answers before it returns. Responses to other requests
+    /// are kept for the next serve.
+    pub fn wait(&mut self, outbound_id: i32) -> Pdus {
+        loop {
+            if let Some(pos) = self.finished.iter().position(|(id, _)| *id == outbound_id) {
+                return self.finished.remove(pos).1;
+            }
+            assert!(
+                self.pending.contains_key(&outbound_id),
+                "Waiting for a request that is not pending"
+            );
+            let readable = transport::wait_readable(&self.fds(), self.next_timeout())
+                .is_ok_and(|ready| !ready.is_empty());
+            let finished = self.serve(readable);
+            self.finished.extend(finished);
+        }
+    }
+
+    /// A request to the entry's target, sent with outbound_id, still to be sent.
+    fn start(
+        &mut self,
+        entry: &ProxyEntry,
+        scoped_pdu: &ScopedPdu,
+        outbound_id: i32,
+    ) -> io::Result<Pending> {
+        let (proxy_type, request_id, request_vb) =
+            request_fields(&scoped_pdu.data).ok_or(io::ErrorKind::Unsupported)?;
+        let data = with_request_id(&scoped_pdu.data, outbound_id);
+        let target = self
+            .targets
+            .addr(&entry.single_target_out)
+            .ok_or_else(|| io::Error::other("no targetAddr"))?;
+        let params = self
+            .targets
+            .params(&target.params)
+            .ok_or_else(|| io::Error::other("no targetParams"))?;
+        let mut pending = Pending {
+            entry: entry.name.clone(),
+            proxy_type,
+            request_id,
+            request_vb,
+            address: target.address,
+            timeout: target.timeout,
+            retry_count: target.retry_count,
+            retries: 0,
+            deadline: Instant::now(),
+            buf: vec![],
+            usm: None,
+        };
+        if params.mp_model == MpModel::V2c {
+            let message = v2c::Message {
+                version: Integer::from(1),
+                community: OctetString::from(params.security_name.clone()),
+                data,
+            };
+            pending.buf = rasn::ber::encode(&message).unwrap();
+            return Ok(pending);
+        }
+        if params.security_model != SECURITY_MODEL_USM {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        pending.usm = Some(UsmForward {
+            exchange: UsmExchange::new(target.address, outbound_id),
+            user: params.security_name.clone(),
+            security_level: params.security_level,
+            request: UsmRequest {
+                context_engine_id: entry.context_engine_id.clone(),
+                context_name: scoped_pdu.name.clone(),
+                data,
+            },
+        });
+        Ok(pending)
+    }
+
+    /// Send the next message of a request, with a fresh set of retries.
+    ///
+    /// Over USM that is the next message of its exchange, otherwise the one message built by
+    /// start.
+    fn send(&mut self, pending: &mut Pending) -> io::Result<()> {
+        if let Some(usm) = &mut pending.usm {
+            let user = self
+                .targets
+                .user(&usm.user)
+                .ok_or_else(|| io::Error::other("no usmUser"))?;
+            let target = UsmTarget {
+                address: pending.address,
+                timeout: pending.timeout,
+                retries: pending.retry_count,
+                user,
+                security_level: usm.security_level,
+            };
+            let engine = self
+                .engines
+                .entry(pending.address)
+                .or_insert_with(RemoteEngine::unknown);
+            pending.buf = usm
+                .exchange
+                .message(&target, engine, &mut self.next_id, |engine| {
+                    usm.request.scoped_pdu(engine)
+                })?;
+        }
+        pending.retries = pending.retry_count;
+        pending.deadline = Instant::now() + pending.timeout;
+        self.outbound.send_to(&pending.buf, pending.address)
+    }
+
+    /// Resend a request whose timeout has passed, or give it up once the retries are used up.
+    fn retry(&mut self, outbound_id: i32) -> Option<(i32, Pdus)> {
+        let pending = self.pending.get_mut(&outbound_id)?;
+        let sent = if pending.retries == 0 {
+            Err(match &pending.usm {
+                Some(usm) => {
+                    let engine = self
+                        .engines
+                        .entry(pending.address)
+                        .or_insert_with(RemoteEngine::unknown);
+                    usm.exchange.timed_out(engine)
+                }
+                None => io::ErrorKind::TimedOut.into(),
+            })
+        } else {
+            pending.retries -= 1;
+            pending.deadline = Instant::now() + pending.timeout;
+            self.outbound.send_to(&pending.buf, pending.address)
+        };
+        let err = sent.err()?;
+        let pending = self.pending.remove(&outbound_id)?;
+        Some((outbound_id, pending.finish(Err(err))))
+    }
+
+    /// Match a datagram from src to the request it answers, returning the Response for the
+    /// manager if that is the end of it.
+    fn reply(&mut self, src: SocketAddr, buf: &[u8]) -> Option<(i32, Pdus)> {
+        if let Ok(message) = rasn::ber::decode::<v2c::Message<Pdus>>(buf) {
+            let Pdus::Response(r) = message.data else {
+                return None;
+            };
+            let outbound_id = r.0.request_id;
+            let pending = self.pending.get(&outbound_id)?;
+            if pending.address != src || pending.usm.is_some() {
+                return None;
+            }
+            let pending = self.pending.remove(&outbound_id)?;
+            return Some((outbound_id, pending.finish(Ok(r.0))));
+        }
+        // A USM reply is only known by its msgID, once checked for the request's user
+        let (outbound_id, reply) = self.pending.iter().find_map(|(outbound_id, pending)| {
+            let usm = pending.usm.as_ref().filter(|_| pending.address == src)?;
+            let user = self.targets.user(&usm.user)?;
+            let reply = usm.exchange.accept(buf, user)?;
+            Some((*outbound_id, reply))
+        })?;
+        let mut pending = self.pending.remove(&outbound_id)?;
+        let usm = pending.usm.as_mut().expect("Matched by its exchange");
+        let engine = self
+            .engines
+            .entry(pending.address)
+            .or_insert_with(RemoteEngine::unknown);
+        let result = match usm.exchange.handle(reply, engine) {
+            Ok(None) => self.send(&mut pending).map(|()| None),
+            result => result,
+        };
+        match result {
+            Ok(None) => {
+                self.pending.insert(outbound_id, pending);
+                None
+            }
+            Ok(Some(pdu)) => Some((outbound_id, pending.finish(Ok(pdu)))),
+            Err(err) => Some((outbound_id, pending.finish(Err(err)))),
+        }
+    }
+}
+
+/// The type, request-id and varbinds of a request the proxy forwards.
+fn request_fields(data: &Pdus) -> Option<(ProxyType, i32, Vec<VarBind>)> {
+    let (proxy_type, request_id, request_vb) = match data {
+        Pdus::GetRequest(r) => (ProxyType::Read, r.0.request_id, &r.0.variable_bindings),
+        Pdus::GetNextRequest(r) => (ProxyType::Read, r.0.request_id, &r.0.variable_bindings),
+        Pdus::SetRequest(r) => (ProxyType::Write, r.0.request_id, &r.0.variable_bindings),
+        Pdus::GetBulkRequest(r) => (ProxyType::Read, r.0.request_id, &r.0.variable_bindings),
+        _ => return None,
+    };
+    Some((proxy_type, request_id, request_vb.clone()))
+}
+
+/// The genErr Response to a request that could not be forwarded, echoing its varbinds.
+fn gen_err(request_id: i32, variable_bindings: Vec<VarBind>) -> Pdus {
+    Pdus::Response(Response(Pdu {
+        request_id,
+        error_status: Pdu::ERROR_STATUS_GEN_ERR,
+        error_index: 0,
+        variable_bindings,
+    }))
+}
+
+/// A copy of a request PDU with another request-id.
+fn with_request_id(data: &Pdus, request_id: i32) -> Pdus {
+    let mut data = data.clone();
+    match &mut data {
+        Pdus::GetRequest(r) => r.0.request_id = request_id,
+        Pdus::GetNextRequest(r) => r.0.request_id = request_id,
+        Pdus::SetRequest(r) => r.0.request_id = request_id,
+        Pdus::GetBulkRequest(r) => r.0.request_id = request_id,
+        _ => (),
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keeper::{Access, OType};
+    use crate::oidmap::OidMap;
+    use crate::perms::{Perm, View};
+    use crate::scalar::ScalarMemOid;
+    use crate::snmp_agent::Agent;
+    use crate::usm::{User, Users};
+    use rasn::types::ObjectIdentifier;
+    use rasn_smi::v2::{ObjectSyntax, SimpleSyntax};
+    use rasn_snmp::v3::{GetRequest, SetRequest, VarBind, VarBindValue};
+    use std::net::UdpSocket;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const KEYS: &str = "sha1 0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b aes 0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c";
+    const SYS_NAME: [u32; 9] = [1, 3, 6, 1, 2, 1, 1, 5, 0];
+    const NOC: (u32, &[u8], u8) = (SECURITY_MODEL_USM, b"noc", 3);
+
+    fn string(value: &'static [u8]) -> ObjectSyntax {
+        ObjectSyntax::Simple(SimpleSyntax::String(OctetString::from_static(value)))
+    }
+
+    fn pdu(request_id: i32, value: VarBindValue) -> Pdu {
+        Pdu {
+            request_id,
+            error_status: 0,
+            error_index: 0,
+            variable_bindings: vec![VarBind {
+                name: ObjectIdentifier::new(&SYS_NAME).unwrap(),
+                value,
+            }],
+        }
+    }
+
+    fn scoped(context: &'static [u8], data: Pdus) -> ScopedPdu {
+        ScopedPdu {
+            engine_id: OctetString::from_static(b"gateway"),
+            name: OctetString::from_static(context),
+            data,
+        }
+    }
+
+    fn get(context: &'static [u8], request_id: i32) -> ScopedPdu {
+        let request = pdu(request_id, VarBindValue::Unspecified);
+        scoped(context, Pdus::GetRequest(GetRequest(request)))
+    }
+
+    fn response(pdus: Option<Pdus>) -> Pdu {
+        match pdus {
+            Some(Pdus::Response(resp)) => resp.0,
+            other => panic!("Expected a Response, got {other:?}"),
+        }
+    }
+
+    /// Forward a request and wait for the Response, as process_message does.
+    fn forward(
+        proxy: &mut Proxy,
+        security: (u32, &[u8], u8),
+        scoped_pdu: &ScopedPdu,
+    ) -> Option<Pdus> {
+        match proxy.forward(security, scoped_pdu)? {
+            Forwarded::Sent(outbound_id) => Some(proxy.wait(outbound_id)),
+            Forwarded::Response(resp) => Some(resp),
+        }
+    }
+
+    fn proxy(lines: &[String]) -> Proxy {
+        let mut targets = Targets::new();
+        let mut entries = vec![];
+        for line in lines {
+            if !targets.add_line(line).unwrap() {
+                entries.push(ProxyEntry::from_str(line).unwrap());
+            }
+        }
+        let mut proxy = Proxy::new(targets);
+        for entry in entries {
+            proxy.add(entry).unwrap();
+        }
+        proxy
+    }
+
+    /// A legacy v2c agent, answering Gets with "legacy", and refusing Sets with SNMPv1 readOnly.
+    fn legacy_agent(socket: UdpSocket, requests: usize) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let mut buf = [0; 1500];
+            for _ in 0..requests {
+                let (amt, src) = socket.recv_from(&mut buf).unwrap();
+                let message: v2c::Message<Pdus> = rasn::ber::decode(&buf[..amt]).unwrap();
+                assert_eq!(message.community.as_ref(), b"public");
+                let reply = match message.data {
+                    Pdus::GetRequest(r) => {
+                        pdu(r.0.request_id, VarBindValue::Value(string(b"legacy")))
+                    }
+                    Pdus::SetRequest(r) => Pdu {
+                        error_status: Pdu::ERROR_STATUS_READ_ONLY,
+                        error_index: 1,
+                        ..r.0
+                    },
+                    other => panic!("Unexpected {other:?}"),
+                };
+                let message = v2c::Message {
+                    version: Integer::from(1),
+                    community: message.community,
+                    data: Pdus::Response(Response(reply)),
+                };
+                socket
+                    .send_to(&rasn::ber::encode(&message).unwrap(), src)
+                    .unwrap();
+            }
+        })
+    }
+
+    #[test]
+    fn test_forward_v2c() {
+        let legacy = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let legacy_addr = legacy.local_addr().unwrap();
+        // Never answers
+        let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dead_addr = dead.local_addr().unwrap();
+        let mut proxy = proxy(&[
+            format!("targetAddr legacy {legacy_addr} 500 0 legacy-v2c"),
+            format!("targetAddr dead {dead_addr} 5 1 legacy-v2c"),
+            "targetParams legacy-v2c v2c v2c public noAuthNoPriv".to_string(),
+            "targetParams noc-in v3 usm noc authPriv".to_string(),
+            "proxy legacy-read read - legacy noc-in legacy".to_string(),
+            "proxy legacy-write write - legacy noc-in legacy".to_string(),
+            "proxy dead-read read - dead noc-in dead".to_string(),
+        ]);
+        let legacy = legacy_agent(legacy, 2);
+
+        let resp = response(forward(&mut proxy, NOC, &get(b"legacy", 7)));
+        assert_eq!(resp.request_id, 7);
+        assert_eq!(resp.error_status, Pdu::ERROR_STATUS_NO_ERROR);
+        assert_eq!(
+            resp.variable_bindings[0].value,
+            VarBindValue::Value(string(b"legacy"))
+        );
+
+        // The SNMPv1 error comes back as its SNMPv2 equivalent
+        let set = pdu(8, VarBindValue::Value(string(b"new")));
+        let set = scoped(b"legacy", Pdus::SetRequest(SetRequest(set)));
+        let resp = response(forward(&mut proxy, NOC, &set));
+        assert_eq!(resp.request_id, 8);
+        assert_eq!(resp.error_status, Pdu::ERROR_STATUS_NOT_WRITABLE);
+        assert_eq!(resp.error_index, 1);
+        legacy.join().unwrap();
+
+        // Requests that match no entry are left to the agent
+        assert!(proxy
+            .forward((SECURITY_MODEL_USM, b"noc", 2), &get(b"legacy", 9))
+            .is_none());
+        assert!(proxy
+            .forward((SECURITY_MODEL_USM, b"other", 3), &get(b"legacy", 9))
+            .is_none());
+        assert!(proxy.forward(NOC, &get(b"elsewhere", 9)).is_none());
+
+        let resp = response(forward(&mut proxy, NOC, &get(b"dead", 10)));
+        assert_eq!(resp.request_id, 10);
+        assert_eq!(resp.error_status, Pdu::ERROR_STATUS_GEN_ERR);
+        assert_eq!(resp.variable_bindings[0].value, VarBindValue::Unspecified);
+        drop(dead);
+    }
+
+    #[test]
+    fn test_forward_usm() {
+        // The downstream agent is one of ours, with the proxy's user
+        let (tx, rx) = mpsc::channel();
+        let downstream = thread::spawn(move || {
+            let perms = vec![Perm {
+                read: true,
+                write: true,
+                security_level: 3,
+                group_name: b"test".to_vec(),
+                context: vec![],
+                read_view: View::default(),
+                write_view: View::default(),
+            }];
+            let mut users = Users::new();
+            let user = User::from_str(&format!("proxy test {KEYS}"), &perms).unwrap();
+            users.users.push(user);
+            let mut oid_map = OidMap::new();
+            let sys_name =
+                ScalarMemOid::new(string(b"downstream"), OType::String, Access::ReadOnly);
+            oid_map.push(
+                ObjectIdentifier::new(&SYS_NAME[..8]).unwrap(),
+                Box::new(sys_name),
+            );
+            let mut agent = Agent::new(OctetString::from_static(b"downstream"));
+            agent.listen("127.0.0.1:0").unwrap();
+            tx.send(agent.local_addrs()[0]).unwrap();
+            agent.sort_maps(&mut oid_map);
+            // Discovery, then two Gets
+            let start = Instant::now();
+            let mut served = 0;
+            while served < 3 && start.elapsed() < Duration::from_secs(10) {
+                served += agent
+                    .serve_ready(&mut oid_map, &users, Some(Duration::from_secs(1)))
+                    .unwrap();
+            }
+            served
+        });
+        let addr = rx.recv().unwrap();
+        let mut proxy = proxy(&[
+            format!("targetAddr downstream {addr} 500 1 downstream-usm"),
+            "targetParams downstream-usm v3 usm proxy authPriv".to_string(),
+            format!("usmUser proxy {KEYS}"),
+            "targetParams noc-in v3 usm noc authPriv".to_string(),
+            "proxy downstream read - downstream noc-in downstream".to_string(),
+        ]);
+        for request_id in [11, 12] {
+            let resp = response(forward(&mut proxy, NOC, &get(b"downstream", request_id)));
+            assert_eq!(resp.request_id, request_id);
+            assert_eq!(resp.error_status, Pdu::ERROR_STATUS_NO_ERROR);
+            assert_eq!(
+                resp.variable_bindings[0].value,
+                VarBindValue::Value(string(b"downstream"))
+            );
+        }
+        assert_eq!(proxy.engines[&addr].engine_id.as_ref(), b"downstream");
+        assert_eq!(downstream.join().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_serve_proxied() {
+        let legacy = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let legacy_addr = legacy.local_addr().unwrap();
+        let dead = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let dead_addr = dead.local_addr().unwrap();
+        let proxy = proxy(&[
+            format!("targetAddr legacy {legacy_addr} 500 0 legacy-v2c"),
+            format!("targetAddr dead {dead_addr} 30 1 legacy-v2c"),
+            "targetParams legacy-v2c v2c v2c public noAuthNoPriv".to_string(),
+            "targetParams noc-in v3 usm noc noAuthNoPriv".to_string(),
+            "proxy legacy-read read - legacy noc-in legacy".to_string(),
+            "proxy dead-read read - dead noc-in dead".to_string(),
+        ]);
+        let legacy = legacy_agent(legacy, 1);
+        let perms = vec![Perm {
+            read: true,
+            write: false,
+            security_level: 1,
+            group_name: b"test".to_vec(),
+            context: vec![],
+            read_view: View::default(),
+            write_view: View::default(),
+        }];
+        let mut users = Users::new();
+        users
+            .users
+            .push(User::from_str("noc test none - none -", &perms).unwrap());
+        let mut agent = Agent::new(OctetString::from_static(b"gateway"));
+        agent.listen("127.0.0.1:0").unwrap();
+        let addr = agent.local_addrs()[0];
+        agent.set_proxy(proxy);
+        let mut oid_map = OidMap::new();
+        agent.sort_maps(&mut oid_map);
+
+        // The dead target is asked first, but does not hold up the request behind it
+        let manager = UdpSocket::bind("127.0.0.1:0").unwrap();
+        manager.set_nonblocking(true).unwrap();
+        let user = &users.users[0];
+        let gateway = RemoteEngine::new(OctetString::from_static(b"gateway"), 0, 0);
+        for (request_id, context) in [(21, &b"dead"[..]), (22, &b"legacy"[..])] {
+            let scoped_pdu = get(context, request_id);
+            let buf = notifier::usm_message(request_id, 4, user, &gateway, scoped_pdu);
+            manager.send_to(&buf, addr).unwrap();
+        }
+        let start = Instant::now();
+        let mut replies = vec![];
+        let mut buf = [0; 1500];
+        while replies.len() < 2 && start.elapsed() < Duration::from_secs(10) {
+            agent
+                .serve_ready(&mut oid_map, &users, Some(Duration::from_millis(50)))
+                .unwrap();
+            while let Ok(amt) = manager.recv(&mut buf) {
+                let reply = notifier::open_usm(&buf[..amt], user).unwrap();
+                replies.push(response(Some(reply.scoped_pdu.data)));
+            }
+        }
+        legacy.join().unwrap();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0].request_id, 22);
+        assert_eq!(
+            replies[0].variable_bindings[0].value,
+            VarBindValue::Value(string(b"legacy"))
+        );
+        assert_eq!(replies[1].request_id, 21);
+        assert_eq!(replies[1].error_status, Pdu::ERROR_STATUS_GEN_ERR);
+        drop(dead);
+    }
+}
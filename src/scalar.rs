@@ -17,6 +17,7 @@ pub struct ScalarMemOid {
     access: Access,
     transaction: bool,
     pending: ObjectSyntax,
+    previous: ObjectSyntax,
 }
 
 impl ScalarMemOid {
@@ -39,7 +40,8 @@ impl ScalarMemOid {
             otype,
             access,
             transaction: false,
-            pending: value,
+            pending: value.clone(),
+            previous: value,
         }
     }
 }
@@ -95,7 +97,7 @@ impl OidKeeper for ScalarMemOid {
                                 self.pending = ObjectSyntax::Simple(SimpleSyntax::Integer(incr1));
                             }
                         } else {
-                            return Err(OidErr::OutOfRange); // InconsistentValue
+                            return Err(OidErr::InconsistentValue);
                         }
                     }
                     self.pending = new_value;
@@ -110,7 +112,7 @@ impl OidKeeper for ScalarMemOid {
     }
 
     fn commit(&mut self) -> Result<(), OidErr> {
-        self.value = self.pending.clone();
+        self.previous = std::mem::replace(&mut self.value, self.pending.clone());
         self.transaction = false;
         Ok(())
     }
@@ -119,6 +121,11 @@ impl OidKeeper for ScalarMemOid {
         self.transaction = false;
         Ok(())
     }
+
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.value = self.previous.clone();
+        Ok(())
+    }
 }
 
 #[derive(PartialEq, Eq, Hash)]
@@ -147,6 +154,22 @@ impl PersistentScalar {
             }
         }
     }
+
+    /// Write the current value to the file
+    fn save(&self) {
+        let bytes_res = encode::<ObjectSyntax>(&self.scalar.value);
+        match bytes_res {
+            Ok(bytes) => {
+                let outcome = std::fs::write(&self.file_name, bytes);
+                if outcome.is_err() {
+                    error!["Write failure saving to {0}", self.file_name]
+                }
+            }
+            Err(err) => {
+                error!["Persistence failure {err:?}"];
+            }
+        }
+    }
 }
 
 impl OidKeeper for PersistentScalar {
@@ -181,20 +204,15 @@ impl OidKeeper for PersistentScalar {
 
     fn commit(&mut self) -> Result<(), OidErr> {
         let comm_res = self.scalar.commit();
-        let bytes_res = encode::<ObjectSyntax>(&self.scalar.value);
-        match bytes_res {
-            Ok(bytes) => {
-                let outcome = std::fs::write(&self.file_name, bytes);
-                if outcome.is_err() {
-                    error!["Write failure saving to {0}", self.file_name]
-                }
-            }
-            Err(err) => {
-                error!["Persistence failure {err:?}"];
-            }
-        }
+        self.save();
         comm_res
     }
+
+    fn undo(&mut self) -> Result<(), OidErr> {
+        let undo_res = self.scalar.undo();
+        self.save();
+        undo_res
+    }
 }
 
 #[cfg(test)]
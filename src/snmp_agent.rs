@@ -4,33 +4,336 @@
 //! it might be used.
 
 //pub use crate::engine_id;
+use crate::agentx;
+use crate::contexts::Contexts;
 use crate::keeper::OidErr;
-//use crate::keeper::OidKeeper;
+use crate::keeper::OidKeeper;
 use crate::notifier;
 use crate::oidmap::OidMap;
-use crate::perms::Perm;
+use crate::perms::{Perm, NO_ACCESS};
 use crate::privacy;
+use crate::proxy;
+use crate::transport;
+use crate::transport::TransportDomain;
+#[cfg(feature = "tsm")]
+use crate::tsm;
 use crate::usm;
+use crate::vacm::{Vacm, SECURITY_MODEL_TSM, SECURITY_MODEL_USM};
 use log::{debug, error, warn};
 use rasn;
 use rasn::types::{Integer, ObjectIdentifier, OctetString};
-use rasn_smi::v2::{ObjectSyntax, SimpleSyntax};
+use rasn_smi::v2::{ApplicationSyntax, Counter32, ObjectSyntax, SimpleSyntax};
 use rasn_snmp::v2::{Pdu, Report, VarBind};
 use rasn_snmp::v3::VarBindValue;
 use rasn_snmp::v3::{GetBulkRequest, GetNextRequest, GetRequest, SetRequest};
 use rasn_snmp::v3::{HeaderData, Message, Pdus, ScopedPdu, USMSecurityParameters};
 use rasn_snmp::v3::{Response, ScopedPduData};
-use std::collections::HashSet;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::Display;
 use std::fs::{read_to_string, write};
-use std::net::{SocketAddr, UdpSocket};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
 use std::str::FromStr;
-use std::time::Instant;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 const BOOT_CNT_FILENAME: &str = "boot-cnt.txt";
-const B12: [u8; 12] = [0; 12];
-const Z12: OctetString = OctetString::from_static(&B12);
 const ZB: OctetString = OctetString::from_static(b"");
+pub(crate) const REPORTABLE_FLAG: u8 = 4;
+/// Largest message we send or accept, advertised as our msgMaxSize.
+pub(crate) const MAX_MSG_SIZE: usize = 65000;
+/// Largest message over DTLS, where each one must fit a single record.
+const DTLS_MAX_MSG_SIZE: usize = 16384;
+/// Default limit on open TCP connections
+const TCP_MAX_CONNECTIONS: usize = 16;
+/// Default time after which an idle TCP connection is closed
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Allowance for BER length fields growing as the varbind list gets longer.
+const LENGTH_SLACK: usize = 16;
+/// snmpUnknownContexts.0, RFC 3413
+const UNKNOWN_CONTEXTS_ARC: [u32; 10] = [1, 3, 6, 1, 6, 3, 12, 1, 5, 0];
+/// snmpEnableAuthenTraps.0, RFC 3418
+const ENABLE_AUTHEN_TRAPS_ARC: [u32; 9] = [1, 3, 6, 1, 2, 1, 11, 30, 0];
+/// Default least time between authenticationFailure notifications
+const AUTH_TRAP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Stand-ins for the tsm module, so that TLS and DTLS Listen addresses fail cleanly without it.
+#[cfg(not(feature = "tsm"))]
+mod tsm {
+    use crate::transport::HandshakeStep;
+    use std::io;
+    use std::net::{SocketAddr, TcpStream};
+    use std::os::fd::{AsRawFd, RawFd};
+    use std::time::Duration;
+
+    pub enum TlsConfig {}
+
+    impl TlsConfig {
+        pub fn accept(&self, _: TcpStream) -> io::Result<HandshakeStep> {
+            match *self {}
+        }
+    }
+
+    pub enum DtlsTransport {}
+
+    impl DtlsTransport {
+        pub fn bind(_: &str) -> io::Result<Self> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+
+        pub fn local_addr(&self) -> io::Result<SocketAddr> {
+            match *self {}
+        }
+
+        pub fn recv(&mut self, _: &TlsConfig, _: usize) -> io::Result<(SocketAddr, Vec<Vec<u8>>)> {
+            match *self {}
+        }
+
+        pub fn security_name(&self, _: &SocketAddr) -> Option<&[u8]> {
+            match *self {}
+        }
+
+        pub fn send(&mut self, _: &SocketAddr, _: &[u8]) -> io::Result<()> {
+            match *self {}
+        }
+
+        pub fn next_idle(&self, _: Duration) -> Option<Duration> {
+            match *self {}
+        }
+
+        pub fn close_idle(&mut self, _: Duration) {
+            match *self {}
+        }
+    }
+
+    impl AsRawFd for DtlsTransport {
+        fn as_raw_fd(&self) -> RawFd {
+            match *self {}
+        }
+    }
+}
+
+/// Failures detected by the User-based Security Model, RFC 3414 section 3.2
+///
+/// Each one is reported to the manager with the matching usmStats counter.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum UsmFailure {
+    NotInTimeWindow,
+    UnknownUserName,
+    UnknownEngineId,
+    WrongDigest,
+    DecryptionError,
+    UnsupportedSecLevel,
+}
+
+impl UsmFailure {
+    /// OID of the usmStats counter instance carried in the Report
+    pub(crate) fn arc(&self) -> &'static [u32] {
+        match self {
+            UsmFailure::NotInTimeWindow => &[1, 3, 6, 1, 6, 3, 15, 1, 1, 2, 0],
+            UsmFailure::UnknownUserName => &[1, 3, 6, 1, 6, 3, 15, 1, 1, 3, 0],
+            UsmFailure::UnknownEngineId => &[1, 3, 6, 1, 6, 3, 15, 1, 1, 4, 0],
+            UsmFailure::WrongDigest => &[1, 3, 6, 1, 6, 3, 15, 1, 1, 5, 0],
+            UsmFailure::DecryptionError => &[1, 3, 6, 1, 6, 3, 15, 1, 1, 6, 0],
+            UsmFailure::UnsupportedSecLevel => &[1, 3, 6, 1, 6, 3, 15, 1, 1, 1, 0],
+        }
+    }
+}
+
+/// Statistics counter shared between the Agent and the handlers that report it.
+///
+/// Clones refer to the same count, so a handler built at load time sees later increments.
+#[derive(Clone, Debug, Default)]
+pub struct StatsCounter(Arc<AtomicU32>);
+
+impl StatsCounter {
+    /// Increment, returning the new value
+    pub fn incr(&self) -> u32 {
+        self.0.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+    }
+
+    pub fn get(&self) -> u32 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Transport and source address of the request being processed, shared with handlers.
+///
+/// Clone it from Agent::request_source when a handler is built, like a StatsCounter. The agent
+/// updates it before each request, so handlers can take access decisions on the transport.
+#[derive(Clone, Debug, Default)]
+pub struct RequestSource(Arc<Mutex<Option<(TransportDomain, SocketAddr)>>>);
+
+impl RequestSource {
+    /// Transport and address of the current request, None before the first.
+    pub fn get(&self) -> Option<(TransportDomain, SocketAddr)> {
+        *self.0.lock().unwrap()
+    }
+
+    fn set(&self, domain: TransportDomain, src: SocketAddr) {
+        *self.0.lock().unwrap() = Some((domain, src));
+    }
+}
+
+/// Who a request comes from, as seen by access control.
+///
+/// perm is what applies when the Agent has no VACM. For the Transport Security Model, that is
+/// the group of the USM user with the same name, if there is one.
+struct Principal<'a> {
+    model: u32,
+    name: &'a [u8],
+    perm: &'a Perm,
+}
+
+impl<'a> Principal<'a> {
+    fn usm(user: &'a usm::User) -> Self {
+        Principal {
+            model: SECURITY_MODEL_USM,
+            name: &user.name,
+            perm: user.perm,
+        }
+    }
+}
+
+/// What do_context made of a request.
+enum Handled {
+    /// The PDU to send back
+    Now(Pdus),
+    /// Forwarded by the proxy, which hands back the Response under this request-id
+    Later(i32),
+}
+
+/// How a request reached serve_ready, so a Response the proxy gets later can follow it back.
+#[derive(Clone, Copy, Debug)]
+enum ReplyPath {
+    /// UDP socket by index, and the address the request was sent to
+    Udp(usize, Option<IpAddr>),
+    /// DTLS socket by index
+    Dtls(usize),
+    /// TCP or TLS connection, found by its domain and peer
+    Tcp(TransportDomain),
+}
+
+/// How to build the Response message, from the request.
+enum ReplySecurity {
+    Usm {
+        message_id: Integer,
+        flags: OctetString,
+        user_name: Vec<u8>,
+        usp: Box<USMSecurityParameters>,
+        max_size: usize,
+    },
+    /// max_size is the manager's msgMaxSize, our_max our own
+    Tsm {
+        message_id: Integer,
+        flags: u8,
+        max_size: usize,
+        our_max: usize,
+    },
+}
+
+/// A request the proxy is forwarding, and what its Response needs.
+struct Proxied {
+    path: ReplyPath,
+    src: SocketAddr,
+    context_name: OctetString,
+    budget: usize,
+    security: ReplySecurity,
+}
+
+/// Security level requested by message flags, on the same 1 to 3 scale as Perm and User.
+///
+/// None if the flags are invalid, that is privacy without authentication.
+fn flags_level(flags: u8) -> Option<u8> {
+    match flags & 3 {
+        0 => Some(1),
+        1 => Some(2),
+        3 => Some(3),
+        _ => None,
+    }
+}
+
+/// Message flags for a security level on the 1 to 3 scale, the inverse of flags_level.
+pub(crate) fn level_flags(level: u8) -> u8 {
+    match level {
+        0 | 1 => 0,
+        2 => 1,
+        _ => 3,
+    }
+}
+
+/// Varbind carrying a Counter32 statistic, as sent in Reports.
+fn counter_varbind(arc: &[u32], count: u32) -> VarBind {
+    VarBind {
+        name: ObjectIdentifier::new_unchecked(arc.to_vec().into()),
+        value: VarBindValue::Value(ObjectSyntax::ApplicationWide(ApplicationSyntax::Counter(
+            Counter32 { 0: count },
+        ))),
+    }
+}
+
+/// Response with no varbinds and the longest request-id, for measuring message overhead.
+fn empty_response() -> Pdus {
+    Pdus::Response(Response(Pdu {
+        request_id: i32::MAX,
+        error_index: 0,
+        error_status: 0,
+        variable_bindings: vec![],
+    }))
+}
+
+/// Encoded size of a varbind, or varbind list, in bytes.
+/// Trim a proxied Response that does not fit the budget to tooBig, as do_scoped_pdu does.
+fn fit_budget(resp: &mut Pdus, budget: usize) {
+    if let Pdus::Response(Response(pdu)) = resp {
+        if pdu.variable_bindings.iter().map(encoded_len).sum::<usize>() > budget {
+            warn!("Proxied response too big for budget {budget}");
+            pdu.error_status = Pdu::ERROR_STATUS_TOO_BIG;
+            pdu.error_index = 0;
+            pdu.variable_bindings.clear();
+        }
+    }
+}
+
+fn encoded_len<T: rasn::Encode>(value: &T) -> usize {
+    rasn::ber::encode(value).map_or(usize::MAX, |buf| buf.len())
+}
+
+/// Dig the request-id out of a PDU, for use in Reports.
+fn pdu_request_id(pdus: &Pdus) -> i32 {
+    match pdus {
+        Pdus::GetRequest(r) => r.0.request_id,
+        Pdus::GetNextRequest(r) => r.0.request_id,
+        Pdus::Response(r) => r.0.request_id,
+        Pdus::SetRequest(r) => r.0.request_id,
+        Pdus::GetBulkRequest(r) => r.0.request_id,
+        Pdus::InformRequest(r) => r.0.request_id,
+        Pdus::Trap(r) => r.0.request_id,
+        Pdus::Report(r) => r.0.request_id,
+    }
+}
+
+/// Request-id of a plaintext scoped PDU, or zero if it is encrypted.
+fn clear_request_id(scoped_data: &ScopedPduData) -> i32 {
+    match scoped_data {
+        ScopedPduData::CleartextPdu(scoped_pdu) => pdu_request_id(&scoped_pdu.data),
+        ScopedPduData::EncryptedPdu(_) => 0,
+    }
+}
+
+/// Encode a Message for sending, or None if it cannot be encoded.
+fn encode_message(message: &Message) -> Option<Vec<u8>> {
+    match rasn::ber::encode(message) {
+        Ok(buf) => Some(buf),
+        Err(err) => {
+            error!("encodeError on returned Message{err:?}, dropping packet");
+            None
+        }
+    }
+}
 
 /// Get the boot count from non-volatile storage, creating file if it does not exist.
 /// Panic if the file cannot be parsed or updated, as that indicates tampering or hardware failure.
@@ -54,19 +357,139 @@ fn get_increment_boot_cnt() -> isize {
     boots
 }
 
+/// The value of roid for a Get, with missing objects and instances as exceptions.
+pub(crate) fn get_value(
+    roid: &ObjectIdentifier,
+    oid_map: &mut OidMap,
+) -> Result<VarBindValue, OidErr> {
+    match oid_map.search(roid) {
+        Err(insert_point) => {
+            debug!("Get miss case {insert_point}");
+            Ok(VarBindValue::NoSuchObject)
+        }
+        Ok(which) => match oid_map.idx(which).get(roid.clone()) {
+            Ok(value) => Ok(value),
+            Err(OidErr::NoAccess | OidErr::NoSuchName) => Ok(VarBindValue::NoSuchObject),
+            Err(OidErr::NoSuchInstance | OidErr::OutOfRange) => Ok(VarBindValue::NoSuchInstance),
+            Err(err) => Err(err),
+        },
+    }
+}
+
+/// Find the first readable varbind after roid, scanning forward through the keepers.
+///
+/// Keepers with nothing readable past roid are skipped, as are instances for which
+/// in_view is false, so walks step over excluded subtrees. If nothing follows,
+/// the result is roid with an endOfMibView value. Other errors are returned.
+pub(crate) fn next_varbind(
+    roid: &ObjectIdentifier,
+    oid_map: &mut OidMap,
+    in_view: impl Fn(&ObjectIdentifier) -> bool,
+) -> Result<VarBind, OidErr> {
+    // Errors that just mean "nothing readable here, try further on"
+    fn skip(err: &OidErr) -> bool {
+        matches!(
+            err,
+            OidErr::OutOfRange | OidErr::NoAccess | OidErr::NoSuchInstance | OidErr::NoSuchName
+        )
+    }
+    let visible =
+        |bind: &VarBind| matches!(bind.value, VarBindValue::Value(_)) && in_view(&bind.name);
+    // Walk a table keeper from cursor until a visible instance turns up.
+    let next_in_table = |okeep: &mut Box<dyn OidKeeper>,
+                         mut cursor: ObjectIdentifier|
+     -> Result<Option<VarBind>, OidErr> {
+        loop {
+            match okeep.get_next(cursor.clone()) {
+                Ok(bind) if visible(&bind) => return Ok(Some(bind)),
+                Ok(bind) if matches!(bind.value, VarBindValue::Value(_)) && bind.name > cursor => {
+                    cursor = bind.name
+                }
+                Ok(_) => return Ok(None),
+                Err(err) if skip(&err) => return Ok(None),
+                Err(err) => return Err(err),
+            }
+        }
+    };
+
+    let mut which = match oid_map.search(roid) {
+        Ok(which) => {
+            debug!("hit case {which}");
+            // Tables may have more rows or columns after roid, scalars never do.
+            let okeep = oid_map.idx(which);
+            if !okeep.is_scalar(roid.clone()) {
+                if let Some(bind) = next_in_table(okeep, roid.clone())? {
+                    return Ok(bind);
+                }
+            }
+            which + 1
+        }
+        Err(insert_point) => insert_point,
+    };
+    while which < oid_map.len() {
+        let oid = oid_map.oid(which).clone();
+        let okeep = oid_map.idx(which);
+        if okeep.is_scalar(oid.clone()) {
+            match okeep.get(oid.clone()) {
+                Ok(value) => {
+                    let bind = VarBind { name: oid, value };
+                    if visible(&bind) {
+                        return Ok(bind);
+                    }
+                }
+                Err(err) if skip(&err) => (),
+                Err(err) => return Err(err),
+            }
+        } else if let Some(bind) = next_in_table(okeep, oid)? {
+            return Ok(bind);
+        }
+        which += 1;
+    }
+    debug!("End of oids");
+    Ok(VarBind {
+        name: roid.clone(),
+        value: VarBindValue::EndOfMibView,
+    })
+}
+
 /// Main Agent object.
 pub struct Agent {
-    socket: UdpSocket,
+    sockets: Vec<transport::UdpTransport>,
+    dtls: Vec<tsm::DtlsTransport>,
+    tcp_listeners: Vec<(TransportDomain, TcpListener)>,
+    connections: Vec<transport::TcpConnection>,
+    tcp_max_connections: usize,
+    tcp_idle_timeout: Duration,
+    request_source: RequestSource,
+    tls: Option<tsm::TlsConfig>,
+    agentx: Option<agentx::master::Master>,
+    proxy: Option<proxy::Proxy>,
+    /// Requests the proxy is forwarding, by the request-id it sent them with
+    proxied: HashMap<i32, Proxied>,
+    /// Set by serve_ready while it processes a message
+    reply_path: Option<ReplyPath>,
+    notifier: Option<notifier::Notifier>,
+    notification_sender: notifier::NotificationSender,
+    warm_start: bool,
+    started: bool,
+    auth_trap_interval: Duration,
+    last_auth_trap: Option<Instant>,
     engine_id: OctetString,
     pub start_time: Instant,
     boots: isize,
     pub in_pkts: u64,
-    pub unknown_users: u32,
-    pub wrong_digests: u32,
-    pub not_in_time_window: u32,
-    pub unknown_engine_ids: u32,
+    pub unknown_users: StatsCounter,
+    pub wrong_digests: StatsCounter,
+    pub not_in_time_window: StatsCounter,
+    pub unknown_engine_ids: StatsCounter,
     pub decode_error_cnt: u32,
-    pub decryption_errors: u32,
+    pub decryption_errors: StatsCounter,
+    pub unsupported_sec_levels: StatsCounter,
+    pub unknown_contexts: StatsCounter,
+    pub unknown_security_models: StatsCounter,
+    pub tsm_invalid_caches: StatsCounter,
+    contexts: Contexts,
+    vacm: Option<Vacm>,
 }
 
 impl Agent {
@@ -77,39 +500,283 @@ impl Agent {
     /// addr_str is the address to listen on - often "0.0.0.0:161" can be a good choice
     /// But systems with multiple interfaces (like a firewall, router or crypto) might only listen
     /// on an internal address.
+    ///
+    /// Use new and listen to serve more than one address.
     pub fn build(eid: OctetString, addr_str: &str) -> Self {
-        let sock = UdpSocket::bind(addr_str).expect("Couldn't bind to address");
+        let mut agent = Agent::new(eid);
+        agent.listen(addr_str).expect("Couldn't bind to address");
+        agent
+    }
 
+    /// Constructor for an Agent without a socket.
+    ///
+    /// Messages from some other transport, a test harness or a capture can be fed
+    /// to process_message. Call listen before loop_forever or run.
+    pub fn new(eid: OctetString) -> Self {
         Agent {
-            socket: sock,
+            sockets: vec![],
+            dtls: vec![],
+            tcp_listeners: vec![],
+            connections: vec![],
+            tcp_max_connections: TCP_MAX_CONNECTIONS,
+            tcp_idle_timeout: TCP_IDLE_TIMEOUT,
+            request_source: RequestSource::default(),
+            tls: None,
+            agentx: None,
+            proxy: None,
+            proxied: HashMap::new(),
+            reply_path: None,
+            notifier: None,
+            notification_sender: notifier::NotificationSender::default(),
+            warm_start: false,
+            started: false,
+            auth_trap_interval: AUTH_TRAP_INTERVAL,
+            last_auth_trap: None,
             engine_id: eid,
             start_time: Instant::now(),
             boots: get_increment_boot_cnt(),
             in_pkts: 0u64,
-            unknown_users: 0u32,
-            wrong_digests: 0u32,
-            not_in_time_window: 0u32,
-            unknown_engine_ids: 0u32,
+            unknown_users: StatsCounter::default(),
+            wrong_digests: StatsCounter::default(),
+            not_in_time_window: StatsCounter::default(),
+            unknown_engine_ids: StatsCounter::default(),
             decode_error_cnt: 0u32,
-            decryption_errors: 0u32,
+            decryption_errors: StatsCounter::default(),
+            unsupported_sec_levels: StatsCounter::default(),
+            unknown_contexts: StatsCounter::default(),
+            unknown_security_models: StatsCounter::default(),
+            tsm_invalid_caches: StatsCounter::default(),
+            contexts: Contexts::new(),
+            vacm: None,
+        }
+    }
+
+    /// Add a socket bound to addr_str, as written on a Listen line.
+    ///
+    /// UDP responses always leave through the socket the request arrived on, and with the
+    /// "/pktinfo" suffix, from the address the request was sent to. A "tcp:" prefix listens
+    /// for SNMP over TCP instead. "tls:" and "dtls:" need the tsm feature, and set_tls first.
+    pub fn listen(&mut self, addr_str: &str) -> io::Result<()> {
+        match transport::split_domain(addr_str) {
+            (TransportDomain::Udp, addr_str) => {
+                self.sockets.push(transport::UdpTransport::bind(addr_str)?)
+            }
+            (TransportDomain::Dtls, addr_str) => {
+                self.check_tls()?;
+                self.dtls.push(tsm::DtlsTransport::bind(addr_str)?)
+            }
+            (domain, addr_str) => {
+                if domain == TransportDomain::Tls {
+                    self.check_tls()?;
+                }
+                self.tcp_listeners
+                    .push((domain, transport::bind_tcp(addr_str)?))
+            }
+        }
+        Ok(())
+    }
+
+    /// Error unless TLS and DTLS can be served.
+    fn check_tls(&self) -> io::Result<()> {
+        if !cfg!(feature = "tsm") {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TLS and DTLS need the tsm feature",
+            ))
+        } else if self.tls.is_none() {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "set_tls before listening on TLS or DTLS",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Certificates and certificate map for the Transport Security Model over TLS and DTLS.
+    #[cfg(feature = "tsm")]
+    pub fn set_tls(&mut self, config: tsm::TlsConfig) {
+        self.tls = Some(config);
+    }
+
+    /// Act as an AgentX master, accepting subagents on the Unix domain socket at path.
+    ///
+    /// Subtrees registered by subagents are served from the OidMap of the default context,
+    /// alongside the agent's own keepers. See the agentx::master module for what is supported.
+    pub fn listen_agentx(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.agentx = Some(agentx::master::Master::bind(path)?);
+        Ok(())
+    }
+
+    /// Addresses the agent is listening on, UDP, DTLS, then TCP and TLS, useful after binding
+    /// to port 0.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        let udp = self.sockets.iter().map(|socket| socket.local_addr());
+        let dtls = self.dtls.iter().map(|socket| socket.local_addr());
+        let tcp = self
+            .tcp_listeners
+            .iter()
+            .map(|(_, listener)| listener.local_addr());
+        udp.chain(dtls)
+            .chain(tcp)
+            .filter_map(|addr| addr.ok())
+            .collect()
+    }
+
+    /// Limit the number of open TCP connections, and close those idle for longer than idle_timeout.
+    ///
+    /// Connections over the limit are closed as soon as they are accepted. TLS connections count
+    /// towards the same limit, and each DTLS socket has as many sessions again.
+    pub fn set_tcp_limits(&mut self, max_connections: usize, idle_timeout: Duration) {
+        self.tcp_max_connections = max_connections;
+        self.tcp_idle_timeout = idle_timeout;
+    }
+
+    /// Handle on the transport and address of the request being processed.
+    pub fn request_source(&self) -> RequestSource {
+        self.request_source.clone()
+    }
+
+    /// Register the OidMap served for a non-empty context name.
+    ///
+    /// The default context is the OidMap passed to loop_forever. Requests for a context
+    /// that has not been registered get a snmpUnknownContexts Report.
+    pub fn add_context(&mut self, name: &[u8], oid_map: OidMap) {
+        self.contexts.insert(name, oid_map);
+    }
+
+    /// Forward requests for the contexts in the proxy's entries to other agents.
+    ///
+    /// Contexts served here, with add_context or the default, are only forwarded when the
+    /// request names another contextEngineID. serve_ready goes on with other requests while
+    /// one is forwarded, and answers it when the Response arrives; process_message waits for
+    /// it. See the proxy module for what is supported.
+    pub fn set_proxy(&mut self, proxy: proxy::Proxy) {
+        self.proxy = Some(proxy);
+    }
+
+    /// Take access decisions from the VACM tables, rather than the user's group.
+    pub fn set_vacm(&mut self, vacm: Vacm) {
+        self.vacm = Some(vacm);
+    }
+
+    /// Create a notifier thread, with the agent's engine ID, boots and time, if there is none yet.
+    ///
+    /// Give it destinations to send anything.
+    pub fn start_notifier(&mut self) -> &notifier::Notifier {
+        let (engine_id, boots) = (self.engine_id.clone(), self.boots.try_into().unwrap_or(0));
+        let start_time = self.start_time;
+        let notifier = self
+            .notifier
+            .get_or_insert_with(|| notifier::Notifier::new(engine_id, boots, start_time));
+        notifier.connect(&self.notification_sender);
+        notifier
+    }
+
+    /// The notifier, for sending traps and informs, if start_notifier has been called.
+    pub fn notifier(&self) -> Option<&notifier::Notifier> {
+        self.notifier.as_ref()
+    }
+
+    /// Handle for raising notifications, for handlers and application threads.
+    ///
+    /// Clones can be taken before start_notifier is called, but notifications are only sent after.
+    pub fn notification_sender(&self) -> notifier::NotificationSender {
+        self.notification_sender.clone()
+    }
+
+    /// Announce the start with warmStart rather than coldStart, RFC 3418.
+    ///
+    /// For agents restarted without their configuration changing.
+    pub fn set_warm_start(&mut self, warm_start: bool) {
+        self.warm_start = warm_start;
+    }
+
+    /// Send at most one authenticationFailure notification per interval.
+    ///
+    /// Failures in between are only counted, so guessing passwords cannot flood the receivers.
+    pub fn set_auth_trap_interval(&mut self, interval: Duration) {
+        self.auth_trap_interval = interval;
+    }
+
+    /// Send coldStart, or warmStart if set_warm_start was called, the first time this is called.
+    ///
+    /// loop_forever, serve_ready and run do this, so call it only when serving requests some other
+    /// way, such as through an AgentX master. Nothing is sent without a notifier.
+    pub fn send_start_trap(&mut self) {
+        if std::mem::replace(&mut self.started, true) {
+            return;
+        }
+        let arc: &'static [u32] = if self.warm_start {
+            &notifier::WARM_START_ARC
+        } else {
+            &notifier::COLD_START_ARC
+        };
+        if let Some(notifier) = &self.notifier {
+            notifier.notify(ObjectIdentifier::new(arc).unwrap(), vec![]);
         }
     }
 
-    /// Create a notifier thread.
-    pub fn start_notifier(&mut self, sink: &str) -> () {
-        let notifier = notifier::Notifier::new(sink, self.engine_id.clone(), self.start_time);
-        notifier.sender.send(137).expect("Send failure");
-        ()
+    /// Send authenticationFailure, RFC 3418, for a message that failed authentication.
+    ///
+    /// Only while snmpEnableAuthenTraps.0 in oid_map is enabled(1), and no more often than
+    /// the interval given to set_auth_trap_interval.
+    fn auth_failure_trap(&mut self, oid_map: &mut OidMap) {
+        let Some(notifier) = &self.notifier else {
+            return;
+        };
+        let enable = ObjectIdentifier::new(&ENABLE_AUTHEN_TRAPS_ARC).unwrap();
+        let enabled = match oid_map.search(&enable) {
+            Ok(which) => matches!(
+                oid_map.idx(which).get(enable),
+                Ok(VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::Integer(i))))
+                    if i == Integer::from(1)
+            ),
+            Err(_) => false,
+        };
+        if !enabled {
+            return;
+        }
+        if self
+            .last_auth_trap
+            .is_some_and(|last| last.elapsed() < self.auth_trap_interval)
+        {
+            debug!("authenticationFailure not sent, one was sent recently");
+            return;
+        }
+        self.last_auth_trap = Some(Instant::now());
+        let arc = ObjectIdentifier::new(&notifier::AUTHENTICATION_FAILURE_ARC).unwrap();
+        notifier.notify(arc, vec![]);
     }
 
     /// Internal method for supporting engine ID discovery by managers
     fn id_response(&self, request_id: i32, message_id: Integer) -> Message {
-        let vb: Vec<VarBind> = vec![VarBind {
-            name: ObjectIdentifier::new_unchecked(vec![1, 3, 6, 1, 6, 3, 15, 1, 1, 4].into()),
-            value: VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(
-                self.unknown_engine_ids,
-            )))),
-        }];
+        self.report(
+            request_id,
+            message_id,
+            UsmFailure::UnknownEngineId,
+            self.unknown_engine_ids.get(),
+            ZB,
+            None,
+        )
+    }
+
+    /// Internal method that builds Report PDUs for USM failures, see RFC 3414 section 3.2
+    ///
+    /// The Report carries a single varbind, the usmStats counter matching the failure.
+    /// If auth_user is given, the Report is authenticated with that user's key (authNoPriv),
+    /// which is needed for notInTimeWindow so the manager can trust our boots and time.
+    /// Otherwise it is sent noAuthNoPriv.
+    fn report(
+        &self,
+        request_id: i32,
+        message_id: Integer,
+        failure: UsmFailure,
+        count: u32,
+        user_name: OctetString,
+        auth_user: Option<&usm::User>,
+    ) -> Message {
+        let vb: Vec<VarBind> = vec![counter_varbind(failure.arc(), count)];
 
         let pdu = Pdu {
             request_id,
@@ -118,10 +785,15 @@ impl Agent {
             variable_bindings: vb,
         };
         let report: Report = Report(pdu);
+        let flags = if auth_user.is_some() {
+            OctetString::from_static(b"\x01")
+        } else {
+            OctetString::from_static(b"\x00")
+        };
         let head = HeaderData {
-            flags: OctetString::from_static(b"\x00"),
+            flags,
             message_id,
-            max_size: Integer::from(65000),
+            max_size: Integer::from(MAX_MSG_SIZE),
             security_model: Integer::from(3),
         };
         let scpd: ScopedPdu = ScopedPdu {
@@ -140,7 +812,7 @@ impl Agent {
             authoritative_engine_boots: Integer::from(self.boots),
             authoritative_engine_id: self.engine_id.clone(),
             authoritative_engine_time: Integer::from(run_time),
-            user_name: ZB,
+            user_name,
             authentication_parameters: ZB,
             privacy_parameters: ZB,
         };
@@ -151,14 +823,49 @@ impl Agent {
             security_parameters: ZB,
         };
         _ = message.encode_security_parameters(rasn::Codec::Ber, &usm);
+        if let Some(user) = auth_user {
+            usm::set_auth(&mut message, user);
+        }
         message
     }
 
-    /// Internal method that builds response packets.
+    /// Count a USM failure, and return the matching Report if the manager asked for one.
+    ///
+    /// The request_id is zero when the PDU could not be read, as RFC 3412 allows.
+    #[allow(clippy::too_many_arguments)]
+    fn usm_failure(
+        &self,
+        src: SocketAddr,
+        flags: u8,
+        request_id: i32,
+        message_id: Integer,
+        failure: UsmFailure,
+        user_name: OctetString,
+        auth_user: Option<&usm::User>,
+    ) -> Option<Vec<u8>> {
+        let count = match failure {
+            UsmFailure::UnknownEngineId => self.unknown_engine_ids.incr(),
+            UsmFailure::UnknownUserName => self.unknown_users.incr(),
+            UsmFailure::WrongDigest => self.wrong_digests.incr(),
+            UsmFailure::NotInTimeWindow => self.not_in_time_window.incr(),
+            UsmFailure::DecryptionError => self.decryption_errors.incr(),
+            UsmFailure::UnsupportedSecLevel => self.unsupported_sec_levels.incr(),
+        };
+        warn!("USM failure {failure:?} from {src}");
+        if flags & REPORTABLE_FLAG == 0 {
+            debug!("Report not requested, dropping");
+            return None;
+        }
+        let report = self.report(request_id, message_id, failure, count, user_name, auth_user);
+        encode_message(&report)
+    }
+
+    /// Internal method that builds response packets, including Reports sent at the request's security level.
     fn prepare_back(
         &self,
         message_id: Integer,
-        resp: Response,
+        context_name: OctetString,
+        data: Pdus,
         user: &usm::User,
         usp: USMSecurityParameters,
         encrypted: bool,
@@ -167,13 +874,13 @@ impl Agent {
         let head = HeaderData {
             flags: OctetString::from_static(b"\x00"),
             message_id,
-            max_size: Integer::from(65000),
+            max_size: Integer::from(MAX_MSG_SIZE),
             security_model: Integer::from(3),
         };
         let scpd: ScopedPdu = ScopedPdu {
             engine_id: self.engine_id.clone(),
-            name: ZB,
-            data: Pdus::Response(resp),
+            name: context_name,
+            data,
         };
         let user_name = OctetString::from_slice(&user.name);
 
@@ -196,7 +903,8 @@ impl Agent {
             usm.privacy_parameters = usp.privacy_parameters.clone();
             let key = &user.priv_key;
             let enc_octs = rasn::ber::encode(&spd).unwrap();
-            let value: Vec<u8> = privacy::encrypt(&mut enc_octs.to_vec(), usp, key);
+            // The IV is from the boots and time we send, not those in the request
+            let value: Vec<u8> = privacy::encrypt(&mut enc_octs.to_vec(), usm.clone(), key);
             spd = ScopedPduData::EncryptedPdu(OctetString::from(value));
         }
         let mut output: Message = Message {
@@ -210,6 +918,34 @@ impl Agent {
         output
     }
 
+    /// Room left for varbinds in a response, given the largest message the manager accepts.
+    ///
+    /// Measured by building an empty response with the same security parameters.
+    fn varbind_budget(
+        &self,
+        max_size: usize,
+        user: &usm::User,
+        usp: &USMSecurityParameters,
+        flags: u8,
+    ) -> usize {
+        let mut empty = self.prepare_back(
+            Integer::from(i32::MAX),
+            ZB,
+            empty_response(),
+            user,
+            usp.clone(),
+            flags & 2 == 2,
+        );
+        if flags & 1 == 1 {
+            usm::set_auth(&mut empty, user);
+        }
+        max_size.saturating_sub(encoded_len(&empty) + LENGTH_SLACK)
+    }
+
+    /// Get processing, RFC 3416 section 4.2.1
+    ///
+    /// Objects and instances that are missing, or outside the read view, are reported
+    /// as exceptions in the varbind value, leaving error-status at noError.
     fn get(
         &self,
         oid_map: &mut OidMap,
@@ -218,198 +954,45 @@ impl Agent {
         perm: &Perm,
         flags: u8,
     ) -> (u32, u32, i32) {
-        let mut error_status = Pdu::ERROR_STATUS_NO_ERROR;
-        let mut error_index = 0;
         let request_id = r.0.request_id;
-        let mut vb_cnt = 0;
-        for vbind in r.0.variable_bindings {
-            let roid = vbind.name.clone();
-            if !perm.check(flags, false, &roid) {
-                error_status = Pdu::ERROR_STATUS_NO_ACCESS;
-                error_index = vb_cnt;
-                return (error_status, error_index, request_id);
+        for (vb_cnt, vbind) in (1u32..).zip(r.0.variable_bindings) {
+            let roid = vbind.name;
+            if !perm.check_access(flags, false) {
+                return (
+                    OidErr::AuthorizationError.error_status(),
+                    vb_cnt,
+                    request_id,
+                );
             }
-            let opt_get: Result<usize, usize> = oid_map.search(&roid);
-            match opt_get {
-                Err(insert_point) => {
-                    debug!("Get miss case {insert_point}");
-                    error_status = Pdu::ERROR_STATUS_NO_SUCH_NAME;
-                    vb.push(VarBind {
-                        name: roid,
-                        value: VarBindValue::NoSuchObject,
-                    });
-                    error_index = vb_cnt;
-                    break;
-                }
-                Ok(which) => {
-                    vb_cnt += 1;
-                    let okeep = &mut oid_map.idx(which);
-                    let value_res = okeep.get(roid.clone());
-                    if let Ok(value) = value_res {
-                        vb.push(VarBind {
-                            name: roid.clone(),
-                            value,
-                        });
-                    } else {
-                        error_status = Pdu::ERROR_STATUS_NO_SUCH_NAME;
-                        vb.push(VarBind {
-                            name: roid,
-                            value: VarBindValue::NoSuchInstance,
-                        });
-                        error_index = vb_cnt;
-                        break;
-                    }
+            if !perm.in_view(false, &roid) {
+                vb.push(VarBind {
+                    name: roid,
+                    value: VarBindValue::NoSuchObject,
+                });
+                continue;
+            }
+            match get_value(&roid, oid_map) {
+                Ok(value) => vb.push(VarBind { name: roid, value }),
+                Err(err) => {
+                    warn!("Get failed {err:?}");
+                    return (err.read_error_status(), vb_cnt, request_id);
                 }
             }
         }
-        (error_status, error_index, request_id)
+        (Pdu::ERROR_STATUS_NO_ERROR, 0, request_id)
     }
 
+    /// Find the first readable varbind after roid, in the read view, see next_varbind.
     fn do_next(
         &self,
-        roid: ObjectIdentifier,
+        roid: &ObjectIdentifier,
         oid_map: &mut OidMap,
-        vb: &mut Vec<VarBind>,
-        error_status: &mut u32,
-        error_index: &mut u32,
-        vb_cnt: u32,
-    ) {
-        let opt_get: Result<usize, usize> = oid_map.search(&roid);
-        match opt_get {
-            Err(insert_point) => {
-                debug!("Get next miss case {insert_point}");
-                if insert_point == 0 {
-                    // Off the front of our range - give the first thing
-                    let oid1 = oid_map.oid(0).clone();
-                    let okeep = &mut oid_map.idx(0);
-                    if okeep.is_scalar(oid1.clone()) {
-                        let value_res = okeep.get(oid1.clone());
-                        match value_res {
-                            Ok(value) => vb.push(VarBind {
-                                name: oid1.clone(),
-                                value,
-                            }),
-                            Err(_err) => {
-                                // FIXME map errors
-                                *error_index = vb_cnt;
-                                *error_status = Pdu::ERROR_STATUS_GEN_ERR;
-                                vb.push(VarBind {
-                                    name: oid1.clone(),
-                                    value: VarBindValue::Unspecified,
-                                });
-                                return;
-                            }
-                        };
-                    } else {
-                        match okeep.get_next(oid1.clone()) {
-                            Ok(bind) => vb.push(bind),
-                            Err(_) => vb.push(VarBind {
-                                name: oid1.clone(),
-                                value: VarBindValue::EndOfMibView,
-                            }),
-                        }
-                    }
-                } else if insert_point >= oid_map.len() {
-                    debug!("miss case {insert_point} >= oid_map.len()");
-                    vb.push(VarBind {
-                        name: roid.clone(),
-                        value: VarBindValue::EndOfMibView,
-                    });
-                } else {
-                    warn!("Insert point in map, but is a miss, should never happen.");
-                    *error_index = vb_cnt;
-                    *error_status = Pdu::ERROR_STATUS_GEN_ERR;
-                    vb.push(VarBind {
-                        name: roid,
-                        value: VarBindValue::Unspecified,
-                    });
-                }
-            }
-            Ok(which) => {
-                debug!("hit case {which}");
-                if which == oid_map.len() - 1 && oid_map.idx(which).is_scalar(roid.clone()) {
-                    debug!("End of oids, ");
-                    // This should error and generate a report?
-                    vb.push(VarBind {
-                        name: roid.clone(),
-                        value: VarBindValue::EndOfMibView,
-                    });
-                } else if oid_map.idx(which).is_scalar(roid.clone()) {
-                    let next_oid: ObjectIdentifier = oid_map.oid(which + 1).clone();
-                    let okeep = &mut oid_map.idx(which + 1);
-                    if okeep.is_scalar(next_oid.clone()) {
-                        let value_res = okeep.get(next_oid.clone());
-                        match value_res {
-                            Err(_) => vb.push(VarBind {
-                                name: next_oid.clone(),
-                                value: VarBindValue::Unspecified,
-                            }),
-                            Ok(value) => vb.push(VarBind {
-                                name: next_oid.clone(),
-                                value,
-                            }),
-                        }
-                    } else {
-                        // This is table case!
-                        let mut gn_res = okeep.get_next(roid.clone());
-                        let mut which2 = which;
-                        while gn_res.is_err() {
-                            which2 += 1;
-                            let next_oid = oid_map.oid(which2).clone();
-                            debug!("in gn_res loop, which2 {which2}, {next_oid:?}");
-                            let okeep = &mut oid_map.idx(which2);
-                            gn_res = okeep.get_next(next_oid.clone());
-                        }
-                        vb.push(gn_res.unwrap());
-                    };
-                } else {
-                    // Last oid was table.
-                    let okeep = &mut oid_map.idx(which);
-                    debug!("Last item was table. Trying okeep ");
-                    let gn_res = okeep.get_next(roid.clone());
-                    debug!("gn_res {gn_res:?}");
-                    match gn_res {
-                        Ok(nvb) => vb.push(nvb),
-                        //FIXME More cases here - permissions, general error etc, not just end
-                        Err(err) => {
-                            if err == OidErr::OutOfRange && which < oid_map.len() - 1 {
-                                let next_oid: ObjectIdentifier = oid_map.oid(which + 1).clone();
-
-                                let okeep = &mut oid_map.idx(which + 1);
-                                if okeep.is_scalar(next_oid.clone()) {
-                                    let value_res = okeep.get(next_oid.clone());
-                                    match value_res {
-                                        Err(_) => vb.push(VarBind {
-                                            name: next_oid.clone(),
-                                            value: VarBindValue::Unspecified,
-                                        }),
-                                        Ok(value) => vb.push(VarBind {
-                                            name: next_oid.clone(),
-                                            value,
-                                        }),
-                                    }
-                                } else {
-                                    let n_res = okeep.get_next(next_oid.clone());
-                                    if let Ok(var_b) = n_res {
-                                        vb.push(var_b);
-                                    } else {
-                                        panic!("Fault in next_oid");
-                                    }
-                                };
-                            } else {
-                                vb.push(VarBind {
-                                    name: roid.clone(),
-                                    value: VarBindValue::EndOfMibView,
-                                });
-                            }
-                        }
-                    }
-                }
-            }
-        }
-        debug!("do_next returning {vb:?}");
+        perm: &Perm,
+    ) -> Result<VarBind, OidErr> {
+        next_varbind(roid, oid_map, |name| perm.in_view(false, name))
     }
 
+    /// GetNext processing, RFC 3416 section 4.2.2
     fn getnext(
         &self,
         oid_map: &mut OidMap,
@@ -418,35 +1001,35 @@ impl Agent {
         perm: &Perm,
         flags: u8,
     ) -> (u32, u32, i32) {
-        let mut error_status = Pdu::ERROR_STATUS_NO_ERROR;
-        let mut error_index = 0u32;
         let request_id = r.0.request_id;
-        for (vb_cnt, vbind) in r.0.variable_bindings.iter().enumerate() {
-            let roid = vbind.name.clone();
-            if !perm.check(flags, false, &roid) {
-                error_status = Pdu::ERROR_STATUS_NO_ACCESS;
-                error_index = vb_cnt as u32;
-                return (error_status, error_index, request_id);
+        for (vb_cnt, vbind) in (1u32..).zip(r.0.variable_bindings) {
+            let roid = vbind.name;
+            if !perm.check_access(flags, false) {
+                return (
+                    OidErr::AuthorizationError.error_status(),
+                    vb_cnt,
+                    request_id,
+                );
             }
-            self.do_next(
-                roid,
-                oid_map,
-                vb,
-                &mut error_status,
-                &mut error_index,
-                vb_cnt.try_into().unwrap(),
-            );
-            if error_status != Pdu::ERROR_STATUS_NO_ERROR {
-                break;
+            match self.do_next(&roid, oid_map, perm) {
+                Ok(bind) => vb.push(bind),
+                Err(err) => {
+                    warn!("GetNext failed {err:?}");
+                    return (err.read_error_status(), vb_cnt, request_id);
+                }
             }
         }
-        (error_status, error_index, request_id)
+        (Pdu::ERROR_STATUS_NO_ERROR, 0, request_id)
     }
 
     /// Make changes!
     ///
-    /// Current implementation is not transactional, and does not return full set of errors.
+    /// Two passes: every varbind is set, then tested by its keeper, before any keeper commits.
+    /// Keepers commit in OID order. If one fails, those already committed are undone,
+    /// giving commitFailed, or undoFailed if an undo fails too. Either way, every keeper
+    /// involved is then told the Set is over by cleanup.
     ///
+    /// Handler errors are mapped to error-status by OidErr::error_status, with a 1-based error_index.
     fn set(
         &self,
         oid_map: &mut OidMap,
@@ -455,13 +1038,11 @@ impl Agent {
         perm: &Perm,
         flags: u8,
     ) -> (u32, u32, i32) {
-        // FIXME need to do two passes - validation, error return if need be and then actually apply the changes.
-        //let mut keeps = HashSet::<&mut Box<dyn OidKeeper>>::new();
-        let mut keeps = HashSet::<usize>::new();
+        // Keepers in OID order, so commits and undos happen in a predictable order.
+        let mut keeps = BTreeSet::<usize>::new();
         let mut error_status = Pdu::ERROR_STATUS_NO_ERROR;
         let mut error_index = 0;
         let request_id = r.0.request_id;
-        let mut vb_cnt = 0;
         for vbind in &r.0.variable_bindings {
             let roid = vbind.name.clone();
 
@@ -478,86 +1059,103 @@ impl Agent {
             let okeep = oid_map.idx(*indx);
             let _ = okeep.begin_transaction();
         }
-        for vbind in r.0.variable_bindings {
-            let roid = vbind.name.clone();
+        // Keeper for each varbind, in PDU order
+        let mut staged: Vec<usize> = vec![];
+        for (vb_cnt, vbind) in (1u32..).zip(r.0.variable_bindings) {
+            let roid = vbind.name;
 
-            if !perm.check(flags, true, &roid) {
-                error_status = Pdu::ERROR_STATUS_NO_ACCESS;
+            if !perm.check_access(flags, true) {
+                error_status = OidErr::AuthorizationError.error_status();
                 error_index = vb_cnt;
-                break; //return (error_status, error_index, request_id);
+                break;
             }
-            let opt_set: Result<usize, usize> = oid_map.search(&roid);
-            match opt_set {
+            if !perm.in_view(true, &roid) {
+                error_status = OidErr::NoAccess.error_status();
+                error_index = vb_cnt;
+                break;
+            }
+            let set_result = match oid_map.search(&roid) {
                 Err(insert_point) => {
                     debug!("Set miss case {insert_point}");
-                    let okeep = &mut oid_map.idx(insert_point - 1);
-                    if okeep.is_scalar(roid.clone()) {
-                        debug!("Scalar set miss"); // This should error and generate a report?
-                        error_status = Pdu::ERROR_STATUS_NO_SUCH_NAME;
-                        error_index = vb_cnt;
-                        vb.push(VarBind {
-                            name: roid,
-                            value: VarBindValue::NoSuchObject,
-                        });
-                        break;
-                    } else {
-                        debug!("Table set ");
-                        let set_res = okeep.set(roid.clone(), vbind.value);
-                        debug!("Table set {set_res:?}");
-                        match set_res {
-                            Ok(res) => vb.push(VarBind {
-                                name: roid.clone(),
-                                value: res,
-                            }),
-                            Err(_) => {
-                                error_status = Pdu::ERROR_STATUS_NO_SUCH_NAME;
-                                vb.push(VarBind {
-                                    name: roid,
-                                    value: VarBindValue::NoSuchObject,
-                                });
-                            }
-                        }
-                    }
+                    Err(OidErr::NoCreation)
                 }
                 Ok(which) => {
-                    vb_cnt += 1;
-                    let okeep = &mut oid_map.idx(which);
-                    let set_result = (**okeep).set(roid.clone(), vbind.value.clone());
-                    if let Err(OidErr::WrongType) = set_result {
-                        error_status = Pdu::ERROR_STATUS_WRONG_TYPE;
-                        vb.push(VarBind {
-                            name: roid.clone(),
-                            value: vbind.value,
-                        });
-                    } else {
-                        let svalue = set_result.unwrap();
-                        // Need to catch size, data type etc
-                        vb.push(VarBind {
-                            name: roid.clone(),
-                            value: svalue,
-                        });
-                    }
+                    staged.push(which);
+                    oid_map.idx(which).set(roid.clone(), vbind.value)
+                }
+            };
+            debug!("Set {set_result:?}");
+            match set_result {
+                Ok(value) => vb.push(VarBind { name: roid, value }),
+                Err(err) => {
+                    error_status = err.error_status();
+                    error_index = vb_cnt;
+                    break;
                 }
             }
         }
-        for indx in &keeps {
-            let keep = oid_map.idx(*indx);
-            if error_status == Pdu::ERROR_STATUS_NO_ERROR {
-                let cres = keep.commit();
-                if cres.is_err() {
-                    error_status = Pdu::ERROR_STATUS_COMMIT_FAILED;
+        // Second pass, now each keeper can see everything it has been asked to change.
+        if error_status == Pdu::ERROR_STATUS_NO_ERROR {
+            for ((vb_cnt, which), vbind) in (1u32..).zip(&staged).zip(vb.iter()) {
+                let test_result = oid_map.idx(*which).test(vbind.name.clone(), &vbind.value);
+                if let Err(err) = test_result {
+                    debug!("Test failed {err:?}");
+                    error_status = err.error_status();
+                    error_index = vb_cnt;
                     break;
                 }
-            } else {
-                let rres = keep.rollback();
-                if rres.is_err() {
+            }
+        }
+        if error_status != Pdu::ERROR_STATUS_NO_ERROR {
+            for indx in &keeps {
+                let okeep = oid_map.idx(*indx);
+                if let Err(err) = okeep.rollback() {
+                    warn!("Rollback failed {err:?}");
+                }
+                okeep.cleanup();
+            }
+            return (error_status, error_index, request_id);
+        }
+        let order: Vec<usize> = keeps.into_iter().collect();
+        for (n, indx) in order.iter().enumerate() {
+            let cres = oid_map.idx(*indx).commit();
+            if let Err(err) = cres {
+                warn!("Commit failed {err:?}, undoing");
+                // Roll back the failed keeper too, in case it changed anything before failing
+                for later in &order[n..] {
+                    let _ = oid_map.idx(*later).rollback();
+                }
+                let mut undo_ok = true;
+                for earlier in order[..n].iter().rev() {
+                    if let Err(err) = oid_map.idx(*earlier).undo() {
+                        warn!("Undo failed {err:?}");
+                        undo_ok = false;
+                    }
+                }
+                if undo_ok {
+                    // Report the first varbind handled by the failing keeper
+                    let pos = staged.iter().position(|which| which == indx).unwrap_or(0);
+                    error_status = Pdu::ERROR_STATUS_COMMIT_FAILED;
+                    error_index = (pos + 1).try_into().unwrap_or(0);
+                } else {
                     error_status = Pdu::ERROR_STATUS_UNDO_FAILED;
+                    error_index = 0;
                 }
+                break;
             }
         }
+        for indx in &order {
+            oid_map.idx(*indx).cleanup();
+        }
         (error_status, error_index, request_id)
     }
 
+    /// GetBulk processing, RFC 3416 section 4.2.3
+    ///
+    /// budget is the room left for the varbind list in the response. Repetitions stop
+    /// being added once the list would outgrow it, as RFC 3416 section 4.2.3 allows.
+    /// They also stop once a whole repetition is endOfMibView.
+    #[allow(clippy::too_many_arguments)]
     fn bulk(
         &self,
         oid_map: &mut OidMap,
@@ -565,94 +1163,123 @@ impl Agent {
         vb: &mut Vec<VarBind>,
         perm: &Perm,
         flags: u8,
+        budget: usize,
     ) -> (u32, u32, i32) {
-        let mut error_status = Pdu::ERROR_STATUS_NO_ERROR;
-        let mut error_index = 0;
-        let mut vb_cnt = 0u32;
         let request_id = r.0.request_id;
-        let non_repeaters: usize = r.0.non_repeaters.try_into().unwrap();
+        let non_repeaters: usize = r.0.non_repeaters.try_into().unwrap_or(usize::MAX);
         let max_repeats = r.0.max_repetitions;
         let mut rep_oids: Vec<ObjectIdentifier> = vec![];
-        for (n, vbind) in r.0.variable_bindings.iter().enumerate() {
-            if n < non_repeaters {
-                let roid = vbind.name.clone();
-                if !perm.check(flags, false, &roid) {
-                    error_status = Pdu::ERROR_STATUS_NO_ACCESS;
-                    error_index = vb_cnt;
-                    return (error_status, error_index, request_id);
-                }
-                self.do_next(
-                    roid,
-                    oid_map,
-                    vb,
-                    &mut error_status,
-                    &mut error_index,
+        // Encoded size of the varbinds so far
+        let mut used = 0usize;
+        for (vb_cnt, vbind) in (1u32..).zip(&r.0.variable_bindings) {
+            let roid = &vbind.name;
+            if !perm.check_access(flags, false) {
+                return (
+                    OidErr::AuthorizationError.error_status(),
                     vb_cnt,
+                    request_id,
                 );
-                if error_status != Pdu::ERROR_STATUS_NO_ERROR {
-                    return (error_status, error_index, request_id);
+            }
+            if (vb_cnt as usize) <= non_repeaters {
+                match self.do_next(roid, oid_map, perm) {
+                    Ok(bind) => {
+                        used += encoded_len(&bind);
+                        vb.push(bind);
+                    }
+                    Err(err) => {
+                        warn!("GetBulk failed {err:?}");
+                        return (err.read_error_status(), vb_cnt, request_id);
+                    }
                 }
-                vb_cnt += 1;
             } else {
                 // construct repeater row
                 debug!("Repeat {vbind:?}");
-                rep_oids.push(vbind.name.clone());
+                rep_oids.push(roid.clone());
             }
         }
+        if rep_oids.is_empty() {
+            return (Pdu::ERROR_STATUS_NO_ERROR, 0, request_id);
+        }
         // Now do repeating rows
+        let first_rep = (r.0.variable_bindings.len() - rep_oids.len()) as u32;
         for i in 0..max_repeats {
-            let mut new_oids: Vec<ObjectIdentifier> = vec![];
-            for roid in &rep_oids {
-                if !perm.check(flags, false, roid) {
-                    error_status = Pdu::ERROR_STATUS_NO_ACCESS;
-                    error_index = vb_cnt;
-                    return (error_status, error_index, request_id);
-                }
-                self.do_next(
-                    roid.clone(),
-                    oid_map,
-                    vb,
-                    &mut error_status,
-                    &mut error_index,
-                    vb_cnt,
-                );
-                if error_status != Pdu::ERROR_STATUS_NO_ERROR {
-                    return (error_status, error_index, request_id);
+            let mut all_ended = true;
+            for (vb_cnt, roid) in (first_rep + 1..).zip(rep_oids.iter_mut()) {
+                match self.do_next(roid, oid_map, perm) {
+                    Ok(bind) => {
+                        used += encoded_len(&bind);
+                        if used > budget {
+                            debug!("Response full after {0} varbinds", vb.len());
+                            return (Pdu::ERROR_STATUS_NO_ERROR, 0, request_id);
+                        }
+                        if bind.value != VarBindValue::EndOfMibView {
+                            all_ended = false;
+                        }
+                        *roid = bind.name.clone();
+                        vb.push(bind);
+                    }
+                    Err(err) => {
+                        warn!("GetBulk failed {err:?}");
+                        return (err.read_error_status(), vb_cnt, request_id);
+                    }
                 }
-                let last = vb.last().unwrap();
-                new_oids.push(last.name.clone());
-                vb_cnt += 1;
             }
             debug!("{i}th the repetition");
-            for (n, oid) in new_oids.iter().enumerate() {
-                rep_oids[n] = oid.clone();
+            if all_ended {
+                break;
             }
         }
-        (error_status, error_index, request_id)
+        (Pdu::ERROR_STATUS_NO_ERROR, 0, request_id)
     }
 
     /// Process a Scoped PDU, returning an Option<Response>
     ///
     /// Returns None on unsupported PDU types, like Notify
     ///
+    /// budget is the room for varbinds in the response. If they do not fit, the
+    /// response is tooBig with an empty varbind list, RFC 3416 section 4.2.1.
+    ///
     /// When everything is supported, remove Option
     fn do_scoped_pdu(
         &self,
         flags: u8,
-        user: &usm::User,
+        principal: &Principal,
         scoped_pdu: ScopedPdu,
         oid_map: &mut OidMap,
+        budget: usize,
     ) -> Option<Response> {
         //
         let mut skip_pdu = false;
         let mut vb: Vec<VarBind> = Vec::new();
+        // On error the request varbinds are echoed back, RFC 3416 section 4.2
+        let request_vb = match &scoped_pdu.data {
+            Pdus::GetRequest(r) => r.0.variable_bindings.clone(),
+            Pdus::GetNextRequest(r) => r.0.variable_bindings.clone(),
+            Pdus::SetRequest(r) => r.0.variable_bindings.clone(),
+            Pdus::GetBulkRequest(r) => r.0.variable_bindings.clone(),
+            _ => vec![],
+        };
         let mut error_status = Pdu::ERROR_STATUS_NO_ERROR;
         let mut error_index = 0;
-        let mut request_id = 0;
-        let _context_name = scoped_pdu.name;
-        let perm = user.perm;
+        let mut request_id = pdu_request_id(&scoped_pdu.data);
+        let vacm_perm = self
+            .vacm
+            .as_ref()
+            .map(|vacm| vacm.perm(principal.model, principal.name, flags, &scoped_pdu.name));
+        let perm = vacm_perm.as_ref().unwrap_or(principal.perm);
+        let allowed = perm.check_context(&scoped_pdu.name);
 
         match scoped_pdu.data {
+            Pdus::GetRequest(_)
+            | Pdus::GetNextRequest(_)
+            | Pdus::SetRequest(_)
+            | Pdus::GetBulkRequest(_)
+                if !allowed =>
+            {
+                warn!("Context {:?} not allowed for this group", scoped_pdu.name);
+                error_status = OidErr::AuthorizationError.error_status();
+                error_index = 1;
+            }
             Pdus::GetRequest(r) => {
                 (error_status, error_index, request_id) =
                     self.get(oid_map, r, &mut vb, perm, flags);
@@ -667,10 +1294,19 @@ impl Agent {
             }
             Pdus::GetBulkRequest(r) => {
                 (error_status, error_index, request_id) =
-                    self.bulk(oid_map, r, &mut vb, perm, flags);
+                    self.bulk(oid_map, r, &mut vb, perm, flags, budget);
             }
             _ => skip_pdu = true,
         }
+        if error_status != Pdu::ERROR_STATUS_NO_ERROR {
+            vb = request_vb;
+        }
+        if vb.iter().map(encoded_len).sum::<usize>() > budget {
+            warn!("Response too big for budget {budget}");
+            error_status = Pdu::ERROR_STATUS_TOO_BIG;
+            error_index = 0;
+            vb.clear();
+        }
         if skip_pdu {
             warn!["skip_pdu is true"];
             None
@@ -685,170 +1321,877 @@ impl Agent {
         }
     }
 
-    /// Send Message back to originator at addr
-    fn send(&self, addr: SocketAddr, message: Message) {
-        let buf_res = rasn::ber::encode(&message);
-        match buf_res {
-            Ok(buf) => {
-                let _ = self.socket.send_to(&buf, addr);
+    /// Pick the OidMap for the context named in the scoped PDU, and process the PDU against it.
+    ///
+    /// Requests for other contexts, or other contextEngineIDs, go to the proxy forwarder first,
+    /// if there is one. In serve_ready they are answered later, when the Response arrives,
+    /// otherwise the proxy is waited for. The empty context uses oid_map. Unknown contexts are
+    /// counted, and answered with a snmpUnknownContexts Report if the manager asked for one,
+    /// RFC 3412 section 4.2.2.1.
+    /// Returns the context name to echo along with the PDU to send.
+    fn do_context(
+        &mut self,
+        flags: u8,
+        principal: &Principal,
+        scoped_pdu: ScopedPdu,
+        oid_map: &mut OidMap,
+        budget: usize,
+    ) -> Option<(OctetString, Handled)> {
+        let name = scoped_pdu.name.clone();
+        let local = name.is_empty() || self.contexts.contains(&name);
+        let ours = scoped_pdu.engine_id.is_empty() || scoped_pdu.engine_id == self.engine_id;
+        if let Some(proxy) = self.proxy.as_mut().filter(|_| !(local && ours)) {
+            let level = flags_level(flags).unwrap_or(1);
+            let security = (principal.model, principal.name, level);
+            let resp = match proxy.forward(security, &scoped_pdu) {
+                None => None,
+                Some(proxy::Forwarded::Sent(outbound_id)) if self.reply_path.is_some() => {
+                    return Some((name, Handled::Later(outbound_id)));
+                }
+                Some(proxy::Forwarded::Sent(outbound_id)) => Some(proxy.wait(outbound_id)),
+                Some(proxy::Forwarded::Response(resp)) => Some(resp),
+            };
+            if let Some(mut resp) = resp {
+                fit_budget(&mut resp, budget);
+                return Some((name, Handled::Now(resp)));
+            }
+        }
+        if name.is_empty() {
+            let resp = self.do_scoped_pdu(flags, principal, scoped_pdu, oid_map, budget)?;
+            return Some((name, Handled::Now(Pdus::Response(resp))));
+        }
+        match self.contexts.take(&name) {
+            Some(mut context_map) => {
+                let resp =
+                    self.do_scoped_pdu(flags, principal, scoped_pdu, &mut context_map, budget);
+                self.contexts.insert(&name, context_map);
+                Some((name, Handled::Now(Pdus::Response(resp?))))
+            }
+            None => {
+                let count = self.unknown_contexts.incr();
+                warn!("Unknown context {name:?}");
+                if flags & REPORTABLE_FLAG == 0 {
+                    return None;
+                }
+                let pdu = Pdu {
+                    request_id: pdu_request_id(&scoped_pdu.data),
+                    error_index: 0,
+                    error_status: 0,
+                    variable_bindings: vec![counter_varbind(&UNKNOWN_CONTEXTS_ARC, count)],
+                };
+                Some((name, Handled::Now(Pdus::Report(Report(pdu)))))
             }
-            Err(err) => error!("encodeError on returned Message{err:?}, dropping packet"),
         }
     }
-    /// Main server loop entry point
+
+    /// Sort the OidMaps, as the lookups use binary search.
     ///
-    /// oid_map is Vec of tuples of (&ObjectIdentifier, &mut OidKeeper)
+    /// Call this before process_message, if not using loop_forever or run.
+    pub fn sort_maps(&mut self, oid_map: &mut OidMap) {
+        oid_map.sort();
+        self.contexts.sort();
+    }
+
+    /// Handle one message received over UDP, returning the encoded reply, if there is one.
     ///
-    /// This can be populated in any order, as it is sorted on the Oids before the loop starts.
+    /// This is the whole of the agent apart from the transport: decoding, USM checks,
+    /// decryption, dispatch to the keepers and encoding of the Response or Report.
+    /// src is used for logging and passed to handlers through request_source. oid_map, and
+    /// any OidMaps added with add_context, must already be sorted, as loop_forever does
+    /// before it starts.
+    pub fn process_message(
+        &mut self,
+        src: SocketAddr,
+        buf: &[u8],
+        oid_map: &mut OidMap,
+        users: &usm::Users,
+    ) -> Option<Vec<u8>> {
+        self.process_message_over(TransportDomain::Udp, src, buf, oid_map, users)
+    }
+
+    /// Handle one message received over the given transport, as process_message.
+    pub fn process_message_over(
+        &mut self,
+        domain: TransportDomain,
+        src: SocketAddr,
+        buf: &[u8],
+        oid_map: &mut OidMap,
+        users: &usm::Users,
+    ) -> Option<Vec<u8>> {
+        self.process(domain, src, None, buf, oid_map, users)
+    }
+
+    /// Decode a message and pass it to its security model.
     ///
-    pub fn loop_forever(&mut self, oid_map: &mut OidMap, users: usm::Users) {
-        let mut buf = [0; 65100];
-        let mut opt_user: Option<&usm::User>;
-        // Sort by oid, the lookups use binary search.
-        oid_map.sort();
-        loop {
-            let recv_res = self.socket.recv_from(&mut buf);
-            // If the socket read fails, there is nothing much we can do.
-            if recv_res.is_err() {
-                continue;
+    /// tm_security_name is the tmSecurityName of a TLS or DTLS session, which the
+    /// Transport Security Model needs.
+    fn process(
+        &mut self,
+        domain: TransportDomain,
+        src: SocketAddr,
+        tm_security_name: Option<&[u8]>,
+        buf: &[u8],
+        oid_map: &mut OidMap,
+        users: &usm::Users,
+    ) -> Option<Vec<u8>> {
+        self.request_source.set(domain, src);
+        self.in_pkts += 1;
+        let decode_res: Result<Message, rasn::error::DecodeError> = rasn::ber::decode(buf);
+        // Simply ignore packets that do not decode
+        // In theory, should send decode error
+        if decode_res.is_err() {
+            self.decode_error_cnt += 1;
+            return None;
+        }
+        let message: Message = decode_res.unwrap();
+        if message.global_data.flags.is_empty() {
+            self.decode_error_cnt += 1;
+            return None;
+        }
+        // The response must fit both the manager's msgMaxSize and our own limit.
+        // RFC 3412 does not allow a msgMaxSize below 484.
+        let limit = if domain == TransportDomain::Dtls {
+            DTLS_MAX_MSG_SIZE
+        } else {
+            MAX_MSG_SIZE
+        };
+        let max_size: usize = message
+            .global_data
+            .max_size
+            .clone()
+            .try_into()
+            .unwrap_or(limit)
+            .clamp(484, limit);
+        let model: u32 = message
+            .global_data
+            .security_model
+            .clone()
+            .try_into()
+            .unwrap_or(0);
+        match model {
+            SECURITY_MODEL_USM => self.process_usm(src, message, max_size, oid_map, users),
+            SECURITY_MODEL_TSM => self.process_tsm(
+                src,
+                message,
+                tm_security_name,
+                (max_size, limit),
+                oid_map,
+                users,
+            ),
+            _ => {
+                // RFC 3412 section 7.2 step 4, discarded without a Report
+                self.unknown_security_models.incr();
+                warn!("Unknown security model {model} from {src}");
+                None
             }
+        }
+    }
 
-            self.in_pkts += 1;
-            let (amt, src) = recv_res.unwrap();
+    /// User-based Security Model processing, RFC 3414 section 3.2
+    fn process_usm(
+        &mut self,
+        src: SocketAddr,
+        mut message: Message,
+        max_size: usize,
+        oid_map: &mut OidMap,
+        users: &usm::Users,
+    ) -> Option<Vec<u8>> {
+        let opt_user: Option<&usm::User>;
+        let resp_opt: Option<(OctetString, Handled)>;
+        let message_id = message.global_data.message_id.to_owned();
+        let flags: u8 = *message.global_data.flags.first().unwrap();
 
-            // Redeclare `buf` as slice of the received data
-            let buf = &mut buf[..amt];
-            let decode_res: Result<Message, rasn::error::DecodeError> = rasn::ber::decode(buf);
-            // Simply ignore packets that do not decode
-            // In theory, should send decode error
-            if decode_res.is_err() {
-                self.decode_error_cnt += 1;
-                continue;
+        // Now do inner decode of security parameters
+        let r_sp: Result<USMSecurityParameters, Box<dyn Display>> =
+            message.decode_security_parameters(rasn::Codec::Ber);
+        // Simply ignore packets that do not decode
+        // In theory, should send decode error
+        if r_sp.is_err() {
+            self.decode_error_cnt += 1;
+            return None;
+        }
+        let usp: USMSecurityParameters = r_sp.ok().expect("Errors caught above");
+
+        if flags_level(flags).is_none() {
+            warn!("Invalid flags {flags}, privacy without authentication");
+            return self.usm_failure(
+                src,
+                flags,
+                clear_request_id(&message.scoped_data),
+                message_id,
+                UsmFailure::UnsupportedSecLevel,
+                usp.user_name,
+                None,
+            );
+        }
+
+        if !usp.user_name.is_empty() {
+            opt_user = users.lookup_user(usp.user_name.to_vec());
+            if opt_user.is_none() {
+                return self.usm_failure(
+                    src,
+                    flags,
+                    clear_request_id(&message.scoped_data),
+                    message_id,
+                    UsmFailure::UnknownUserName,
+                    usp.user_name,
+                    None,
+                );
             }
-            let mut message: Message = decode_res.unwrap();
-            let resp_opt: Option<Response>;
-            let mut out_message: Message;
-            let message_id = message.global_data.message_id.to_owned();
-            let flags: u8 = *message.global_data.flags.first().unwrap();
-
-            // Now do inner decode of security parameters
-            let r_sp: Result<USMSecurityParameters, Box<dyn Display>> =
-                message.decode_security_parameters(rasn::Codec::Ber);
-            // Simply ignore packets that do not decode
-            // In theory, should send decode error
-            if r_sp.is_err() {
-                self.decode_error_cnt += 1;
-                continue;
+        } else {
+            if let ScopedPduData::CleartextPdu(ref scoped_pdu) = message.scoped_data {
+                // FIXME Add extra conditions here on engine_id discovery
+                if scoped_pdu.engine_id.to_vec() == b"" {
+                    // Return EngineId if manager does not know it yet.
+                    // This has to be in clear, as engine_id is used in the
+                    // encryption.
+                    if let Pdus::GetRequest(r) = &scoped_pdu.data {
+                        let request_id = r.0.request_id;
+                        self.unknown_engine_ids.incr();
+                        return encode_message(&self.id_response(request_id, message_id));
+                    }
+                }
+            }
+            return None;
+        }
+        let user = opt_user.unwrap();
+        // The user's protocols must be able to provide the requested level
+        if flags_level(flags) > Some(user.security_level()) {
+            return self.usm_failure(
+                src,
+                flags,
+                clear_request_id(&message.scoped_data),
+                message_id,
+                UsmFailure::UnsupportedSecLevel,
+                usp.user_name,
+                None,
+            );
+        }
+        // An encrypted PDU without the privacy flag can't be parsed, RFC 3412 section 7.2 step 2
+        if flags & 2 == 0 && matches!(message.scoped_data, ScopedPduData::EncryptedPdu(_)) {
+            warn!("Encrypted scoped PDU without the privacy flag, dropping");
+            self.decode_error_cnt += 1;
+            return None;
+        }
+        // Check the authentication
+        if flags & 1 == 1 {
+            if let Some(failure) = self.check_auth(&mut message, user, &usp) {
+                // Only a message that passed the digest check can be trusted
+                // with an authenticated report.
+                let auth_user = if failure == UsmFailure::NotInTimeWindow {
+                    Some(user)
+                } else {
+                    None
+                };
+                if failure == UsmFailure::WrongDigest {
+                    self.auth_failure_trap(oid_map);
+                }
+                let request_id = match &message.scoped_data {
+                    ScopedPduData::EncryptedPdu(enc_octs) if auth_user.is_some() => {
+                        privacy::decrypt(&mut enc_octs.to_vec(), usp.clone(), &user.priv_key)
+                            .ok()
+                            .and_then(|buf2| rasn::ber::decode::<ScopedPdu>(&buf2).ok())
+                            .map_or(0, |scoped_pdu| pdu_request_id(&scoped_pdu.data))
+                    }
+                    scoped_data => clear_request_id(scoped_data),
+                };
+                return self.usm_failure(
+                    src,
+                    flags,
+                    request_id,
+                    message_id,
+                    failure,
+                    usp.user_name,
+                    auth_user,
+                );
+            }
+        }
+
+        // The salt makes the IV for decrypting the request and encrypting the reply
+        if flags & 2 == 2 && usp.privacy_parameters.len() != 8 {
+            warn!("msgPrivacyParameters must be 8 bytes");
+            return self.usm_failure(
+                src,
+                flags,
+                0,
+                message_id,
+                UsmFailure::DecryptionError,
+                usp.user_name,
+                None,
+            );
+        }
+
+        let budget = self.varbind_budget(max_size, user, &usp, flags);
+        let principal = Principal::usm(user);
+
+        match message.scoped_data {
+            ScopedPduData::CleartextPdu(scoped_pdu) => {
+                resp_opt = self.do_context(flags, &principal, scoped_pdu, oid_map, budget);
+            }
+            ScopedPduData::EncryptedPdu(enc_octs) => {
+                let key = &opt_user.unwrap().priv_key;
+                let decrypted = privacy::decrypt(&mut enc_octs.to_vec(), usp.clone(), key)
+                    .map(|buf2| rasn::ber::decode::<ScopedPdu>(&buf2));
+                let scoped_pdu = match decrypted {
+                    Ok(Ok(scoped_pdu)) => scoped_pdu,
+                    failed => {
+                        warn!("Decryption error {failed:?}");
+                        return self.usm_failure(
+                            src,
+                            flags,
+                            0,
+                            message_id,
+                            UsmFailure::DecryptionError,
+                            usp.user_name,
+                            None,
+                        );
+                    }
+                };
+                resp_opt = self.do_context(flags, &principal, scoped_pdu, oid_map, budget);
+            }
+        }
+
+        if resp_opt.is_none() {
+            warn!("No response, discarding");
+            return None;
+        }
+        let (context_name, resp) = resp_opt.unwrap();
+        let security = ReplySecurity::Usm {
+            message_id,
+            flags: message.global_data.flags,
+            user_name: user.name.clone(),
+            usp: Box::new(usp),
+            max_size,
+        };
+        self.reply(src, security, context_name, resp, budget, users)
+    }
+
+    /// Encode the Response to a request, or keep what it needs until the proxy has it.
+    fn reply(
+        &mut self,
+        src: SocketAddr,
+        security: ReplySecurity,
+        context_name: OctetString,
+        resp: Handled,
+        budget: usize,
+        users: &usm::Users,
+    ) -> Option<Vec<u8>> {
+        match (resp, self.reply_path) {
+            (Handled::Now(resp), _) => self.secure_reply(&security, context_name, resp, users),
+            (Handled::Later(outbound_id), Some(path)) => {
+                let proxied = Proxied {
+                    path,
+                    src,
+                    context_name,
+                    budget,
+                    security,
+                };
+                self.proxied.insert(outbound_id, proxied);
+                None
+            }
+            (Handled::Later(_), None) => unreachable!("do_context waits outside serve_ready"),
+        }
+    }
+
+    /// Build and encode the Response message, or None if it is too big even so.
+    fn secure_reply(
+        &self,
+        security: &ReplySecurity,
+        context_name: OctetString,
+        resp: Pdus,
+        users: &usm::Users,
+    ) -> Option<Vec<u8>> {
+        let (out_message, max_size) = match security {
+            ReplySecurity::Usm {
+                message_id,
+                flags,
+                user_name,
+                usp,
+                max_size,
+            } => {
+                let user = users.lookup_user(user_name.clone())?;
+                let encrypted = flags.first().is_some_and(|flags| flags & 2 == 2);
+                let mut out_message = self.prepare_back(
+                    message_id.clone(),
+                    context_name,
+                    resp,
+                    user,
+                    usp.as_ref().clone(),
+                    encrypted,
+                );
+                out_message.global_data.flags = flags.clone();
+                if flags.first().is_some_and(|flags| flags & 1 == 1) {
+                    usm::set_auth(&mut out_message, user);
+                }
+                (out_message, *max_size)
             }
-            let usp: USMSecurityParameters = r_sp.ok().expect("Errors caught above");
+            ReplySecurity::Tsm {
+                message_id,
+                flags,
+                max_size,
+                our_max,
+            } => {
+                let out_message =
+                    self.prepare_tsm(message_id.clone(), *flags, context_name, resp, *our_max);
+                (out_message, *max_size)
+            }
+        };
+        if encoded_len(&out_message) > max_size {
+            warn!("Response larger than {max_size} bytes even after trimming, dropping");
+            return None;
+        }
+        encode_message(&out_message)
+    }
+
+    /// Transport Security Model processing, RFC 5591 section 5.2
+    ///
+    /// The securityName is the tmSecurityName of the TLS or DTLS session, which authenticates
+    /// and encrypts everything, so any security level can be granted and the scoped PDU is
+    /// always in clear. sizes are the manager's msgMaxSize and our own, as clamped by process.
+    fn process_tsm(
+        &mut self,
+        src: SocketAddr,
+        message: Message,
+        tm_security_name: Option<&[u8]>,
+        sizes: (usize, usize),
+        oid_map: &mut OidMap,
+        users: &usm::Users,
+    ) -> Option<Vec<u8>> {
+        let Some(name) = tm_security_name else {
+            // Only a secure transport can vouch for the sender
+            self.tsm_invalid_caches.incr();
+            warn!("TSM message from {src} without a TLS or DTLS session, dropping");
+            return None;
+        };
+        let flags: u8 = *message.global_data.flags.first().unwrap();
+        if flags_level(flags).is_none() {
+            warn!("Invalid flags {flags}, privacy without authentication");
+            return None;
+        }
+        let ScopedPduData::CleartextPdu(scoped_pdu) = message.scoped_data else {
+            self.decode_error_cnt += 1;
+            return None;
+        };
+        let (max_size, our_max) = sizes;
+        let message_id = message.global_data.message_id;
+        let principal = Principal {
+            model: SECURITY_MODEL_TSM,
+            name,
+            perm: users
+                .lookup_user(name.to_vec())
+                .map_or(&NO_ACCESS, |user| user.perm),
+        };
+        let empty = self.prepare_tsm(
+            Integer::from(i32::MAX),
+            flags,
+            ZB,
+            empty_response(),
+            our_max,
+        );
+        let budget = max_size.saturating_sub(encoded_len(&empty) + LENGTH_SLACK);
+        let (context_name, resp) =
+            self.do_context(flags, &principal, scoped_pdu, oid_map, budget)?;
+        let security = ReplySecurity::Tsm {
+            message_id,
+            flags,
+            max_size,
+            our_max,
+        };
+        self.reply(src, security, context_name, resp, budget, users)
+    }
+
+    /// Internal method that builds Transport Security Model responses, RFC 5591 section 5.1
+    ///
+    /// The security level is that of the request, and msgSecurityParameters is empty.
+    fn prepare_tsm(
+        &self,
+        message_id: Integer,
+        flags: u8,
+        context_name: OctetString,
+        data: Pdus,
+        max_size: usize,
+    ) -> Message {
+        let head = HeaderData {
+            flags: OctetString::from(vec![flags & 3]),
+            message_id,
+            max_size: Integer::from(max_size),
+            security_model: Integer::from(SECURITY_MODEL_TSM),
+        };
+        let scpd: ScopedPdu = ScopedPdu {
+            engine_id: self.engine_id.clone(),
+            name: context_name,
+            data,
+        };
+        Message {
+            version: Integer::from(3),
+            global_data: head,
+            scoped_data: ScopedPduData::CleartextPdu(scpd),
+            security_parameters: ZB,
+        }
+    }
 
-            if !usp.user_name.is_empty() {
-                opt_user = users.lookup_user(usp.user_name.to_vec());
-                if opt_user.is_none() {
-                    self.unknown_users += 1;
-                    // FIXME should send auth failure back.
+    /// Handle the messages waiting on any of the sockets, waiting up to timeout for some to arrive.
+    ///
+    /// For applications with their own main loop. Returns the number of messages read, AgentX
+    /// PDUs included, which is zero on timeout. With TCP connections open, it may return early
+    /// to close idle ones.
+    /// As for process_message, the maps must already be sorted.
+    pub fn serve_ready(
+        &mut self,
+        oid_map: &mut OidMap,
+        users: &usm::Users,
+        timeout: Option<Duration>,
+    ) -> io::Result<usize> {
+        self.send_start_trap();
+        let sockets = std::mem::take(&mut self.sockets);
+        let mut dtls = std::mem::take(&mut self.dtls);
+        let listeners = std::mem::take(&mut self.tcp_listeners);
+        let mut connections = std::mem::take(&mut self.connections);
+        let mut master = self.agentx.take();
+        let fds: Vec<(RawFd, bool)> = sockets
+            .iter()
+            .map(AsRawFd::as_raw_fd)
+            .chain(dtls.iter().map(AsRawFd::as_raw_fd))
+            .chain(listeners.iter().map(|(_, listener)| listener.as_raw_fd()))
+            .map(|fd| (fd, false))
+            .chain(
+                connections
+                    .iter()
+                    .map(|connection| (connection.as_raw_fd(), connection.wants_write())),
+            )
+            .chain(
+                master
+                    .iter()
+                    .flat_map(|master| master.fds())
+                    .map(|fd| (fd, false)),
+            )
+            .chain(
+                self.proxy
+                    .iter()
+                    .flat_map(|proxy| proxy.fds())
+                    .map(|fd| (fd, false)),
+            )
+            .collect();
+        let proxy_base = fds.len() - self.proxy.as_ref().map_or(0, |proxy| proxy.fds().len());
+        let agentx_base = proxy_base - master.as_ref().map_or(0, |master| master.fds().len());
+        // Wake up in time to close the next idle connection or DTLS session, or to resend
+        // or give up a proxied request
+        let idle_wait = connections
+            .iter()
+            .map(|connection| connection.time_left(self.tcp_idle_timeout))
+            .chain(
+                dtls.iter()
+                    .filter_map(|socket| socket.next_idle(self.tcp_idle_timeout)),
+            )
+            .chain(self.proxy.iter().filter_map(proxy::Proxy::next_timeout))
+            .min();
+        let timeout = match (timeout, idle_wait) {
+            (Some(timeout), Some(idle_wait)) => Some(timeout.min(idle_wait)),
+            (timeout, idle_wait) => timeout.or(idle_wait),
+        };
+        // AgentX PDUs queued while waiting for a subagent are handled straight away
+        let timeout = if master.as_ref().is_some_and(|master| master.has_pending()) {
+            Some(Duration::ZERO)
+        } else {
+            timeout
+        };
+        let ready = transport::wait_ready(&fds, timeout);
+        let mut buf = [0; 65100];
+        let mut count = 0;
+        let mut closed = vec![false; connections.len()];
+        let mut agentx_ready = vec![];
+        let mut proxy_ready = false;
+        for &idx in ready.as_deref().unwrap_or_default() {
+            if idx >= proxy_base {
+                proxy_ready = true;
+            } else if idx >= agentx_base {
+                agentx_ready.push(idx - agentx_base);
+            } else if let Some(socket) = sockets.get(idx) {
+                // If the socket read fails, there is nothing much we can do.
+                let Ok((amt, src, dst)) = socket.recv(&mut buf) else {
+                    continue;
+                };
+                count += 1;
+                self.reply_path = Some(ReplyPath::Udp(idx, dst));
+                if let Some(reply) = self.process_message(src, &buf[..amt], oid_map, users) {
+                    let _ = socket.send(&reply, src, dst);
+                }
+            } else if let Some(socket) = dtls.get_mut(idx - sockets.len()) {
+                let Some(tls) = &self.tls else {
+                    continue;
+                };
+                let Ok((peer, messages)) = socket.recv(tls, self.tcp_max_connections) else {
                     continue;
+                };
+                for message in messages {
+                    count += 1;
+                    self.reply_path = Some(ReplyPath::Dtls(idx - sockets.len()));
+                    let name = socket.security_name(&peer).map(<[u8]>::to_vec);
+                    let reply = self.process(
+                        TransportDomain::Dtls,
+                        peer,
+                        name.as_deref(),
+                        &message,
+                        oid_map,
+                        users,
+                    );
+                    if let Some(reply) = reply {
+                        let _ = socket.send(&peer, &reply);
+                    }
                 }
+            } else if let Some((domain, listener)) = listeners.get(idx - sockets.len() - dtls.len())
+            {
+                self.accept(*domain, listener, &mut connections);
             } else {
-                if let ScopedPduData::CleartextPdu(ref scoped_pdu) = message.scoped_data {
-                    // FIXME Add extra conditions here on engine_id discovery
-                    if scoped_pdu.engine_id.to_vec() == b"" {
-                        // Return EngineId if manager does not know it yet.
-                        // This has to be in clear, as engine_id is used in the
-                        // encryption.
-                        if let Pdus::GetRequest(r) = &scoped_pdu.data {
-                            let request_id = r.0.request_id;
-                            self.unknown_engine_ids += 1;
-                            self.send(src, self.id_response(request_id, message_id));
+                let conn_idx = idx - sockets.len() - dtls.len() - listeners.len();
+                let connection = &mut connections[conn_idx];
+                let messages = match connection.read_messages(MAX_MSG_SIZE) {
+                    Ok(messages) => messages,
+                    Err(err) => {
+                        debug!("Closing TCP connection from {0}: {err}", connection.peer());
+                        closed[conn_idx] = true;
+                        continue;
+                    }
+                };
+                for message in messages {
+                    count += 1;
+                    let src = connection.peer();
+                    self.reply_path = Some(ReplyPath::Tcp(connection.domain()));
+                    let reply = self.process(
+                        connection.domain(),
+                        src,
+                        connection.security_name(),
+                        &message,
+                        oid_map,
+                        users,
+                    );
+                    if let Some(reply) = reply {
+                        if let Err(err) = connection.send(&reply) {
+                            debug!("Closing TCP connection from {src}: {err}");
+                            closed[conn_idx] = true;
+                            break;
                         }
                     }
                 }
-                continue;
             }
-            let user = opt_user.unwrap();
-            // Check the authentication
-            if flags & 1 == 1 {
-                // FIXME
-                // Both these cases should send Authentication Failure, rather
-                // than silently dropping the packet. Maybe some
-                // other auth types have different lengths, so logic
-                // may be more complex. Probably have to look up user,
-                // and take authentication length from the required method.
-                if usp.authentication_parameters.len() != 12 {
-                    warn!("Authentication parameters must be 12 bytes");
+        }
+        self.reply_path = None;
+        if let Some(proxy) = &mut self.proxy {
+            for (outbound_id, mut resp) in proxy.serve(proxy_ready) {
+                let Some(proxied) = self.proxied.remove(&outbound_id) else {
                     continue;
-                }
-                if self.wrong_auth(&mut message, user, usp.clone()) {
-                    warn!("Wrong auth, dropping");
+                };
+                fit_budget(&mut resp, proxied.budget);
+                let reply = self.secure_reply(&proxied.security, proxied.context_name, resp, users);
+                let Some(reply) = reply else {
                     continue;
-                }
-            }
-
-            match message.scoped_data {
-                ScopedPduData::CleartextPdu(scoped_pdu) => {
-                    resp_opt = self.do_scoped_pdu(flags, user, scoped_pdu, oid_map);
-                }
-                ScopedPduData::EncryptedPdu(enc_octs) => {
-                    let key = &opt_user.unwrap().priv_key;
-                    let buf2: Vec<u8> = privacy::decrypt(&mut enc_octs.to_vec(), usp.clone(), key);
-                    let pdu_decode_res: Result<ScopedPdu, rasn::error::DecodeError> =
-                        rasn::ber::decode(&buf2);
-                    if pdu_decode_res.is_err() {
-                        // Should return decode error
-                        self.decryption_errors += 1;
-                        warn!("Decode error {pdu_decode_res:?}");
-                        continue;
+                };
+                let src = proxied.src;
+                match proxied.path {
+                    ReplyPath::Udp(idx, dst) => {
+                        let _ = sockets[idx].send(&reply, src, dst);
+                    }
+                    ReplyPath::Dtls(idx) => {
+                        let _ = dtls[idx].send(&src, &reply);
+                    }
+                    ReplyPath::Tcp(domain) => {
+                        let conn_idx = connections.iter().position(|connection| {
+                            connection.domain() == domain && connection.peer() == src
+                        });
+                        let Some(conn_idx) = conn_idx else {
+                            debug!("TCP connection from {src} closed before its proxied response");
+                            continue;
+                        };
+                        if let Err(err) = connections[conn_idx].send(&reply) {
+                            debug!("Closing TCP connection from {src}: {err}");
+                            closed[conn_idx] = true;
+                        }
                     }
-                    let scoped_pdu: ScopedPdu = pdu_decode_res.unwrap();
-                    resp_opt = self.do_scoped_pdu(flags, user, scoped_pdu, oid_map);
                 }
             }
+        }
+        let mut conn_idx = 0;
+        connections.retain(|connection| {
+            let keep = !closed.get(conn_idx).unwrap_or(&false)
+                && !connection.time_left(self.tcp_idle_timeout).is_zero();
+            conn_idx += 1;
+            keep
+        });
+        for socket in &mut dtls {
+            socket.close_idle(self.tcp_idle_timeout);
+        }
+        if let Some(master) = &mut master {
+            let sys_up_time = (self.start_time.elapsed().as_millis() / 10) as u32;
+            count += master.serve(&agentx_ready, oid_map, sys_up_time);
+        }
+        self.sockets = sockets;
+        self.dtls = dtls;
+        self.tcp_listeners = listeners;
+        self.connections = connections;
+        self.agentx = master;
+        ready.map(|_| count)
+    }
 
-            if resp_opt.is_none() {
-                warn!("No response, discarding");
-                continue;
+    /// Accept a TCP connection, unless there are already as many as allowed.
+    ///
+    /// For TLS, the handshake is only started, and the connection finishes it as the
+    /// manager's messages arrive. Connections still in their handshake count towards the limit.
+    fn accept(
+        &self,
+        domain: TransportDomain,
+        listener: &TcpListener,
+        connections: &mut Vec<transport::TcpConnection>,
+    ) {
+        let (stream, peer) = match listener.accept() {
+            Ok((_, peer)) if connections.len() >= self.tcp_max_connections => {
+                warn!(
+                    "Refusing TCP connection from {peer}, already have {0}",
+                    connections.len()
+                );
+                return;
             }
-            let resp = resp_opt.unwrap();
-            out_message = self.prepare_back(message_id, resp, user, usp, flags & 2 == 2);
-            out_message.global_data.flags = message.global_data.flags;
-            if flags & 1 == 1 {
-                self.set_auth(&mut out_message, user);
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("TCP accept failed {err}");
+                return;
             }
-            self.send(src, out_message);
+        };
+        let connection = match (domain, &self.tls) {
+            (TransportDomain::Tls, Some(tls)) => tls
+                .accept(stream)
+                .and_then(|step| transport::TcpConnection::with_handshake(step, peer, domain)),
+            _ => transport::TcpConnection::new(stream, peer),
+        };
+        match connection {
+            Ok(connection) => connections.push(connection),
+            Err(err) => warn!("Couldn't set up {domain:?} connection from {peer}: {err}"),
         }
     }
 
-    fn set_auth(&self, message: &mut Message, usr: &usm::User) -> Vec<u8> {
-        let r_sp: Result<USMSecurityParameters, Box<dyn Display>> =
-            message.decode_security_parameters(rasn::Codec::Ber);
-        if r_sp.is_err() {
-            return vec![];
+    /// Main server loop entry point
+    ///
+    /// oid_map is Vec of tuples of (&ObjectIdentifier, &mut OidKeeper)
+    ///
+    /// This can be populated in any order, as it is sorted on the Oids before the loop starts.
+    ///
+    pub fn loop_forever(&mut self, oid_map: &mut OidMap, users: usm::Users) {
+        assert!(
+            !self.sockets.is_empty()
+                || !self.dtls.is_empty()
+                || !self.tcp_listeners.is_empty()
+                || self.agentx.is_some(),
+            "loop_forever needs an Agent with at least one listen address"
+        );
+        self.sort_maps(oid_map);
+        loop {
+            if let Err(err) = self.serve_ready(oid_map, &users, None) {
+                error!("Poll failed {err}");
+            }
         }
-        let mut usp: USMSecurityParameters = r_sp.ok().expect("Errors caught above");
-        usp.authentication_parameters = Z12;
-        let _ = message.encode_security_parameters(rasn::Codec::Ber, &usp);
-        let buf = rasn::ber::encode(message).unwrap();
-
-        let auth = usr.auth_from_bytes(&buf);
-        usp.authentication_parameters = OctetString::from_slice(&auth);
-        let _ = message.encode_security_parameters(rasn::Codec::Ber, &usp);
-        auth
     }
 
-    /// Return true if the auth is wrong
-    fn wrong_auth(
+    /// Async server loop, for applications built on tokio.
+    ///
+    /// Serves the sockets added by build or listen until shutdown completes, then returns.
+    /// Packets are handled one at a time on the calling task, exactly as in loop_forever,
+    /// so keepers are never called concurrently and need no locking. As OidMap is not Send,
+    /// run the future on a LocalSet, or await it directly from main.
+    ///
+    /// The sockets are switched to non-blocking mode, so loop_forever cannot be used afterwards.
+    /// TCP, TLS, DTLS, AgentX and the proxy forwarder are only served by loop_forever and
+    /// serve_ready, so an Agent using any of them gets an Unsupported error.
+    #[cfg(feature = "tokio")]
+    pub async fn run(
         &mut self,
+        oid_map: &mut OidMap,
+        users: usm::Users<'_>,
+        shutdown: impl std::future::Future<Output = ()>,
+    ) -> io::Result<()> {
+        if self.sockets.is_empty() {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+        if !self.tcp_listeners.is_empty()
+            || !self.dtls.is_empty()
+            || self.agentx.is_some()
+            || self.proxy.is_some()
+        {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        let mut sockets = vec![];
+        for socket in &self.sockets {
+            let socket = socket.try_clone()?;
+            socket.set_nonblocking(true)?;
+            sockets.push(tokio::io::unix::AsyncFd::new(socket)?);
+        }
+        let mut buf = [0; 65100];
+        self.sort_maps(oid_map);
+        self.send_start_trap();
+        tokio::pin!(shutdown);
+        loop {
+            let recv = std::future::poll_fn(|cx| {
+                for (idx, socket) in sockets.iter().enumerate() {
+                    // Keep polling until the socket is drained, so the waker is registered
+                    while let std::task::Poll::Ready(guard) = socket.poll_read_ready(cx) {
+                        let mut guard = match guard {
+                            Ok(guard) => guard,
+                            Err(err) => return std::task::Poll::Ready((idx, Err(err))),
+                        };
+                        if let Ok(res) = guard.try_io(|inner| inner.get_ref().recv(&mut buf)) {
+                            return std::task::Poll::Ready((idx, res));
+                        }
+                    }
+                }
+                std::task::Poll::Pending
+            });
+            let (idx, recv_res) = tokio::select! {
+                _ = &mut shutdown => return Ok(()),
+                ready = recv => ready,
+            };
+            // If the socket read fails, there is nothing much we can do.
+            let Ok((amt, src, dst)) = recv_res else {
+                continue;
+            };
+            if let Some(reply) = self.process_message(src, &buf[..amt], oid_map, &users) {
+                let _ = sockets[idx]
+                    .async_io(tokio::io::Interest::WRITABLE, |inner| {
+                        inner.send(&reply, src, dst)
+                    })
+                    .await;
+            }
+        }
+    }
+
+    /// Check digest and timeliness of an authenticated message, RFC 3414 section 3.2 steps 6 and 7
+    ///
+    /// The digest is checked first, so a notInTimeWindow failure is only
+    /// returned for messages that really come from the user.
+    fn check_auth(
+        &self,
         message: &mut Message,
         user: &usm::User,
-        usp: USMSecurityParameters,
-    ) -> bool {
+        usp: &USMSecurityParameters,
+    ) -> Option<UsmFailure> {
+        // Maybe some other auth types have different lengths, so this may
+        // have to take the length from the user's auth method.
+        if usp.authentication_parameters.len() != 12 {
+            warn!("Authentication parameters must be 12 bytes");
+            return Some(UsmFailure::WrongDigest);
+        }
+        let hmac = usp.authentication_parameters.clone().to_vec();
+        let our_hmac = usm::set_auth(message, user);
+        // Actually check the auth
+        if hmac != our_hmac {
+            debug!("Message hmac {hmac:?} ours {our_hmac:?} ");
+            return Some(UsmFailure::WrongDigest);
+        }
+
         let boots: isize = usp
             .authoritative_engine_boots
+            .clone()
             .try_into()
             .unwrap_or(isize::MAX);
         if boots != self.boots {
-            self.not_in_time_window += 1;
-            return true;
+            return Some(UsmFailure::NotInTimeWindow);
         }
         let run_time: i32 = self
             .start_time
@@ -856,36 +2199,33 @@ impl Agent {
             .as_secs()
             .try_into()
             .unwrap_or(i32::MAX);
-        let man_time: i32 = usp.authoritative_engine_time.try_into().unwrap_or(i32::MAX);
-        let delta_t: i32 = man_time - run_time;
+        let man_time: i32 = usp
+            .authoritative_engine_time
+            .clone()
+            .try_into()
+            .unwrap_or(i32::MAX);
+        let delta_t: i32 = man_time.saturating_sub(run_time);
         if !(-150..=150).contains(&delta_t) {
-            self.not_in_time_window += 1;
-            return true;
+            return Some(UsmFailure::NotInTimeWindow);
         }
-
-        let hmac = usp.authentication_parameters.clone().to_vec();
-        let our_hmac = self.set_auth(message, user);
-        // Actually check the auth
-        if hmac != our_hmac {
-            debug!("Message hmac {hmac:?} ours {our_hmac:?} ");
-            self.wrong_digests += 1;
-            return true;
-        }
-        false
-    }
-}
+        None
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::keeper::{Access, OType, OidKeeper};
+    use crate::keeper::{Access, OType};
     use crate::oidmap;
+    use crate::perms::{View, ViewEntry};
+    use crate::scalar::ScalarMemOid;
     use crate::table::TableMemOid;
+    use rasn_snmp::v2::BulkPdu;
+
+    const Z12: OctetString = OctetString::from_static(&[0; 12]);
 
-    fn make_agent(port: &str) -> Agent {
-        let eid = OctetString::from_static(b"test");
-        let addr = "127.0.0.1:".to_owned() + port;
-        Agent::build(eid, &addr)
+    fn make_agent() -> Agent {
+        Agent::new(OctetString::from_static(b"test"))
     }
 
     fn get_pdu(arg: &'static [u32]) -> GetRequest {
@@ -981,20 +2321,34 @@ mod tests {
             write: true,
             security_level: 1u8, // Just flags
             group_name: "test".as_bytes().to_vec(),
+            context: vec![],
+            read_view: View::default(),
+            write_view: View::default(),
         }]
     }
 
     #[test]
     fn test_get() {
-        let agent = make_agent("3161");
+        let agent = make_agent();
         let gp = get_pdu(&ARC2);
         let mut vb: Vec<VarBind> = vec![];
         let mut oid_map = make_oid_map();
         let (status, idx, r_id) = agent.get(&mut oid_map, gp, &mut vb, &perms()[0], 3);
         assert_eq!(r_id, 1);
-        assert_eq!(idx, 1);
-        assert_eq!(status, Pdu::ERROR_STATUS_NO_SUCH_NAME);
+        assert_eq!(idx, 0);
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_ERROR);
         assert_eq!(vb.len(), 1);
+        assert_eq!(vb[0].value, VarBindValue::NoSuchInstance);
+        vb.clear();
+        let gp = get_pdu(&[1, 5]);
+        let (status, _, _) = agent.get(&mut oid_map, gp, &mut vb, &perms()[0], 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_ERROR);
+        assert_eq!(vb[0].value, VarBindValue::NoSuchObject);
+        vb.clear();
+        let gp = get_pdu(&[1, 6, 1, 2, 3, 120, 121, 121, 5]);
+        let (status, _, _) = agent.get(&mut oid_map, gp, &mut vb, &perms()[0], 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_ERROR);
+        assert_eq!(vb[0].value, VarBindValue::NoSuchInstance);
         vb.clear();
         let gp = get_pdu(&[1, 6, 1, 2, 3, 120, 121, 122, 5]);
         let (status, idx, r_id) = agent.get(&mut oid_map, gp, &mut vb, &perms()[0], 3);
@@ -1007,7 +2361,7 @@ mod tests {
 
     #[test]
     fn test_get_next() {
-        let agent = make_agent("3162");
+        let agent = make_agent();
         let gp = get_next_pdu(&ARC2);
         let mut vb: Vec<VarBind> = vec![];
         let mut oid_map = make_oid_map();
@@ -1026,11 +2380,100 @@ mod tests {
         assert_eq!(status, Pdu::ERROR_STATUS_NO_ERROR);
         assert_eq!(vb.len(), 1);
         assert_eq!(vb[0].value, VarBindValue::Value(simple_from_int(41)));
+        vb.clear();
+        // Past the last column of the only keeper
+        let gp = get_next_pdu(&[1, 6, 1, 3, 3, 120, 121, 122, 5]);
+        let (status, _, _) = agent.getnext(&mut oid_map, gp, &mut vb, &perms()[0], 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_ERROR);
+        assert_eq!(vb[0].value, VarBindValue::EndOfMibView);
+        assert_eq!(
+            vb[0].name,
+            ObjectIdentifier::new(&[1, 6, 1, 3, 3, 120, 121, 122, 5]).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_set_errors() {
+        let agent = make_agent();
+        let mut oid_map = make_oid_map();
+        let mut vb: Vec<VarBind> = vec![];
+        let sp = set_pdu(&[1, 5, 1], simple_from_int(4));
+        let (status, idx, _) = agent.set(&mut oid_map, sp, &mut vb, &perms()[0], 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_CREATION);
+        assert_eq!(idx, 1);
+        vb.clear();
+        let sp = set_pdu(&[1, 6, 1, 3, 3, 120, 121, 122, 5], simple_from_str(b"x"));
+        let (status, idx, _) = agent.set(&mut oid_map, sp, &mut vb, &perms()[0], 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_WRONG_TYPE);
+        assert_eq!(idx, 1);
+        vb.clear();
+        let read_only = Perm {
+            read: true,
+            write: false,
+            security_level: 1u8,
+            group_name: "test".as_bytes().to_vec(),
+            context: vec![],
+            read_view: View::default(),
+            write_view: View::default(),
+        };
+        let sp = set_pdu(&[1, 6, 1, 3, 3, 120, 121, 122, 5], simple_from_int(4));
+        let (status, idx, _) = agent.set(&mut oid_map, sp, &mut vb, &read_only, 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_AUTHORIZATION_ERROR);
+        assert_eq!(idx, 1);
+    }
+
+    #[test]
+    fn test_views() {
+        let agent = make_agent();
+        let mut oid_map = make_oid_map();
+        let mut perm = perms().remove(0);
+        perm.read_view.entries = vec![
+            ViewEntry {
+                subtree: ARC2.to_vec(),
+                mask: vec![],
+                included: true,
+            },
+            ViewEntry {
+                subtree: vec![1, 6, 1, 2],
+                mask: vec![],
+                included: false,
+            },
+        ];
+        perm.write_view.entries = vec![ViewEntry {
+            subtree: vec![1, 6, 1, 2],
+            mask: vec![],
+            included: true,
+        }];
+        let mut vb: Vec<VarBind> = vec![];
+
+        // Walk steps over the excluded column
+        let gp = get_next_pdu(&[1, 6, 1, 1, 3, 120, 121, 122, 5]);
+        let (status, _, _) = agent.getnext(&mut oid_map, gp, &mut vb, &perm, 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_ERROR);
+        assert_eq!(
+            vb[0].name,
+            ObjectIdentifier::new(&[1, 6, 1, 3, 3, 97, 98, 99, 4]).unwrap()
+        );
+        assert_eq!(vb[0].value, VarBindValue::Value(simple_from_int(41)));
+        vb.clear();
+
+        // Get in the excluded column is noSuchObject
+        let gp = get_pdu(&[1, 6, 1, 2, 3, 120, 121, 122, 5]);
+        let (status, _, _) = agent.get(&mut oid_map, gp, &mut vb, &perm, 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_ERROR);
+        assert_eq!(vb[0].value, VarBindValue::NoSuchObject);
+        vb.clear();
+
+        // Writable column, but outside the write view
+        let sp = set_pdu(&[1, 6, 1, 3, 3, 120, 121, 122, 5], simple_from_int(4));
+        let (status, idx, _) = agent.set(&mut oid_map, sp, &mut vb, &perm, 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_ACCESS);
+        assert_eq!(idx, 1);
     }
 
     #[test]
     fn test_set() {
-        let agent = make_agent("3163");
+        let agent = make_agent();
         let sp = set_pdu(&[1, 6, 1, 3, 3, 120, 121, 122, 5], simple_from_int(4));
         let mut vb: Vec<VarBind> = vec![];
         let mut oid_map = make_oid_map();
@@ -1051,6 +2494,1369 @@ mod tests {
         assert_eq!(vb[0].value, VarBindValue::Value(simple_from_int(41))); */
     }
 
+    const USER_LINE: &str = "test test sha1 0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b aes 0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c";
+
+    #[test]
+    fn test_contexts() {
+        let mut agent = make_agent();
+        let mut vrf_map = make_oid_map();
+        vrf_map.sort();
+        agent.add_context(b"vrf1", vrf_map);
+        let pv = vec![
+            Perm {
+                read: true,
+                write: true,
+                security_level: 1u8,
+                group_name: "test".as_bytes().to_vec(),
+                context: b"vrf*".to_vec(),
+                read_view: View::default(),
+                write_view: View::default(),
+            },
+            Perm {
+                read: true,
+                write: true,
+                security_level: 1u8,
+                group_name: "other".as_bytes().to_vec(),
+                context: vec![],
+                read_view: View::default(),
+                write_view: View::default(),
+            },
+        ];
+        let user = usm::User::from_str(USER_LINE, &pv).unwrap();
+        let principal = Principal::usm(&user);
+        let mut default_map = OidMap::new();
+        let scoped = |name: &'static [u8]| ScopedPdu {
+            engine_id: OctetString::from_static(b"test"),
+            name: OctetString::from_static(name),
+            data: Pdus::GetRequest(get_pdu(&[1, 6, 1, 2, 3, 120, 121, 122, 5])),
+        };
+
+        // Registered context is served from its own map, and the name echoed
+        let (name, pdus) = agent
+            .do_context(
+                REPORTABLE_FLAG,
+                &principal,
+                scoped(b"vrf1"),
+                &mut default_map,
+                1000,
+            )
+            .unwrap();
+        assert_eq!(name.to_vec(), b"vrf1".to_vec());
+        let Handled::Now(Pdus::Response(resp)) = pdus else {
+            panic!("Expected Response PDU");
+        };
+        assert_eq!(resp.0.error_status, Pdu::ERROR_STATUS_NO_ERROR);
+        assert_eq!(
+            resp.0.variable_bindings[0].value,
+            VarBindValue::Value(simple_from_int(5))
+        );
+
+        // Unknown context gets snmpUnknownContexts, unless no report is wanted
+        let (_, pdus) = agent
+            .do_context(
+                REPORTABLE_FLAG,
+                &principal,
+                scoped(b"vrf2"),
+                &mut default_map,
+                1000,
+            )
+            .unwrap();
+        let Handled::Now(Pdus::Report(rep)) = pdus else {
+            panic!("Expected Report PDU");
+        };
+        assert_eq!(rep.0.request_id, 1);
+        assert_eq!(
+            rep.0.variable_bindings[0].name,
+            ObjectIdentifier::new(&UNKNOWN_CONTEXTS_ARC).unwrap()
+        );
+        assert!(agent
+            .do_context(0, &principal, scoped(b"vrf2"), &mut default_map, 1000)
+            .is_none());
+        assert_eq!(agent.unknown_contexts.get(), 2);
+
+        // Default context is not in this group's allowed contexts
+        let (_, pdus) = agent
+            .do_context(
+                REPORTABLE_FLAG,
+                &principal,
+                scoped(b""),
+                &mut default_map,
+                1000,
+            )
+            .unwrap();
+        let Handled::Now(Pdus::Response(resp)) = pdus else {
+            panic!("Expected Response PDU");
+        };
+        assert_eq!(resp.0.error_status, Pdu::ERROR_STATUS_AUTHORIZATION_ERROR);
+        assert_eq!(resp.0.error_index, 1);
+    }
+
+    /// Engine ID discovery request: no user, empty engine ID, reportable
+    fn discovery_message() -> Vec<u8> {
+        let usm = USMSecurityParameters {
+            authoritative_engine_boots: Integer::from(0),
+            authoritative_engine_id: ZB,
+            authoritative_engine_time: Integer::from(0),
+            user_name: ZB,
+            authentication_parameters: ZB,
+            privacy_parameters: ZB,
+        };
+        let mut message = Message {
+            version: Integer::from(3),
+            global_data: HeaderData {
+                flags: OctetString::from_static(b"\x04"),
+                message_id: Integer::from(99),
+                max_size: Integer::from(MAX_MSG_SIZE),
+                security_model: Integer::from(3),
+            },
+            scoped_data: ScopedPduData::CleartextPdu(ScopedPdu {
+                engine_id: ZB,
+                name: ZB,
+                data: Pdus::GetRequest(get_pdu(&ARC2)),
+            }),
+            security_parameters: ZB,
+        };
+        _ = message.encode_security_parameters(rasn::Codec::Ber, &usm);
+        rasn::ber::encode(&message).unwrap()
+    }
+
+    #[test]
+    fn test_process_discovery() {
+        let mut agent = make_agent();
+        let mut oid_map = make_oid_map();
+        let users = usm::Users::new();
+        let src: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let reply = agent
+            .process_message(src, &discovery_message(), &mut oid_map, &users)
+            .unwrap();
+        let reply: Message = rasn::ber::decode(&reply).unwrap();
+        let ScopedPduData::CleartextPdu(scoped_pdu) = reply.scoped_data else {
+            panic!("Report should be in clear");
+        };
+        let Pdus::Report(rep) = scoped_pdu.data else {
+            panic!("Expected Report PDU");
+        };
+        assert_eq!(
+            rep.0.variable_bindings[0].name,
+            ObjectIdentifier::new(UsmFailure::UnknownEngineId.arc()).unwrap()
+        );
+        assert_eq!(scoped_pdu.engine_id.to_vec(), b"test".to_vec());
+        // Garbage is counted and dropped
+        assert!(agent
+            .process_message(src, b"\x30\x03junk", &mut oid_map, &users)
+            .is_none());
+        assert_eq!(agent.decode_error_cnt, 1);
+        assert_eq!(agent.in_pkts, 2);
+    }
+
+    /// Request as a manager that already knows our engine ID and time would send it.
+    ///
+    /// flags picks auth (1) and priv (2); the message is returned before encoding
+    /// so tests can tamper with it.
+    fn manager_message(agent: &Agent, user: &usm::User, flags: u8, data: Pdus) -> Message {
+        let mut usm = USMSecurityParameters {
+            authoritative_engine_boots: Integer::from(agent.boots),
+            authoritative_engine_id: agent.engine_id.clone(),
+            authoritative_engine_time: Integer::from(agent.start_time.elapsed().as_secs()),
+            user_name: OctetString::from_slice(&user.name),
+            authentication_parameters: ZB,
+            privacy_parameters: ZB,
+        };
+        let scoped_pdu = ScopedPdu {
+            engine_id: agent.engine_id.clone(),
+            name: ZB,
+            data,
+        };
+        let scoped_data = if flags & 2 == 2 {
+            usm.privacy_parameters = OctetString::from_static(b"saltsalt");
+            let mut clear = rasn::ber::encode(&scoped_pdu).unwrap();
+            let enc = privacy::encrypt(&mut clear, usm.clone(), &user.priv_key);
+            ScopedPduData::EncryptedPdu(OctetString::from(enc))
+        } else {
+            ScopedPduData::CleartextPdu(scoped_pdu)
+        };
+        let mut message = Message {
+            version: Integer::from(3),
+            global_data: HeaderData {
+                flags: OctetString::from(vec![flags | REPORTABLE_FLAG]),
+                message_id: Integer::from(42),
+                max_size: Integer::from(MAX_MSG_SIZE),
+                security_model: Integer::from(3),
+            },
+            scoped_data,
+            security_parameters: ZB,
+        };
+        _ = message.encode_security_parameters(rasn::Codec::Ber, &usm);
+        if flags & 1 == 1 {
+            usm::set_auth(&mut message, user);
+        }
+        message
+    }
+
+    /// Decode a reply the way the manager would, returning its flags and PDU
+    fn manager_reply(reply: &[u8], user: &usm::User) -> (u8, Pdus) {
+        let message: Message = rasn::ber::decode(reply).unwrap();
+        let flags = message.global_data.flags[0];
+        let usp: USMSecurityParameters = message
+            .decode_security_parameters(rasn::Codec::Ber)
+            .ok()
+            .unwrap();
+        let scoped_pdu = match message.scoped_data {
+            ScopedPduData::CleartextPdu(scoped_pdu) => scoped_pdu,
+            ScopedPduData::EncryptedPdu(enc_octs) => {
+                let clear = privacy::decrypt(&mut enc_octs.to_vec(), usp, &user.priv_key).unwrap();
+                rasn::ber::decode(&clear).unwrap()
+            }
+        };
+        (flags, scoped_pdu.data)
+    }
+
+    #[test]
+    fn test_process_auth_priv() {
+        let mut agent = make_agent();
+        let mut oid_map = make_oid_map();
+        let pv = perms();
+        let mut users = usm::Users::new();
+        users
+            .users
+            .push(usm::User::from_str(USER_LINE, &pv).unwrap());
+        let user = users.lookup_user(b"test".to_vec()).unwrap();
+        let src: SocketAddr = "[::1]:50000".parse().unwrap();
+        agent.sort_maps(&mut oid_map);
+
+        // Set over authPriv, then read the new value back
+        let set = Pdus::SetRequest(set_pdu(
+            &[1, 6, 1, 3, 3, 120, 121, 122, 5],
+            simple_from_int(43),
+        ));
+        let request = rasn::ber::encode(&manager_message(&agent, user, 3, set)).unwrap();
+        let reply = agent
+            .process_message(src, &request, &mut oid_map, &users)
+            .unwrap();
+        let (flags, pdus) = manager_reply(&reply, user);
+        assert_eq!(flags & 3, 3);
+        let Pdus::Response(resp) = pdus else {
+            panic!("Expected Response PDU");
+        };
+        assert_eq!(resp.0.error_status, Pdu::ERROR_STATUS_NO_ERROR);
+
+        let get = Pdus::GetRequest(get_pdu(&[1, 6, 1, 3, 3, 120, 121, 122, 5]));
+        let request = rasn::ber::encode(&manager_message(&agent, user, 3, get)).unwrap();
+        let reply = agent
+            .process_message(src, &request, &mut oid_map, &users)
+            .unwrap();
+        let (_, pdus) = manager_reply(&reply, user);
+        let Pdus::Response(resp) = pdus else {
+            panic!("Expected Response PDU");
+        };
+        assert_eq!(
+            resp.0.variable_bindings[0].value,
+            VarBindValue::Value(simple_from_int(43))
+        );
+        assert_eq!(agent.in_pkts, 2);
+    }
+
+    #[test]
+    fn test_process_usm_failures() {
+        let mut agent = make_agent();
+        let mut oid_map = make_oid_map();
+        let pv = perms();
+        let mut users = usm::Users::new();
+        users
+            .users
+            .push(usm::User::from_str(USER_LINE, &pv).unwrap());
+        let user = users.lookup_user(b"test".to_vec()).unwrap();
+        let src: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        agent.sort_maps(&mut oid_map);
+        let report_arc = |reply: &[u8]| {
+            let (_, pdus) = manager_reply(reply, user);
+            let Pdus::Report(rep) = pdus else {
+                panic!("Expected Report PDU");
+            };
+            rep.0.variable_bindings[0].name.clone()
+        };
+
+        // Changing the message after it was signed breaks the digest
+        let get = Pdus::GetRequest(get_pdu(&ARC2));
+        let mut message = manager_message(&agent, user, 1, get);
+        message.global_data.message_id = Integer::from(43);
+        let request = rasn::ber::encode(&message).unwrap();
+        let reply = agent
+            .process_message(src, &request, &mut oid_map, &users)
+            .unwrap();
+        assert_eq!(
+            report_arc(&reply),
+            ObjectIdentifier::new(UsmFailure::WrongDigest.arc()).unwrap()
+        );
+        assert_eq!(agent.wrong_digests.get(), 1);
+
+        // A user we have never heard of
+        let other = usm::User::from_str(
+            "other test sha1 0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b aes 0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c",
+            &pv,
+        )
+        .unwrap();
+        let get = Pdus::GetRequest(get_pdu(&ARC2));
+        let request = rasn::ber::encode(&manager_message(&agent, &other, 1, get)).unwrap();
+        let reply = agent
+            .process_message(src, &request, &mut oid_map, &users)
+            .unwrap();
+        assert_eq!(
+            report_arc(&reply),
+            ObjectIdentifier::new(UsmFailure::UnknownUserName.arc()).unwrap()
+        );
+        assert_eq!(agent.unknown_users.get(), 1);
+
+        // A salt that isn't 8 bytes can't make an IV
+        let get = Pdus::GetRequest(get_pdu(&ARC2));
+        let mut message = manager_message(&agent, user, 3, get);
+        let mut usp: USMSecurityParameters = message
+            .decode_security_parameters(rasn::Codec::Ber)
+            .ok()
+            .unwrap();
+        usp.privacy_parameters = ZB;
+        usp.authentication_parameters = ZB;
+        _ = message.encode_security_parameters(rasn::Codec::Ber, &usp);
+        usm::set_auth(&mut message, user);
+        let request = rasn::ber::encode(&message).unwrap();
+        let reply = agent
+            .process_message(src, &request, &mut oid_map, &users)
+            .unwrap();
+        assert_eq!(
+            report_arc(&reply),
+            ObjectIdentifier::new(UsmFailure::DecryptionError.arc()).unwrap()
+        );
+        assert_eq!(agent.decryption_errors.get(), 1);
+    }
+
+    #[test]
+    fn test_encrypted_without_privacy() {
+        let mut agent = make_agent();
+        let mut oid_map = make_oid_map();
+        let pv = perms();
+        let mut users = usm::Users::new();
+        users
+            .users
+            .push(usm::User::from_str(USER_LINE, &pv).unwrap());
+        users.users.push(
+            usm::User::from_str(
+                "plain test sha1 0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b none -",
+                &pv,
+            )
+            .unwrap(),
+        );
+        let src: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        agent.sort_maps(&mut oid_map);
+
+        // Neither needs a digest, so anyone could send them
+        for (n, name) in [b"plain".to_vec(), b"test".to_vec()]
+            .into_iter()
+            .enumerate()
+        {
+            let user = users.lookup_user(name).unwrap();
+            let get = Pdus::GetRequest(get_pdu(&ARC2));
+            let mut message = manager_message(&agent, user, 0, get);
+            let mut usp: USMSecurityParameters = message
+                .decode_security_parameters(rasn::Codec::Ber)
+                .ok()
+                .unwrap();
+            usp.privacy_parameters = OctetString::from_static(b"saltsalt");
+            _ = message.encode_security_parameters(rasn::Codec::Ber, &usp);
+            message.scoped_data =
+                ScopedPduData::EncryptedPdu(OctetString::from_static(b"\x04\x02no"));
+            let request = rasn::ber::encode(&message).unwrap();
+            assert!(agent
+                .process_message(src, &request, &mut oid_map, &users)
+                .is_none());
+            assert_eq!(agent.decode_error_cnt, n as u32 + 1);
+        }
+    }
+
+    #[test]
+    fn test_start_and_auth_failure_traps() {
+        let mut agent = make_agent();
+        let mut oid_map = make_oid_map();
+        let pv = perms();
+        let mut users = usm::Users::new();
+        users
+            .users
+            .push(usm::User::from_str(USER_LINE, &pv).unwrap());
+        let user = users.lookup_user(b"test".to_vec()).unwrap();
+        let src: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        let sink = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        sink.set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let storage = "/tmp/snmp-rust-agent-traps";
+        let _ = std::fs::remove_dir_all(storage);
+        std::fs::create_dir_all(storage).unwrap();
+        let notify_config = storage.to_string() + ".txt";
+        std::fs::write(
+            &notify_config,
+            format!(
+                "targetAddr sink {0} 100 0 sink-v2c sink\n\
+                 targetParams sink-v2c v2c v2c public noAuthNoPriv\n\
+                 notify sink sink trap\n",
+                sink.local_addr().unwrap()
+            ),
+        )
+        .unwrap();
+        let config = crate::config::Config {
+            storage_path: storage.to_string(),
+            notify_config,
+            ..Default::default()
+        };
+        let destinations = crate::notify::load_notify(&mut OidMap::new(), &config, &users).unwrap();
+        agent.start_notifier().set_destinations(destinations);
+        // snmpTrapOID.0 of the next trap to arrive, if one does
+        let trap_oid = || {
+            let mut buf = [0; 1500];
+            let amt = sink.recv(&mut buf).ok()?;
+            let message: rasn_snmp::v2c::Message<Pdus> = rasn::ber::decode(&buf[..amt]).unwrap();
+            let Pdus::Trap(trap) = message.data else {
+                panic!("Expected a Trap, got {:?}", message.data);
+            };
+            Some(trap.0.variable_bindings[1].value.clone())
+        };
+        let value = |arc: &'static [u32]| {
+            VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::ObjectId(
+                ObjectIdentifier::new(arc).unwrap(),
+            )))
+        };
+
+        // Only once, however often the loop is entered
+        agent.set_warm_start(true);
+        agent.send_start_trap();
+        agent
+            .serve_ready(&mut oid_map, &users, Some(Duration::ZERO))
+            .unwrap();
+        assert_eq!(trap_oid(), Some(value(&notifier::WARM_START_ARC)));
+        assert_eq!(trap_oid(), None);
+
+        let wrong_digest = |agent: &mut Agent, oid_map: &mut OidMap| {
+            let get = Pdus::GetRequest(get_pdu(&ARC2));
+            let mut message = manager_message(agent, user, 1, get);
+            message.global_data.message_id = Integer::from(43);
+            let request = rasn::ber::encode(&message).unwrap();
+            agent.process_message(src, &request, oid_map, &users);
+        };
+        let enable_authen_traps = |value: i32| -> (ObjectIdentifier, Box<dyn OidKeeper>) {
+            let scalar =
+                ScalarMemOid::new(simple_from_int(value), OType::Integer, Access::ReadWrite);
+            (
+                ObjectIdentifier::new(&ENABLE_AUTHEN_TRAPS_ARC[..8]).unwrap(),
+                Box::new(scalar),
+            )
+        };
+        // Not without snmpEnableAuthenTraps, or while it is disabled(2)
+        wrong_digest(&mut agent, &mut oid_map);
+        assert_eq!(trap_oid(), None);
+        let (oid, keeper) = enable_authen_traps(2);
+        oid_map.insert(oid, keeper);
+        wrong_digest(&mut agent, &mut oid_map);
+        assert_eq!(trap_oid(), None);
+
+        // Then only one per interval
+        let (oid, keeper) = enable_authen_traps(1);
+        oid_map.remove(&oid);
+        oid_map.insert(oid, keeper);
+        wrong_digest(&mut agent, &mut oid_map);
+        wrong_digest(&mut agent, &mut oid_map);
+        assert_eq!(
+            trap_oid(),
+            Some(value(&notifier::AUTHENTICATION_FAILURE_ARC))
+        );
+        assert_eq!(trap_oid(), None);
+        agent.set_auth_trap_interval(Duration::ZERO);
+        wrong_digest(&mut agent, &mut oid_map);
+        assert_eq!(
+            trap_oid(),
+            Some(value(&notifier::AUTHENTICATION_FAILURE_ARC))
+        );
+        assert_eq!(agent.wrong_digests.get(), 5);
+    }
+
+    #[test]
+    fn test_serve_ready() {
+        let mut agent = make_agent();
+        agent.listen("127.0.0.1:0/pktinfo").unwrap();
+        agent.listen("[::1]:0").unwrap();
+        let addrs = agent.local_addrs();
+        let mut oid_map = make_oid_map();
+        let users = usm::Users::new();
+        agent.sort_maps(&mut oid_map);
+        let v4 = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let v6 = std::net::UdpSocket::bind("[::1]:0").unwrap();
+        assert_eq!(
+            agent
+                .serve_ready(&mut oid_map, &users, Some(Duration::from_millis(1)))
+                .unwrap(),
+            0
+        );
+        v4.send_to(&discovery_message(), addrs[0]).unwrap();
+        v6.send_to(&discovery_message(), addrs[1]).unwrap();
+        let mut served = 0;
+        while served < 2 {
+            served += agent
+                .serve_ready(&mut oid_map, &users, Some(Duration::from_secs(5)))
+                .unwrap();
+        }
+        // Each reply comes back from the address the request was sent to
+        let mut buf = [0; 1500];
+        for (manager, addr) in [(v4, addrs[0]), (v6, addrs[1])] {
+            let (amt, src) = manager.recv_from(&mut buf).unwrap();
+            assert_eq!(src, addr);
+            let reply: Message = rasn::ber::decode(&buf[..amt]).unwrap();
+            assert_eq!(reply.global_data.message_id, Integer::from(99));
+        }
+        assert_eq!(agent.unknown_engine_ids.get(), 2);
+    }
+
+    #[test]
+    fn test_serve_tcp() {
+        use std::io::{Read, Write};
+        let mut agent = make_agent();
+        agent.listen("tcp:127.0.0.1:0").unwrap();
+        agent.set_tcp_limits(1, Duration::from_millis(200));
+        let addr = agent.local_addrs()[0];
+        let source = agent.request_source();
+        let mut oid_map = make_oid_map();
+        let users = usm::Users::new();
+        agent.sort_maps(&mut oid_map);
+        let mut serve = |agent: &mut Agent| {
+            agent
+                .serve_ready(&mut oid_map, &users, Some(Duration::from_secs(5)))
+                .unwrap()
+        };
+
+        // Two messages in one write, then a third split across two
+        let mut manager = std::net::TcpStream::connect(addr).unwrap();
+        let message = discovery_message();
+        let (head, tail) = message.split_at(10);
+        manager
+            .write_all(&[message.as_slice(), &message, head].concat())
+            .unwrap();
+        assert_eq!(serve(&mut agent), 0); // accept
+        let mut served = 0;
+        while served < 2 {
+            served += serve(&mut agent);
+        }
+        manager.write_all(tail).unwrap();
+        assert_eq!(serve(&mut agent), 1);
+        let mut buf = vec![0; 3000];
+        let mut got = 0;
+        let mut replies = 0;
+        while replies < 3 {
+            got += manager.read(&mut buf[got..]).unwrap();
+            while let Some(len) = transport::ber_message_len(&buf[..got]).unwrap() {
+                if got < len {
+                    break;
+                }
+                let reply: Message = rasn::ber::decode(&buf[..len]).unwrap();
+                assert_eq!(reply.global_data.message_id, Integer::from(99));
+                buf.copy_within(len..got, 0);
+                got -= len;
+                replies += 1;
+            }
+        }
+        assert_eq!(
+            source.get(),
+            Some((TransportDomain::Tcp, manager.local_addr().unwrap()))
+        );
+
+        // Over the limit of one connection
+        let mut second = std::net::TcpStream::connect(addr).unwrap();
+        assert_eq!(serve(&mut agent), 0);
+        assert_eq!(second.read(&mut buf).unwrap(), 0);
+
+        // The first is closed once idle
+        std::thread::sleep(Duration::from_millis(250));
+        assert_eq!(serve(&mut agent), 0);
+        assert_eq!(manager.read(&mut buf).unwrap(), 0);
+        assert_eq!(agent.unknown_engine_ids.get(), 3);
+    }
+
+    /// Request as a manager would send it over TLS or DTLS with the Transport Security Model.
+    fn tsm_message(agent: &Agent, flags: u8, data: Pdus) -> Vec<u8> {
+        let message = Message {
+            version: Integer::from(3),
+            global_data: HeaderData {
+                flags: OctetString::from(vec![flags | REPORTABLE_FLAG]),
+                message_id: Integer::from(77),
+                max_size: Integer::from(MAX_MSG_SIZE),
+                security_model: Integer::from(SECURITY_MODEL_TSM),
+            },
+            scoped_data: ScopedPduData::CleartextPdu(ScopedPdu {
+                engine_id: agent.engine_id.clone(),
+                name: ZB,
+                data,
+            }),
+            security_parameters: ZB,
+        };
+        rasn::ber::encode(&message).unwrap()
+    }
+
+    /// Check a TSM reply to a get of the table cell, returning its error-status.
+    fn tsm_reply(reply: &[u8]) -> u32 {
+        let message: Message = rasn::ber::decode(reply).unwrap();
+        assert_eq!(message.global_data.flags[0], 3);
+        assert_eq!(message.global_data.message_id, Integer::from(77));
+        assert_eq!(
+            message.global_data.security_model,
+            Integer::from(SECURITY_MODEL_TSM)
+        );
+        assert!(message.security_parameters.is_empty());
+        let ScopedPduData::CleartextPdu(scoped_pdu) = message.scoped_data else {
+            panic!("Expected cleartext scoped PDU");
+        };
+        let Pdus::Response(resp) = scoped_pdu.data else {
+            panic!("Expected Response PDU");
+        };
+        resp.0.error_status
+    }
+
+    #[test]
+    fn test_process_tsm() {
+        let mut agent = make_agent();
+        let mut oid_map = make_oid_map();
+        let pv = perms();
+        let mut users = usm::Users::new();
+        users
+            .users
+            .push(usm::User::from_str(USER_LINE, &pv).unwrap());
+        let src: SocketAddr = "127.0.0.1:50000".parse().unwrap();
+        agent.sort_maps(&mut oid_map);
+        let get = || Pdus::GetRequest(get_pdu(&[1, 6, 1, 3, 3, 120, 121, 122, 5]));
+        let request = tsm_message(&agent, 3, get());
+
+        // The securityName takes the group of the user with the same name
+        let reply = agent
+            .process(
+                TransportDomain::Tls,
+                src,
+                Some(b"test"),
+                &request,
+                &mut oid_map,
+                &users,
+            )
+            .unwrap();
+        assert_eq!(tsm_reply(&reply), Pdu::ERROR_STATUS_NO_ERROR);
+        let reply = agent
+            .process(
+                TransportDomain::Dtls,
+                src,
+                Some(b"stranger"),
+                &request,
+                &mut oid_map,
+                &users,
+            )
+            .unwrap();
+        assert_eq!(tsm_reply(&reply), OidErr::AuthorizationError.error_status());
+
+        // Not over a secure transport
+        assert!(agent
+            .process_message(src, &request, &mut oid_map, &users)
+            .is_none());
+        assert_eq!(agent.tsm_invalid_caches.get(), 1);
+
+        // Neither USM nor TSM
+        let mut message: Message = rasn::ber::decode(&request).unwrap();
+        message.global_data.security_model = Integer::from(9);
+        let request = rasn::ber::encode(&message).unwrap();
+        assert!(agent
+            .process_message(src, &request, &mut oid_map, &users)
+            .is_none());
+        assert_eq!(agent.unknown_security_models.get(), 1);
+
+        // TLS and DTLS must be configured first
+        assert!(agent.listen("tls:127.0.0.1:0").is_err());
+        assert!(agent.listen("dtls:127.0.0.1:0").is_err());
+    }
+
+    /// DTLS client end of a connected UDP socket.
+    #[cfg(feature = "tsm")]
+    #[derive(Debug)]
+    struct UdpStream(std::net::UdpSocket);
+
+    #[cfg(feature = "tsm")]
+    impl std::io::Read for UdpStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.0.recv(buf)
+        }
+    }
+
+    #[cfg(feature = "tsm")]
+    impl std::io::Write for UdpStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.send(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "tsm")]
+    #[test]
+    fn test_serve_tls() {
+        use crate::tsm::tests::{map_line, self_signed};
+        use crate::tsm::{CertMapEntry, CertToTsn, TlsConfig};
+        use openssl::ssl::{Ssl, SslContext, SslMethod, SslOptions, SslVerifyMode};
+        use std::io::{Read, Write};
+        use std::thread;
+
+        let (agent_cert, agent_key) = self_signed("agent", "agent.example.com");
+        let (manager_cert, manager_key) = self_signed("manager", "nms.example.com");
+        let dir = std::env::temp_dir().join(format!("snmp-tsm-{0}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cert_file = dir.join("agent.pem");
+        let key_file = dir.join("agent.key");
+        std::fs::write(&cert_file, agent_cert.to_pem().unwrap()).unwrap();
+        std::fs::write(&key_file, agent_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        let mut cert_map = CertToTsn::new();
+        let line = map_line(1, &manager_cert, "specified test");
+        cert_map.add(CertMapEntry::from_str(&line).unwrap());
+        let tls = TlsConfig::new(&cert_file, &key_file, None, cert_map).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut agent = make_agent();
+        agent.set_tls(tls);
+        agent.listen("dtls:127.0.0.1:0").unwrap();
+        agent.listen("tls:127.0.0.1:0").unwrap();
+        let addrs = agent.local_addrs();
+        let mut oid_map = make_oid_map();
+        let pv = perms();
+        let mut users = usm::Users::new();
+        users
+            .users
+            .push(usm::User::from_str(USER_LINE, &pv).unwrap());
+        agent.sort_maps(&mut oid_map);
+        let request = tsm_message(
+            &agent,
+            3,
+            Pdus::GetRequest(get_pdu(&[1, 6, 1, 3, 3, 120, 121, 122, 5])),
+        );
+        let client_ctx = |method| {
+            let mut builder = SslContext::builder(method).unwrap();
+            builder.set_certificate(&manager_cert).unwrap();
+            builder.set_private_key(&manager_key).unwrap();
+            builder.set_verify(SslVerifyMode::NONE);
+            builder.set_options(SslOptions::NO_QUERY_MTU);
+            builder.build()
+        };
+
+        // A client that connects to the TLS port and says nothing holds up no one
+        let silent = std::net::TcpStream::connect(addrs[1]).unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            agent
+                .serve_ready(&mut oid_map, &users, Some(Duration::from_millis(50)))
+                .unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(agent.connections.len(), 1);
+
+        // The managers run on their own threads, as their ends of the handshakes block
+        let tls_ctx = client_ctx(SslMethod::tls_client());
+        let tls_request = request.clone();
+        let tls_addr = addrs[1];
+        let tls_manager = thread::spawn(move || {
+            let stream = std::net::TcpStream::connect(tls_addr).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            let mut stream = Ssl::new(&tls_ctx).unwrap().connect(stream).unwrap();
+            stream.write_all(&tls_request).unwrap();
+            let mut buf = vec![0; 3000];
+            let mut got = 0;
+            loop {
+                got += stream.read(&mut buf[got..]).unwrap();
+                if let Some(len) = transport::ber_message_len(&buf[..got]).unwrap() {
+                    if got >= len {
+                        return buf[..len].to_vec();
+                    }
+                }
+            }
+        });
+        let dtls_ctx = client_ctx(SslMethod::dtls_client());
+        let dtls_addr = addrs[0];
+        let dtls_manager = thread::spawn(move || {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket.connect(dtls_addr).unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(10)))
+                .unwrap();
+            let mut ssl = Ssl::new(&dtls_ctx).unwrap();
+            ssl.set_mtu(1400).unwrap();
+            let mut stream = ssl.connect(UdpStream(socket)).unwrap();
+            stream.write_all(&request).unwrap();
+            let mut buf = vec![0; 3000];
+            let amt = stream.read(&mut buf).unwrap();
+            buf[..amt].to_vec()
+        });
+        while !(tls_manager.is_finished() && dtls_manager.is_finished()) {
+            agent
+                .serve_ready(&mut oid_map, &users, Some(Duration::from_millis(50)))
+                .unwrap();
+        }
+        let reply = tls_manager.join().unwrap();
+        assert_eq!(tsm_reply(&reply), Pdu::ERROR_STATUS_NO_ERROR);
+        let reply = dtls_manager.join().unwrap();
+        assert_eq!(tsm_reply(&reply), Pdu::ERROR_STATUS_NO_ERROR);
+        assert_eq!(agent.tsm_invalid_caches.get(), 0);
+        drop(silent);
+    }
+
+    #[test]
+    fn test_serve_agentx() {
+        use crate::agentx::{self, AgentxPdu, Payload, Region, NETWORK_BYTE_ORDER};
+        use std::io::{Read, Write};
+        use std::os::unix::net::UnixStream;
+        use std::thread;
+
+        const SUBTREE: [u32; 7] = [1, 3, 6, 1, 4, 1, 99];
+        const INSTANCE: [u32; 9] = [1, 3, 6, 1, 4, 1, 99, 1, 0];
+
+        fn read_pdu(stream: &mut UnixStream) -> AgentxPdu {
+            let mut buf = vec![0; 20];
+            stream.read_exact(&mut buf).unwrap();
+            buf.resize(agentx::pdu_len(&buf).unwrap().unwrap(), 0);
+            stream.read_exact(&mut buf[20..]).unwrap();
+            AgentxPdu::decode(&buf).unwrap()
+        }
+
+        fn request(stream: &mut UnixStream, session_id: u32, payload: Payload) -> u16 {
+            let pdu = AgentxPdu {
+                flags: NETWORK_BYTE_ORDER,
+                session_id,
+                transaction_id: 0,
+                packet_id: 1,
+                context: None,
+                payload,
+            };
+            stream.write_all(&pdu.encode()).unwrap();
+            match read_pdu(stream).payload {
+                Payload::Response { error, .. } => error,
+                other => panic!("Expected a Response, got {other:?}"),
+            }
+        }
+
+        fn register(subtree: &'static [u32]) -> Payload {
+            Payload::Register {
+                timeout: 0,
+                region: Region {
+                    priority: 127,
+                    subtree: ObjectIdentifier::new(subtree).unwrap(),
+                    range_subid: 0,
+                    upper_bound: 0,
+                },
+            }
+        }
+
+        let path = std::env::temp_dir().join(format!("snmp-agentx-{0}", std::process::id()));
+        let mut agent = make_agent();
+        agent.listen_agentx(&path).unwrap();
+        let mut oid_map = make_oid_map();
+        let users = usm::Users::new();
+        agent.sort_maps(&mut oid_map);
+
+        let subagent_path = path.clone();
+        let subagent = thread::spawn(move || {
+            let mut stream = UnixStream::connect(subagent_path).unwrap();
+            // Session zero has not been opened
+            assert_eq!(
+                request(&mut stream, 0, register(&SUBTREE)),
+                agentx::NOT_OPEN
+            );
+            let open = AgentxPdu {
+                flags: NETWORK_BYTE_ORDER,
+                session_id: 0,
+                transaction_id: 0,
+                packet_id: 1,
+                context: None,
+                payload: Payload::Open {
+                    timeout: 0,
+                    id: ObjectIdentifier::new(&SUBTREE).unwrap(),
+                    descr: b"test subagent".to_vec(),
+                },
+            };
+            stream.write_all(&open.encode()).unwrap();
+            let session_id = read_pdu(&mut stream).session_id;
+            // Overlaps the table in the agent's own OidMap
+            assert_eq!(
+                request(&mut stream, session_id, register(&[1, 6, 1])),
+                agentx::DUPLICATE_REGISTRATION
+            );
+            assert_eq!(
+                request(&mut stream, session_id, register(&SUBTREE)),
+                agentx::NO_AGENTX_ERROR
+            );
+            let instance = ObjectIdentifier::new(&INSTANCE).unwrap();
+            let mut seen = vec![];
+            loop {
+                let pdu = read_pdu(&mut stream);
+                let (error, varbinds) = match &pdu.payload {
+                    Payload::Get(ranges) => {
+                        let value = if ranges[0].start == instance {
+                            VarBindValue::Value(simple_from_int(42))
+                        } else {
+                            VarBindValue::NoSuchObject
+                        };
+                        let name = ranges[0].start.clone();
+                        (0, vec![VarBind { name, value }])
+                    }
+                    Payload::GetNext(ranges) if ranges[0].start < instance => {
+                        let value = VarBindValue::Value(simple_from_int(42));
+                        let name = instance.clone();
+                        (0, vec![VarBind { name, value }])
+                    }
+                    Payload::GetNext(ranges) => {
+                        let value = VarBindValue::EndOfMibView;
+                        let name = ranges[0].start.clone();
+                        (0, vec![VarBind { name, value }])
+                    }
+                    Payload::TestSet(varbinds)
+                        if varbinds[0].value == VarBindValue::Value(simple_from_int(7)) =>
+                    {
+                        (0, vec![])
+                    }
+                    Payload::TestSet(_) => (Pdu::ERROR_STATUS_WRONG_VALUE as u16, vec![]),
+                    Payload::CommitSet => (0, vec![]),
+                    Payload::CleanupSet => {
+                        seen.push(pdu.payload);
+                        if seen.len() == 9 {
+                            break;
+                        }
+                        continue;
+                    }
+                    other => panic!("Unexpected {other:?}"),
+                };
+                let index = if error == 0 { 0 } else { 1 };
+                let reply = pdu.response(0, error, index, varbinds);
+                stream.write_all(&reply.encode()).unwrap();
+                seen.push(pdu.payload);
+            }
+            seen
+        });
+
+        let serve_until = |agent: &mut Agent, oid_map: &mut OidMap, registrations| {
+            while agent.agentx.as_ref().unwrap().registrations() != registrations {
+                agent
+                    .serve_ready(oid_map, &users, Some(Duration::from_millis(50)))
+                    .unwrap();
+            }
+        };
+        serve_until(&mut agent, &mut oid_map, 1);
+        assert_eq!(oid_map.len(), 2);
+        let perm = &perms()[0];
+        let mut vb: Vec<VarBind> = vec![];
+        agent.get(&mut oid_map, get_pdu(&INSTANCE), &mut vb, perm, 3);
+        agent.get(
+            &mut oid_map,
+            get_pdu(&[1, 3, 6, 1, 4, 1, 99, 2, 0]),
+            &mut vb,
+            perm,
+            3,
+        );
+        assert_eq!(vb[0].value, VarBindValue::Value(simple_from_int(42)));
+        assert_eq!(vb[1].value, VarBindValue::NoSuchObject);
+        vb.clear();
+
+        // Walk into the subagent's subtree, and out of it to the agent's table
+        agent.getnext(&mut oid_map, get_next_pdu(&[1, 3]), &mut vb, perm, 3);
+        agent.getnext(&mut oid_map, get_next_pdu(&INSTANCE), &mut vb, perm, 3);
+        assert_eq!(vb[0].name, ObjectIdentifier::new(&INSTANCE).unwrap());
+        assert_eq!(
+            vb[1].name,
+            ObjectIdentifier::new(&[1, 6, 1, 1, 3, 97, 98, 99, 4]).unwrap()
+        );
+        vb.clear();
+
+        let sp = set_pdu(&INSTANCE, simple_from_int(8));
+        let (status, idx, _) = agent.set(&mut oid_map, sp, &mut vb, perm, 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_WRONG_VALUE);
+        assert_eq!(idx, 1);
+        vb.clear();
+        let sp = set_pdu(&INSTANCE, simple_from_int(7));
+        let (status, _, _) = agent.set(&mut oid_map, sp, &mut vb, perm, 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_ERROR);
+
+        let seen = subagent.join().unwrap();
+        assert!(matches!(seen[0], Payload::Get(_)));
+        assert!(matches!(seen[3], Payload::GetNext(_)));
+        assert!(matches!(seen[4], Payload::TestSet(_)));
+        assert_eq!(seen[5], Payload::CleanupSet);
+        assert_eq!(seen[7], Payload::CommitSet);
+        assert_eq!(seen[8], Payload::CleanupSet);
+        // Disconnecting removes the registration
+        serve_until(&mut agent, &mut oid_map, 0);
+        assert_eq!(oid_map.len(), 1);
+        drop(agent);
+        assert!(!path.exists());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_run() {
+        let mut agent = Agent::build(OctetString::from_static(b"test"), "127.0.0.1:3173");
+        let mut oid_map = make_oid_map();
+        let users = usm::Users::new();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let manager = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let shutdown = async {
+                manager
+                    .send_to(&discovery_message(), "127.0.0.1:3173")
+                    .await
+                    .unwrap();
+                let mut buf = [0; 1500];
+                let amt = manager.recv(&mut buf).await.unwrap();
+                let reply: Message = rasn::ber::decode(&buf[..amt]).unwrap();
+                assert_eq!(reply.global_data.message_id, Integer::from(99));
+            };
+            agent.run(&mut oid_map, users, shutdown).await.unwrap();
+        });
+        assert_eq!(agent.unknown_engine_ids.get(), 1);
+    }
+
+    #[test]
+    fn test_reports() {
+        let agent = make_agent();
+        let pv = perms();
+        let user = usm::User::from_str(USER_LINE, &pv).unwrap();
+        let message_id = Integer::from(77);
+
+        // Unauthenticated report for an unknown user
+        let report = agent.report(
+            5,
+            message_id.clone(),
+            UsmFailure::UnknownUserName,
+            3,
+            OctetString::from_static(b"nobody"),
+            None,
+        );
+        assert_eq!(report.global_data.flags.to_vec(), vec![0u8]);
+        assert_eq!(report.global_data.message_id, message_id);
+        let ScopedPduData::CleartextPdu(scoped_pdu) = &report.scoped_data else {
+            panic!("Report should be in clear");
+        };
+        let Pdus::Report(rep) = &scoped_pdu.data else {
+            panic!("Expected Report PDU");
+        };
+        assert_eq!(rep.0.request_id, 5);
+        assert_eq!(
+            rep.0.variable_bindings[0].name,
+            ObjectIdentifier::new(&[1, 3, 6, 1, 6, 3, 15, 1, 1, 3, 0]).unwrap()
+        );
+        assert_eq!(
+            rep.0.variable_bindings[0].value,
+            VarBindValue::Value(ObjectSyntax::ApplicationWide(ApplicationSyntax::Counter(
+                Counter32 { 0: 3 }
+            )))
+        );
+
+        // notInTimeWindow report is authenticated, and carries our boots and time,
+        // so it passes our own checks.
+        let mut report = agent.report(
+            0,
+            message_id,
+            UsmFailure::NotInTimeWindow,
+            1,
+            OctetString::from_slice(&user.name),
+            Some(&user),
+        );
+        assert_eq!(report.global_data.flags.to_vec(), vec![1u8]);
+        let usp: USMSecurityParameters = report
+            .decode_security_parameters(rasn::Codec::Ber)
+            .ok()
+            .unwrap();
+        assert_eq!(agent.check_auth(&mut report, &user, &usp), None);
+
+        let mut bad_usp = usp.clone();
+        bad_usp.authentication_parameters = Z12;
+        assert_eq!(
+            agent.check_auth(&mut report, &user, &bad_usp),
+            Some(UsmFailure::WrongDigest)
+        );
+    }
+
+    #[test]
+    fn test_flags_level() {
+        assert_eq!(flags_level(0), Some(1));
+        assert_eq!(flags_level(1 | REPORTABLE_FLAG), Some(2));
+        assert_eq!(flags_level(3), Some(3));
+        // Privacy without authentication is invalid
+        assert_eq!(flags_level(2), None);
+        let counter = StatsCounter::default();
+        let shared = counter.clone();
+        assert_eq!(counter.incr(), 1);
+        assert_eq!(shared.get(), 1);
+    }
+
+    fn bulk_pdu(arg: &'static [u32], max_repetitions: u32) -> GetBulkRequest {
+        let vb = vec![VarBind {
+            name: ObjectIdentifier::new(arg).unwrap(),
+            value: VarBindValue::Unspecified,
+        }];
+        GetBulkRequest(BulkPdu {
+            request_id: 1,
+            non_repeaters: 0,
+            max_repetitions,
+            variable_bindings: vb,
+        })
+    }
+
+    #[test]
+    fn test_bulk_budget() {
+        let agent = make_agent();
+        let mut oid_map = make_oid_map();
+        let mut vb: Vec<VarBind> = vec![];
+        let (status, _, _) = agent.bulk(
+            &mut oid_map,
+            bulk_pdu(&ARC2, 4),
+            &mut vb,
+            &perms()[0],
+            3,
+            MAX_MSG_SIZE,
+        );
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_ERROR);
+        assert_eq!(vb.len(), 4);
+        let one = encoded_len(&vb[0]);
+        vb.clear();
+        // Room for a little over one varbind
+        let (status, _, _) = agent.bulk(
+            &mut oid_map,
+            bulk_pdu(&ARC2, 4),
+            &mut vb,
+            &perms()[0],
+            3,
+            one + 1,
+        );
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_ERROR);
+        assert_eq!(vb.len(), 1);
+        vb.clear();
+        // Six cells in the table, then a repetition of endOfMibView ends the walk
+        let (status, _, _) = agent.bulk(
+            &mut oid_map,
+            bulk_pdu(&ARC2, 20),
+            &mut vb,
+            &perms()[0],
+            3,
+            MAX_MSG_SIZE,
+        );
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_ERROR);
+        assert_eq!(vb.len(), 7);
+        assert_eq!(vb[6].value, VarBindValue::EndOfMibView);
+    }
+
+    #[test]
+    fn test_too_big() {
+        let agent = make_agent();
+        let mut oid_map = make_oid_map();
+        let pv = perms();
+        let user = usm::User::from_str(USER_LINE, &pv).unwrap();
+        let usp = USMSecurityParameters {
+            authoritative_engine_boots: Integer::from(1),
+            authoritative_engine_id: OctetString::from_static(b"test"),
+            authoritative_engine_time: Integer::from(1),
+            user_name: OctetString::from_static(b"test"),
+            authentication_parameters: Z12,
+            privacy_parameters: OctetString::from_static(b"saltsalt"),
+        };
+        let budget = agent.varbind_budget(484, &user, &usp, 7);
+        assert!(budget > 0 && budget < 484);
+        let scoped_pdu = ScopedPdu {
+            engine_id: OctetString::from_static(b"test"),
+            name: ZB,
+            data: Pdus::GetRequest(get_pdu(&[1, 6, 1, 2, 3, 120, 121, 122, 5])),
+        };
+        let resp = agent
+            .do_scoped_pdu(3, &Principal::usm(&user), scoped_pdu, &mut oid_map, 4)
+            .unwrap();
+        assert_eq!(resp.0.error_status, Pdu::ERROR_STATUS_TOO_BIG);
+        assert_eq!(resp.0.error_index, 0);
+        assert!(resp.0.variable_bindings.is_empty());
+    }
+
+    /// Scalar keeper that can be told to fail commit or undo. A failing commit
+    /// leaves the new value in place, as a badly behaved keeper might.
+    struct FakeKeeper {
+        value: i32,
+        pending: i32,
+        previous: i32,
+        fail_commit: bool,
+        fail_undo: bool,
+    }
+
+    impl FakeKeeper {
+        fn boxed(fail_commit: bool, fail_undo: bool) -> Box<dyn OidKeeper> {
+            Box::new(FakeKeeper {
+                value: 1,
+                pending: 1,
+                previous: 1,
+                fail_commit,
+                fail_undo,
+            })
+        }
+    }
+
+    impl OidKeeper for FakeKeeper {
+        fn is_scalar(&self, _oid: ObjectIdentifier) -> bool {
+            true
+        }
+        fn get(&self, _oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
+            Ok(VarBindValue::Value(simple_from_int(self.value)))
+        }
+        fn get_next(&self, _oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
+            Err(OidErr::OutOfRange)
+        }
+        fn access(&self, _oid: ObjectIdentifier) -> Access {
+            Access::ReadWrite
+        }
+        fn begin_transaction(&mut self) -> Result<(), OidErr> {
+            self.previous = self.value;
+            Ok(())
+        }
+        fn set(
+            &mut self,
+            _oid: ObjectIdentifier,
+            value: VarBindValue,
+        ) -> Result<VarBindValue, OidErr> {
+            if let VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::Integer(i))) = &value {
+                self.pending = i.try_into().unwrap();
+                Ok(value)
+            } else {
+                Err(OidErr::WrongType)
+            }
+        }
+        fn commit(&mut self) -> Result<(), OidErr> {
+            self.value = self.pending;
+            if self.fail_commit {
+                return Err(OidErr::GenErr);
+            }
+            Ok(())
+        }
+        fn rollback(&mut self) -> Result<(), OidErr> {
+            self.value = self.previous;
+            self.pending = self.previous;
+            Ok(())
+        }
+        fn undo(&mut self) -> Result<(), OidErr> {
+            if self.fail_undo {
+                return Err(OidErr::GenErr);
+            }
+            self.value = self.previous;
+            Ok(())
+        }
+    }
+
+    fn fake_map(first: Box<dyn OidKeeper>, second: Box<dyn OidKeeper>) -> OidMap {
+        let mut om = OidMap::new();
+        // Pushed out of order, commit order must still follow the OIDs
+        om.push(ObjectIdentifier::new(&[1, 5]).unwrap(), second);
+        om.push(ObjectIdentifier::new(&[1, 4]).unwrap(), first);
+        om.sort();
+        om
+    }
+
+    fn two_set_pdu() -> SetRequest {
+        let vb = vec![
+            VarBind {
+                name: ObjectIdentifier::new(&[1, 5]).unwrap(),
+                value: VarBindValue::Value(simple_from_int(7)),
+            },
+            VarBind {
+                name: ObjectIdentifier::new(&[1, 4]).unwrap(),
+                value: VarBindValue::Value(simple_from_int(8)),
+            },
+        ];
+        SetRequest(Pdu {
+            request_id: 1,
+            error_status: 0,
+            error_index: 0,
+            variable_bindings: vb,
+        })
+    }
+
+    fn fake_value(oid_map: &mut OidMap, arc: &'static [u32]) -> VarBindValue {
+        let oid = ObjectIdentifier::new(arc).unwrap();
+        let which = oid_map.search(&oid).unwrap();
+        oid_map.idx(which).get(oid).unwrap()
+    }
+
+    #[test]
+    fn test_set_commit_ok() {
+        let agent = make_agent();
+        let mut oid_map = fake_map(
+            FakeKeeper::boxed(false, false),
+            FakeKeeper::boxed(false, false),
+        );
+        let mut vb: Vec<VarBind> = vec![];
+        let (status, idx, _) = agent.set(&mut oid_map, two_set_pdu(), &mut vb, &perms()[0], 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_NO_ERROR);
+        assert_eq!(idx, 0);
+        assert_eq!(
+            fake_value(&mut oid_map, &[1, 4]),
+            VarBindValue::Value(simple_from_int(8))
+        );
+        assert_eq!(
+            fake_value(&mut oid_map, &[1, 5]),
+            VarBindValue::Value(simple_from_int(7))
+        );
+    }
+
+    #[test]
+    fn test_set_commit_failed() {
+        let agent = make_agent();
+        // [1, 4] commits first, then [1, 5] fails
+        let mut oid_map = fake_map(
+            FakeKeeper::boxed(false, false),
+            FakeKeeper::boxed(true, false),
+        );
+        let mut vb: Vec<VarBind> = vec![];
+        let (status, idx, _) = agent.set(&mut oid_map, two_set_pdu(), &mut vb, &perms()[0], 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_COMMIT_FAILED);
+        // [1, 5] is the first varbind in the PDU
+        assert_eq!(idx, 1);
+        assert_eq!(
+            fake_value(&mut oid_map, &[1, 4]),
+            VarBindValue::Value(simple_from_int(1))
+        );
+        assert_eq!(
+            fake_value(&mut oid_map, &[1, 5]),
+            VarBindValue::Value(simple_from_int(1))
+        );
+
+        // A failure in the first keeper to commit needs no undo
+        let mut oid_map = fake_map(
+            FakeKeeper::boxed(true, true),
+            FakeKeeper::boxed(false, true),
+        );
+        vb.clear();
+        let (status, idx, _) = agent.set(&mut oid_map, two_set_pdu(), &mut vb, &perms()[0], 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_COMMIT_FAILED);
+        assert_eq!(idx, 2);
+        assert_eq!(
+            fake_value(&mut oid_map, &[1, 5]),
+            VarBindValue::Value(simple_from_int(1))
+        );
+    }
+
+    #[test]
+    fn test_set_undo_failed() {
+        let agent = make_agent();
+        let mut oid_map = fake_map(
+            FakeKeeper::boxed(false, true),
+            FakeKeeper::boxed(true, false),
+        );
+        let mut vb: Vec<VarBind> = vec![];
+        let (status, idx, _) = agent.set(&mut oid_map, two_set_pdu(), &mut vb, &perms()[0], 3);
+        assert_eq!(status, Pdu::ERROR_STATUS_UNDO_FAILED);
+        assert_eq!(idx, 0);
+    }
+
     // FIXME add tests for more set cases and bulk, and maybe do at least some through do_scoped_pdu.
     // Maybe do some cfg[test] to allow testing of main loop code? Or refactor into small loop
     // and handle_packet?
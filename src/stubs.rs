@@ -2,10 +2,14 @@
 //!
 //! Do not edit - it will be over-written next time you run stub-gen
 use crate::config::ComplianceStatements;
+use crate::notifier::NotificationSender;
 use crate::oidmap::OidMap;
 
-
 ///Generated function to load all stubs
-pub fn load_stubs(oid_map: &mut OidMap, comp: &mut ComplianceStatements) {
-    //  for example if_stub::load_stub(oid_map, comp);
-}
\ No newline at end of file
+pub fn load_stubs(
+    _oid_map: &mut OidMap,
+    _notifications: &NotificationSender,
+    _comp: &mut ComplianceStatements,
+) {
+    //  for example if_stub::load_stub(oid_map, notifications, comp);
+}
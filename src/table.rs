@@ -1,9 +1,12 @@
 use crate::keeper::{check_type, Access, OType, OidErr, OidKeeper};
-use log::{debug, warn};
+use log::{debug, error, warn};
 use num_traits::cast::ToPrimitive;
+use rasn::ber::{decode, encode};
 use rasn::types::{Integer, ObjectIdentifier, OctetString};
 use rasn_smi::v2::{ApplicationSyntax, ObjectSyntax, SimpleSyntax};
 use rasn_snmp::v3::{VarBind, VarBindValue};
+use std::io::Error;
+use std::sync::{Arc, Mutex};
 
 pub const ROW_STATUS_ACTIVE: u32 = 1u32;
 pub const ROW_STATUS_NOT_IN_SERVICE: u32 = 2u32;
@@ -24,6 +27,17 @@ pub struct TableMemOid {
     pending: Vec<(Vec<u32>, VarBindValue)>,
     implied_last: bool,
     in_transaction: bool,
+    required_cols: Vec<usize>,
+    previous_rows: Option<Vec<(Vec<u32>, Vec<ObjectSyntax>)>>,
+}
+
+/// RowStatus value carried by a varbind, if it is an integer.
+fn row_status(value: &VarBindValue) -> Option<u32> {
+    if let VarBindValue::Value(ObjectSyntax::Simple(SimpleSyntax::Integer(i))) = value {
+        i.to_u32()
+    } else {
+        None
+    }
 }
 
 impl TableMemOid {
@@ -52,9 +66,41 @@ impl TableMemOid {
             pending: vec![],
             implied_last,
             in_transaction: false,
+            required_cols: vec![],
+            previous_rows: None,
         }
     }
 
+    /// Columns (1 based) that must be supplied along with createAndGo.
+    ///
+    /// Only called from generated stubs, so may be unused in a given build.
+    #[allow(dead_code)]
+    pub fn set_required_cols(&mut self, cols: Vec<usize>) {
+        self.required_cols = cols;
+    }
+
+    fn status_col(&self) -> Option<usize> {
+        self.otypes
+            .iter()
+            .position(|otype| *otype == OType::RowStatus)
+            .map(|n| n + 1)
+    }
+
+    fn row_exists(&self, index: &[u32]) -> bool {
+        self.rows
+            .binary_search_by(|a| a.0.as_slice().cmp(index))
+            .is_ok()
+    }
+
+    /// RowStatus value pending in this transaction for the row with this index
+    fn pending_status(&self, index: &[u32]) -> Option<u32> {
+        let status_col = self.status_col()?;
+        self.pending
+            .iter()
+            .find(|(psuffix, _)| psuffix[1] as usize == status_col && psuffix[2..] == *index)
+            .and_then(|(_, value)| row_status(value))
+    }
+
     /// Bulk load data at creation for normal indexing
     pub fn set_data(&mut self, data: Vec<Vec<ObjectSyntax>>) {
         let mut row_data = Vec::new();
@@ -66,7 +112,15 @@ impl TableMemOid {
         self.rows = row_data;
     }
 
+    /// Row data in index order, without the indices.
+    pub fn rows(&self) -> impl Iterator<Item = &Vec<ObjectSyntax>> {
+        self.rows.iter().map(|(_, row)| row)
+    }
+
     /// Alternate data load for foreign indexed cases
+    ///
+    /// Only called from generated stubs, so may be unused in a given build.
+    #[allow(dead_code)]
     pub fn set_indexed_data(&mut self, data: Vec<(Vec<u32>, Vec<ObjectSyntax>)>) {
         let mut row_data = Vec::new();
         for row in data {
@@ -93,7 +147,7 @@ impl TableMemOid {
                             let sl: u32 = s.len().try_into().unwrap();
                             ret.push(sl);
                         }
-                        for ir in s.to_vec() {
+                        for ir in s.iter().copied() {
                             let ui32: u32 = ir.into();
                             ret.push(ui32);
                         }
@@ -169,6 +223,7 @@ impl TableMemOid {
                             let item = *itemp;
                             text.push(item.try_into().unwrap());
                         }
+                        idx_idx += slen;
                     }
                     row[*index_column_number - 1] =
                         ObjectSyntax::Simple(SimpleSyntax::String(OctetString::from_slice(&text)));
@@ -188,6 +243,7 @@ impl TableMemOid {
                             let item = *itemp;
                             arc.push(item);
                         }
+                        idx_idx += slen;
                     }
                     row[*index_column_number - 1] = ObjectSyntax::Simple(SimpleSyntax::ObjectId(
                         ObjectIdentifier::new(arc).unwrap().to_owned(),
@@ -282,7 +338,7 @@ impl OidKeeper for TableMemOid {
                 return Ok(VarBindValue::Value(row.1[col - 1].clone()));
             }
         }
-        Err(OidErr::NoSuchName)
+        Err(OidErr::NoSuchInstance)
     }
 
     fn get_next(&self, oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
@@ -379,8 +435,6 @@ impl OidKeeper for TableMemOid {
 
     /// Supports updating existing cells, and new row creation via RowStatus column using CreateAndWait
     ///
-    /// CreateAndGo will be in future version
-    ///
     /// This doesn't actual make changes yet, but does checking, and adds the arguments
     /// to the pending transaction. If the whole PDU is OK, then the transaction is applied.
     /// If an error is found, possibly from a completely different MIB, then the transaction is rolled back.
@@ -423,118 +477,140 @@ impl OidKeeper for TableMemOid {
             }
         }
         let index = &suffix[2..];
-        let s_res = self.rows.binary_search_by(|a| a.0.cmp(&index.to_vec()));
-        if s_res.is_err() {
-            // Then it is either row creation, or refers to a row created earlier the transaction,
-            // or it is an error.
-            // Is there a pending row with this index?
-            for (pidx, _) in &self.pending {
-                if pidx[2..] == *index {
-                    self.pending.push((suffix, value.clone()));
-                    return Ok(value);
+        if !self.row_exists(index) && self.status_col().is_none() {
+            // No RowStatus, so rows cannot be created
+            return Err(OidErr::NoSuchInstance);
+        }
+        // Values for new rows are staged here, and test checks that the row
+        // really is being created in this transaction.
+        self.pending.push((suffix, value.clone()));
+        Ok(value)
+    }
+
+    /// Check the staged changes for consistency across the whole transaction.
+    ///
+    /// createAndGo needs all the required columns in the same PDU, columns of a new
+    /// row need the row to be created, and RowStatus changes must suit the row.
+    fn test(&self, oid: ObjectIdentifier, value: &VarBindValue) -> Result<(), OidErr> {
+        let suffix = self.suffix(oid);
+        let col: usize = suffix[1] as usize;
+        let index = &suffix[2..];
+        let exists = self.row_exists(index);
+        if self.otypes[col - 1] != OType::RowStatus {
+            if exists {
+                return Ok(());
+            }
+            return match self.pending_status(index) {
+                Some(ROW_STATUS_CREATE_AND_GO) | Some(ROW_STATUS_CREATE_AND_WAIT) => Ok(()),
+                _ => Err(OidErr::InconsistentName),
+            };
+        }
+        match row_status(value) {
+            Some(ROW_STATUS_ACTIVE)
+            | Some(ROW_STATUS_NOT_IN_SERVICE)
+            | Some(ROW_STATUS_DESTROY) => {
+                if exists {
+                    Ok(())
+                } else {
+                    Err(OidErr::InconsistentValue)
                 }
             }
-            // Is it row creation?
-            if let VarBindValue::Value(new_value) = value.clone() {
-                if self.otypes[col - 1] == OType::RowStatus
-                    && new_value
-                        == ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(
-                            ROW_STATUS_CREATE_AND_WAIT,
-                        )))
-                {
-                    self.pending.push((suffix, value.clone()));
-                    return Ok(value);
+            Some(ROW_STATUS_CREATE_AND_WAIT) => {
+                if exists {
+                    Err(OidErr::InconsistentValue)
+                } else {
+                    Ok(())
                 }
             }
-            // Then it is an error
-            return Err(OidErr::NoSuchInstance);
+            Some(ROW_STATUS_CREATE_AND_GO) => {
+                if exists {
+                    return Err(OidErr::InconsistentValue);
+                }
+                for req in &self.required_cols {
+                    let supplied = self
+                        .pending
+                        .iter()
+                        .any(|(psuffix, _)| psuffix[1] as usize == *req && psuffix[2..] == *index);
+                    if !supplied {
+                        debug!("createAndGo without column {req}");
+                        return Err(OidErr::InconsistentValue);
+                    }
+                }
+                Ok(())
+            }
+            // notReady can never be written
+            _ => Err(OidErr::WrongValue),
         }
-        self.pending.push((suffix, value.clone()));
-        Ok(value)
     }
 
+    /// Apply the pending changes, which test has already checked.
+    ///
+    /// New rows are created first, then column values are written, then
+    /// RowStatus changes applied, so the order of varbinds in the PDU does not matter.
     fn commit(&mut self) -> Result<(), OidErr> {
         if !self.in_transaction {
             warn!("Commit - but not in transaction!");
             // Should raise some sort of error?
         }
         self.in_transaction = false;
-        let pending = self.pending.clone();
-        for (suffix, value) in pending {
+        let pending = std::mem::take(&mut self.pending);
+        // Kept for undo, and to put back if this commit fails part way
+        self.previous_rows = Some(self.rows.clone());
+        let status_col = self.status_col();
+        // Row creation
+        for (suffix, value) in &pending {
             let col: usize = suffix[1] as usize;
             let index = &suffix[2..];
-            let mut delete_me = false;
-            let mut delete_idx: usize = 0;
-            let s_res = self.rows.binary_search_by(|a| a.0.cmp(&index.to_vec()));
-            match s_res {
-                Ok(sidx) => {
-                    let row = &mut self.rows[sidx];
-                    if let VarBindValue::Value(new_value) = value.clone() {
-                        if check_type(self.otypes[col - 1], &new_value) {
-                            if self.otypes[col - 1] == OType::RowStatus {
-                                if new_value
-                                    == ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(
-                                        ROW_STATUS_DESTROY,
-                                    )))
-                                {
-                                    delete_me = true;
-                                    delete_idx = sidx;
-                                } else if new_value
-                                    == ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(
-                                        ROW_STATUS_ACTIVE,
-                                    )))
-                                    || new_value
-                                        == ObjectSyntax::Simple(SimpleSyntax::Integer(
-                                            Integer::from(ROW_STATUS_NOT_IN_SERVICE),
-                                        ))
-                                {
-                                    continue;
-                                } else {
-                                    return Err(OidErr::WrongType);
-                                }
-                            } else {
-                                row.1[col - 1] = new_value;
-                                continue;
-                            }
-                        } else {
-                            return Err(OidErr::WrongType);
-                        }
-                    }
-                    if delete_me {
-                        self.rows.remove(delete_idx);
-                        continue;
+            if Some(col) != status_col || self.row_exists(index) {
+                continue;
+            }
+            let mut row: Vec<ObjectSyntax> = self.row_from_index(index);
+            if row_status(value) == Some(ROW_STATUS_CREATE_AND_GO) {
+                row[col - 1] =
+                    ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(ROW_STATUS_ACTIVE)));
+            }
+            self.add_row(&row);
+        }
+        // Column values
+        for (suffix, value) in &pending {
+            let col: usize = suffix[1] as usize;
+            if Some(col) == status_col {
+                continue;
+            }
+            let index = &suffix[2..];
+            if let VarBindValue::Value(new_value) = value {
+                if !check_type(self.otypes[col - 1], new_value) {
+                    if let Some(rows) = self.previous_rows.take() {
+                        self.rows = rows;
                     }
+                    return Err(OidErr::WrongType);
                 }
-                Err(_) => {
-                    warn!("index not matched in set {index:?}");
-                    if let VarBindValue::Value(new_value) = value.clone() {
-                        if self.otypes[col - 1] == OType::RowStatus {
-                            if new_value
-                                == ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(
-                                    ROW_STATUS_CREATE_AND_WAIT,
-                                )))
-                            {
-                                let row: Vec<ObjectSyntax> = self.row_from_index(index);
-                                self.add_row(&row);
-                                continue;
-                            }
-                            if new_value
-                                == ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(
-                                    ROW_STATUS_CREATE_AND_GO,
-                                )))
-                            {
-                                warn!["CreateAndGo not supported"];
-                                return Err(OidErr::WrongType);
-                            }
-                        }
+                match self.rows.binary_search_by(|a| a.0.as_slice().cmp(index)) {
+                    Ok(sidx) => self.rows[sidx].1[col - 1] = new_value.clone(),
+                    Err(_) => warn!("index not matched in set {index:?}"),
+                }
+            }
+        }
+        // RowStatus changes to existing rows
+        for (suffix, value) in &pending {
+            let col: usize = suffix[1] as usize;
+            if Some(col) != status_col {
+                continue;
+            }
+            let index = &suffix[2..];
+            if let Ok(sidx) = self.rows.binary_search_by(|a| a.0.as_slice().cmp(index)) {
+                match row_status(value) {
+                    Some(ROW_STATUS_DESTROY) => {
+                        self.rows.remove(sidx);
+                    }
+                    Some(status @ (ROW_STATUS_ACTIVE | ROW_STATUS_NOT_IN_SERVICE)) => {
+                        self.rows[sidx].1[col - 1] =
+                            ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(status)));
                     }
+                    _ => (),
                 }
             }
-
-            // No existing row matches. So either it is a row creation request
-            // or an error.
         }
-        self.pending.clear();
         Ok(())
     }
 
@@ -547,6 +623,212 @@ impl OidKeeper for TableMemOid {
         self.pending.clear();
         Ok(())
     }
+
+    fn undo(&mut self) -> Result<(), OidErr> {
+        // Only the last commit can be undone, and only once
+        if let Some(rows) = self.previous_rows.take() {
+            self.rows = rows;
+        }
+        Ok(())
+    }
+}
+
+/// Table that writes its rows to a file after every commit or undo.
+///
+/// Rows are reloaded with set_data, so the index columns must all be real columns.
+pub struct PersistentTable {
+    table: TableMemOid,
+    file_name: String,
+}
+
+impl PersistentTable {
+    pub fn new(table: TableMemOid, file_name: String) -> Self {
+        PersistentTable { table, file_name }
+    }
+
+    pub fn load(&mut self) -> Result<(), Error> {
+        debug!["file name {0:?}", self.file_name];
+        let bytes = std::fs::read(self.file_name.clone())?;
+        match decode::<Vec<Vec<ObjectSyntax>>>(&bytes) {
+            Ok(data) => {
+                self.table.set_data(data);
+                Ok(())
+            }
+            Err(err) => {
+                panic!["Decode failure {err:?}"];
+            }
+        }
+    }
+
+    /// The table itself, for handlers that read rows directly.
+    pub fn table(&self) -> &TableMemOid {
+        &self.table
+    }
+
+    /// Replace the rows, without saving.
+    pub fn set_data(&mut self, data: Vec<Vec<ObjectSyntax>>) {
+        self.table.set_data(data);
+    }
+
+    /// Write the current rows to the file
+    fn save(&self) {
+        let data: Vec<Vec<ObjectSyntax>> = self.table.rows().cloned().collect();
+        match encode(&data) {
+            Ok(bytes) => {
+                if std::fs::write(&self.file_name, bytes).is_err() {
+                    error!["Write failure saving to {0}", self.file_name]
+                }
+            }
+            Err(err) => {
+                error!["Persistence failure {err:?}"];
+            }
+        }
+    }
+}
+
+impl OidKeeper for PersistentTable {
+    fn is_scalar(&self, _oid: ObjectIdentifier) -> bool {
+        false
+    }
+
+    fn is_empty(&self) -> bool {
+        self.table.is_empty()
+    }
+
+    fn get(&self, oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
+        self.table.get(oid)
+    }
+
+    fn get_next(&self, oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
+        self.table.get_next(oid)
+    }
+
+    fn access(&self, oid: ObjectIdentifier) -> Access {
+        self.table.access(oid)
+    }
+
+    fn begin_transaction(&mut self) -> Result<(), OidErr> {
+        self.table.begin_transaction()
+    }
+
+    fn set(&mut self, oid: ObjectIdentifier, value: VarBindValue) -> Result<VarBindValue, OidErr> {
+        self.table.set(oid, value)
+    }
+
+    fn test(&self, oid: ObjectIdentifier, value: &VarBindValue) -> Result<(), OidErr> {
+        self.table.test(oid, value)
+    }
+
+    fn commit(&mut self) -> Result<(), OidErr> {
+        let comm_res = self.table.commit();
+        self.save();
+        comm_res
+    }
+
+    fn rollback(&mut self) -> Result<(), OidErr> {
+        self.table.rollback()
+    }
+
+    fn undo(&mut self) -> Result<(), OidErr> {
+        let undo_res = self.table.undo();
+        self.save();
+        undo_res
+    }
+}
+
+/// A PersistentTable shared between its keeper and the code that acts on its rows.
+pub type SharedTable = Arc<Mutex<PersistentTable>>;
+
+/// Integer value in column col (1 based) of row, or 0.
+pub(crate) fn int_col(row: &[ObjectSyntax], col: usize) -> u32 {
+    match &row[col - 1] {
+        ObjectSyntax::Simple(SimpleSyntax::Integer(i)) => i.to_u32().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// String value in column col (1 based) of row, or empty.
+pub(crate) fn str_col(row: &[ObjectSyntax], col: usize) -> &[u8] {
+    match &row[col - 1] {
+        ObjectSyntax::Simple(SimpleSyntax::String(s)) => s,
+        _ => &[],
+    }
+}
+
+/// OID value in column col (1 based) of row, or empty.
+pub(crate) fn oid_col(row: &[ObjectSyntax], col: usize) -> Vec<u32> {
+    match &row[col - 1] {
+        ObjectSyntax::Simple(SimpleSyntax::ObjectId(o)) => o.to_vec(),
+        _ => vec![],
+    }
+}
+
+/// Keeper for a SharedTable, such as the VACM tables shared with Vacm.
+///
+/// A table indexed by a column of another table, like vacmAccessTable by vacmGroupName, keeps
+/// that index in an extra last column, so the rows can be saved and reloaded. The keeper hides
+/// it from managers.
+pub(crate) struct SharedTableKeeper {
+    table: SharedTable,
+    base_len: usize,
+    hidden_col: Option<u32>,
+}
+
+impl SharedTableKeeper {
+    /// Keeper for table, with its OID base_len long, hiding column hidden_col if given.
+    pub(crate) fn new(table: SharedTable, base_len: usize, hidden_col: Option<usize>) -> Self {
+        SharedTableKeeper {
+            table,
+            base_len,
+            hidden_col: hidden_col.map(|col| col as u32),
+        }
+    }
+
+    /// Column that oid refers to, if it is the hidden one
+    fn is_hidden(&self, oid: &ObjectIdentifier) -> bool {
+        self.hidden_col.is_some() && oid.get(self.base_len + 1).copied() == self.hidden_col
+    }
+}
+
+impl OidKeeper for SharedTableKeeper {
+    fn is_scalar(&self, _oid: ObjectIdentifier) -> bool {
+        false
+    }
+    fn get(&self, oid: ObjectIdentifier) -> Result<VarBindValue, OidErr> {
+        if self.is_hidden(&oid) {
+            return Err(OidErr::NoSuchName);
+        }
+        self.table.lock().unwrap().get(oid)
+    }
+    fn get_next(&self, oid: ObjectIdentifier) -> Result<VarBind, OidErr> {
+        let bind = self.table.lock().unwrap().get_next(oid)?;
+        // The hidden column is last, so reaching it is the end of the table
+        if self.is_hidden(&bind.name) {
+            return Err(OidErr::OutOfRange);
+        }
+        Ok(bind)
+    }
+    fn access(&self, oid: ObjectIdentifier) -> Access {
+        self.table.lock().unwrap().access(oid)
+    }
+    fn set(&mut self, oid: ObjectIdentifier, value: VarBindValue) -> Result<VarBindValue, OidErr> {
+        self.table.lock().unwrap().set(oid, value)
+    }
+    fn begin_transaction(&mut self) -> Result<(), OidErr> {
+        self.table.lock().unwrap().begin_transaction()
+    }
+    fn test(&self, oid: ObjectIdentifier, value: &VarBindValue) -> Result<(), OidErr> {
+        self.table.lock().unwrap().test(oid, value)
+    }
+    fn commit(&mut self) -> Result<(), OidErr> {
+        self.table.lock().unwrap().commit()
+    }
+    fn rollback(&mut self) -> Result<(), OidErr> {
+        self.table.lock().unwrap().rollback()
+    }
+    fn undo(&mut self) -> Result<(), OidErr> {
+        self.table.lock().unwrap().undo()
+    }
 }
 
 #[cfg(test)]
@@ -666,6 +948,32 @@ mod tests {
         assert_eq!(tab.rows.len(), 3);
     }
 
+    #[test]
+    fn test_undo() {
+        let mut tab = tab_fixture();
+        let o3 = ObjectIdentifier::new(&[1, 6, 1, 3, 3, 120, 121, 122, 5]).unwrap();
+        // Nothing committed yet, so nothing to undo
+        assert!(tab.undo().is_ok());
+        assert_eq!(tab.rows.len(), 2);
+        assert!(tab.begin_transaction().is_ok());
+        let set_res = tab.set(o3.clone(), VarBindValue::Value(simple_from_int(99)));
+        assert!(set_res.is_ok());
+        assert!(tab.commit().is_ok());
+        assert_eq!(
+            tab.get(o3.clone()),
+            Ok(VarBindValue::Value(simple_from_int(99)))
+        );
+        assert!(tab.undo().is_ok());
+        assert_eq!(
+            tab.get(o3.clone()),
+            Ok(VarBindValue::Value(simple_from_int(42)))
+        );
+        // A second undo leaves the table alone
+        assert!(tab.undo().is_ok());
+        assert_eq!(tab.rows.len(), 2);
+        assert_eq!(tab.get(o3), Ok(VarBindValue::Value(simple_from_int(42))));
+    }
+
     #[test]
     fn test_create_and_wait() {
         let oid2: ObjectIdentifier = ObjectIdentifier::new(&ARC2).unwrap();
@@ -702,6 +1010,100 @@ mod tests {
         assert!(tab.rollback().is_ok());
     }
 
+    fn status_fixture() -> TableMemOid {
+        let oid2: ObjectIdentifier = ObjectIdentifier::new(&ARC2).unwrap();
+        let mut tab = TableMemOid::new(
+            vec![simple_from_int(1), simple_from_int(0), simple_from_int(1)],
+            3,
+            &oid2,
+            vec![OType::Integer, OType::Integer, OType::RowStatus],
+            vec![Access::ReadOnly, Access::ReadCreate, Access::ReadCreate],
+            vec![1usize],
+            false,
+        );
+        tab.set_required_cols(vec![2]);
+        tab
+    }
+
+    #[test]
+    fn test_create_and_go() {
+        let mut tab = status_fixture();
+        let status: ObjectIdentifier = ObjectIdentifier::new(&[1, 6, 1, 3, 7]).unwrap();
+        let value: ObjectIdentifier = ObjectIdentifier::new(&[1, 6, 1, 2, 7]).unwrap();
+        let go = VarBindValue::Value(simple_from_int(4));
+
+        // Missing the required column
+        assert!(tab.begin_transaction().is_ok());
+        assert!(tab.set(status.clone(), go.clone()).is_ok());
+        assert_eq!(
+            tab.test(status.clone(), &go),
+            Err(OidErr::InconsistentValue)
+        );
+        assert!(tab.rollback().is_ok());
+        assert_eq!(tab.rows.len(), 0);
+
+        // Column before RowStatus in the PDU is fine
+        let v9 = VarBindValue::Value(simple_from_int(9));
+        assert!(tab.begin_transaction().is_ok());
+        assert!(tab.set(value.clone(), v9.clone()).is_ok());
+        assert!(tab.set(status.clone(), go.clone()).is_ok());
+        assert_eq!(tab.test(value.clone(), &v9), Ok(()));
+        assert_eq!(tab.test(status.clone(), &go), Ok(()));
+        assert!(tab.commit().is_ok());
+        assert_eq!(tab.get(value.clone()), Ok(v9));
+        assert_eq!(
+            tab.get(status.clone()),
+            Ok(VarBindValue::Value(simple_from_int(1)))
+        );
+
+        // Creating again is inconsistent
+        assert!(tab.begin_transaction().is_ok());
+        assert!(tab.set(status.clone(), go.clone()).is_ok());
+        assert_eq!(tab.test(status, &go), Err(OidErr::InconsistentValue));
+        assert!(tab.rollback().is_ok());
+    }
+
+    #[test]
+    fn test_no_row_creation() {
+        let mut tab = status_fixture();
+        let value: ObjectIdentifier = ObjectIdentifier::new(&[1, 6, 1, 2, 8]).unwrap();
+        let v9 = VarBindValue::Value(simple_from_int(9));
+        assert!(tab.begin_transaction().is_ok());
+        assert!(tab.set(value.clone(), v9.clone()).is_ok());
+        assert_eq!(tab.test(value, &v9), Err(OidErr::InconsistentName));
+        assert!(tab.rollback().is_ok());
+        let status: ObjectIdentifier = ObjectIdentifier::new(&[1, 6, 1, 3, 8]).unwrap();
+        let not_ready = VarBindValue::Value(simple_from_int(3));
+        assert!(tab.begin_transaction().is_ok());
+        assert!(tab.set(status.clone(), not_ready.clone()).is_ok());
+        assert_eq!(tab.test(status, &not_ready), Err(OidErr::WrongValue));
+        assert!(tab.rollback().is_ok());
+    }
+
+    #[test]
+    fn test_two_string_index() {
+        let oid2: ObjectIdentifier = ObjectIdentifier::new(&ARC2).unwrap();
+        let mut tab = TableMemOid::new(
+            vec![
+                simple_from_str(b""),
+                simple_from_str(b""),
+                simple_from_int(0),
+            ],
+            3,
+            &oid2,
+            vec![OType::String, OType::String, OType::Integer],
+            vec![Access::NoAccess, Access::NoAccess, Access::ReadCreate],
+            vec![1usize, 2usize],
+            false,
+        );
+        let row = tab.row_from_index(&[2, 97, 98, 3, 120, 121, 122]);
+        assert_eq!(row[0], simple_from_str(b"ab"));
+        assert_eq!(row[1], simple_from_str(b"xyz"));
+        tab.set_data(vec![row]);
+        let cell = ObjectIdentifier::new(&[1, 6, 1, 3, 2, 97, 98, 3, 120, 121, 122]).unwrap();
+        assert_eq!(tab.get(cell), Ok(VarBindValue::Value(simple_from_int(0))));
+    }
+
     /*#[test]
     fn test_foreign_table() {
         let tab = tab_fixture();
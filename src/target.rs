@@ -0,0 +1,214 @@
+//! Management targets, in the style of SNMP-TARGET-MIB, RFC 3413 section 4.1
+//!
+//! A target address says where messages go, how long to wait for a reply and how many times to
+//! retry, and names the target parameters, which say how the messages are secured. Both are read
+//! from a text file with a line per entry, starting with a keyword:
+//! * "targetAddr name address timeout retries params [tag ...]", like snmpTargetAddrTable. The
+//!   address is host:port, or [address]:port for IPv6, with an optional "udp:" prefix, as UDP is
+//!   the only transport supported. The timeout is in hundredths of a second.
+//! * "targetParams name mpModel securityModel securityName securityLevel", like
+//!   snmpTargetParamsTable. mpModel is "v2c" or "v3", securityModel is "v2c", "usm" or "tsm",
+//!   and securityLevel is "noAuthNoPriv", "authNoPriv" or "authPriv". With v2c, the
+//!   securityName is the community.
+//! * "usmUser name hash authkey privacy privkey", a user of another engine for usm targets, as in
+//!   users.txt without the group. The keys are localized to that engine's snmpEngineID.
+//!
+//! For example "targetAddr router1 192.0.2.1:161 100 2 router1-v2c" with
+//! "targetParams router1-v2c v2c v2c public noAuthNoPriv".
+//!
+//! Blank lines and lines starting with "#" are skipped. Other keywords are left to the modules
+//! using the targets, like the proxy module.
+use crate::transport::{self, TransportDomain};
+use crate::usm::User;
+use crate::vacm::{SECURITY_MODEL_TSM, SECURITY_MODEL_USM};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// SNMPv2c security model, the community string
+pub const SECURITY_MODEL_V2C: u32 = 2;
+
+/// Message processing model, snmpTargetParamsMPModel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MpModel {
+    V2c,
+    V3,
+}
+
+/// Where to send messages, a row of snmpTargetAddrTable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TargetAddr {
+    pub name: String,
+    pub address: SocketAddr,
+    pub timeout: Duration,
+    pub retry_count: u32,
+    pub params: String,
+    pub tags: Vec<String>,
+}
+
+/// How to secure messages, a row of snmpTargetParamsTable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TargetParams {
+    pub name: String,
+    pub mp_model: MpModel,
+    pub security_model: u32,
+    pub security_name: Vec<u8>,
+    /// Same scale as Perm: 1 noAuthNoPriv, 2 authNoPriv, 3 authPriv
+    pub security_level: u8,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseTargetError;
+
+impl FromStr for TargetAddr {
+    type Err = ParseTargetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() < 6 || parts[0] != "targetAddr" {
+            return Err(ParseTargetError);
+        }
+        let address = match transport::split_domain(parts[2]) {
+            (TransportDomain::Udp, addr) => {
+                transport::parse_listen_addr(addr).map_err(|_| ParseTargetError)?
+            }
+            _ => return Err(ParseTargetError),
+        };
+        let centiseconds = u64::from_str(parts[3]).map_err(|_| ParseTargetError)?;
+        Ok(TargetAddr {
+            name: parts[1].to_string(),
+            address,
+            timeout: Duration::from_millis(centiseconds * 10),
+            retry_count: u32::from_str(parts[4]).map_err(|_| ParseTargetError)?,
+            params: parts[5].to_string(),
+            tags: parts[6..].iter().map(|tag| tag.to_string()).collect(),
+        })
+    }
+}
+
+impl FromStr for TargetParams {
+    type Err = ParseTargetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() != 6 || parts[0] != "targetParams" {
+            return Err(ParseTargetError);
+        }
+        let mp_model = match parts[2] {
+            "v2c" => MpModel::V2c,
+            "v3" => MpModel::V3,
+            _ => return Err(ParseTargetError),
+        };
+        let security_model = match (mp_model, parts[3]) {
+            (MpModel::V2c, "v2c") => SECURITY_MODEL_V2C,
+            (MpModel::V3, "usm") => SECURITY_MODEL_USM,
+            (MpModel::V3, "tsm") => SECURITY_MODEL_TSM,
+            _ => return Err(ParseTargetError),
+        };
+        let security_level = match parts[5] {
+            "noAuthNoPriv" => 1,
+            "authNoPriv" => 2,
+            "authPriv" => 3,
+            _ => return Err(ParseTargetError),
+        };
+        // A community gives no security at all
+        if security_model == SECURITY_MODEL_V2C && security_level != 1 {
+            return Err(ParseTargetError);
+        }
+        Ok(TargetParams {
+            name: parts[1].to_string(),
+            mp_model,
+            security_model,
+            security_name: parts[4].as_bytes().to_vec(),
+            security_level,
+        })
+    }
+}
+
+/// Target addresses and parameters, with the users of other engines they refer to.
+#[derive(Default)]
+pub struct Targets {
+    pub addrs: Vec<TargetAddr>,
+    pub params: Vec<TargetParams>,
+    pub users: Vec<User<'static>>,
+}
+
+impl Targets {
+    pub fn new() -> Self {
+        Targets::default()
+    }
+
+    /// Add the entry on a line of the file, returning false if the keyword is not ours.
+    pub fn add_line(&mut self, line: &str) -> Result<bool, ParseTargetError> {
+        match line.split_whitespace().next() {
+            Some("targetAddr") => self.addrs.push(TargetAddr::from_str(line)?),
+            Some("targetParams") => self.params.push(TargetParams::from_str(line)?),
+            Some("usmUser") => {
+                let rest: Vec<&str> = line.split_whitespace().skip(1).collect();
+                let user = User::remote_from_str(&rest.join(" ")).map_err(|_| ParseTargetError)?;
+                self.users.push(user);
+            }
+            _ => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// The target address called name.
+    pub fn addr(&self, name: &str) -> Option<&TargetAddr> {
+        self.addrs.iter().find(|addr| addr.name == name)
+    }
+
+    /// The target parameters called name.
+    pub fn params(&self, name: &str) -> Option<&TargetParams> {
+        self.params.iter().find(|params| params.name == name)
+    }
+
+    /// The user of another engine called name.
+    pub fn user(&self, name: &[u8]) -> Option<&User<'static>> {
+        self.users.iter().find(|user| user.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_targets() {
+        let mut targets = Targets::new();
+        for line in [
+            "targetAddr router1 192.0.2.1:161 150 2 router1-v2c tag1 tag2",
+            "targetAddr router2 udp:[2001:db8::1]:1161 100 0 router2-usm",
+            "targetParams router1-v2c v2c v2c public noAuthNoPriv",
+            "targetParams router2-usm v3 usm proxy authPriv",
+            "usmUser proxy sha1 0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b aes 0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c",
+        ] {
+            assert_eq!(targets.add_line(line), Ok(true));
+        }
+        assert_eq!(targets.add_line("proxy p1 read - ctx in out"), Ok(false));
+        let router1 = targets.addr("router1").unwrap();
+        assert_eq!(router1.address, "192.0.2.1:161".parse().unwrap());
+        assert_eq!(router1.timeout, Duration::from_millis(1500));
+        assert_eq!(router1.retry_count, 2);
+        assert_eq!(router1.tags, ["tag1", "tag2"]);
+        let router2 = targets.addr("router2").unwrap();
+        assert_eq!(router2.address, "[2001:db8::1]:1161".parse().unwrap());
+        assert!(router2.tags.is_empty());
+        let params = targets.params("router2-usm").unwrap();
+        assert_eq!(params.mp_model, MpModel::V3);
+        assert_eq!(params.security_model, SECURITY_MODEL_USM);
+        assert_eq!(params.security_level, 3);
+        assert_eq!(targets.user(b"proxy").unwrap().security_level(), 3);
+
+        for bad in [
+            "targetAddr router3 tcp:192.0.2.1:161 100 2 params",
+            "targetAddr router3 192.0.2.1:161 ten 2 params",
+            "targetParams bad v2c usm public noAuthNoPriv",
+            "targetParams bad v2c v2c public authNoPriv",
+            "targetParams bad v3 usm user someLevel",
+            "usmUser proxy md5 - none -",
+        ] {
+            assert_eq!(targets.add_line(bad), Err(ParseTargetError), "{bad}");
+        }
+    }
+}
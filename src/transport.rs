@@ -0,0 +1,847 @@
+//! UDP and TCP sockets for the agent loop.
+//!
+//! Listen addresses are written as for std, for example "0.0.0.0:161" or "[2001:db8::1]:161".
+//! IPv6 link local addresses need a scope ID, which may be numeric, "[fe80::1%2]:161", or an
+//! interface name, "[fe80::1%eth0]:161".
+//!
+//! Binding the unspecified IPv6 address "[::]:161" gives a dual stack socket, which also accepts
+//! IPv4 whatever the system default for IPV6_V6ONLY. Don't list "0.0.0.0:161" as well, as the
+//! two would clash on the port.
+//!
+//! On Linux, a "/pktinfo" suffix, as in "0.0.0.0:161/pktinfo", records the destination
+//! address of each request with IP_PKTINFO or IPV6_RECVPKTINFO, and sends the response from
+//! that address. Without it, a socket bound to a wildcard address replies from whatever address
+//! the kernel picks, and on multi-homed hosts managers that check the source drop the reply.
+//!
+//! A "tcp:" prefix, as in "tcp:0.0.0.0:161", listens for SNMP over TCP as in RFC 3430. There is
+//! no framing beyond the BER encoding, each message being delimited by its own length. "udp:" may
+//! be given for UDP, which is the default. "tls:" and "dtls:" listen for the Transport Security
+//! Model over TLS and DTLS, RFC 6353, which needs the tsm feature.
+//!
+use socket2::{Domain, Protocol, Socket, Type};
+use std::ffi::CString;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, SocketAddrV6, TcpListener, TcpStream, UdpSocket};
+use std::os::fd::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+/// Transport a request arrived over.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransportDomain {
+    #[default]
+    Udp,
+    Tcp,
+    Tls,
+    Dtls,
+}
+
+/// Split the transport prefix, if any, from a Listen address.
+pub fn split_domain(listen: &str) -> (TransportDomain, &str) {
+    for (prefix, domain) in [
+        ("udp:", TransportDomain::Udp),
+        ("tcp:", TransportDomain::Tcp),
+        ("tls:", TransportDomain::Tls),
+        ("dtls:", TransportDomain::Dtls),
+    ] {
+        if let Some(rest) = listen.strip_prefix(prefix) {
+            return (domain, rest);
+        }
+    }
+    (TransportDomain::Udp, listen)
+}
+
+/// Parse a Listen address, resolving an interface name used as an IPv6 scope ID.
+pub fn parse_listen_addr(addr_str: &str) -> io::Result<SocketAddr> {
+    if let Ok(addr) = addr_str.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, addr_str.to_string());
+    // Only "[addr%ifname]:port" is left to try
+    let (host, port) = addr_str.rsplit_once("]:").ok_or_else(invalid)?;
+    let (ip, if_name) = host
+        .strip_prefix('[')
+        .and_then(|h| h.split_once('%'))
+        .ok_or_else(invalid)?;
+    let ip = ip.parse().map_err(|_| invalid())?;
+    let port = port.parse().map_err(|_| invalid())?;
+    let c_name = CString::new(if_name).map_err(|_| invalid())?;
+    // SAFETY: c_name is a valid NUL terminated string that outlives the call.
+    let scope_id = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+    if scope_id == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No interface {if_name} for {addr_str}"),
+        ));
+    }
+    Ok(SocketAddr::V6(SocketAddrV6::new(ip, port, 0, scope_id)))
+}
+
+/// Bind a UDP socket to a Listen address, dual stack for "[::]".
+pub fn bind_udp(addr_str: &str) -> io::Result<UdpSocket> {
+    let addr = parse_listen_addr(addr_str)?;
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if let SocketAddr::V6(v6) = addr {
+        socket.set_only_v6(!v6.ip().is_unspecified())?;
+    }
+    socket.bind(&addr.into())?;
+    Ok(socket.into())
+}
+
+/// Bind a TCP listener to a Listen address, dual stack for "[::]".
+pub fn bind_tcp(addr_str: &str) -> io::Result<TcpListener> {
+    let addr = parse_listen_addr(addr_str)?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if let SocketAddr::V6(v6) = addr {
+        socket.set_only_v6(!v6.ip().is_unspecified())?;
+    }
+    // Allow a restart while old connections are in TIME_WAIT
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Suffix on a Listen address that turns on replying from the request's destination address.
+const PKTINFO_OPTION: &str = "/pktinfo";
+
+/// A UDP socket the agent listens on, with its per address options.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    pktinfo: bool,
+}
+
+impl UdpTransport {
+    /// Bind to a Listen address, which may have the "/pktinfo" suffix.
+    pub fn bind(listen: &str) -> io::Result<Self> {
+        let (addr_str, pktinfo) = match listen.strip_suffix(PKTINFO_OPTION) {
+            Some(addr_str) => (addr_str, true),
+            None => (listen, false),
+        };
+        let socket = bind_udp(addr_str)?;
+        if pktinfo {
+            pktinfo::enable(&socket)?;
+        }
+        Ok(UdpTransport { socket, pktinfo })
+    }
+
+    /// Local address the socket is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Another handle to the same socket, with the same options.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(UdpTransport {
+            socket: self.socket.try_clone()?,
+            pktinfo: self.pktinfo,
+        })
+    }
+
+    /// Switch the socket to or from non-blocking mode.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.socket.set_nonblocking(nonblocking)
+    }
+
+    /// Receive a datagram, returning its length, its source and, with pktinfo, the address it was sent to.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+        if self.pktinfo {
+            return pktinfo::recv(&self.socket, buf);
+        }
+        let (amt, src) = self.socket.recv_from(buf)?;
+        Ok((amt, src, None))
+    }
+
+    /// Send a datagram to dst, from local address from if pktinfo is on and it is known.
+    pub fn send(&self, buf: &[u8], dst: SocketAddr, from: Option<IpAddr>) -> io::Result<usize> {
+        match from {
+            Some(from) if self.pktinfo => pktinfo::send(&self.socket, buf, dst, from),
+            _ => self.socket.send_to(buf, dst),
+        }
+    }
+}
+
+impl AsRawFd for UdpTransport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+/// Total length of the BER encoded message at the start of buf, including its header.
+///
+/// Ok(None) if more bytes are needed to tell. An error means the peer is not sending
+/// SNMP messages, as they are always a definite length SEQUENCE.
+pub fn ber_message_len(buf: &[u8]) -> io::Result<Option<usize>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let Some(&tag) = buf.first() else {
+        return Ok(None);
+    };
+    if tag != 0x30 {
+        return Err(invalid("Message is not a SEQUENCE"));
+    }
+    let Some(&first) = buf.get(1) else {
+        return Ok(None);
+    };
+    if first < 0x80 {
+        return Ok(Some(2 + first as usize));
+    }
+    let len_len = (first & 0x7f) as usize;
+    if len_len == 0 || len_len > 4 {
+        return Err(invalid("Indefinite or oversized length"));
+    }
+    let Some(len_bytes) = buf.get(2..2 + len_len) else {
+        return Ok(None);
+    };
+    let len = len_bytes
+        .iter()
+        .fold(0usize, |acc, byte| acc << 8 | *byte as usize);
+    Ok(Some(2 + len_len + len))
+}
+
+/// Longest a handshake, such as TLS, may take before the connection is closed.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Byte stream carrying SNMP messages, either plain TCP or TLS on top of it.
+pub trait MessageStream: Read + Write + Send {
+    /// The TCP stream underneath, for polling and socket options.
+    fn tcp(&self) -> &TcpStream;
+}
+
+impl MessageStream for TcpStream {
+    fn tcp(&self) -> &TcpStream {
+        self
+    }
+}
+
+/// A handshake in progress on a non-blocking socket, taken a step further each time it is ready.
+pub trait Handshake: Send {
+    /// The TCP stream underneath, for polling.
+    fn tcp(&self) -> &TcpStream;
+    /// Whether the handshake is waiting to write, rather than to read.
+    fn wants_write(&self) -> bool;
+    /// Go on with the handshake as far as it can without blocking.
+    fn resume(self: Box<Self>) -> io::Result<HandshakeStep>;
+}
+
+/// Where a handshake has got to.
+pub enum HandshakeStep {
+    /// Finished, giving the stream and the tmSecurityName of the session.
+    Done(Box<dyn MessageStream>, Vec<u8>),
+    /// Waiting on the manager.
+    Pending(Box<dyn Handshake>),
+}
+
+enum StreamState {
+    Handshake(Box<dyn Handshake>),
+    Open(Box<dyn MessageStream>),
+    /// Left by a handshake that failed, until the connection is dropped.
+    Closed,
+}
+
+/// An SNMP over TCP or TLS connection, holding any part of a message received so far.
+///
+/// The socket is non-blocking, so a manager that is slow with its handshake, or slow to take its
+/// responses, holds up only its own connection.
+pub struct TcpConnection {
+    state: StreamState,
+    peer: SocketAddr,
+    buf: Vec<u8>,
+    out: Vec<u8>,
+    opened: Instant,
+    last_active: Instant,
+    domain: TransportDomain,
+    security_name: Option<Vec<u8>>,
+}
+
+impl TcpConnection {
+    /// Wrap an accepted TCP stream.
+    pub fn new(stream: TcpStream, peer: SocketAddr) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        let state = StreamState::Open(Box::new(stream));
+        TcpConnection::with_state(state, peer, TransportDomain::Tcp, None)
+    }
+
+    /// Wrap a connection for domain whose handshake has been started on a non-blocking socket.
+    pub fn with_handshake(
+        step: HandshakeStep,
+        peer: SocketAddr,
+        domain: TransportDomain,
+    ) -> io::Result<Self> {
+        match step {
+            HandshakeStep::Done(stream, name) => {
+                TcpConnection::with_state(StreamState::Open(stream), peer, domain, Some(name))
+            }
+            HandshakeStep::Pending(handshake) => {
+                TcpConnection::with_state(StreamState::Handshake(handshake), peer, domain, None)
+            }
+        }
+    }
+
+    fn with_state(
+        state: StreamState,
+        peer: SocketAddr,
+        domain: TransportDomain,
+        security_name: Option<Vec<u8>>,
+    ) -> io::Result<Self> {
+        let now = Instant::now();
+        let connection = TcpConnection {
+            state,
+            peer,
+            buf: vec![],
+            out: vec![],
+            opened: now,
+            last_active: now,
+            domain,
+            security_name,
+        };
+        if let Some(tcp) = connection.tcp() {
+            tcp.set_nodelay(true)?;
+        }
+        Ok(connection)
+    }
+
+    fn tcp(&self) -> Option<&TcpStream> {
+        match &self.state {
+            StreamState::Handshake(handshake) => Some(handshake.tcp()),
+            StreamState::Open(stream) => Some(stream.tcp()),
+            StreamState::Closed => None,
+        }
+    }
+
+    /// Address of the manager.
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Transport of the connection.
+    pub fn domain(&self) -> TransportDomain {
+        self.domain
+    }
+
+    /// tmSecurityName from the manager's certificate, for TLS.
+    pub fn security_name(&self) -> Option<&[u8]> {
+        self.security_name.as_deref()
+    }
+
+    /// Time since anything was received or sent.
+    pub fn idle(&self) -> Duration {
+        self.last_active.elapsed()
+    }
+
+    /// Time left before the connection should be closed, as it has been idle for idle_timeout,
+    /// or its handshake has gone on for longer than HANDSHAKE_TIMEOUT.
+    pub fn time_left(&self, idle_timeout: Duration) -> Duration {
+        match self.state {
+            StreamState::Open(_) => idle_timeout.saturating_sub(self.idle()),
+            _ => HANDSHAKE_TIMEOUT.saturating_sub(self.opened.elapsed()),
+        }
+    }
+
+    /// Whether to wait for the socket to be writable rather than readable, as the handshake
+    /// needs to write, or there are responses the manager hasn't taken yet.
+    pub fn wants_write(&self) -> bool {
+        match &self.state {
+            StreamState::Handshake(handshake) => handshake.wants_write(),
+            _ => !self.out.is_empty(),
+        }
+    }
+
+    /// Go on with the connection now its socket is ready, returning the messages now complete.
+    ///
+    /// Call when the socket is readable, or writable if wants_write said so. A handshake in
+    /// progress is taken a step further, and queued responses are sent. Then, unless some are
+    /// still waiting, everything available is read, as a TLS record may arrive in pieces.
+    /// Nothing blocks. An error, including end of file or a message longer than max_len,
+    /// means the connection should be closed.
+    pub fn read_messages(&mut self, max_len: usize) -> io::Result<Vec<Vec<u8>>> {
+        match std::mem::replace(&mut self.state, StreamState::Closed) {
+            StreamState::Handshake(handshake) => match handshake.resume()? {
+                HandshakeStep::Pending(handshake) => {
+                    self.state = StreamState::Handshake(handshake);
+                    return Ok(vec![]);
+                }
+                HandshakeStep::Done(stream, name) => {
+                    self.state = StreamState::Open(stream);
+                    self.security_name = Some(name);
+                    self.last_active = Instant::now();
+                }
+            },
+            state => self.state = state,
+        }
+        self.flush()?;
+        // No more requests until the manager has taken the responses so far
+        if !self.out.is_empty() {
+            return Ok(vec![]);
+        }
+        let StreamState::Open(stream) = &mut self.state else {
+            return Err(io::ErrorKind::NotConnected.into());
+        };
+        let mut messages = vec![];
+        let mut chunk = [0; 8192];
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(amt) => {
+                    self.buf.extend_from_slice(&chunk[..amt]);
+                    self.last_active = Instant::now();
+                    // Checked as it arrives, so buf never holds more than one message
+                    take_messages(&mut self.buf, max_len, &mut messages)?;
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Send a whole message, queueing whatever the socket won't take straight away.
+    pub fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.out.extend_from_slice(buf);
+        self.flush()
+    }
+
+    /// Write as much queued output as the socket will take.
+    fn flush(&mut self) -> io::Result<()> {
+        let StreamState::Open(stream) = &mut self.state else {
+            return Ok(());
+        };
+        while !self.out.is_empty() {
+            match stream.write(&self.out) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(amt) => {
+                    self.out.drain(..amt);
+                    self.last_active = Instant::now();
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Move the complete messages at the start of buf to messages.
+fn take_messages(buf: &mut Vec<u8>, max_len: usize, messages: &mut Vec<Vec<u8>>) -> io::Result<()> {
+    while let Some(len) = ber_message_len(buf)? {
+        if len > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Message of {len} bytes is too long"),
+            ));
+        }
+        if buf.len() < len {
+            break;
+        }
+        messages.push(buf.drain(..len).collect());
+    }
+    Ok(())
+}
+
+impl AsRawFd for TcpConnection {
+    fn as_raw_fd(&self) -> RawFd {
+        // poll ignores negative descriptors
+        self.tcp().map_or(-1, AsRawFd::as_raw_fd)
+    }
+}
+
+/// Wait until at least one of the file descriptors is ready, or timeout passes.
+///
+/// Each descriptor comes with whether to wait for it to be writable, rather than readable.
+/// Returns the indices of the ready descriptors, which is empty on timeout or when
+/// a signal interrupted the wait. None waits for ever.
+pub fn wait_ready(fds: &[(RawFd, bool)], timeout: Option<Duration>) -> io::Result<Vec<usize>> {
+    let mut fds: Vec<libc::pollfd> = fds
+        .iter()
+        .map(|(fd, write)| libc::pollfd {
+            fd: *fd,
+            events: if *write { libc::POLLOUT } else { libc::POLLIN },
+            revents: 0,
+        })
+        .collect();
+    let timeout_ms = timeout.map_or(-1, |t| t.as_millis().min(i32::MAX as u128) as i32);
+    // SAFETY: fds is a valid array of fds.len() pollfd structs for the whole call.
+    let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+    if ready < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            return Ok(vec![]);
+        }
+        return Err(err);
+    }
+    // Errors are reported as ready too, so the following recv sees them.
+    Ok(fds
+        .iter()
+        .enumerate()
+        .filter(|(_, fd)| fd.revents != 0)
+        .map(|(idx, _)| idx)
+        .collect())
+}
+
+/// Wait until at least one of the file descriptors is readable, or timeout passes, as for wait_ready.
+pub fn wait_readable(fds: &[RawFd], timeout: Option<Duration>) -> io::Result<Vec<usize>> {
+    let fds: Vec<(RawFd, bool)> = fds.iter().map(|fd| (*fd, false)).collect();
+    wait_ready(&fds, timeout)
+}
+
+#[cfg(target_os = "linux")]
+mod pktinfo {
+    use socket2::{SockAddr, SockAddrStorage};
+    use std::io;
+    use std::mem::size_of;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+    use std::os::fd::AsRawFd;
+
+    /// Control message buffer, with room for either pktinfo and aligned for cmsghdr
+    type ControlBuf = [u64; 8];
+
+    fn check(rc: isize) -> io::Result<usize> {
+        if rc < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(rc as usize)
+        }
+    }
+
+    /// Ask for the destination address of each datagram on the socket.
+    pub fn enable(socket: &UdpSocket) -> io::Result<()> {
+        let (level, name) = match socket.local_addr()? {
+            SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_PKTINFO),
+            // Also covers IPv4 on a dual stack socket, as mapped addresses
+            SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO),
+        };
+        let one: libc::c_int = 1;
+        // SAFETY: the option value points to a c_int of the given size.
+        let rc = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                (&one as *const libc::c_int).cast(),
+                size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        check(rc as isize).map(|_| ())
+    }
+
+    pub fn recv(
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+        let mut storage = SockAddrStorage::zeroed();
+        let mut control: ControlBuf = [0; 8];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr().cast(),
+            iov_len: buf.len(),
+        };
+        // SAFETY: all zeros is a valid msghdr, and every pointer set below outlives the call.
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_name = (&mut storage as *mut SockAddrStorage).cast();
+        msg.msg_namelen = storage.size_of();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = size_of::<ControlBuf>();
+        // SAFETY: msg describes valid buffers of the given lengths.
+        let amt = check(unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, 0) })?;
+        // SAFETY: recvmsg filled in storage and set msg_namelen to its length.
+        let src = unsafe { SockAddr::new(storage, msg.msg_namelen) }
+            .as_socket()
+            .ok_or(io::ErrorKind::InvalidData)?;
+        let mut dst = None;
+        // SAFETY: the control messages were written by the kernel into control, and
+        // the CMSG macros keep within msg_controllen.
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                let data = libc::CMSG_DATA(cmsg);
+                match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                    (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                        let info = (data as *const libc::in_pktinfo).read_unaligned();
+                        let addr = u32::from_be(info.ipi_addr.s_addr);
+                        dst = Some(IpAddr::V4(Ipv4Addr::from(addr)));
+                    }
+                    (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                        let info = (data as *const libc::in6_pktinfo).read_unaligned();
+                        dst = Some(IpAddr::V6(Ipv6Addr::from(info.ipi6_addr.s6_addr)));
+                    }
+                    _ => {}
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+        Ok((amt, src, dst))
+    }
+
+    pub fn send(
+        socket: &UdpSocket,
+        buf: &[u8],
+        dst: SocketAddr,
+        from: IpAddr,
+    ) -> io::Result<usize> {
+        let dst = SockAddr::from(dst);
+        let mut control: ControlBuf = [0; 8];
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // SAFETY: all zeros is a valid msghdr, and every pointer set below outlives the call.
+        let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+        msg.msg_name = dst.as_ptr() as *mut libc::c_void;
+        msg.msg_namelen = dst.len();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = size_of::<ControlBuf>();
+        // SAFETY: control is large and aligned enough for one pktinfo message, and
+        // msg_controllen is cut down to the space that message takes.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            let data = libc::CMSG_DATA(cmsg);
+            match from {
+                IpAddr::V4(addr) => {
+                    let size = size_of::<libc::in_pktinfo>() as u32;
+                    (*cmsg).cmsg_level = libc::IPPROTO_IP;
+                    (*cmsg).cmsg_type = libc::IP_PKTINFO;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(size) as usize;
+                    let info = libc::in_pktinfo {
+                        ipi_ifindex: 0,
+                        ipi_spec_dst: libc::in_addr {
+                            s_addr: u32::from(addr).to_be(),
+                        },
+                        ipi_addr: libc::in_addr { s_addr: 0 },
+                    };
+                    (data as *mut libc::in_pktinfo).write_unaligned(info);
+                    msg.msg_controllen = libc::CMSG_SPACE(size) as usize;
+                }
+                IpAddr::V6(addr) => {
+                    let size = size_of::<libc::in6_pktinfo>() as u32;
+                    (*cmsg).cmsg_level = libc::IPPROTO_IPV6;
+                    (*cmsg).cmsg_type = libc::IPV6_PKTINFO;
+                    (*cmsg).cmsg_len = libc::CMSG_LEN(size) as usize;
+                    let info = libc::in6_pktinfo {
+                        ipi6_addr: libc::in6_addr {
+                            s6_addr: addr.octets(),
+                        },
+                        ipi6_ifindex: 0,
+                    };
+                    (data as *mut libc::in6_pktinfo).write_unaligned(info);
+                    msg.msg_controllen = libc::CMSG_SPACE(size) as usize;
+                }
+            }
+        }
+        // SAFETY: msg describes valid buffers of the given lengths.
+        check(unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, 0) })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod pktinfo {
+    use std::io;
+    use std::net::{IpAddr, SocketAddr, UdpSocket};
+
+    pub fn enable(_socket: &UdpSocket) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "pktinfo is only supported on Linux",
+        ))
+    }
+
+    pub fn recv(
+        _socket: &UdpSocket,
+        _buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr, Option<IpAddr>)> {
+        unreachable!("pktinfo is never enabled")
+    }
+
+    pub fn send(
+        _socket: &UdpSocket,
+        _buf: &[u8],
+        _dst: SocketAddr,
+        _from: IpAddr,
+    ) -> io::Result<usize> {
+        unreachable!("pktinfo is never enabled")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen_addr() {
+        let addr = parse_listen_addr("0.0.0.0:161").unwrap();
+        assert_eq!(addr.port(), 161);
+        let SocketAddr::V6(addr) = parse_listen_addr("[fe80::1%2]:161").unwrap() else {
+            panic!("Expected IPv6");
+        };
+        assert_eq!(addr.scope_id(), 2);
+        let SocketAddr::V6(addr) = parse_listen_addr("[fe80::1%lo]:2161").unwrap() else {
+            panic!("Expected IPv6");
+        };
+        assert_ne!(addr.scope_id(), 0);
+        assert_eq!(addr.port(), 2161);
+        assert!(parse_listen_addr("[fe80::1%nosuchif0]:161").is_err());
+        assert!(parse_listen_addr("[fe80::1%lo]").is_err());
+        assert!(parse_listen_addr("localhost:161").is_err());
+    }
+
+    #[test]
+    fn test_dual_stack() {
+        let socket = bind_udp("[::]:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let v4 = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let v6 = UdpSocket::bind("[::1]:0").unwrap();
+        let fds = [socket.as_raw_fd()];
+        assert!(wait_readable(&fds, Some(Duration::from_millis(1)))
+            .unwrap()
+            .is_empty());
+        v4.send_to(b"v4", ("127.0.0.1", port)).unwrap();
+        v6.send_to(b"v6", ("::1", port)).unwrap();
+        let mut buf = [0; 8];
+        for _ in 0..2 {
+            let ready = wait_readable(&fds, Some(Duration::from_secs(5))).unwrap();
+            assert_eq!(ready, vec![0]);
+            let (amt, src) = socket.recv_from(&mut buf).unwrap();
+            // IPv4 senders arrive as mapped addresses
+            match &buf[..amt] {
+                b"v4" => assert_eq!(src.ip().to_canonical(), v4.local_addr().unwrap().ip()),
+                b"v6" => assert_eq!(src, v6.local_addr().unwrap()),
+                other => panic!("Unexpected {other:?}"),
+            }
+        }
+    }
+
+    /// Send to the agent at addr, echo the reply, and return the address the reply came from
+    fn echo_from(agent: &UdpTransport, manager: &UdpSocket, addr: SocketAddr) -> SocketAddr {
+        manager.send_to(b"ping", addr).unwrap();
+        let mut buf = [0; 8];
+        let (amt, src, dst) = agent.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..amt], b"ping");
+        agent.send(b"pong", src, dst).unwrap();
+        let (amt, from) = manager.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..amt], b"pong");
+        from
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_pktinfo() {
+        // All of 127/8 is loopback, so 127.0.0.2 stands in for a second interface address
+        let manager = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let plain = UdpTransport::bind("0.0.0.0:0").unwrap();
+        let port = plain.local_addr().unwrap().port();
+        let from = echo_from(&plain, &manager, ([127, 0, 0, 2], port).into());
+        assert_eq!(from.ip(), IpAddr::from([127, 0, 0, 1]));
+
+        let agent = UdpTransport::bind("0.0.0.0:0/pktinfo").unwrap();
+        let port = agent.local_addr().unwrap().port();
+        let from = echo_from(&agent, &manager, ([127, 0, 0, 2], port).into());
+        assert_eq!(from, ([127, 0, 0, 2], port).into());
+
+        // Dual stack, where IPv4 requests are seen as mapped addresses
+        let agent = UdpTransport::bind("[::]:0/pktinfo").unwrap();
+        let port = agent.local_addr().unwrap().port();
+        let from = echo_from(&agent, &manager, ([127, 0, 0, 3], port).into());
+        assert_eq!(from, ([127, 0, 0, 3], port).into());
+        let manager = UdpSocket::bind("[::1]:0").unwrap();
+        let from = echo_from(
+            &agent,
+            &manager,
+            ("::1".parse::<IpAddr>().unwrap(), port).into(),
+        );
+        assert_eq!(from.ip(), "::1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_ber_message_len() {
+        assert_eq!(ber_message_len(b"").unwrap(), None);
+        assert_eq!(ber_message_len(b"\x30").unwrap(), None);
+        assert_eq!(ber_message_len(b"\x30\x03").unwrap(), Some(5));
+        assert_eq!(ber_message_len(b"\x30\x82\x01").unwrap(), None);
+        assert_eq!(ber_message_len(b"\x30\x82\x01\x00").unwrap(), Some(260));
+        assert!(ber_message_len(b"\x04\x03").is_err());
+        assert!(ber_message_len(b"\x30\x80").is_err());
+        assert!(ber_message_len(b"\x30\x85\x01\x00\x00\x00\x00").is_err());
+    }
+
+    #[test]
+    fn test_tcp_framing() {
+        let listener = bind_tcp("127.0.0.1:0").unwrap();
+        let mut manager = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+        assert_eq!(
+            split_domain("tcp:[::]:161"),
+            (TransportDomain::Tcp, "[::]:161")
+        );
+        assert_eq!(
+            split_domain("udp:[::]:161"),
+            (TransportDomain::Udp, "[::]:161")
+        );
+        assert_eq!(split_domain("[::]:161"), (TransportDomain::Udp, "[::]:161"));
+        assert_eq!(
+            split_domain("dtls:[::]:10161"),
+            (TransportDomain::Dtls, "[::]:10161")
+        );
+        let mut connection = TcpConnection::new(stream, peer).unwrap();
+        let fds = [connection.as_raw_fd()];
+        // One and a half messages, then the rest of the second
+        manager.write_all(b"\x30\x01a\x30\x02").unwrap();
+        wait_readable(&fds, Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(connection.read_messages(100).unwrap(), vec![b"\x30\x01a"]);
+        manager.write_all(b"bc").unwrap();
+        wait_readable(&fds, Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(connection.read_messages(100).unwrap(), vec![b"\x30\x02bc"]);
+        connection.send(b"\x30\x00").unwrap();
+        let mut buf = [0; 2];
+        manager.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"\x30\x00");
+        // Too long, then closed
+        manager.write_all(b"\x30\x7f").unwrap();
+        wait_readable(&fds, Some(Duration::from_secs(5))).unwrap();
+        assert!(connection.read_messages(100).is_err());
+        drop(manager);
+        wait_readable(&fds, Some(Duration::from_secs(5))).unwrap();
+        assert!(connection.read_messages(100).is_err());
+    }
+
+    #[test]
+    fn test_tcp_slow_reader() {
+        let listener = bind_tcp("127.0.0.1:0").unwrap();
+        let mut manager = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+        socket2::SockRef::from(&stream)
+            .set_send_buffer_size(4096)
+            .unwrap();
+        let mut connection = TcpConnection::new(stream, peer).unwrap();
+        // More than the socket buffers hold, while the manager reads nothing
+        let response = [&b"\x30\x84\x00\x10\x00\x00"[..], &[0; 0x100000]].concat();
+        let start = Instant::now();
+        connection.send(&response).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(connection.wants_write());
+        // No requests are read until the manager has taken the response
+        manager.write_all(b"\x30\x00").unwrap();
+        let reader = std::thread::spawn(move || {
+            let mut buf = vec![0; response.len()];
+            manager.read_exact(&mut buf).unwrap();
+            assert_eq!(buf, response);
+            manager
+        });
+        let fds = [(connection.as_raw_fd(), true)];
+        let mut messages = vec![];
+        while connection.wants_write() {
+            wait_ready(&fds, Some(Duration::from_secs(5))).unwrap();
+            let read = connection.read_messages(100).unwrap();
+            assert!(read.is_empty() || !connection.wants_write());
+            messages.extend(read);
+        }
+        reader.join().unwrap();
+        if messages.is_empty() {
+            wait_readable(&[connection.as_raw_fd()], Some(Duration::from_secs(5))).unwrap();
+            messages = connection.read_messages(100).unwrap();
+        }
+        assert_eq!(messages, vec![b"\x30\x00"]);
+    }
+}
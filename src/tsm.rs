@@ -0,0 +1,698 @@
+//! TLS and DTLS transports for the Transport Security Model, RFC 5591 and RFC 6353
+//!
+//! Messages with securityModel 4 take their securityName from the TLS session they arrive on,
+//! rather than from a user name and keys in the message. The session is authenticated with
+//! certificates on both sides, and the manager's certificate is mapped to a securityName by a
+//! table in the style of snmpTlstmCertToTSNTable, loaded from a text file with a line per entry:
+//! * priority, lower numbers are tried first
+//! * fingerprint of the manager's certificate, or of a CA certificate in its chain, as the hash
+//!   algorithm and hex digest, for example "sha256:4F:2C:..."; the colons are optional
+//! * how the name is found: "specified", "san_rfc822_name", "san_dns_name", "san_ip_address",
+//!   "san_any" or "common_name"
+//! * the securityName, for "specified" only
+//!
+//! For example "1 sha256:4F2C...E1 specified admin" or "10 sha256:99D0...7A san_dns_name".
+//! Names taken from the certificate are used as written, except that DNS names and the domain
+//! part of email addresses are folded to lower case, and IP addresses become upper case hex.
+//! The first entry that yields a name of 1 to 32 octets wins; if none does, the session is closed.
+//!
+//! A manager certificate signed by one of the trust anchors is accepted, as is a self-signed one
+//! whose own fingerprint is in the table.
+//!
+//! The securityName then goes through the same VACM or group lookup as a USM user name.
+use crate::transport;
+use log::{debug, warn};
+use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::ssl::{
+    ErrorCode, HandshakeError, MidHandshakeSslStream, Ssl, SslContext, SslFiletype, SslMethod,
+    SslMode, SslOptions, SslStream, SslVerifyMode,
+};
+use openssl::x509::{X509Ref, X509};
+use std::collections::{HashMap, VecDeque};
+use std::fs::read_to_string;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// MTU used for DTLS handshake messages, allowing for IPv6 and tunnel headers.
+const DTLS_MTU: u32 = 1400;
+/// SnmpAdminString limit on a tmSecurityName
+const MAX_SECURITY_NAME: usize = 32;
+
+/// How a certificate is turned into a securityName, snmpTlstmCertToTSNMapType.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MapType {
+    Specified(Vec<u8>),
+    SanRfc822Name,
+    SanDnsName,
+    SanIpAddress,
+    SanAny,
+    CommonName,
+}
+
+/// A line of the certificate map.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CertMapEntry {
+    pub priority: u32,
+    pub hash: String,
+    pub fingerprint: Vec<u8>,
+    pub map_type: MapType,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseCertMapError;
+
+fn digest_by_name(name: &str) -> Option<MessageDigest> {
+    match name.to_ascii_lowercase().as_str() {
+        "sha1" => Some(MessageDigest::sha1()),
+        "sha224" => Some(MessageDigest::sha224()),
+        "sha256" => Some(MessageDigest::sha256()),
+        "sha384" => Some(MessageDigest::sha384()),
+        "sha512" => Some(MessageDigest::sha512()),
+        _ => None,
+    }
+}
+
+impl FromStr for CertMapEntry {
+    type Err = ParseCertMapError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        if parts.len() < 3 {
+            return Err(ParseCertMapError);
+        }
+        let priority = u32::from_str(parts[0]).map_err(|_| ParseCertMapError)?;
+        let (hash, hex_digest) = parts[1].split_once(':').ok_or(ParseCertMapError)?;
+        let digest = digest_by_name(hash).ok_or(ParseCertMapError)?;
+        let fingerprint =
+            hex::decode(hex_digest.replace(':', "")).map_err(|_| ParseCertMapError)?;
+        if fingerprint.len() != digest.size() {
+            return Err(ParseCertMapError);
+        }
+        let map_type = match (parts[2], parts.get(3)) {
+            ("specified", Some(name)) if name.len() <= MAX_SECURITY_NAME => {
+                MapType::Specified(name.as_bytes().to_vec())
+            }
+            ("san_rfc822_name", None) => MapType::SanRfc822Name,
+            ("san_dns_name", None) => MapType::SanDnsName,
+            ("san_ip_address", None) => MapType::SanIpAddress,
+            ("san_any", None) => MapType::SanAny,
+            ("common_name", None) => MapType::CommonName,
+            _ => return Err(ParseCertMapError),
+        };
+        if parts.len() > 4 {
+            return Err(ParseCertMapError);
+        }
+        Ok(CertMapEntry {
+            priority,
+            hash: hash.to_ascii_lowercase(),
+            fingerprint,
+            map_type,
+        })
+    }
+}
+
+/// Fingerprint of cert with the named hash, or None if it cannot be computed.
+fn fingerprint(cert: &X509Ref, hash: &str) -> Option<Vec<u8>> {
+    let digest = digest_by_name(hash)?;
+    cert.digest(digest).ok().map(|bytes| bytes.to_vec())
+}
+
+/// Lower case the domain of an email address, RFC 6353 section 5.1.
+fn fold_rfc822(email: &str) -> Vec<u8> {
+    match email.rsplit_once('@') {
+        Some((local, domain)) => format!("{local}@{0}", domain.to_ascii_lowercase()).into_bytes(),
+        None => email.as_bytes().to_vec(),
+    }
+}
+
+/// The securityName a map type gives for a certificate, if it has the field needed.
+fn name_from_cert(cert: &X509Ref, map_type: &MapType) -> Option<Vec<u8>> {
+    let sans = cert.subject_alt_names();
+    let sans = sans.iter().flat_map(|stack| stack.iter());
+    let name = match map_type {
+        MapType::Specified(name) => Some(name.clone()),
+        MapType::SanRfc822Name => sans.filter_map(|san| san.email()).map(fold_rfc822).next(),
+        MapType::SanDnsName => sans
+            .filter_map(|san| san.dnsname())
+            .map(|dns| dns.to_ascii_lowercase().into_bytes())
+            .next(),
+        MapType::SanIpAddress => sans
+            .filter_map(|san| san.ipaddress())
+            .map(|ip| hex::encode_upper(ip).into_bytes())
+            .next(),
+        MapType::SanAny => sans
+            .filter_map(|san| {
+                san.email()
+                    .map(fold_rfc822)
+                    .or_else(|| {
+                        san.dnsname()
+                            .map(|dns| dns.to_ascii_lowercase().into_bytes())
+                    })
+                    .or_else(|| san.ipaddress().map(|ip| hex::encode_upper(ip).into_bytes()))
+            })
+            .next(),
+        MapType::CommonName => cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .filter_map(|entry| entry.data().to_string().ok())
+            .map(String::into_bytes)
+            .next(),
+    };
+    name.filter(|name| !name.is_empty() && name.len() <= MAX_SECURITY_NAME)
+}
+
+/// Certificate to securityName map, snmpTlstmCertToTSNTable.
+#[derive(Clone, Debug, Default)]
+pub struct CertToTsn {
+    entries: Vec<CertMapEntry>,
+}
+
+impl CertToTsn {
+    pub fn new() -> Self {
+        CertToTsn::default()
+    }
+
+    /// Add an entry, keeping them in priority order.
+    pub fn add(&mut self, entry: CertMapEntry) {
+        self.entries.push(entry);
+        self.entries.sort_by_key(|entry| entry.priority);
+    }
+
+    /// Read the map from a file in the format of the module documentation.
+    ///
+    /// Blank lines and lines starting with "#" are skipped.
+    pub fn load_from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut map = CertToTsn::new();
+        for (line_no, line) in read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let entry = CertMapEntry::from_str(line).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Bad certificate map entry on line {0}", line_no + 1),
+                )
+            })?;
+            map.add(entry);
+        }
+        Ok(map)
+    }
+
+    /// True if cert itself is listed, which lets a self-signed manager certificate through.
+    fn lists(&self, cert: &X509Ref) -> bool {
+        self.entries
+            .iter()
+            .any(|entry| fingerprint(cert, &entry.hash).as_ref() == Some(&entry.fingerprint))
+    }
+
+    /// tmSecurityName for a manager, from its certificate and the chain it was verified with.
+    ///
+    /// RFC 6353 section 5.1: entries are tried in priority order, and the first whose
+    /// fingerprint matches a certificate in the chain, and which yields a name, is used.
+    pub fn security_name(&self, leaf: &X509Ref, chain: &[X509]) -> Option<Vec<u8>> {
+        self.entries.iter().find_map(|entry| {
+            let matched = std::iter::once(leaf)
+                .chain(chain.iter().map(|cert| cert.as_ref()))
+                .any(|cert| fingerprint(cert, &entry.hash).as_ref() == Some(&entry.fingerprint));
+            if matched {
+                name_from_cert(leaf, &entry.map_type)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// tmSecurityName for the manager at the far end of ssl.
+    fn session_name(&self, ssl: &openssl::ssl::SslRef) -> Option<Vec<u8>> {
+        let leaf = ssl.peer_certificate()?;
+        let chain: Vec<X509> = ssl
+            .verified_chain()
+            .map(|stack| stack.iter().map(|cert| cert.to_owned()).collect())
+            .unwrap_or_default();
+        self.security_name(&leaf, &chain)
+    }
+}
+
+fn ssl_error(err: ErrorStack) -> io::Error {
+    io::Error::other(err)
+}
+
+/// Our certificate and key, the trust anchors and the certificate map, for TLS and DTLS.
+pub struct TlsConfig {
+    tls: SslContext,
+    dtls: SslContext,
+    peer_index: Index<Ssl, SocketAddr>,
+    cert_map: Arc<CertToTsn>,
+}
+
+impl TlsConfig {
+    /// Load the agent's certificate chain and private key from PEM files.
+    ///
+    /// trust_anchors is a PEM file of CA certificates that sign manager certificates. Without it,
+    /// only managers whose own certificate fingerprint is in cert_map can connect.
+    pub fn new(
+        cert_file: &Path,
+        key_file: &Path,
+        trust_anchors: Option<&Path>,
+        cert_map: CertToTsn,
+    ) -> io::Result<Self> {
+        let cert_map = Arc::new(cert_map);
+        let peer_index = Ssl::new_ex_index::<SocketAddr>().map_err(ssl_error)?;
+        let mut cookie_secret = [0u8; 32];
+        openssl::rand::rand_bytes(&mut cookie_secret).map_err(ssl_error)?;
+        let mut contexts = vec![];
+        for method in [SslMethod::tls_server(), SslMethod::dtls_server()] {
+            let mut builder = SslContext::builder(method).map_err(ssl_error)?;
+            builder
+                .set_certificate_chain_file(cert_file)
+                .map_err(ssl_error)?;
+            builder
+                .set_private_key_file(key_file, SslFiletype::PEM)
+                .map_err(ssl_error)?;
+            builder.check_private_key().map_err(ssl_error)?;
+            if let Some(trust_anchors) = trust_anchors {
+                builder.set_ca_file(trust_anchors).map_err(ssl_error)?;
+            }
+            let verify_map = cert_map.clone();
+            builder.set_verify_callback(
+                SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+                move |preverify_ok, ctx| {
+                    preverify_ok
+                        || (ctx.error_depth() == 0
+                            && ctx
+                                .current_cert()
+                                .is_some_and(|cert| verify_map.lists(cert)))
+                },
+            );
+            contexts.push(builder);
+        }
+        let mut dtls = contexts.pop().expect("Two contexts built");
+        let mut tls = contexts.pop().expect("Two contexts built");
+        // TLS writes are non-blocking, and retried with more output queued behind them
+        tls.set_mode(SslMode::ENABLE_PARTIAL_WRITE | SslMode::ACCEPT_MOVING_WRITE_BUFFER);
+        // Cookies stop spoofed ClientHellos being used to flood a third party.
+        dtls.set_options(SslOptions::COOKIE_EXCHANGE | SslOptions::NO_QUERY_MTU);
+        dtls.set_cookie_generate_cb(move |ssl, cookie| {
+            let peer = ssl.ex_data(peer_index).ok_or_else(ErrorStack::get)?;
+            let digest = dtls_cookie(&cookie_secret, peer);
+            cookie[..digest.len()].copy_from_slice(&digest);
+            Ok(digest.len())
+        });
+        dtls.set_cookie_verify_cb(move |ssl, cookie| {
+            ssl.ex_data(peer_index).is_some_and(|peer| {
+                let digest = dtls_cookie(&cookie_secret, peer);
+                cookie.len() == digest.len() && openssl::memcmp::eq(cookie, &digest)
+            })
+        });
+        Ok(TlsConfig {
+            tls: tls.build(),
+            dtls: dtls.build(),
+            peer_index,
+            cert_map: cert_map.clone(),
+        })
+    }
+
+    /// Start the TLS handshake on an accepted connection.
+    ///
+    /// The socket is made non-blocking, and the connection takes the handshake on as the
+    /// manager's messages arrive, so a slow or silent manager holds up no one else.
+    pub fn accept(&self, stream: TcpStream) -> io::Result<transport::HandshakeStep> {
+        stream.set_nonblocking(true)?;
+        let ssl = Ssl::new(&self.tls).map_err(ssl_error)?;
+        tls_step(ssl.accept(stream), self.cert_map.clone())
+    }
+
+    fn dtls_ssl(&self, peer: SocketAddr) -> io::Result<Ssl> {
+        let mut ssl = Ssl::new(&self.dtls).map_err(ssl_error)?;
+        ssl.set_mtu(DTLS_MTU).map_err(ssl_error)?;
+        ssl.set_ex_data(self.peer_index, peer);
+        Ok(ssl)
+    }
+}
+
+/// A TLS handshake waiting on the manager.
+struct TlsHandshake {
+    mid: MidHandshakeSslStream<TcpStream>,
+    cert_map: Arc<CertToTsn>,
+}
+
+impl transport::Handshake for TlsHandshake {
+    fn tcp(&self) -> &TcpStream {
+        self.mid.get_ref()
+    }
+
+    fn wants_write(&self) -> bool {
+        self.mid.error().code() == ErrorCode::WANT_WRITE
+    }
+
+    fn resume(self: Box<Self>) -> io::Result<transport::HandshakeStep> {
+        tls_step(self.mid.handshake(), self.cert_map)
+    }
+}
+
+fn tls_step(
+    result: Result<SslStream<TcpStream>, HandshakeError<TcpStream>>,
+    cert_map: Arc<CertToTsn>,
+) -> io::Result<transport::HandshakeStep> {
+    match result {
+        Ok(stream) => {
+            let name = cert_map.session_name(stream.ssl()).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "No certificate map entry for manager",
+                )
+            })?;
+            Ok(transport::HandshakeStep::Done(Box::new(stream), name))
+        }
+        Err(HandshakeError::WouldBlock(mid)) => {
+            Ok(transport::HandshakeStep::Pending(Box::new(TlsHandshake {
+                mid,
+                cert_map,
+            })))
+        }
+        Err(err) => Err(io::Error::other(err.to_string())),
+    }
+}
+
+/// HelloVerifyRequest cookie for a manager address.
+fn dtls_cookie(secret: &[u8], peer: &SocketAddr) -> [u8; 32] {
+    let mut input = secret.to_vec();
+    input.extend_from_slice(peer.to_string().as_bytes());
+    openssl::sha::sha256(&input)
+}
+
+impl transport::MessageStream for SslStream<TcpStream> {
+    fn tcp(&self) -> &TcpStream {
+        self.get_ref()
+    }
+}
+
+/// One manager's end of a shared DTLS socket, as a stream of datagrams for OpenSSL.
+struct DatagramChannel {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    inbox: VecDeque<Vec<u8>>,
+}
+
+impl Read for DatagramChannel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let datagram = self.inbox.pop_front().ok_or(io::ErrorKind::WouldBlock)?;
+        let amt = datagram.len().min(buf.len());
+        buf[..amt].copy_from_slice(&datagram[..amt]);
+        Ok(amt)
+    }
+}
+
+impl Write for DatagramChannel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.send_to(buf, self.peer)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+enum SessionState {
+    Handshake(MidHandshakeSslStream<DatagramChannel>),
+    Established(SslStream<DatagramChannel>, Vec<u8>),
+}
+
+struct DtlsSession {
+    state: SessionState,
+    last_active: Instant,
+}
+
+/// Outcome of feeding a datagram to a session.
+enum Progress {
+    Continue(SessionState, Vec<Vec<u8>>),
+    Close,
+}
+
+/// A UDP socket carrying DTLS sessions with any number of managers, told apart by address.
+pub struct DtlsTransport {
+    socket: Arc<UdpSocket>,
+    sessions: HashMap<SocketAddr, DtlsSession>,
+}
+
+impl DtlsTransport {
+    /// Bind to a listen address, as for UDP.
+    pub fn bind(addr_str: &str) -> io::Result<Self> {
+        Ok(DtlsTransport {
+            socket: Arc::new(transport::bind_udp(addr_str)?),
+            sessions: HashMap::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Number of sessions, including those still in their handshake.
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Read a datagram and pass it to the manager's session, creating one if there is room.
+    ///
+    /// Returns the manager's address with any SNMP messages the datagram completed. Call when
+    /// the socket is readable, as it blocks otherwise.
+    pub fn recv(
+        &mut self,
+        config: &TlsConfig,
+        max_sessions: usize,
+    ) -> io::Result<(SocketAddr, Vec<Vec<u8>>)> {
+        let mut buf = [0; 65536];
+        let (amt, peer) = self.socket.recv_from(&mut buf)?;
+        let datagram = buf[..amt].to_vec();
+        let progress = match self.sessions.remove(&peer) {
+            Some(session) => advance(session.state, datagram, config),
+            None if self.sessions.len() >= max_sessions => {
+                warn!("Refusing DTLS session from {peer}, already have {max_sessions}");
+                Progress::Close
+            }
+            None => {
+                let channel = DatagramChannel {
+                    socket: self.socket.clone(),
+                    peer,
+                    inbox: VecDeque::from([datagram]),
+                };
+                handshake_result(config.dtls_ssl(peer)?.accept(channel), config)
+            }
+        };
+        match progress {
+            Progress::Continue(state, messages) => {
+                let session = DtlsSession {
+                    state,
+                    last_active: Instant::now(),
+                };
+                self.sessions.insert(peer, session);
+                Ok((peer, messages))
+            }
+            Progress::Close => {
+                debug!("Closing DTLS session with {peer}");
+                Ok((peer, vec![]))
+            }
+        }
+    }
+
+    /// tmSecurityName of an established session.
+    pub fn security_name(&self, peer: &SocketAddr) -> Option<&[u8]> {
+        match &self.sessions.get(peer)?.state {
+            SessionState::Established(_, name) => Some(name),
+            SessionState::Handshake(_) => None,
+        }
+    }
+
+    /// Send an SNMP message as a single DTLS record.
+    pub fn send(&mut self, peer: &SocketAddr, buf: &[u8]) -> io::Result<()> {
+        match self
+            .sessions
+            .get_mut(peer)
+            .map(|session| &mut session.state)
+        {
+            Some(SessionState::Established(stream, _)) => stream.write_all(buf),
+            _ => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    /// Time until the next session reaches idle_timeout, if there are any.
+    pub fn next_idle(&self, idle_timeout: Duration) -> Option<Duration> {
+        self.sessions
+            .values()
+            .map(|session| idle_timeout.saturating_sub(session.last_active.elapsed()))
+            .min()
+    }
+
+    /// Forget sessions idle for idle_timeout or longer.
+    pub fn close_idle(&mut self, idle_timeout: Duration) {
+        self.sessions
+            .retain(|_, session| session.last_active.elapsed() < idle_timeout);
+    }
+}
+
+impl AsRawFd for DtlsTransport {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+fn handshake_result(
+    result: Result<SslStream<DatagramChannel>, HandshakeError<DatagramChannel>>,
+    config: &TlsConfig,
+) -> Progress {
+    match result {
+        Ok(stream) => match config.cert_map.session_name(stream.ssl()) {
+            Some(name) => read_records(stream, name),
+            None => {
+                warn!("No certificate map entry for DTLS manager");
+                Progress::Close
+            }
+        },
+        Err(HandshakeError::WouldBlock(mid)) => {
+            Progress::Continue(SessionState::Handshake(mid), vec![])
+        }
+        Err(HandshakeError::Failure(mid)) => {
+            warn!("DTLS handshake failed {0}", mid.error());
+            Progress::Close
+        }
+        Err(HandshakeError::SetupFailure(err)) => {
+            warn!("DTLS setup failed {err}");
+            Progress::Close
+        }
+    }
+}
+
+fn advance(state: SessionState, datagram: Vec<u8>, config: &TlsConfig) -> Progress {
+    match state {
+        SessionState::Handshake(mut mid) => {
+            mid.get_mut().inbox.push_back(datagram);
+            handshake_result(mid.handshake(), config)
+        }
+        SessionState::Established(mut stream, name) => {
+            stream.get_mut().inbox.push_back(datagram);
+            read_records(stream, name)
+        }
+    }
+}
+
+/// Read the application data records waiting in an established session.
+fn read_records(mut stream: SslStream<DatagramChannel>, name: Vec<u8>) -> Progress {
+    let mut messages = vec![];
+    let mut buf = [0; 16384];
+    loop {
+        match stream.read(&mut buf) {
+            // close_notify from the manager
+            Ok(0) => return Progress::Close,
+            Ok(amt) => messages.push(buf[..amt].to_vec()),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => {
+                debug!("DTLS read failed {err}");
+                return Progress::Close;
+            }
+        }
+    }
+    Progress::Continue(SessionState::Established(stream, name), messages)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::{BigNum, MsbOption};
+    use openssl::pkey::{PKey, Private};
+    use openssl::rsa::Rsa;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::X509NameBuilder;
+
+    /// Self-signed certificate with a common name and a DNS subject alternative name.
+    pub(crate) fn self_signed(cn: &str, dns: &str) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, cn).unwrap();
+        let name = name.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        let mut serial = BigNum::new().unwrap();
+        serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+        builder
+            .set_serial_number(&serial.to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        let san = SubjectAlternativeName::new()
+            .dns(dns)
+            .ip("192.0.2.1")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(san).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    /// Map line for a certificate's sha256 fingerprint.
+    pub(crate) fn map_line(priority: u32, cert: &X509, map_type: &str) -> String {
+        let digest = hex::encode(cert.digest(MessageDigest::sha256()).unwrap());
+        format!("{priority} sha256:{digest} {map_type}")
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        let digest = "00".repeat(32);
+        let entry = CertMapEntry::from_str(&format!("5 SHA256:{digest} specified admin")).unwrap();
+        assert_eq!(entry.priority, 5);
+        assert_eq!(entry.hash, "sha256");
+        assert_eq!(entry.map_type, MapType::Specified(b"admin".to_vec()));
+        let colons = ["AB"; 20].join(":");
+        let entry = CertMapEntry::from_str(&format!("1 sha1:{colons} san_any")).unwrap();
+        assert_eq!(entry.fingerprint, vec![0xab; 20]);
+        // Wrong digest length, missing or unexpected name, unknown types
+        assert!(CertMapEntry::from_str(&format!("1 sha1:{digest} san_any")).is_err());
+        assert!(CertMapEntry::from_str(&format!("1 sha256:{digest} specified")).is_err());
+        assert!(CertMapEntry::from_str(&format!("1 sha256:{digest} common_name x")).is_err());
+        assert!(CertMapEntry::from_str(&format!("1 md5:{digest} common_name")).is_err());
+        assert!(CertMapEntry::from_str(&format!("1 sha256:{digest} subject")).is_err());
+    }
+
+    #[test]
+    fn test_security_name() {
+        let (cert, _) = self_signed("Manager One", "NMS.Example.com");
+        let (other, _) = self_signed("Other", "other.example.com");
+        let mut map = CertToTsn::new();
+        map.add(CertMapEntry::from_str(&map_line(20, &cert, "san_dns_name")).unwrap());
+        assert_eq!(
+            map.security_name(&cert, &[]),
+            Some(b"nms.example.com".to_vec())
+        );
+        assert_eq!(map.security_name(&other, &[]), None);
+        // A matching CA in the chain is enough
+        assert_eq!(
+            map.security_name(&other, std::slice::from_ref(&cert)),
+            Some(b"other.example.com".to_vec())
+        );
+        // Lower priority numbers win, and entries that give no name are passed over
+        map.add(CertMapEntry::from_str(&map_line(10, &cert, "san_rfc822_name")).unwrap());
+        map.add(CertMapEntry::from_str(&map_line(15, &cert, "san_ip_address")).unwrap());
+        assert_eq!(map.security_name(&cert, &[]), Some(b"C0000201".to_vec()));
+        map.add(CertMapEntry::from_str(&map_line(1, &cert, "common_name")).unwrap());
+        assert_eq!(map.security_name(&cert, &[]), Some(b"Manager One".to_vec()));
+        assert!(map.lists(&cert));
+        assert!(!map.lists(&other));
+    }
+}
@@ -7,23 +7,31 @@
 //! The fields on the line are:
 //! * the username (no spaces!)
 //! * the group name of the user (must match a name in groups.txt, see perms module)
-//! * the hash type in use. Currently only sha1 is accepted here.
-//! * the localized authentication hash
-//! * the privacy type (only aes allowed)
-//! * the localized privacy hash
+//! * the hash type in use. Currently only sha1 or none is accepted here.
+//! * the localized authentication hash, or - if there is none
+//! * the privacy type (aes or none)
+//! * the localized privacy hash, or - if there is none
 //!
+//! A user without authentication cannot have privacy. The protocols limit the
+//! security level the user can request, whatever their group allows.
 //!
-use crate::perms::Perm;
+use crate::perms::{Perm, NO_ACCESS};
 use log::warn;
-use regex::Regex;
+use rasn::types::OctetString;
+use rasn_snmp::v3::{Message, USMSecurityParameters};
+use regex::{Captures, Regex};
 use sha1::{Digest, Sha1};
+use std::fmt::Display;
 use std::fs::File;
 use std::io::{Error, Write};
 //use sha2::{Digest, Sha224, Sha256, Sha384, Sha512};
 use std::fs::read_to_string;
 
+const Z12: OctetString = OctetString::from_static(&[0; 12]);
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 enum WhatHash {
+    NoAuth,
     Sha1,
     /* Sha224,
     Sha256,
@@ -31,6 +39,12 @@ enum WhatHash {
     Sha512, */
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+enum WhatPriv {
+    NoPriv,
+    Aes,
+}
+
 /// User struct holds data about user.
 ///
 /// Contains localized hashes, and pre calculated values for k1 and k2, used
@@ -38,6 +52,7 @@ enum WhatHash {
 #[derive(Debug, PartialEq)]
 pub struct User<'a> {
     what: WhatHash,
+    privacy: WhatPriv,
     pub group: Vec<u8>,
     pub perm: &'a Perm,
     pub name: Vec<u8>,
@@ -55,7 +70,7 @@ impl<'a> User<'a> {
     ///
     /// Will throw ParseUserError on problems.
     /// User group name (the second item on the line) must match a group in perms
-    fn from_str(s: &str, perms: &'a Vec<Perm>) -> Result<Self, ParseUserError> {
+    pub(crate) fn from_str(s: &str, perms: &'a Vec<Perm>) -> Result<Self, ParseUserError> {
         if perms.is_empty() {
             return Err(ParseUserError);
         }
@@ -64,34 +79,53 @@ impl<'a> User<'a> {
                 .unwrap();
 
         let captures = re.captures(s).ok_or(ParseUserError)?;
+        let group = captures["group"].as_bytes().to_vec();
+        for perm_entry in perms {
+            if group == perm_entry.group_name {
+                let mut user = User::from_captures(&captures, perm_entry)?;
+                user.group = group;
+                return Ok(user);
+            }
+        }
+        Err(ParseUserError)
+    }
 
+    /// Build a User from the name, hash, keys and privacy captured from a line.
+    fn from_captures(captures: &Captures, perm: &'a Perm) -> Result<Self, ParseUserError> {
         // Change this when we support additional hash types from RFC7630
         let what = match &captures["hash"] {
+            "none" => WhatHash::NoAuth,
             "sha1" => WhatHash::Sha1,
             _ => return Err(ParseUserError),
         };
 
-        if captures["priv"] != *"aes" {
+        let privacy = match &captures["priv"] {
+            "none" => WhatPriv::NoPriv,
+            "aes" => WhatPriv::Aes,
+            _ => return Err(ParseUserError),
+        };
+        if what == WhatHash::NoAuth && privacy != WhatPriv::NoPriv {
             return Err(ParseUserError);
         }
-        let akb = hex::decode(&captures["ak"]).unwrap();
-        let group = captures["group"].as_bytes().to_vec();
-
-        for perm_entry in perms {
-            if group == perm_entry.group_name {
-                return Ok(User {
-                    what,
-                    group,
-                    perm: perm_entry,
-                    name: captures["name"].as_bytes().to_vec(),
-                    auth_key: akb.clone(),
-                    priv_key: hex::decode(&captures["pk"]).unwrap(),
-                    k1: k1_from_ak(&akb),
-                    k2: k2_from_ak(&akb),
-                });
-            }
-        }
-        Err(ParseUserError)
+        let akb = match what {
+            WhatHash::NoAuth => vec![],
+            WhatHash::Sha1 => hex::decode(&captures["ak"]).map_err(|_| ParseUserError)?,
+        };
+        let pkb = match privacy {
+            WhatPriv::NoPriv => vec![],
+            WhatPriv::Aes => hex::decode(&captures["pk"]).map_err(|_| ParseUserError)?,
+        };
+        Ok(User {
+            what,
+            privacy,
+            group: vec![],
+            perm,
+            name: captures["name"].as_bytes().to_vec(),
+            auth_key: akb.clone(),
+            priv_key: pkb,
+            k1: k1_from_ak(&akb),
+            k2: k2_from_ak(&akb),
+        })
     }
 
     /// Generates the bytes for a line in the file for the user.
@@ -100,28 +134,63 @@ impl<'a> User<'a> {
         out.extend(self.name.clone());
         out.push(b' ');
         out.extend(self.group.clone());
-        out.extend(match self.what {
-            WhatHash::Sha1 => b" sha1 ",
-        });
-        out.extend(hex::encode(self.auth_key.clone()).as_bytes());
-        out.extend(b" aes ");
-        out.extend(hex::encode(self.priv_key.clone()).as_bytes());
+        match self.what {
+            WhatHash::NoAuth => out.extend(b" none -"),
+            WhatHash::Sha1 => {
+                out.extend(b" sha1 ");
+                out.extend(hex::encode(self.auth_key.clone()).as_bytes());
+            }
+        }
+        match self.privacy {
+            WhatPriv::NoPriv => out.extend(b" none -"),
+            WhatPriv::Aes => {
+                out.extend(b" aes ");
+                out.extend(hex::encode(self.priv_key.clone()).as_bytes());
+            }
+        }
         out.push(b'\n');
         out
     }
 
+    /// Highest security level the user's protocols can provide.
+    ///
+    /// Same scale as Perm: 1 noAuthNoPriv, 2 authNoPriv, 3 authPriv.
+    pub fn security_level(&self) -> u8 {
+        match (self.what, self.privacy) {
+            (WhatHash::NoAuth, _) => 1,
+            (_, WhatPriv::NoPriv) => 2,
+            _ => 3,
+        }
+    }
+
+    /// A copy of the user with no access, to secure messages the agent sends from another thread.
+    pub fn to_sender(&self) -> User<'static> {
+        User {
+            what: self.what,
+            privacy: self.privacy,
+            group: self.group.clone(),
+            perm: &NO_ACCESS,
+            name: self.name.clone(),
+            auth_key: self.auth_key.clone(),
+            priv_key: self.priv_key.clone(),
+            k1: self.k1,
+            k2: self.k2,
+        }
+    }
+
     /// Calculate the HMAC checksum from the data.
     ///
     /// Will need to be templated or parameterized to support RFC7630
     pub fn auth_from_bytes(&self, data: &[u8]) -> Vec<u8> {
         let mut hasher = match self.what {
+            WhatHash::NoAuth => return vec![],
             WhatHash::Sha1 => Sha1::new(),
         };
         hasher.update(self.k1);
         hasher.update(data);
         let mid = hasher.finalize();
         let mut hash2 = match self.what {
-            WhatHash::Sha1 => Sha1::new(),
+            WhatHash::NoAuth | WhatHash::Sha1 => Sha1::new(),
         };
         hash2.update(self.k2);
         hash2.update(mid);
@@ -139,6 +208,7 @@ impl<'a> User<'a> {
             temp.push(*item);
         }
         let mut hasher = match self.what {
+            WhatHash::NoAuth => return vec![],
             WhatHash::Sha1 => Sha1::new(),
         };
         hasher.update(temp);
@@ -151,9 +221,45 @@ impl<'a> User<'a> {
     }
 }
 
+impl User<'static> {
+    /// A user of another engine, for messages the agent sends rather than receives.
+    ///
+    /// The line is as in users.txt without the group, "name hash authkey privacy privkey",
+    /// with the keys localized to the other engine's snmpEngineID. The user has no access here.
+    pub fn remote_from_str(s: &str) -> Result<Self, ParseUserError> {
+        let re =
+            Regex::new(r"^(?<name>[^ ]+) (?<hash>[^ ]+) (?<ak>[^ ]+) (?<priv>[^ ]+) (?<pk>[^ ]+)$")
+                .unwrap();
+        let captures = re.captures(s).ok_or(ParseUserError)?;
+        User::from_captures(&captures, &NO_ACCESS)
+    }
+}
+
+/// Authenticate message for usr, setting msgAuthenticationParameters, and return the digest.
+///
+/// RFC 3414 section 6.3.1: the digest is over the whole message, with the parameters zeroed.
+pub(crate) fn set_auth(message: &mut Message, usr: &User) -> Vec<u8> {
+    let r_sp: Result<USMSecurityParameters, Box<dyn Display>> =
+        message.decode_security_parameters(rasn::Codec::Ber);
+    if r_sp.is_err() {
+        return vec![];
+    }
+    let mut usp: USMSecurityParameters = r_sp.ok().expect("Errors caught above");
+    usp.authentication_parameters = Z12;
+    let _ = message.encode_security_parameters(rasn::Codec::Ber, &usp);
+    let buf = rasn::ber::encode(message).unwrap();
+
+    let auth = usr.auth_from_bytes(&buf);
+    usp.authentication_parameters = OctetString::from_slice(&auth);
+    let _ = message.encode_security_parameters(rasn::Codec::Ber, &usp);
+    auth
+}
+
 fn k1_from_ak(ak: &[u8]) -> [u8; 64] {
     let mut eak: [u8; 64] = [0; 64];
-    eak[..20].copy_from_slice(&ak[..20]);
+    if ak.len() >= 20 {
+        eak[..20].copy_from_slice(&ak[..20]);
+    }
     for i in &mut eak {
         // XOR with 0x36
         *i ^= 0x36;
@@ -163,7 +269,9 @@ fn k1_from_ak(ak: &[u8]) -> [u8; 64] {
 
 fn k2_from_ak(ak: &[u8]) -> [u8; 64] {
     let mut eak: [u8; 64] = [0; 64];
-    eak[..20].copy_from_slice(&ak[..20]);
+    if ak.len() >= 20 {
+        eak[..20].copy_from_slice(&ak[..20]);
+    }
     for i in &mut eak {
         // XOR with 0x5C
         *i ^= 0x5C;
@@ -222,6 +330,7 @@ impl<'a> Users<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::perms::View;
 
     fn perms() -> Vec<Perm> {
         vec![Perm {
@@ -229,6 +338,9 @@ mod tests {
             write: true,
             security_level: 1u8, // Just flags
             group_name: "test".as_bytes().to_vec(),
+            context: vec![],
+            read_view: View::default(),
+            write_view: View::default(),
         }]
     }
 
@@ -249,6 +361,32 @@ mod tests {
         assert!(u.is_err());
     }
 
+    #[test]
+    fn priv_without_auth() {
+        let s = "test test none - aes 0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c";
+        let pv = perms();
+        let u = User::from_str(s, &pv);
+        assert!(u.is_err());
+    }
+
+    #[test]
+    fn security_levels() {
+        let pv = perms();
+        let s = "test test none - none -";
+        let u = User::from_str(s, &pv).unwrap();
+        assert_eq!(u.security_level(), 1);
+        let b = u.to_bytes();
+        assert_eq!(s.as_bytes(), b.split_last().unwrap().1);
+        let s = "test test sha1 0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b none -";
+        let u = User::from_str(s, &pv).unwrap();
+        assert_eq!(u.security_level(), 2);
+        let b = u.to_bytes();
+        assert_eq!(s.as_bytes(), b.split_last().unwrap().1);
+        let s ="test test sha1 0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b aes 0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c";
+        let u = User::from_str(s, &pv).unwrap();
+        assert_eq!(u.security_level(), 3);
+    }
+
     #[test]
     fn rfc2202_case1_test() {
         let s ="test test sha1 0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b aes 0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c0c";
@@ -290,6 +428,7 @@ mod tests {
         let p = perms();
         let u = User {
             what: WhatHash::Sha1,
+            privacy: WhatPriv::NoPriv,
             group: vec![0, 1],
             perm: &p[0],
             name: b"test".to_vec(),
@@ -0,0 +1,563 @@
+//! View-based Access Control Model, RFC 3415
+//!
+//! The vacmSecurityToGroupTable, vacmAccessTable and vacmViewTreeFamilyTable are held as
+//! keepers in the OidMap, so managers can change them with RowStatus. Every change is written
+//! to a file under StoragePath. Vacm shares the same tables with the Agent, which asks it for
+//! the Perm to apply to each request, in place of the user's group from groups.txt.
+//!
+//! The first time the agent runs, the tables are seeded from groups.txt, views.txt and users.txt.
+//! Each user is mapped to their group, under both USM and the Transport Security Model so a
+//! certificate mapped to the same securityName gets the same group. Each group gets an access
+//! entry for its context, for any security model, with read and write views named "group-read"
+//! and "group-write". A group with no views.txt entries for an access gets a view of the whole
+//! 1.3 subtree. After that the stored tables are used, and the files only matter for users and
+//! group names.
+//!
+//! vacmAccessTable is indexed by vacmGroupName, which is a column of vacmSecurityToGroupTable.
+//! It is kept in an extra column 10, which is hidden from managers.
+//!
+use crate::config::Config;
+use crate::keeper::{Access, OType};
+use crate::oidmap::OidMap;
+use crate::perms::{Perm, View, ViewEntry};
+use crate::scalar::ScalarMemOid;
+use crate::table::{
+    int_col, oid_col, str_col, PersistentTable, SharedTable, SharedTableKeeper, TableMemOid,
+    ROW_STATUS_ACTIVE,
+};
+use crate::usm::Users;
+use log::{debug, warn};
+use rasn::types::{Integer, ObjectIdentifier, OctetString};
+use rasn_smi::v2::{ObjectSyntax, SimpleSyntax};
+use std::sync::{Arc, Mutex};
+
+const ARC_VACM_SECURITY_TO_GROUP_TABLE: [u32; 9] = [1, 3, 6, 1, 6, 3, 16, 1, 2];
+const ARC_VACM_ACCESS_TABLE: [u32; 9] = [1, 3, 6, 1, 6, 3, 16, 1, 4];
+const ARC_VACM_VIEW_SPIN_LOCK: [u32; 10] = [1, 3, 6, 1, 6, 3, 16, 1, 5, 1];
+const ARC_VACM_VIEW_TREE_FAMILY_TABLE: [u32; 10] = [1, 3, 6, 1, 6, 3, 16, 1, 5, 2];
+
+/// The whole tree, for groups with no view entries in views.txt
+const ARC_EVERYTHING: [u32; 2] = [1, 3];
+
+const SECURITY_MODEL_ANY: u32 = 0;
+/// User-based Security Model, RFC 3414
+pub const SECURITY_MODEL_USM: u32 = 3;
+/// Transport Security Model, RFC 5591
+pub const SECURITY_MODEL_TSM: u32 = 4;
+const CONTEXT_MATCH_EXACT: u32 = 1;
+const CONTEXT_MATCH_PREFIX: u32 = 2;
+const VIEW_INCLUDED: u32 = 1;
+const VIEW_EXCLUDED: u32 = 2;
+const STORAGE_NON_VOLATILE: u32 = 3;
+
+// Columns, 1 based, of the three tables
+const GROUP_MODEL_COL: usize = 1;
+const GROUP_SECURITY_NAME_COL: usize = 2;
+const GROUP_NAME_COL: usize = 3;
+const GROUP_STATUS_COL: usize = 5;
+const ACCESS_PREFIX_COL: usize = 1;
+const ACCESS_MODEL_COL: usize = 2;
+const ACCESS_LEVEL_COL: usize = 3;
+const ACCESS_MATCH_COL: usize = 4;
+const ACCESS_READ_VIEW_COL: usize = 5;
+const ACCESS_WRITE_VIEW_COL: usize = 6;
+const ACCESS_STATUS_COL: usize = 9;
+const ACCESS_GROUP_COL: usize = 10;
+const VIEW_NAME_COL: usize = 1;
+const VIEW_SUBTREE_COL: usize = 2;
+const VIEW_MASK_COL: usize = 3;
+const VIEW_TYPE_COL: usize = 4;
+const VIEW_STATUS_COL: usize = 6;
+
+fn simple_from_int(value: u32) -> ObjectSyntax {
+    ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(value)))
+}
+
+fn simple_from_str(value: &[u8]) -> ObjectSyntax {
+    ObjectSyntax::Simple(SimpleSyntax::String(OctetString::from_slice(value)))
+}
+
+fn simple_from_vec(value: &[u32]) -> ObjectSyntax {
+    ObjectSyntax::Simple(SimpleSyntax::ObjectId(
+        ObjectIdentifier::new(value.to_vec()).unwrap(),
+    ))
+}
+
+/// Access decisions from the VACM tables, RFC 3415 section 3.2
+#[derive(Clone)]
+pub struct Vacm {
+    groups: SharedTable,
+    access: SharedTable,
+    views: SharedTable,
+}
+
+impl Vacm {
+    /// Permissions for a securityName under a security model, at the security level in flags, in a context.
+    ///
+    /// If no group or access entry matches, the Perm allows nothing, which gives an
+    /// authorizationError. The context is always allowed, as the access entry has matched it.
+    pub fn perm(&self, model: u32, security_name: &[u8], flags: u8, context: &[u8]) -> Perm {
+        let level = 1 + (flags & 1) + (flags & 2);
+        let mut perm = Perm {
+            read: false,
+            write: false,
+            security_level: level,
+            group_name: vec![],
+            context: context.to_vec(),
+            read_view: View::default(),
+            write_view: View::default(),
+        };
+        let Some(group_name) = self.group(model, security_name) else {
+            debug!("No VACM group for {security_name:?}");
+            return perm;
+        };
+        let access = self.access.lock().unwrap();
+        // Preference order from RFC 3415 section 4, step 4 of isAccessAllowed
+        let best = access
+            .table()
+            .rows()
+            .filter(|row| {
+                int_col(row, ACCESS_STATUS_COL) == ROW_STATUS_ACTIVE
+                    && str_col(row, ACCESS_GROUP_COL) == group_name.as_slice()
+                    && [SECURITY_MODEL_ANY, model].contains(&int_col(row, ACCESS_MODEL_COL))
+                    && int_col(row, ACCESS_LEVEL_COL) <= u32::from(level)
+                    && match int_col(row, ACCESS_MATCH_COL) {
+                        CONTEXT_MATCH_PREFIX => {
+                            context.starts_with(str_col(row, ACCESS_PREFIX_COL))
+                        }
+                        _ => context == str_col(row, ACCESS_PREFIX_COL),
+                    }
+            })
+            .max_by_key(|row| {
+                (
+                    int_col(row, ACCESS_MODEL_COL) == model,
+                    context == str_col(row, ACCESS_PREFIX_COL),
+                    str_col(row, ACCESS_PREFIX_COL).len(),
+                    int_col(row, ACCESS_LEVEL_COL),
+                )
+            });
+        let Some(row) = best else {
+            debug!("No VACM access entry for group {group_name:?}");
+            perm.group_name = group_name;
+            return perm;
+        };
+        perm.security_level = int_col(row, ACCESS_LEVEL_COL).try_into().unwrap_or(3);
+        perm.read_view = self.view(str_col(row, ACCESS_READ_VIEW_COL));
+        perm.write_view = self.view(str_col(row, ACCESS_WRITE_VIEW_COL));
+        // An empty View allows everything, but a missing VACM view allows nothing
+        perm.read = !perm.read_view.entries.is_empty();
+        perm.write = !perm.write_view.entries.is_empty();
+        perm.group_name = group_name;
+        perm
+    }
+
+    /// Group for a security name, from active rows of vacmSecurityToGroupTable
+    fn group(&self, model: u32, security_name: &[u8]) -> Option<Vec<u8>> {
+        let groups = self.groups.lock().unwrap();
+        let group = groups
+            .table()
+            .rows()
+            .find(|row| {
+                int_col(row, GROUP_STATUS_COL) == ROW_STATUS_ACTIVE
+                    && int_col(row, GROUP_MODEL_COL) == model
+                    && str_col(row, GROUP_SECURITY_NAME_COL) == security_name
+            })
+            .map(|row| str_col(row, GROUP_NAME_COL).to_vec());
+        group
+    }
+
+    /// View family entries for a view name, from active rows of vacmViewTreeFamilyTable
+    fn view(&self, name: &[u8]) -> View {
+        if name.is_empty() {
+            return View::default();
+        }
+        let views = self.views.lock().unwrap();
+        let entries = views
+            .table()
+            .rows()
+            .filter(|row| {
+                int_col(row, VIEW_STATUS_COL) == ROW_STATUS_ACTIVE
+                    && str_col(row, VIEW_NAME_COL) == name
+            })
+            .map(|row| ViewEntry {
+                subtree: oid_col(row, VIEW_SUBTREE_COL),
+                mask: str_col(row, VIEW_MASK_COL).to_vec(),
+                included: int_col(row, VIEW_TYPE_COL) == VIEW_INCLUDED,
+            })
+            .collect();
+        View { entries }
+    }
+}
+
+fn groups_table() -> TableMemOid {
+    let base = ObjectIdentifier::new(&ARC_VACM_SECURITY_TO_GROUP_TABLE).unwrap();
+    let mut table = TableMemOid::new(
+        vec![
+            simple_from_int(SECURITY_MODEL_USM),
+            simple_from_str(b""),
+            simple_from_str(b""),
+            simple_from_int(STORAGE_NON_VOLATILE),
+            simple_from_int(ROW_STATUS_ACTIVE),
+        ],
+        5,
+        &base,
+        vec![
+            OType::Integer,
+            OType::String,
+            OType::String,
+            OType::Integer,
+            OType::RowStatus,
+        ],
+        vec![
+            Access::NoAccess,
+            Access::NoAccess,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+        ],
+        vec![GROUP_MODEL_COL, GROUP_SECURITY_NAME_COL],
+        false,
+    );
+    table.set_required_cols(vec![GROUP_NAME_COL]);
+    table
+}
+
+fn access_table() -> TableMemOid {
+    let base = ObjectIdentifier::new(&ARC_VACM_ACCESS_TABLE).unwrap();
+    TableMemOid::new(
+        vec![
+            simple_from_str(b""),
+            simple_from_int(SECURITY_MODEL_USM),
+            simple_from_int(1),
+            simple_from_int(CONTEXT_MATCH_EXACT),
+            simple_from_str(b""),
+            simple_from_str(b""),
+            simple_from_str(b""),
+            simple_from_int(STORAGE_NON_VOLATILE),
+            simple_from_int(ROW_STATUS_ACTIVE),
+            simple_from_str(b""),
+        ],
+        10,
+        &base,
+        vec![
+            OType::String,
+            OType::Integer,
+            OType::Integer,
+            OType::Integer,
+            OType::String,
+            OType::String,
+            OType::String,
+            OType::Integer,
+            OType::RowStatus,
+            OType::String,
+        ],
+        vec![
+            Access::NoAccess,
+            Access::NoAccess,
+            Access::NoAccess,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::NoAccess,
+        ],
+        vec![
+            ACCESS_GROUP_COL,
+            ACCESS_PREFIX_COL,
+            ACCESS_MODEL_COL,
+            ACCESS_LEVEL_COL,
+        ],
+        false,
+    )
+}
+
+fn views_table() -> TableMemOid {
+    let base = ObjectIdentifier::new(&ARC_VACM_VIEW_TREE_FAMILY_TABLE).unwrap();
+    TableMemOid::new(
+        vec![
+            simple_from_str(b""),
+            simple_from_vec(&[0, 0]),
+            simple_from_str(b""),
+            simple_from_int(VIEW_INCLUDED),
+            simple_from_int(STORAGE_NON_VOLATILE),
+            simple_from_int(ROW_STATUS_ACTIVE),
+        ],
+        6,
+        &base,
+        vec![
+            OType::String,
+            OType::ObjectId,
+            OType::String,
+            OType::Integer,
+            OType::Integer,
+            OType::RowStatus,
+        ],
+        vec![
+            Access::NoAccess,
+            Access::NoAccess,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+            Access::ReadCreate,
+        ],
+        vec![VIEW_NAME_COL, VIEW_SUBTREE_COL],
+        false,
+    )
+}
+
+/// Rows for a view named name, from a groups.txt / views.txt View
+fn seed_view(name: &[u8], view: &View, rows: &mut Vec<Vec<ObjectSyntax>>) {
+    if view.entries.is_empty() {
+        rows.push(vec![
+            simple_from_str(name),
+            simple_from_vec(&ARC_EVERYTHING),
+            simple_from_str(b""),
+            simple_from_int(VIEW_INCLUDED),
+            simple_from_int(STORAGE_NON_VOLATILE),
+            simple_from_int(ROW_STATUS_ACTIVE),
+        ]);
+    }
+    for entry in &view.entries {
+        let kind = if entry.included {
+            VIEW_INCLUDED
+        } else {
+            VIEW_EXCLUDED
+        };
+        rows.push(vec![
+            simple_from_str(name),
+            simple_from_vec(&entry.subtree),
+            simple_from_str(&entry.mask),
+            simple_from_int(kind),
+            simple_from_int(STORAGE_NON_VOLATILE),
+            simple_from_int(ROW_STATUS_ACTIVE),
+        ]);
+    }
+}
+
+/// Table rows equivalent to the groups.txt, views.txt and users.txt permissions
+fn seed(perms: &[Perm], users: &Users) -> [Vec<Vec<ObjectSyntax>>; 3] {
+    let mut groups = vec![];
+    for user in &users.users {
+        for model in [SECURITY_MODEL_USM, SECURITY_MODEL_TSM] {
+            groups.push(vec![
+                simple_from_int(model),
+                simple_from_str(&user.name),
+                simple_from_str(&user.perm.group_name),
+                simple_from_int(STORAGE_NON_VOLATILE),
+                simple_from_int(ROW_STATUS_ACTIVE),
+            ]);
+        }
+    }
+    let mut access = vec![];
+    let mut views = vec![];
+    for perm in perms {
+        let (prefix, context_match) = match perm.context.strip_suffix(b"*") {
+            Some(prefix) => (prefix, CONTEXT_MATCH_PREFIX),
+            None => (perm.context.as_slice(), CONTEXT_MATCH_EXACT),
+        };
+        let mut read_name = vec![];
+        if perm.read {
+            read_name = [perm.group_name.as_slice(), b"-read"].concat();
+            seed_view(&read_name, &perm.read_view, &mut views);
+        }
+        let mut write_name = vec![];
+        if perm.write {
+            write_name = [perm.group_name.as_slice(), b"-write"].concat();
+            seed_view(&write_name, &perm.write_view, &mut views);
+        }
+        access.push(vec![
+            simple_from_str(prefix),
+            simple_from_int(SECURITY_MODEL_ANY),
+            simple_from_int(perm.security_level.into()),
+            simple_from_int(context_match),
+            simple_from_str(&read_name),
+            simple_from_str(&write_name),
+            simple_from_str(b""),
+            simple_from_int(STORAGE_NON_VOLATILE),
+            simple_from_int(ROW_STATUS_ACTIVE),
+            simple_from_str(&perm.group_name),
+        ]);
+    }
+    [groups, access, views]
+}
+
+/// Create the VACM keepers, add them to oid_map, and return the Vacm to give to the Agent.
+///
+/// Tables are loaded from StoragePath if they have been saved, otherwise seeded from
+/// perms and users.
+pub fn load_vacm(oid_map: &mut OidMap, config: &Config, perms: &[Perm], users: &Users) -> Vacm {
+    let [group_rows, access_rows, view_rows] = seed(perms, users);
+    let mut shared = vec![];
+    for (name, table, rows) in [
+        ("vacm_security_to_group", groups_table(), group_rows),
+        ("vacm_access", access_table(), access_rows),
+        ("vacm_view_tree_family", views_table(), view_rows),
+    ] {
+        let file_name = config.storage_path.clone() + "/" + name;
+        let mut ptable = PersistentTable::new(table, file_name);
+        if ptable.load().is_ok() {
+            debug!("{name} reloaded from storage");
+        } else {
+            warn!("{name} seeded from groups and users - load from storage failed");
+            ptable.set_data(rows);
+        }
+        shared.push(Arc::new(Mutex::new(ptable)));
+    }
+    let vacm = Vacm {
+        groups: shared[0].clone(),
+        access: shared[1].clone(),
+        views: shared[2].clone(),
+    };
+    for (arc, table, hidden_col) in [
+        (&ARC_VACM_SECURITY_TO_GROUP_TABLE[..], &vacm.groups, None),
+        (
+            &ARC_VACM_ACCESS_TABLE[..],
+            &vacm.access,
+            Some(ACCESS_GROUP_COL),
+        ),
+        (&ARC_VACM_VIEW_TREE_FAMILY_TABLE[..], &vacm.views, None),
+    ] {
+        let keeper = SharedTableKeeper::new(table.clone(), arc.len(), hidden_col);
+        oid_map.push(
+            ObjectIdentifier::new(arc.to_vec()).unwrap(),
+            Box::new(keeper),
+        );
+    }
+    let spin_lock = ScalarMemOid::new(
+        ObjectSyntax::Simple(SimpleSyntax::Integer(Integer::from(0))),
+        OType::TestAndIncr,
+        Access::ReadWrite,
+    );
+    oid_map.push(
+        ObjectIdentifier::new(&ARC_VACM_VIEW_SPIN_LOCK).unwrap(),
+        Box::new(spin_lock),
+    );
+    vacm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keeper::OidErr;
+    use crate::usm::User;
+    use rasn_snmp::v3::VarBindValue;
+    use std::str::FromStr;
+
+    const USER_LINE: &str = "guest1 guest sha1 0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b0b none -";
+    fn fixture(perms: &Vec<Perm>, storage: &str) -> (OidMap, Vacm) {
+        let _ = std::fs::remove_dir_all(storage);
+        std::fs::create_dir_all(storage).unwrap();
+        let config = Config {
+            engine_id: OctetString::from_static(b"test"),
+            storage_path: storage.to_string(),
+            ..Default::default()
+        };
+        let mut users = Users::new();
+        users.users.push(User::from_str(USER_LINE, perms).unwrap());
+        let mut oid_map = OidMap::new();
+        let vacm = load_vacm(&mut oid_map, &config, perms, &users);
+        oid_map.sort();
+        (oid_map, vacm)
+    }
+
+    fn oid(arc: &[u32]) -> ObjectIdentifier {
+        ObjectIdentifier::new(arc.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn seeded_perms() {
+        let mut perms = vec![Perm::from_str("t f 2 guest").unwrap()];
+        perms[0].read_view.entries.push(ViewEntry {
+            subtree: vec![1, 3, 6, 1, 2, 1, 1],
+            mask: vec![],
+            included: true,
+        });
+        let (mut oid_map, vacm) = fixture(&perms, "/tmp/snmp-rust-vacm-seed");
+
+        let perm = vacm.perm(SECURITY_MODEL_USM, b"guest1", 1, b"");
+        assert!(perm.check(1, false, &oid(&[1, 3, 6, 1, 2, 1, 1, 5, 0])));
+        assert!(!perm.check(1, false, &oid(&[1, 3, 6, 1, 2, 1, 2, 1, 0])));
+        assert!(!perm.check(1, true, &oid(&[1, 3, 6, 1, 2, 1, 1, 5, 0])));
+        // Security level too low, unknown user, and unknown context
+        assert!(!vacm
+            .perm(SECURITY_MODEL_USM, b"guest1", 0, b"")
+            .check_access(0, false));
+        assert!(!vacm
+            .perm(SECURITY_MODEL_USM, b"nobody", 1, b"")
+            .check_access(1, false));
+        assert!(!vacm
+            .perm(SECURITY_MODEL_USM, b"guest1", 1, b"vrf1")
+            .check_access(1, false));
+        // The same name under TSM gets the same group, but not under another model
+        assert!(vacm.perm(SECURITY_MODEL_TSM, b"guest1", 1, b"").check(
+            1,
+            false,
+            &oid(&[1, 3, 6, 1, 2, 1, 1, 5, 0])
+        ));
+        assert!(!vacm.perm(2, b"guest1", 1, b"").check_access(1, false));
+
+        // The hidden group name column stays hidden
+        let okeep = oid_map.idx(oid_map.search(&oid(&ARC_VACM_ACCESS_TABLE)).unwrap());
+        let last = okeep
+            .get_next(oid(&[1, 3, 6, 1, 6, 3, 16, 1, 4, 1, 9, 0]))
+            .unwrap();
+        assert_eq!(last.name.get(10), Some(&9));
+        assert_eq!(okeep.get_next(last.name), Err(OidErr::OutOfRange));
+    }
+
+    #[test]
+    fn create_access_row() {
+        let perms = vec![Perm::from_str("t f 2 guest").unwrap()];
+        let (mut oid_map, vacm) = fixture(&perms, "/tmp/snmp-rust-vacm-create");
+        assert!(!vacm
+            .perm(SECURITY_MODEL_USM, b"guest1", 1, b"vrf1")
+            .check_access(1, false));
+
+        // guest may read, with the same view, in any context starting vrf
+        let mut index = vec![5];
+        index.extend(b"guest".map(u32::from));
+        index.push(3);
+        index.extend(b"vrf".map(u32::from));
+        index.extend([3, 2]);
+        let column = |col: u32| {
+            let mut arc = ARC_VACM_ACCESS_TABLE.to_vec();
+            arc.extend([1, col]);
+            arc.extend(&index);
+            oid(&arc)
+        };
+        let which = oid_map.search(&oid(&ARC_VACM_ACCESS_TABLE)).unwrap();
+        let okeep = oid_map.idx(which);
+        let changes = [
+            (column(9), simple_from_int(4)),
+            (column(4), simple_from_int(CONTEXT_MATCH_PREFIX)),
+            (column(5), simple_from_str(b"guest-read")),
+        ];
+        okeep.begin_transaction().unwrap();
+        for (name, value) in &changes {
+            okeep
+                .set(name.clone(), VarBindValue::Value(value.clone()))
+                .unwrap();
+        }
+        for (name, value) in &changes {
+            okeep
+                .test(name.clone(), &VarBindValue::Value(value.clone()))
+                .unwrap();
+        }
+        okeep.commit().unwrap();
+
+        let perm = vacm.perm(SECURITY_MODEL_USM, b"guest1", 1, b"vrf1");
+        assert!(perm.check_access(1, false));
+        assert!(perm.check_context(b"vrf1"));
+        assert!(perm.check(1, false, &oid(&[1, 3, 6, 1, 2, 1, 1, 5, 0])));
+
+        // Reloaded from storage rather than seeded again
+        let mut reloaded = PersistentTable::new(
+            access_table(),
+            "/tmp/snmp-rust-vacm-create/vacm_access".to_string(),
+        );
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.table().rows().count(), 2);
+    }
+}